@@ -1,5 +1,5 @@
 use crate::{
-    data_structures::{Comm, CommRandomness, Proof, ProvingKey},
+    data_structures::{Comm, CommRandomness, CommitterKey, Proof, ProvingKey},
     CPGroth16, MultiStageConstraintSynthesizer, MultiStageConstraintSystem,
 };
 
@@ -9,7 +9,7 @@ use ark_ec::{pairing::Pairing, AffineRepr, CurveGroup, VariableBaseMSM};
 use ark_ff::UniformRand;
 use ark_groth16::{r1cs_to_qap::R1CSToQAP, Proof as ProofWithoutComms};
 use ark_relations::r1cs::{OptimizationGoal, SynthesisError};
-use ark_std::{end_timer, rand::Rng, start_timer};
+use ark_std::{end_timer, rand::Rng, start_timer, vec::Vec};
 
 /// A struct that sequentially runs [`InputAllocators`] and commits to the variables allocated therein
 pub struct CommitmentBuilder<'a, C, E, QAP>
@@ -49,8 +49,16 @@ where
         }
     }
 
-    // TODO: Make a nicer way for committers to check their idea of the assignments with the actual
-    // given assignments. This is important for possibly opening the commitments later.
+    /// Returns the witness assignment that was committed to in the given stage. Together with the
+    /// commitment randomness, this is the opening of that stage's commitment. See
+    /// [`CommitterKey::verify_opening`].
+    pub fn stage_witness(&self, stage: usize) -> Vec<E::ScalarField> {
+        assert!(
+            stage < self.cur_stage,
+            "stage {stage} has not been committed to"
+        );
+        self.cs.stage_witness_assignment(stage)
+    }
 
     pub fn commit(
         &mut self,
@@ -68,33 +76,19 @@ where
         // Get *all* the witness assignments from the underlying constraint system
         let current_witness = self.cs.current_stage_witness_assignment();
 
-        // Pick out the instance values that resulted from this allocator. Also pick the associated
-        // group elements for calculating the commitment. These better be the same length.
-
-        // The below unwrap is permitted because `run_allocator` is guaranteed to add a range to
-        // the list (though it may be empty)
-        let current_ck = &self
-            .pk
-            .ck
-            .deltas_abc_g
-            .get(self.cur_stage)
-            .expect("no more values left in committing key");
-
-        assert_eq!(current_witness.len(), current_ck.len());
-
+        // Commit to the witness values that resulted from this stage, using this stage's group
+        // elements. These better be the same length.
         let randomness = E::ScalarField::rand(rng);
-        // Compute the commitment.
         let commitment =
-            // First compute [J(s)/ηᵢ]₁ where i is the current stage.
-            E::G1::msm(current_ck, &current_witness).unwrap()
-            // Then add in the randomizer
-            + (self.pk.ck.last_delta_g * randomness);
+            self.pk
+                .ck
+                .open_commitment(self.cur_stage, &current_witness, &randomness)?;
 
         self.cur_stage += 1;
         end_timer!(commit_timer);
 
         // Return the commitment and the randomness
-        Ok((commitment.into(), randomness))
+        Ok((commitment, randomness))
     }
 
     pub fn prove(
@@ -121,3 +115,44 @@ where
         })
     }
 }
+
+impl<E: Pairing> CommitterKey<E> {
+    /// Computes the commitment to the given stage's witness assignment with the given randomness.
+    /// Errors if there is no such stage or if the witness is the wrong length for it.
+    pub fn open_commitment(
+        &self,
+        stage: usize,
+        witness: &[E::ScalarField],
+        randomness: &CommRandomness<E>,
+    ) -> Result<Comm<E>, SynthesisError> {
+        let stage_ck = self
+            .deltas_abc_g
+            .get(stage)
+            .ok_or(SynthesisError::MalformedVerifyingKey)?;
+        if witness.len() != stage_ck.len() {
+            return Err(SynthesisError::Unsatisfiable);
+        }
+
+        let commitment =
+            // First compute [J(s)/ηᵢ]₁ where i is the stage
+            E::G1::msm(stage_ck, witness).unwrap()
+            // Then add in the randomizer
+            + (self.last_delta_g * randomness);
+
+        Ok(commitment.into_affine())
+    }
+
+    /// Checks that `com` is a commitment to the given stage's witness assignment with the given
+    /// randomness. To check that a proof was made wrt this witness, pass `proof.ds[stage]` as
+    /// `com`.
+    pub fn verify_opening(
+        &self,
+        stage: usize,
+        com: &Comm<E>,
+        witness: &[E::ScalarField],
+        randomness: &CommRandomness<E>,
+    ) -> bool {
+        self.open_commitment(stage, witness, randomness)
+            .is_ok_and(|expected| &expected == com)
+    }
+}
@@ -74,11 +74,11 @@ impl<F: Field> MultiStageConstraintSystem<F> {
         Ok(())
     }
 
-    // /// Returns the witness variables allocated in stage `i`.
-    // pub fn witness_variables_for_stage(&self, i: usize) -> &[F] {
-    //     let range = self.variable_range_for_stage[i];
-    //     &self.cs.witness_variables()[range]
-    // }
+    /// Returns the assignments to witness variables allocated in stage `i`.
+    pub fn stage_witness_assignment(&self, i: usize) -> Vec<F> {
+        let range = self.variable_range_for_stage[i].clone();
+        self.cs.borrow().unwrap().witness_assignment[range].to_vec()
+    }
 
     pub fn num_instance_variables(&self) -> usize {
         self.cs.num_instance_variables()
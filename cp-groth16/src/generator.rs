@@ -14,9 +14,49 @@ use ark_std::{cfg_into_iter, cfg_iter, end_timer, rand::Rng, start_timer};
 #[cfg(feature = "parallel")]
 use rayon::prelude::*;
 
+/// The toxic waste and group generators that a set of CRSs can have in common. Every CRS generated
+/// with the same `SharedTrapdoor` has the same α, β, and γ, and thus the same `e(α, β)`. Only the
+/// deltas and the circuit-specific elements differ. This MUST be dropped once the CRSs are made.
+pub struct SharedTrapdoor<E: Pairing> {
+    alpha: E::ScalarField,
+    beta: E::ScalarField,
+    gamma: E::ScalarField,
+    g1_generator: E::G1,
+    g2_generator: E::G2,
+}
+
+impl<E: Pairing> SharedTrapdoor<E> {
+    /// Samples fresh toxic waste and group generators
+    pub fn rand(rng: &mut impl Rng) -> Self {
+        SharedTrapdoor {
+            alpha: E::ScalarField::rand(rng),
+            beta: E::ScalarField::rand(rng),
+            gamma: E::ScalarField::rand(rng),
+            g1_generator: E::G1::rand(rng),
+            g2_generator: E::G2::rand(rng),
+        }
+    }
+}
+
 /// Create parameters for a circuit, given some toxic waste, R1CS to QAP calculator and group generators
 pub fn generate_parameters<C, E, QAP>(
+    circuit: C,
+    rng: &mut impl Rng,
+) -> Result<ProvingKey<E>, SynthesisError>
+where
+    C: MultiStageConstraintSynthesizer<E::ScalarField>,
+    E: Pairing,
+    QAP: R1CSToQAP,
+{
+    let trapdoor = SharedTrapdoor::rand(rng);
+    generate_parameters_with_trapdoor::<C, E, QAP>(circuit, &trapdoor, rng)
+}
+
+/// Like [`generate_parameters`], but takes α, β, γ, and the group generators from the given
+/// trapdoor. Only the deltas and the QAP evaluation point are sampled fresh.
+pub fn generate_parameters_with_trapdoor<C, E, QAP>(
     mut circuit: C,
+    trapdoor: &SharedTrapdoor<E>,
     rng: &mut impl Rng,
 ) -> Result<ProvingKey<E>, SynthesisError>
 where
@@ -25,16 +65,17 @@ where
     QAP: R1CSToQAP,
 {
     type D<F> = GeneralEvaluationDomain<F>;
-    let alpha = E::ScalarField::rand(rng);
-    let beta = E::ScalarField::rand(rng);
-    let gamma = E::ScalarField::rand(rng);
+    let SharedTrapdoor {
+        alpha,
+        beta,
+        gamma,
+        g1_generator,
+        g2_generator,
+    } = *trapdoor;
     let deltas = (0..circuit.total_num_stages())
         .map(|_| E::ScalarField::rand(rng))
         .collect::<Vec<_>>();
 
-    let g1_generator = E::G1::rand(rng);
-    let g2_generator = E::G2::rand(rng);
-
     let setup_time = start_timer!(|| "CPGroth16::Generator");
     let mut mscs = MultiStageConstraintSystem::default();
     mscs.set_optimization_goal(OptimizationGoal::Constraints);
@@ -33,8 +33,8 @@ mod tests {
 
     use crate::{
         committer::CommitmentBuilder,
-        generator::generate_parameters,
-        verifier::{prepare_verifying_key, verify_proof},
+        generator::{generate_parameters, generate_parameters_with_trapdoor, SharedTrapdoor},
+        verifier::{prepare_verifying_key, verify_proof, verify_proofs_batch},
         MultiStageConstraintSynthesizer, MultiStageConstraintSystem,
     };
 
@@ -178,6 +178,122 @@ mod tests {
             let pvk = prepare_verifying_key(&pk.vk());
             assert!(verify_proof(&pvk, &proof, &inputs).unwrap());
         }
+
+        // Open the stage 0 commitment and check it against the proof
+        #[test]
+        fn commitment_opening_test() {
+            let mut rng = test_rng();
+
+            let degree = 10;
+            let mut polynomial = (0..degree).map(|_| F::rand(&mut rng)).collect::<Vec<_>>();
+            polynomial.push(F::one());
+            let circuit = PolyEvalCircuit::new(polynomial.clone());
+
+            let pk = generate_parameters::<_, E, QAP>(circuit.clone(), &mut rng).unwrap();
+
+            let mut cb = CommitmentBuilder::<_, E, QAP>::new(circuit, &pk);
+            let (comm, rand) = cb.commit(&mut rng).unwrap();
+            let witness = cb.stage_witness(0);
+            cb.circuit.add_point(F::rand(&mut rng));
+            let proof = cb.prove(&[comm], &[rand], &mut rng).unwrap();
+
+            // The opening matches the commitment in the proof
+            assert_eq!(pk.ck.open_commitment(0, &witness, &rand).unwrap(), comm);
+            assert!(pk.ck.verify_opening(0, &proof.ds[0], &witness, &rand));
+
+            // A wrong witness, wrong randomness, or wrong stage does not
+            let mut bad_witness = witness.clone();
+            bad_witness[0] += F::one();
+            assert!(!pk.ck.verify_opening(0, &proof.ds[0], &bad_witness, &rand));
+            assert!(!pk
+                .ck
+                .verify_opening(0, &proof.ds[0], &witness, &(rand + F::one())));
+            assert!(!pk.ck.verify_opening(1, &proof.ds[0], &witness, &rand));
+        }
+
+        // Batch-verify several proofs, then make sure a bad one gets caught
+        #[test]
+        fn batch_verify_test() {
+            let mut rng = test_rng();
+
+            let degree = 10;
+            let mut polynomial = (0..degree).map(|_| F::rand(&mut rng)).collect::<Vec<_>>();
+            polynomial.push(F::one());
+            let circuit = PolyEvalCircuit::new(polynomial.clone());
+
+            let pk = generate_parameters::<_, E, QAP>(circuit.clone(), &mut rng).unwrap();
+            let pvk = prepare_verifying_key(&pk.vk());
+
+            // Make a handful of proofs at different points
+            let num_proofs = 5;
+            let (proofs, inputs): (Vec<_>, Vec<_>) = (0..num_proofs)
+                .map(|_| {
+                    let mut cb = CommitmentBuilder::<_, E, QAP>::new(circuit.clone(), &pk);
+                    let (comm, rand) = cb.commit(&mut rng).unwrap();
+                    let point = F::rand(&mut rng);
+                    cb.circuit.add_point(point);
+                    let inputs = vec![point, cb.circuit.evaluation.unwrap()];
+                    let proof = cb.prove(&[comm], &[rand], &mut rng).unwrap();
+                    (proof, inputs)
+                })
+                .unzip();
+
+            let instances = proofs
+                .iter()
+                .zip(&inputs)
+                .map(|(proof, inputs)| (&pvk, proof, inputs.as_slice()))
+                .collect::<Vec<_>>();
+            assert_eq!(verify_proofs_batch(&instances, &mut rng).unwrap(), None);
+
+            // Now give proof 3 the wrong public inputs
+            let bad_inputs = [inputs[3][0], inputs[3][1] + F::one()];
+            let mut instances = instances;
+            instances[3].2 = &bad_inputs;
+            assert_eq!(verify_proofs_batch(&instances, &mut rng).unwrap(), Some(3));
+        }
+
+        // Make two CRSs from the same trapdoor, and check that they share alpha and beta but that
+        // proofs still verify under their own keys only
+        #[test]
+        fn shared_trapdoor_test() {
+            let mut rng = test_rng();
+
+            let make_circuit = |rng: &mut ark_std::rand::rngs::StdRng| {
+                let degree = 10;
+                let mut polynomial = (0..degree).map(|_| F::rand(rng)).collect::<Vec<_>>();
+                polynomial.push(F::one());
+                PolyEvalCircuit::new(polynomial)
+            };
+            let circuits = [make_circuit(&mut rng), make_circuit(&mut rng)];
+
+            let trapdoor = SharedTrapdoor::<E>::rand(&mut rng);
+            let pks = circuits
+                .iter()
+                .map(|circuit| {
+                    generate_parameters_with_trapdoor::<_, E, QAP>(
+                        circuit.clone(),
+                        &trapdoor,
+                        &mut rng,
+                    )
+                    .unwrap()
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(pks[0].vk.alpha_g, pks[1].vk.alpha_g);
+            assert_eq!(pks[0].vk.beta_h, pks[1].vk.beta_h);
+            assert_eq!(pks[0].vk.gamma_h, pks[1].vk.gamma_h);
+            assert_ne!(pks[0].vk.deltas_h, pks[1].vk.deltas_h);
+
+            // Prove wrt the first key
+            let mut cb = CommitmentBuilder::<_, E, QAP>::new(circuits[0].clone(), &pks[0]);
+            let (comm, rand) = cb.commit(&mut rng).unwrap();
+            let point = F::rand(&mut rng);
+            cb.circuit.add_point(point);
+            let inputs = [point, cb.circuit.evaluation.unwrap()];
+            let proof = cb.prove(&[comm], &[rand], &mut rng).unwrap();
+
+            assert!(verify_proof(&prepare_verifying_key(&pks[0].vk()), &proof, &inputs).unwrap());
+            assert!(!verify_proof(&prepare_verifying_key(&pks[1].vk()), &proof, &inputs).unwrap());
+        }
     }
 
     mod single_stage_test {
@@ -2,6 +2,7 @@ use crate::data_structures::{PreparedVerifyingKey, Proof, VerifyingKey};
 
 use ark_ec::{pairing::Pairing, AffineRepr, CurveGroup};
 use ark_relations::r1cs::SynthesisError;
+use ark_std::{rand::Rng, vec::Vec};
 
 /// Prepare the verifying key `vk` for use in proof verification.
 pub fn prepare_verifying_key<E: Pairing>(vk: &VerifyingKey<E>) -> PreparedVerifyingKey<E> {
@@ -69,3 +70,79 @@ pub fn verify_proof<E: Pairing>(
     let prepared_inputs = prepare_inputs(&vk, public_inputs)?;
     verify_proof_with_prepared_inputs(vk, proof, &prepared_inputs)
 }
+
+/// An instance to batch-verify: a prepared verification key, a proof, and the proof's public inputs
+pub type BatchInstance<'a, E> = (
+    &'a PreparedVerifyingKey<E>,
+    &'a Proof<E>,
+    &'a [<E as Pairing>::ScalarField],
+);
+
+/// Verify many CP-Groth16 proofs at once. The proofs are combined with a random linear combination, so
+/// this costs one multi-Miller loop and one final exponentiation in total.
+///
+/// Returns `Ok(None)` if every proof verifies. If the batch check fails, the proofs are checked
+/// individually and `Ok(Some(i))` is returned, where `i` is the index of the first failing proof.
+pub fn verify_proofs_batch<E: Pairing>(
+    instances: &[BatchInstance<E>],
+    rng: &mut impl Rng,
+) -> Result<Option<usize>, SynthesisError> {
+    use core::iter::once;
+
+    let prepared_inputs = instances
+        .iter()
+        .map(|(pvk, _, public_inputs)| prepare_inputs(pvk, public_inputs))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Sample 128-bit coefficients for the linear combination. The first coefficient can be 1
+    // without loss of soundness
+    let coeffs = once(E::ScalarField::from(1u64))
+        .chain((1..instances.len()).map(|_| E::ScalarField::from(rng.gen::<u128>())))
+        .collect::<Vec<_>>();
+
+    // Scale the G1 side of every pairing by the proof's coefficient. The G2 side is unchanged
+    let mut lhs = Vec::new();
+    let mut rhs = Vec::new();
+    for (((pvk, proof, _), prepared_input), coeff) in
+        instances.iter().zip(&prepared_inputs).zip(&coeffs)
+    {
+        lhs.push(proof.a * coeff);
+        rhs.push(E::G2Prepared::from(proof.b));
+
+        lhs.push(*prepared_input * coeff);
+        rhs.push(pvk.neg_gamma_h.clone());
+
+        for (g, neg_delta_h) in proof.ds.iter().chain(once(&proof.c)).zip(&pvk.neg_deltas_h) {
+            lhs.push(*g * coeff);
+            rhs.push(neg_delta_h.clone());
+        }
+    }
+    let lhs = E::G1::normalize_batch(&lhs)
+        .into_iter()
+        .map(E::G1Prepared::from);
+
+    let qap = E::multi_miller_loop(lhs, rhs);
+    let test = E::final_exponentiation(qap).ok_or(SynthesisError::UnexpectedIdentity)?;
+
+    // The expected value is the same linear combination of the e(α, β) terms
+    let expected = instances
+        .iter()
+        .zip(&coeffs)
+        .map(|((pvk, _, _), coeff)| pvk.alpha_beta_gt * coeff)
+        .sum();
+
+    if test == expected {
+        return Ok(None);
+    }
+
+    // The batch failed. Find the culprit
+    for (i, ((pvk, proof, _), prepared_input)) in instances.iter().zip(&prepared_inputs).enumerate()
+    {
+        if !verify_proof_with_prepared_inputs(pvk, proof, prepared_input)? {
+            return Ok(Some(i));
+        }
+    }
+
+    // The batch check fails only if some proof fails, except with negligible probability
+    unreachable!("batch verification failed but every proof verified individually")
+}
@@ -1,13 +1,11 @@
 /// 分布式证明系统的基准测试程序。模拟协调者与工人之间的多阶段交互（阶段 0 与阶段 1），并最终聚合证明。
-use ark_ip_proofs::tipa::{Proof as AggProof, TIPA};
+use ark_ip_proofs::tipa::TIPA;
 use distributed_prover::{
-    aggregation::AggProvingKey,
+    aggregation::{tipp_size, AggProvingKey, HekatonProof},
     coordinator::{
         CoordinatorStage0State, FinalAggState, G16ProvingKeyGenerator, Stage0Request, Stage1Request,
     },
-    poseidon_util::{
-        gen_merkle_params, PoseidonTreeConfig as TreeConfig, PoseidonTreeConfigVar as TreeConfigVar,
-    },
+    poseidon_util::{gen_merkle_params, PoseidonTreeConfig, PoseidonTreeConfigVar},
     tree_hash_circuit::{MerkleTreeCircuit, MerkleTreeCircuitParams},
     util::G16ProvingKey,
     worker::{Stage0Response, Stage1Response},
@@ -21,17 +19,15 @@ use ark_std::{end_timer, start_timer};
 
 use criterion::{criterion_group, criterion_main, Criterion};
 
+type TreeConfig = PoseidonTreeConfig<Fr>;
+type TreeConfigVar = PoseidonTreeConfigVar<Fr>;
+
 // Checks the test circuit parameters and puts them in a struct
 fn gen_test_circuit_params(
     num_subcircuits: usize,
     num_sha_iterations: usize,
     num_portals_per_subcircuit: usize,
 ) -> MerkleTreeCircuitParams {
-    assert!(
-        num_subcircuits.is_power_of_two(),
-        "#subcircuits MUST be a power of 2"
-    );
-    assert!(num_subcircuits > 1, "num. of subcircuits MUST be > 1");
     assert!(
         num_sha_iterations > 0,
         "num. of SHA256 iterations per subcircuit MUST be > 0"
@@ -42,7 +38,7 @@ fn gen_test_circuit_params(
     );
 
     MerkleTreeCircuitParams {
-        num_leaves: num_subcircuits / 2,
+        num_leaves: MerkleTreeCircuitParams::num_leaves_for(num_subcircuits),
         num_sha_iters_per_subcircuit: num_sha_iterations,
         num_portals_per_subcircuit,
     }
@@ -54,12 +50,13 @@ fn generate_g16_pk(
     circ_params: &MerkleTreeCircuitParams,
 ) -> G16ProvingKey<E> {
     let mut rng = rand::thread_rng();
-    let tree_params = gen_merkle_params();
+    let tree_params = gen_merkle_params::<Fr>();
 
     // Make an empty circuit of the correct size
     let circ = <MerkleTreeCircuit as CircuitWithPortals<Fr>>::new(&circ_params);
 
     let generator = G16ProvingKeyGenerator::<TreeConfig, TreeConfigVar, E, _>::new(
+        &mut rng,
         circ.clone(),
         tree_params.clone(),
     );
@@ -88,15 +85,16 @@ fn generate_agg_ck<'a>(
 
     // We don't bench the SuperCom key. This is a subset of the KZG key. This will be resolved when
     // verification is resolved.
-    let (tipp_pk, _tipp_vk) = TIPA::<E, Sha256>::setup(num_subcircuits, &mut rng).unwrap();
+    let (tipp_pk, _tipp_vk) =
+        TIPA::<E, Sha256>::setup(tipp_size(num_subcircuits), &mut rng).unwrap();
 
     // c.map(|c| {
     //     c.bench_function(&format!("Coord: generating agg ck {circ_params}"), |b| {
-    //         b.iter(|| AggProvingKey::new(tipp_pk.clone(), pk_fetcher))
+    //         b.iter(|| AggProvingKey::new(tipp_pk.clone(), num_subcircuits, pk_fetcher))
     //     })
     // });
 
-    AggProvingKey::new(tipp_pk, pk_fetcher)
+    AggProvingKey::new(tipp_pk, num_subcircuits, pk_fetcher)
 }
 
 fn begin_stage0(
@@ -140,7 +138,7 @@ fn process_stage0_requests(
     g16_pk: &G16ProvingKey<E>,
 ) -> Stage0Response<E> {
     let mut rng = rand::thread_rng();
-    let tree_params = gen_merkle_params();
+    let tree_params = gen_merkle_params::<Fr>();
 
     // Compute the response
     // c.map(|c| {
@@ -175,10 +173,10 @@ fn process_stage0_resps(
     stage0_resp: Stage0Response<E>,
     agg_ck: AggProvingKey<E>,
 ) -> (
-    FinalAggState<E>,
+    FinalAggState<TreeConfig, E>,
     Stage1Request<TreeConfig, Fr, MerkleTreeCircuit>,
 ) {
-    let tree_params = gen_merkle_params();
+    let tree_params = gen_merkle_params::<Fr>();
 
     let num_subcircuits = 2 * circ_params.num_leaves;
     let stage0_resps = vec![stage0_resp; num_subcircuits];
@@ -216,7 +214,7 @@ fn process_stage1_requests(
     stage1_req: Stage1Request<TreeConfig, Fr, MerkleTreeCircuit>,
 ) -> Stage1Response<E> {
     let mut rng = rand::thread_rng();
-    let tree_params = gen_merkle_params();
+    let tree_params = gen_merkle_params::<Fr>();
 
     // Compute the response. This is a Groth16 proof over a potentially large circuit
     // c.map(|c| {
@@ -250,10 +248,10 @@ fn process_stage1_requests(
 fn process_stage1_resps(
     c: Option<&mut Criterion>,
     circ_params: &MerkleTreeCircuitParams,
-    final_agg_state: FinalAggState<E>,
+    final_agg_state: FinalAggState<TreeConfig, E>,
     agg_ck: AggProvingKey<E>,
     stage1_resp: Stage1Response<E>,
-) -> AggProof<E> {
+) -> HekatonProof<TreeConfig, E> {
     let num_subcircuits = 2 * circ_params.num_leaves;
     let stage1_resps = vec![stage1_resp; num_subcircuits];
 
@@ -269,7 +267,7 @@ fn process_stage1_resps(
 //#region
 fn show_portal_constraint_tradeoff(c: &mut Criterion) {
     let mut rng = rand::thread_rng();
-    let tree_params = gen_merkle_params();
+    let tree_params = gen_merkle_params::<Fr>();
 
     // All of these parameters produce circuits that are ~1.5M constraints. They are of the form
     // (num_subcircuits, num_sha2_iters, num_portal_wires)
@@ -1,9 +1,7 @@
 /// 一个单机（整体式）证明系统，整体生成并证明一个封装了所有子电路的电路，它没有分布式多节点的协作，仅通过单一程序直接生成证明。
 use distributed_prover::{
     portal_manager::PortalManager,
-    poseidon_util::{
-        gen_merkle_params, PoseidonTreeConfig as TreeConfig, PoseidonTreeConfigVar as TreeConfigVar,
-    },
+    poseidon_util::{gen_merkle_params, PoseidonTreeConfig, PoseidonTreeConfigVar},
     subcircuit_circuit::SubcircuitWithPortalsProver,
     transcript::RunningEvaluationVar,
     tree_hash_circuit::{MerkleTreeCircuit, MerkleTreeCircuitParams},
@@ -20,6 +18,9 @@ use ark_relations::r1cs::{
     ConstraintSynthesizer, ConstraintSystem, ConstraintSystemRef, SynthesisError,
 };
 
+type TreeConfig = PoseidonTreeConfig<F>;
+type TreeConfigVar = PoseidonTreeConfigVar<F>;
+
 macro_rules! start_timer {
     ($msg:expr) => {{
         use std::time::Instant;
@@ -50,11 +51,6 @@ fn gen_test_circuit_params(
     num_sha_iterations: usize,
     num_portals_per_subcircuit: usize,
 ) -> MerkleTreeCircuitParams {
-    assert!(
-        num_subcircuits.is_power_of_two(),
-        "#subcircuits MUST be a power of 2"
-    );
-    assert!(num_subcircuits > 1, "num. of subcircuits MUST be > 1");
     assert!(
         num_sha_iterations > 0,
         "num. of SHA256 iterations per subcircuit MUST be > 0"
@@ -65,7 +61,7 @@ fn gen_test_circuit_params(
     );
 
     MerkleTreeCircuitParams {
-        num_leaves: num_subcircuits / 2,
+        num_leaves: MerkleTreeCircuitParams::num_leaves_for(num_subcircuits),
         num_sha_iters_per_subcircuit: num_sha_iterations,
         num_portals_per_subcircuit,
     }
@@ -137,7 +133,7 @@ impl ConstraintSynthesizer<F> for MonolithicCircuit {
 fn circuit_overhead() {
     // let num_sha2_iters = 33;
     // let num_portals = 11_538;
-    let tree_params = gen_merkle_params();
+    let tree_params = gen_merkle_params::<F>();
 
     for num_subcircuits in [16, 32, 64, 128, 256].into_iter().rev() {
         for num_sha2_iters in 1..34 {
@@ -6,7 +6,7 @@ cargo build --bin coordinator
 cargo build --bin worker
 
 start_time=$(date +%s)
-/home/wh/hekaton-system/target/debug/coordinator  gen-keys --g16-pk-dir ./pk-nc=4-ns=4-np=4  --coord-state-dir ./co-nc=4-ns=4-np=4 --num-subcircuits 4 --num-sha2-iters 4 --num-portals 4
+/home/wh/hekaton-system/target/debug/coordinator  setup-big-merkle --g16-pk-dir ./pk-nc=4-ns=4-np=4  --coord-state-dir ./co-nc=4-ns=4-np=4 --num-subcircuits 4 --num-sha2-iters 4 --num-portals 4
 end_time=$(date +%s)
 elapsed=$((end_time - start_time))
 echo "gen-keys elapsed time: ${elapsed} seconds"
@@ -1,176 +1,858 @@
 use crate::{
+    eval_tree::{ExecTreeParams, SerializedLeaf, TreeConfig},
     pairing_ops::{pairing, scalar_pairing, structured_scalar_power},
     par,
-    util::{G16Proof, G16ProvingKey, ProtoTranscript, TranscriptProtocol},
+    transcript::{round_challenges, RunningEvaluation},
+    util::{
+        digest_circ_params, CircParamsDigest, G16Com, G16Proof, G16ProvingKey, ProtoTranscript,
+        TranscriptProtocol,
+    },
+    CircuitWithPortals,
 };
 
-use ark_ec::{pairing::Pairing, AffineRepr, CurveGroup};
+use ark_crypto_primitives::merkle_tree::Path as MerklePath;
+use ark_ec::{
+    pairing::{Pairing, PairingOutput},
+    AffineRepr, CurveGroup,
+};
 use ark_inner_products::{InnerProduct, PairingInnerProduct};
 use ark_ip_proofs::{
     ip_commitment::snarkpack::TIPPCommitment,
-    tipa::{Proof, TIPA},
+    tipa::{Proof, VerifierKey, TIPA},
+};
+use ark_serialize::{
+    CanonicalDeserialize, CanonicalSerialize, Compress, Read, SerializationError, Valid, Validate,
+    Write,
 };
-use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
-use ark_std::{end_timer, start_timer};
+use ark_std::{end_timer, start_timer, Zero};
+use rand::Rng;
 use rayon::prelude::*;
 use sha2::Sha256;
+use std::ops::Range;
 
 pub type IppCom<E> = ark_ip_proofs::ip_commitment::Commitment<TIPPCommitment<E>>;
 pub use ark_ip_proofs::ip_commitment::{IPCommKey, IPCommitment};
 pub use ark_ip_proofs::tipa::ProverKey;
 
+/// Returns the size of the TIPP key needed to aggregate the proofs of the given number of
+/// subcircuits. The proofs are padded with zeros to a power of two, and to at least 2 so that GIPA
+/// has a round to do
+pub fn tipp_size(num_subcircuits: usize) -> usize {
+    num_subcircuits.next_power_of_two().max(2)
+}
+
+/// A key that commits to the commitments of a committed stage, one per subcircuit. The result is a
+/// super-commitment
+pub trait SuperComKey<E: Pairing> {
+    fn super_commit(&self, coms: &[G16Com<E>]) -> IppCom<E>;
+}
+
+impl<'b, E: Pairing> SuperComKey<E> for ProverKey<'b, E> {
+    /// The commitments are in G1, so this is a "left" commitment. Don't worry about what that
+    /// means. The commitments are padded with zeros to the size of the key, same as the proofs are
+    /// in aggregation
+    fn super_commit(&self, coms: &[G16Com<E>]) -> IppCom<E> {
+        let mut coms_group = coms.iter().map(|&com| com.into()).collect::<Vec<E::G1>>();
+        coms_group.resize(self.supported_size, E::G1::zero());
+        TIPPCommitment::commit_only_left(&self.pk.ck, &coms_group).unwrap()
+    }
+}
+
+/// The aggregate of all the subcircuit proofs. Besides the TIPA proof, this contains the
+/// commitments and cross terms that the verifier needs to reconstruct the TIPA instance
+#[derive(Clone, CanonicalSerialize, CanonicalDeserialize)]
+pub struct AggProof<E: Pairing> {
+    /// Commitment to the A and B values of the Groth16 proofs
+    pub com_ab: IppCom<E>,
+    /// Commitment to the C values of the Groth16 proofs
+    pub com_c: IppCom<E>,
+    /// The pairings of every LHS with every RHS, i.e., `[A, S, D₀, ..., Dₙ₋₁, C] × [B, H, δ₀, ...,
+    /// δₙ]`, where n is the number of committed stages
+    pub cross_terms: Vec<Vec<PairingOutput<E>>>,
+    /// The TIPA proof of the combined pairing product
+    pub tipp_proof: Proof<E>,
+}
+
+/// The aggregate of one group of subcircuit proofs. The group's super-commitments are made under
+/// the group's own TIPP key, and sum to the super-commitments of the whole circuit
+#[derive(Clone, CanonicalSerialize, CanonicalDeserialize)]
+pub struct GroupAggProof<E: Pairing> {
+    /// The super-commitments to this group's stage0 commitments, followed by the ones to the
+    /// commitments of each extra round
+    pub super_coms: Vec<IppCom<E>>,
+    /// The aggregate of this group's subcircuit proofs
+    pub agg_proof: AggProof<E>,
+}
+
 #[derive(Clone, CanonicalSerialize, CanonicalDeserialize)]
 pub struct AggProvingKey<'b, E: Pairing> {
     /// This is the key used to produce ALL inner-pairing commitments
     pub tipp_pk: ProverKey<'b, E>,
 
-    // The elements of si are the curve point representing the i-th public input in some set of
-    // Groth16 CRSs. The first public input is always set to 1, so we have a total of 4 here
-    pub(crate) s0: Vec<E::G1Affine>,
-    pub(crate) s1: Vec<E::G1Affine>,
-    pub(crate) s2: Vec<E::G1Affine>,
-    pub(crate) s3: Vec<E::G1Affine>,
+    // The elements of s[j] are the curve points representing the j-th public input in some set of
+    // Groth16 CRSs. The first public input is always set to 1
+    pub(crate) s: Vec<Vec<E::G1Affine>>,
 
-    // Commitments to the above
-    com_s0: IppCom<E>,
-    com_s1: IppCom<E>,
-    com_s2: IppCom<E>,
-    com_s3: IppCom<E>,
+    // Commitments to the above. com_s[k][j] is the commitment to the part of s[j] that belongs to the
+    // k-th instance, i.e., the rest of s[j] is zeroed out
+    com_s: Vec<Vec<IppCom<E>>>,
 
     // The CRS values that get paired with the sum of the s values above
     h: Vec<E::G2Affine>,
     // Commitment to h
     com_h: IppCom<E>,
 
-    // The CRS values that get paired with D and C, respectively
-    delta0: Vec<E::G2Affine>,
-    delta1: Vec<E::G2Affine>,
-    // Commitment to above
-    com_delta0: IppCom<E>,
-    com_delta1: IppCom<E>,
+    // The CRS values that get paired with the D of each committed stage, followed by the ones that
+    // get paired with C. deltas[j][i] is the j-th delta of the i-th CRS
+    deltas: Vec<Vec<E::G2Affine>>,
+    // Commitments to above
+    com_deltas: Vec<IppCom<E>>,
+
+    // The alpha and beta that every CRS shares
+    alpha: E::G1Affine,
+    beta: E::G2Affine,
+
+    // The number of subcircuits per instance, and the number of instances being aggregated together.
+    // The proofs of instance k are at indices [k·num_subcircuits, (k+1)·num_subcircuits). The
+    // vectors above are padded with zeros past the last instance
+    num_subcircuits: usize,
+    num_instances: usize,
+}
+
+/// The magic bytes at the start of every serialized [`HekatonProof`]
+const HEKATON_PROOF_MAGIC: [u8; 4] = *b"HKTN";
+/// The version of the serialized [`HekatonProof`] format. Bump this whenever the format changes.
+pub const HEKATON_PROOF_VERSION: u16 = 3;
+
+/// A self-contained Hekaton proof. This is everything a verifier needs besides the
+/// [`AggVerifyingKey`], the execution tree parameters, and the circuit parameters.
+pub struct HekatonProof<C: TreeConfig, E: Pairing> {
+    /// The inner-pairing commitment to all the stage0 commitments, followed by the ones to the
+    /// commitments of each extra round
+    pub super_coms: Vec<IppCom<E>>,
+    /// The root of the execution tree
+    pub exec_tree_root: E::ScalarField,
+    /// The Fiat-Shamir challenges used for the running evaluations, followed by the ones of each
+    /// extra round. These are derived from `super_coms`. The verifier derives them again, and
+    /// rejects the proof if they differ.
+    pub challenges: Vec<E::ScalarField>,
+    /// The aggregates of each group of subcircuits, in order. With flat aggregation, there is 1
+    /// group
+    pub group_proofs: Vec<GroupAggProof<E>>,
+    /// The digest of the parameters of the circuit this proof is for
+    pub circ_params_digest: CircParamsDigest,
+    /// The public inputs to the circuit
+    pub public_inputs: Vec<E::ScalarField>,
+    /// The membership proof of the public inputs in the execution tree. This is `None` iff there
+    /// are no public inputs.
+    pub public_input_membership: Option<MerklePath<C>>,
+}
+
+// Manually implemented because C needn't be Clone
+impl<C: TreeConfig, E: Pairing> Clone for HekatonProof<C, E> {
+    fn clone(&self) -> Self {
+        HekatonProof {
+            super_coms: self.super_coms.clone(),
+            exec_tree_root: self.exec_tree_root,
+            challenges: self.challenges.clone(),
+            group_proofs: self.group_proofs.clone(),
+            circ_params_digest: self.circ_params_digest,
+            public_inputs: self.public_inputs.clone(),
+            public_input_membership: self.public_input_membership.clone(),
+        }
+    }
+}
+
+impl<C, E> HekatonProof<C, E>
+where
+    C: TreeConfig<Leaf = SerializedLeaf<E::ScalarField>, InnerDigest = E::ScalarField>,
+    E: Pairing,
+{
+    /// Verifies this proof with respect to the given verifying key, execution tree parameters, and
+    /// parameters of the circuit `P`
+    pub fn verify<P: CircuitWithPortals<E::ScalarField>>(
+        &self,
+        vk: &AggVerifyingKey<E>,
+        tree_params: &ExecTreeParams<C>,
+        circ_params: &P::Parameters,
+    ) -> bool {
+        self.verify_groups::<P>(core::slice::from_ref(vk), tree_params, circ_params)
+    }
+
+    /// Verifies this proof with respect to the given grouped verifying key, execution tree
+    /// parameters, and parameters of the circuit `P`. This is for proofs made with a
+    /// [`GroupedAggProvingKey`]
+    pub fn verify_grouped<P: CircuitWithPortals<E::ScalarField>>(
+        &self,
+        vk: &GroupedAggVerifyingKey<E>,
+        tree_params: &ExecTreeParams<C>,
+        circ_params: &P::Parameters,
+    ) -> bool {
+        self.verify_groups::<P>(&vk.groups, tree_params, circ_params)
+    }
+
+    fn verify_groups<P: CircuitWithPortals<E::ScalarField>>(
+        &self,
+        group_vks: &[AggVerifyingKey<E>],
+        tree_params: &ExecTreeParams<C>,
+        circ_params: &P::Parameters,
+    ) -> bool {
+        if self.circ_params_digest != digest_circ_params(circ_params) {
+            return false;
+        }
+        if group_vks.is_empty() || self.group_proofs.len() != group_vks.len() {
+            return false;
+        }
+
+        if !check_challenges::<E, P>(&self.super_coms, &self.challenges) {
+            return false;
+        }
+        let num_subcircuits = group_vks.iter().map(|vk| vk.num_subcircuits()).sum();
+        if !check_public_inputs::<C, E, P>(
+            tree_params,
+            circ_params,
+            &self.exec_tree_root,
+            &self.public_inputs,
+            &self.public_input_membership,
+            num_subcircuits,
+        ) {
+            return false;
+        }
+
+        // Each super-commitment is the sum of the groups' super-commitments for that stage
+        if self
+            .group_proofs
+            .iter()
+            .any(|gp| gp.super_coms.len() != self.super_coms.len())
+        {
+            return false;
+        }
+        for (k, super_com) in self.super_coms.iter().enumerate() {
+            let sum = self.group_proofs[1..]
+                .iter()
+                .fold(self.group_proofs[0].super_coms[k], |acc, gp| {
+                    acc + gp.super_coms[k]
+                });
+            if sum != *super_com {
+                return false;
+            }
+        }
+
+        // Finally, check every group's aggregate proof
+        group_vks
+            .iter()
+            .zip(self.group_proofs.iter())
+            .enumerate()
+            .all(|(group_idx, (vk, gp))| {
+                verify_agg_proof(
+                    vk,
+                    &mut new_agg_transcript(&self.circ_params_digest, group_idx),
+                    &gp.super_coms,
+                    &self.exec_tree_root,
+                    &self.challenges,
+                    &gp.agg_proof,
+                )
+            })
+    }
+}
+
+/// Checks that the memory-checking challenges are derived from the first super-commitment, and the
+/// challenges of each extra round from the super-commitments up to that round's
+fn check_challenges<E, P>(super_coms: &[IppCom<E>], challenges: &[E::ScalarField]) -> bool
+where
+    E: Pairing,
+    P: CircuitWithPortals<E::ScalarField>,
+{
+    if super_coms.len() != P::NUM_EXTRA_ROUNDS + 1 {
+        return false;
+    }
+    let mut expected_challenges =
+        RunningEvaluation::<E::ScalarField>::new(P::MEM_TYPE, &super_coms[0]).challenges();
+    for k in 1..super_coms.len() {
+        expected_challenges.extend(round_challenges::<E>(
+            &super_coms[..=k],
+            P::NUM_ROUND_CHALLENGES,
+        ));
+    }
+    challenges == expected_challenges
+}
+
+/// Checks that the public inputs are in the execution tree with the given root, right after the
+/// leaf of the last subcircuit
+fn check_public_inputs<C, E, P>(
+    tree_params: &ExecTreeParams<C>,
+    circ_params: &P::Parameters,
+    exec_tree_root: &E::ScalarField,
+    public_inputs: &[E::ScalarField],
+    public_input_membership: &Option<MerklePath<C>>,
+    num_subcircuits: usize,
+) -> bool
+where
+    C: TreeConfig<Leaf = SerializedLeaf<E::ScalarField>, InnerDigest = E::ScalarField>,
+    E: Pairing,
+    P: CircuitWithPortals<E::ScalarField>,
+{
+    let num_public_inputs = P::new(circ_params).public_inputs().len();
+    if public_inputs.len() != num_public_inputs {
+        return false;
+    }
+    match public_input_membership {
+        Some(path) if num_public_inputs > 0 => {
+            path.leaf_index == num_subcircuits
+                && path
+                    .verify(
+                        &tree_params.leaf_params,
+                        &tree_params.two_to_one_params,
+                        exec_tree_root,
+                        public_inputs,
+                    )
+                    .unwrap_or(false)
+        },
+        None => num_public_inputs == 0,
+        _ => false,
+    }
+}
+
+impl<C: TreeConfig, E: Pairing> CanonicalSerialize for HekatonProof<C, E> {
+    fn serialize_with_mode<W: Write>(
+        &self,
+        mut writer: W,
+        compress: Compress,
+    ) -> Result<(), SerializationError> {
+        // Write the header first
+        writer.write_all(&HEKATON_PROOF_MAGIC)?;
+        HEKATON_PROOF_VERSION.serialize_with_mode(&mut writer, compress)?;
+
+        self.super_coms.serialize_with_mode(&mut writer, compress)?;
+        self.exec_tree_root
+            .serialize_with_mode(&mut writer, compress)?;
+        self.challenges.serialize_with_mode(&mut writer, compress)?;
+        self.group_proofs
+            .serialize_with_mode(&mut writer, compress)?;
+        self.circ_params_digest
+            .serialize_with_mode(&mut writer, compress)?;
+        self.public_inputs
+            .serialize_with_mode(&mut writer, compress)?;
+        self.public_input_membership
+            .serialize_with_mode(&mut writer, compress)?;
+
+        Ok(())
+    }
+
+    fn serialized_size(&self, compress: Compress) -> usize {
+        HEKATON_PROOF_MAGIC.len()
+            + HEKATON_PROOF_VERSION.serialized_size(compress)
+            + self.super_coms.serialized_size(compress)
+            + self.exec_tree_root.serialized_size(compress)
+            + self.challenges.serialized_size(compress)
+            + self.group_proofs.serialized_size(compress)
+            + self.circ_params_digest.serialized_size(compress)
+            + self.public_inputs.serialized_size(compress)
+            + self.public_input_membership.serialized_size(compress)
+    }
+}
+
+impl<C: TreeConfig, E: Pairing> Valid for HekatonProof<C, E> {
+    fn check(&self) -> Result<(), SerializationError> {
+        self.super_coms.check()?;
+        self.exec_tree_root.check()?;
+        self.challenges.check()?;
+        self.group_proofs.check()?;
+        self.circ_params_digest.check()?;
+        self.public_inputs.check()?;
+        self.public_input_membership.check()
+    }
+}
+
+impl<C: TreeConfig, E: Pairing> CanonicalDeserialize for HekatonProof<C, E> {
+    fn deserialize_with_mode<R: Read>(
+        mut reader: R,
+        compress: Compress,
+        validate: Validate,
+    ) -> Result<Self, SerializationError> {
+        // Check the header before reading anything else
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        let version = u16::deserialize_with_mode(&mut reader, compress, validate)?;
+        if magic != HEKATON_PROOF_MAGIC || version != HEKATON_PROOF_VERSION {
+            return Err(SerializationError::InvalidData);
+        }
+
+        let super_coms = Vec::deserialize_with_mode(&mut reader, compress, validate)?;
+        let exec_tree_root =
+            E::ScalarField::deserialize_with_mode(&mut reader, compress, validate)?;
+        let challenges = Vec::deserialize_with_mode(&mut reader, compress, validate)?;
+        let group_proofs = Vec::deserialize_with_mode(&mut reader, compress, validate)?;
+        let circ_params_digest =
+            CircParamsDigest::deserialize_with_mode(&mut reader, compress, validate)?;
+        let public_inputs = Vec::deserialize_with_mode(&mut reader, compress, validate)?;
+        let public_input_membership =
+            Option::deserialize_with_mode(&mut reader, compress, validate)?;
+
+        Ok(HekatonProof {
+            super_coms,
+            exec_tree_root,
+            challenges,
+            group_proofs,
+            circ_params_digest,
+            public_inputs,
+            public_input_membership,
+        })
+    }
+}
+
+/// The magic bytes at the start of every serialized [`HekatonBatchProof`]. It shares
+/// [`HEKATON_PROOF_VERSION`] with [`HekatonProof`]
+const HEKATON_BATCH_PROOF_MAGIC: [u8; 4] = *b"HKTB";
+
+/// A single proof of several instances of the same circuit, all aggregated together. The instances
+/// share their super-commitments and challenges, and each has its own execution tree. This is
+/// everything a verifier needs besides an [`AggVerifyingKey`] made with
+/// [`AggProvingKey::new_batch`], the execution tree parameters, and the circuit parameters.
+pub struct HekatonBatchProof<C: TreeConfig, E: Pairing> {
+    /// The inner-pairing commitment to the stage0 commitments of every instance, followed by the
+    /// ones to the commitments of each extra round
+    pub super_coms: Vec<IppCom<E>>,
+    /// The Fiat-Shamir challenges used by every instance. These are derived from `super_coms`
+    pub challenges: Vec<E::ScalarField>,
+    /// The root of the execution tree of each instance
+    pub exec_tree_roots: Vec<E::ScalarField>,
+    /// The aggregate of all the subcircuit proofs of all the instances
+    pub agg_proof: AggProof<E>,
+    /// The digest of the parameters of the circuit this proof is for
+    pub circ_params_digest: CircParamsDigest,
+    /// The public inputs of each instance
+    pub public_inputs: Vec<Vec<E::ScalarField>>,
+    /// The membership proof of each instance's public inputs in its execution tree. These are
+    /// `None` iff there are no public inputs.
+    pub public_input_memberships: Vec<Option<MerklePath<C>>>,
+}
+
+// Manually implemented because C needn't be Clone
+impl<C: TreeConfig, E: Pairing> Clone for HekatonBatchProof<C, E> {
+    fn clone(&self) -> Self {
+        HekatonBatchProof {
+            super_coms: self.super_coms.clone(),
+            challenges: self.challenges.clone(),
+            exec_tree_roots: self.exec_tree_roots.clone(),
+            agg_proof: self.agg_proof.clone(),
+            circ_params_digest: self.circ_params_digest,
+            public_inputs: self.public_inputs.clone(),
+            public_input_memberships: self.public_input_memberships.clone(),
+        }
+    }
+}
+
+impl<C, E> HekatonBatchProof<C, E>
+where
+    C: TreeConfig<Leaf = SerializedLeaf<E::ScalarField>, InnerDigest = E::ScalarField>,
+    E: Pairing,
+{
+    /// The number of instances this proves
+    pub fn num_instances(&self) -> usize {
+        self.exec_tree_roots.len()
+    }
+
+    /// Verifies this proof with respect to the given verifying key, execution tree parameters, and
+    /// parameters of the circuit `P`
+    pub fn verify<P: CircuitWithPortals<E::ScalarField>>(
+        &self,
+        vk: &AggVerifyingKey<E>,
+        tree_params: &ExecTreeParams<C>,
+        circ_params: &P::Parameters,
+    ) -> bool {
+        if self.circ_params_digest != digest_circ_params(circ_params) {
+            return false;
+        }
+        let num_instances = self.num_instances();
+        if num_instances != vk.num_instances()
+            || self.public_inputs.len() != num_instances
+            || self.public_input_memberships.len() != num_instances
+        {
+            return false;
+        }
+
+        if !check_challenges::<E, P>(&self.super_coms, &self.challenges) {
+            return false;
+        }
+        for k in 0..num_instances {
+            if !check_public_inputs::<C, E, P>(
+                tree_params,
+                circ_params,
+                &self.exec_tree_roots[k],
+                &self.public_inputs[k],
+                &self.public_input_memberships[k],
+                vk.num_subcircuits(),
+            ) {
+                return false;
+            }
+        }
+
+        // The public inputs to every subcircuit are the challenges followed by its instance's root
+        let g16_pub_inputs = self
+            .exec_tree_roots
+            .iter()
+            .map(|root| [self.challenges.as_slice(), &[*root]].concat())
+            .collect::<Vec<_>>();
+        verify_batch_agg_proof(
+            vk,
+            &mut new_agg_transcript(&self.circ_params_digest, 0),
+            &self.super_coms,
+            &g16_pub_inputs,
+            &self.agg_proof,
+        )
+    }
+}
+
+impl<C: TreeConfig, E: Pairing> CanonicalSerialize for HekatonBatchProof<C, E> {
+    fn serialize_with_mode<W: Write>(
+        &self,
+        mut writer: W,
+        compress: Compress,
+    ) -> Result<(), SerializationError> {
+        // Write the header first
+        writer.write_all(&HEKATON_BATCH_PROOF_MAGIC)?;
+        HEKATON_PROOF_VERSION.serialize_with_mode(&mut writer, compress)?;
+
+        self.super_coms.serialize_with_mode(&mut writer, compress)?;
+        self.challenges.serialize_with_mode(&mut writer, compress)?;
+        self.exec_tree_roots
+            .serialize_with_mode(&mut writer, compress)?;
+        self.agg_proof.serialize_with_mode(&mut writer, compress)?;
+        self.circ_params_digest
+            .serialize_with_mode(&mut writer, compress)?;
+        self.public_inputs
+            .serialize_with_mode(&mut writer, compress)?;
+        self.public_input_memberships
+            .serialize_with_mode(&mut writer, compress)?;
+
+        Ok(())
+    }
+
+    fn serialized_size(&self, compress: Compress) -> usize {
+        HEKATON_BATCH_PROOF_MAGIC.len()
+            + HEKATON_PROOF_VERSION.serialized_size(compress)
+            + self.super_coms.serialized_size(compress)
+            + self.challenges.serialized_size(compress)
+            + self.exec_tree_roots.serialized_size(compress)
+            + self.agg_proof.serialized_size(compress)
+            + self.circ_params_digest.serialized_size(compress)
+            + self.public_inputs.serialized_size(compress)
+            + self.public_input_memberships.serialized_size(compress)
+    }
+}
+
+impl<C: TreeConfig, E: Pairing> Valid for HekatonBatchProof<C, E> {
+    fn check(&self) -> Result<(), SerializationError> {
+        self.super_coms.check()?;
+        self.challenges.check()?;
+        self.exec_tree_roots.check()?;
+        self.agg_proof.check()?;
+        self.circ_params_digest.check()?;
+        self.public_inputs.check()?;
+        self.public_input_memberships.check()
+    }
+}
+
+impl<C: TreeConfig, E: Pairing> CanonicalDeserialize for HekatonBatchProof<C, E> {
+    fn deserialize_with_mode<R: Read>(
+        mut reader: R,
+        compress: Compress,
+        validate: Validate,
+    ) -> Result<Self, SerializationError> {
+        // Check the header before reading anything else
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        let version = u16::deserialize_with_mode(&mut reader, compress, validate)?;
+        if magic != HEKATON_BATCH_PROOF_MAGIC || version != HEKATON_PROOF_VERSION {
+            return Err(SerializationError::InvalidData);
+        }
+
+        let super_coms = Vec::deserialize_with_mode(&mut reader, compress, validate)?;
+        let challenges = Vec::deserialize_with_mode(&mut reader, compress, validate)?;
+        let exec_tree_roots = Vec::deserialize_with_mode(&mut reader, compress, validate)?;
+        let agg_proof = AggProof::deserialize_with_mode(&mut reader, compress, validate)?;
+        let circ_params_digest =
+            CircParamsDigest::deserialize_with_mode(&mut reader, compress, validate)?;
+        let public_inputs = Vec::deserialize_with_mode(&mut reader, compress, validate)?;
+        let public_input_memberships = Vec::deserialize_with_mode(&mut reader, compress, validate)?;
+
+        Ok(HekatonBatchProof {
+            super_coms,
+            challenges,
+            exec_tree_roots,
+            agg_proof,
+            circ_params_digest,
+            public_inputs,
+            public_input_memberships,
+        })
+    }
+}
+
+/// Makes the transcript used for aggregating the given group of subcircuits. This is bound to the
+/// circuit being proven.
+pub(crate) fn new_agg_transcript(
+    circ_params_digest: &CircParamsDigest,
+    group_idx: usize,
+) -> ProtoTranscript {
+    let mut pt = ProtoTranscript::new(b"test-e2e");
+    pt.append_message(b"circ-params-digest", circ_params_digest);
+    pt.append_serializable(b"agg-group", &(group_idx as u64));
+    pt
+}
+
+/// The key necessary to verify an aggregate proof. This is derived from an [`AggProvingKey`]
+#[derive(Clone, CanonicalSerialize, CanonicalDeserialize)]
+pub struct AggVerifyingKey<E: Pairing> {
+    /// The verifier key of the underlying TIPP scheme
+    pub tipp_vk: VerifierKey<E>,
+
+    // Commitments to the public input bases of each instance. See AggProvingKey for details
+    com_s: Vec<Vec<IppCom<E>>>,
+
+    // Commitments to the CRS values that get paired with the public inputs, the Ds, and C
+    com_h: IppCom<E>,
+    com_deltas: Vec<IppCom<E>>,
+
+    // The pairing e(α, β), which every CRS shares
+    alpha_beta: PairingOutput<E>,
+
+    // The number of subcircuit proofs per instance, and the number of instances this key verifies
+    num_subcircuits: usize,
+    num_instances: usize,
 
-    // Temporary values. These are the alpha and beta from the CRSs
-    alpha: Vec<E::G1Affine>,
-    beta: Vec<E::G2Affine>,
+    // The number of subcircuit proofs after padding, i.e., the size of the TIPP instance
+    padded_size: usize,
+}
+
+impl<E: Pairing> AggVerifyingKey<E> {
+    /// The number of subcircuit proofs per instance this key verifies
+    pub fn num_subcircuits(&self) -> usize {
+        self.num_subcircuits
+    }
+
+    /// The number of instances this key verifies at once. This is 1 unless the key was made with
+    /// [`AggProvingKey::new_batch`]
+    pub fn num_instances(&self) -> usize {
+        self.num_instances
+    }
 }
 
 impl<'b, E: Pairing> AggProvingKey<'b, E> {
-    /// Creates an aggregation proving key using an IPP commitment key, a KZG commitment key, and a
-    /// lambda that will fetch the Groth16 proving key of the given circuit
+    /// Derives the verifying key from this proving key
+    pub fn vk(&self) -> AggVerifyingKey<E> {
+        AggVerifyingKey {
+            tipp_vk: self.tipp_pk.vk(),
+            com_s: self.com_s.clone(),
+            com_h: self.com_h,
+            com_deltas: self.com_deltas.clone(),
+            alpha_beta: E::pairing(self.alpha, self.beta),
+            num_subcircuits: self.num_subcircuits,
+            num_instances: self.num_instances,
+            padded_size: self.h.len(),
+        }
+    }
+
+    /// Creates an aggregation proving key using an IPP commitment key, a KZG commitment key, the
+    /// number of subcircuits, and a lambda that will fetch the Groth16 proving key of the given
+    /// circuit. The Groth16 proving keys MUST all share alpha and beta, i.e., come from the same
+    /// `G16ProvingKeyGenerator`. The TIPP key MUST support at least `tipp_size(num_subcircuits)`
+    /// elements.
     pub fn new<'a>(
         tipp_pk: ProverKey<'b, E>,
+        num_subcircuits: usize,
+        pk_fetcher: impl Fn(usize) -> &'a G16ProvingKey<E>,
+    ) -> Self {
+        Self::new_batch(tipp_pk, num_subcircuits, 1, pk_fetcher)
+    }
+
+    /// Creates an aggregation proving key that aggregates the proofs of `num_instances` instances
+    /// of the same circuit at once. The arguments are otherwise the same as in
+    /// [`AggProvingKey::new`], except that the TIPP key MUST support at least
+    /// `tipp_size(num_instances * num_subcircuits)` elements.
+    pub fn new_batch<'a>(
+        tipp_pk: ProverKey<'b, E>,
+        num_subcircuits: usize,
+        num_instances: usize,
         pk_fetcher: impl Fn(usize) -> &'a G16ProvingKey<E>,
     ) -> Self {
+        assert!(num_instances > 0, "need at least 1 instance");
         let num_proofs = tipp_pk.supported_size;
+        assert!(
+            num_proofs >= tipp_size(num_instances * num_subcircuits),
+            "TIPP key is too small for {num_instances} instances of {num_subcircuits} subcircuits"
+        );
+        // The number of public inputs, including the leading 1. Every CRS must agree on this
+        let num_inputs = pk_fetcher(0).vk.gamma_abc_g.len();
+        // The number of deltas, i.e., the number of committed stages plus 1. Every CRS must agree on
+        // this too
+        let num_deltas = pk_fetcher(0).vk.deltas_h.len();
 
         // Group elements in the CRS corresponding to the public inputs
-        let mut s0 = Vec::with_capacity(num_proofs);
-        let mut s1 = Vec::with_capacity(num_proofs);
-        let mut s2 = Vec::with_capacity(num_proofs);
-        let mut s3 = Vec::with_capacity(num_proofs);
+        let mut s = vec![Vec::with_capacity(num_proofs); num_inputs];
         // Group elements in the CRS that get paired with the si values
         let mut h = Vec::with_capacity(num_proofs);
-        // Group elements in the CRS that get paired with the si values
-        let mut delta0 = Vec::with_capacity(num_proofs);
-        let mut delta1 = Vec::with_capacity(num_proofs);
-        // TODO: Remove this once we have proofs sharing alpha and beta
-        let mut alpha = Vec::with_capacity(num_proofs);
-        let mut beta = Vec::with_capacity(num_proofs);
+        // Group elements in the CRS that get paired with the D and C values
+        let mut deltas = vec![Vec::with_capacity(num_proofs); num_deltas];
+        // Every CRS must agree on alpha and beta too
+        let alpha = pk_fetcher(0).vk.alpha_g;
+        let beta = pk_fetcher(0).vk.beta_h;
 
         // Go through each Groth16 proving key and extract the values necessary to fill the above
         // vectors
-        for i in 0..num_proofs {
+        for i in 0..num_subcircuits {
             let pk = pk_fetcher(i);
 
-            s0.push(pk.vk.gamma_abc_g[0].into_group());
-            s1.push(pk.vk.gamma_abc_g[1].into_group());
-            s2.push(pk.vk.gamma_abc_g[2].into_group());
-            s3.push(pk.vk.gamma_abc_g[3].into_group());
+            assert_eq!(
+                pk.vk.gamma_abc_g.len(),
+                num_inputs,
+                "subcircuit {i} has a different number of public inputs"
+            );
+            for (sj, base) in s.iter_mut().zip(&pk.vk.gamma_abc_g) {
+                sj.push(base.into_group());
+            }
             h.push(pk.vk.gamma_h.into_group());
-            delta0.push(pk.vk.deltas_h[0].into_group());
-            delta1.push(pk.vk.deltas_h[1].into_group());
-            alpha.push(pk.vk.alpha_g.into_group());
-            beta.push(pk.vk.beta_h.into_group());
+            assert_eq!(
+                pk.vk.deltas_h.len(),
+                num_deltas,
+                "subcircuit {i} has a different number of stages"
+            );
+            for (dj, delta) in deltas.iter_mut().zip(&pk.vk.deltas_h) {
+                dj.push(delta.into_group());
+            }
+            assert!(
+                pk.vk.alpha_g == alpha && pk.vk.beta_h == beta,
+                "subcircuit {i} doesn't share alpha and beta with subcircuit 0"
+            );
         }
 
-        // Commit to those group elements
-        let com_s0 = TIPPCommitment::<E>::commit_only_left(&tipp_pk.pk.ck, &s0).unwrap();
-        let com_s1 = TIPPCommitment::<E>::commit_only_left(&tipp_pk.pk.ck, &s1).unwrap();
-        let com_s2 = TIPPCommitment::<E>::commit_only_left(&tipp_pk.pk.ck, &s2).unwrap();
-        let com_s3 = TIPPCommitment::<E>::commit_only_left(&tipp_pk.pk.ck, &s3).unwrap();
+        // Every instance uses the same CRSs, so repeat the above for each instance
+        s.iter_mut().for_each(|sj| *sj = sj.repeat(num_instances));
+        h = h.repeat(num_instances);
+        deltas
+            .iter_mut()
+            .for_each(|dj| *dj = dj.repeat(num_instances));
+
+        // Pad with zeros. The padding proofs are all zeros too, so every pairing they're in is the
+        // identity. Only the real proofs get an e(α, β) term
+        s.iter_mut()
+            .for_each(|sj| sj.resize(num_proofs, E::G1::zero()));
+        h.resize(num_proofs, E::G2::zero());
+        deltas
+            .iter_mut()
+            .for_each(|dj| dj.resize(num_proofs, E::G2::zero()));
+
+        // Commit to those group elements. The public inputs of each instance differ, so each
+        // instance gets its own commitments to s
+        let com_s = (0..num_instances)
+            .map(|k| {
+                let instance_range = k * num_subcircuits..(k + 1) * num_subcircuits;
+                s.iter()
+                    .map(|sj| {
+                        let mut masked_sj = vec![E::G1::zero(); num_proofs];
+                        masked_sj[instance_range.clone()]
+                            .copy_from_slice(&sj[instance_range.clone()]);
+                        TIPPCommitment::<E>::commit_only_left(&tipp_pk.pk.ck, &masked_sj).unwrap()
+                    })
+                    .collect()
+            })
+            .collect();
         let com_h = TIPPCommitment::<E>::commit_only_right(&tipp_pk.pk.ck, &h).unwrap();
-        let com_delta0 = TIPPCommitment::<E>::commit_only_right(&tipp_pk.pk.ck, &delta0).unwrap();
-        let com_delta1 = TIPPCommitment::<E>::commit_only_right(&tipp_pk.pk.ck, &delta1).unwrap();
+        let com_deltas = deltas
+            .iter()
+            .map(|dj| TIPPCommitment::<E>::commit_only_right(&tipp_pk.pk.ck, dj).unwrap())
+            .collect();
 
         // This is cheap because the vectors are constructed from affine form.
-        let s0 = s0.into_iter().map(|s| s.into_affine()).collect();
-        let s1 = s1.into_iter().map(|s| s.into_affine()).collect();
-        let s2 = s2.into_iter().map(|s| s.into_affine()).collect();
-        let s3 = s3.into_iter().map(|s| s.into_affine()).collect();
+        let s = s
+            .into_iter()
+            .map(|sj| sj.into_iter().map(|s| s.into_affine()).collect())
+            .collect();
         let h = h.into_iter().map(|s| s.into_affine()).collect();
-        let delta0 = delta0.into_iter().map(|s| s.into_affine()).collect();
-        let delta1 = delta1.into_iter().map(|s| s.into_affine()).collect();
-        let alpha = alpha.into_iter().map(|s| s.into_affine()).collect();
-        let beta = beta.into_iter().map(|s| s.into_affine()).collect();
+        let deltas = deltas
+            .into_iter()
+            .map(|dj| dj.into_iter().map(|s| s.into_affine()).collect())
+            .collect();
 
         AggProvingKey {
             tipp_pk,
-            s0,
-            s1,
-            s2,
-            s3,
-            com_s0,
-            com_s1,
-            com_s2,
-            com_s3,
+            s,
+            com_s,
             h,
             com_h,
-            delta0,
-            delta1,
-            com_delta0,
-            com_delta1,
+            deltas,
+            com_deltas,
             alpha,
             beta,
+            num_subcircuits,
+            num_instances,
         }
     }
 
-    /// Aggregates the subcircuit proofs
+    /// Aggregates the subcircuit proofs. `super_coms` are the inner-pairing commitments to the
+    /// commitments of each committed stage, in order
     pub fn agg_subcircuit_proofs(
         &self,
         pt: &mut ProtoTranscript,
-        super_com: &IppCom<E>,
+        super_coms: &[IppCom<E>],
         proofs: &[G16Proof<E>],
         pub_inputs: &[E::ScalarField],
-    ) -> Proof<E> {
+    ) -> AggProof<E> {
+        self.agg_batch_proofs(pt, super_coms, proofs, &[pub_inputs.to_vec()])
+    }
+
+    /// Aggregates the subcircuit proofs of every instance, in order. `pub_inputs[k]` are the
+    /// Groth16 public inputs of instance k. `super_coms` are the inner-pairing commitments to the
+    /// commitments of each committed stage of ALL the instances, in order
+    pub fn agg_batch_proofs(
+        &self,
+        pt: &mut ProtoTranscript,
+        super_coms: &[IppCom<E>],
+        proofs: &[G16Proof<E>],
+        pub_inputs: &[Vec<E::ScalarField>],
+    ) -> AggProof<E> {
         let start = start_timer!(|| format!("Aggregating {} proofs", proofs.len()));
         let ck = &self.tipp_pk.pk.ck;
 
-        // TODO: uncomment
-        /*assert_eq!(
+        assert_eq!(
             pub_inputs.len(),
-            3,
-            "there are only 3 pub inputs: entry_chal, tr_chal, root"
+            self.num_instances,
+            "wrong number of instances"
+        );
+        assert!(
+            pub_inputs.iter().all(|x| x.len() + 1 == self.s.len()),
+            "wrong number of public inputs"
+        );
+        // The last delta is paired with C. The rest are paired with the committed stages
+        let num_committed_stages = self.deltas.len() - 1;
+        assert_eq!(
+            super_coms.len(),
+            num_committed_stages,
+            "wrong number of super-commitments"
         );
-         */
 
-        let num_proofs = proofs.len();
+        let num_real_proofs = self.num_instances * self.num_subcircuits;
+        assert_eq!(proofs.len(), num_real_proofs, "wrong number of proofs");
+        // The proofs are padded with zeros up to the size of the key
+        let num_proofs = self.h.len();
+        let pad_g1 = |mut vals: Vec<E::G1>| {
+            vals.resize(num_proofs, E::G1::zero());
+            vals
+        };
 
-        let a_vals = proofs.iter().map(|p| p.a.into_group()).collect::<Vec<_>>();
-        let b_vals = proofs.iter().map(|p| p.b.into_group()).collect::<Vec<_>>();
-        let c_vals = proofs.iter().map(|p| p.c.into_group()).collect::<Vec<_>>();
-        // Each proof has only 1 commitment (it's stage0)
-        let d_vals = proofs
-            .iter()
-            .map(|p| p.ds[0].into_group())
+        let a_vals = pad_g1(proofs.iter().map(|p| p.a.into_group()).collect());
+        let mut b_vals = proofs.iter().map(|p| p.b.into_group()).collect::<Vec<_>>();
+        b_vals.resize(num_proofs, E::G2::zero());
+        let c_vals = pad_g1(proofs.iter().map(|p| p.c.into_group()).collect());
+        // Each proof has 1 commitment per committed stage. d_vals[j] are the stage j commitments
+        let d_vals = (0..num_committed_stages)
+            .map(|j| pad_g1(proofs.iter().map(|p| p.ds[j].into_group()).collect()))
             .collect::<Vec<_>>();
 
         let com_ab = TIPPCommitment::<E>::commit_with_ip(&ck, &a_vals, &b_vals, None).unwrap();
         let com_c = TIPPCommitment::<E>::commit_only_left(&ck, &c_vals).unwrap();
-        let com_d = super_com;
-        let com_prepared_input = self.com_s0
-            + self.com_s1 * pub_inputs[0]
-            + self.com_s2 * pub_inputs[1]
-            + self.com_s3 * pub_inputs[2];
+        let com_ds = super_coms;
+        let com_prepared_input = prepare_batch_input_com(&self.com_s, pub_inputs);
         let a_vals = a_vals
             .into_iter()
             .map(|s| s.into_affine())
@@ -186,39 +868,48 @@ impl<'b, E: Pairing> AggProvingKey<'b, E> {
             .collect::<Vec<_>>();
         let d_vals = d_vals
             .into_iter()
-            .map(|s| s.into_affine())
+            .map(|dj| dj.into_iter().map(|s| s.into_affine()).collect::<Vec<_>>())
             .collect::<Vec<_>>();
+        let delta_last = &self.deltas[num_committed_stages];
 
         // Compute the combined public inputs. In the paper this is S₁^1 · S₂^pubinput₁ · ...
-        let prepared_input = self
-            .s0
-            .par_iter()
-            .zip(&self.s1)
-            .zip(&self.s2)
-            .zip(&self.s3)
-            .map(|(((s0, s1), s2), s3)| {
-                // Remember the first public input is always 1, so s0 gets no coeff
-                *s0 + (*s1) * pub_inputs[0] + (*s2) * pub_inputs[1] + (*s3) * pub_inputs[2]
+        let prepared_input = (0..num_proofs)
+            .into_par_iter()
+            .map(|i| {
+                // The padding proofs have zero s values, so it doesn't matter which instance's
+                // public inputs they get
+                let instance_idx = (i / self.num_subcircuits).min(self.num_instances - 1);
+                // Remember the first public input is always 1, so s[0] gets no coeff
+                self.s[1..]
+                    .iter()
+                    .zip(&pub_inputs[instance_idx])
+                    .fold(self.s[0][i].into_group(), |acc, (sj, x)| acc + sj[i] * x)
             })
             .collect::<Vec<_>>();
         // TODO: Rewrite scalar_pairing so that we don't need this to be affine
         let prepared_input = E::G1::normalize_batch(&prepared_input);
 
-        // Sanity check. Does the first proof validate?
-        for i in 0..num_proofs {
+        // Sanity check. Does every proof validate?
+        for i in 0..num_real_proofs {
             debug_assert_eq!(
                 E::pairing(&a_vals[i], &b_vals[i]),
-                E::pairing(&self.alpha[i], &self.beta[i])
+                E::pairing(&self.alpha, &self.beta)
                     + E::pairing(&prepared_input[i], &self.h[i])
-                    + E::pairing(&d_vals[i], &self.delta0[i])
-                    + E::pairing(&c_vals[i], &self.delta1[i])
+                    + d_vals
+                        .iter()
+                        .zip(&self.deltas)
+                        .map(|(dj, deltaj)| E::pairing(&dj[i], &deltaj[i]))
+                        .sum::<PairingOutput<E>>()
+                    + E::pairing(&c_vals[i], &delta_last[i])
             );
         }
 
         // Derive a random scalar to perform a linear combination of proofs
         pt.append_serializable(b"AB-commitment", &com_ab);
         pt.append_serializable(b"C-commitment", &com_c);
-        pt.append_serializable(b"D-commitment", com_d);
+        for com_d in com_ds {
+            pt.append_serializable(b"D-commitment", com_d);
+        }
         let twist = pt.challenge_scalar::<E::ScalarField>(b"r-random-fiatshamir");
 
         // 1,r, r^2, r^3, r^4 ...
@@ -230,106 +921,110 @@ impl<'b, E: Pairing> AggProvingKey<'b, E> {
 
         let a_ref = &a_vals;
         let c_ref = &c_vals;
-        let d_ref = &d_vals;
-        let alpha_ref = &self.alpha;
         let input_ref = &prepared_input;
         par! {
             let a_r = scalar_pairing(a_ref, &twist_powers_ref);
             let c_r = scalar_pairing(c_ref, &twist_powers_ref);
-            let d_r = scalar_pairing(d_ref, &twist_powers_ref);
-            let alpha_r = scalar_pairing(alpha_ref, &twist_powers_ref);
             let prepared_input_r = scalar_pairing(input_ref, &twist_powers_ref)
         }
+        let d_r = d_vals
+            .par_iter()
+            .map(|dj| scalar_pairing(dj, twist_powers_ref))
+            .collect::<Vec<_>>();
+        // The sum of the r coeffs of the real proofs, i.e., the power of e(α, β) in the combined
+        // equation
+        let twist_powers_sum: E::ScalarField = twist_powers[..num_real_proofs].iter().sum();
         // Check each individual equation holds with the r coeffs
-        for i in 0..num_proofs {
-            let left  =  E::pairing(&a_r[i], &b_vals[i]);
-            let right =E::pairing(&alpha_r[i], &self.beta[i])
-                + E::pairing(&prepared_input_r[i], &self.h[i])
-                + E::pairing(&d_r[i], &self.delta0[i])
-                + E::pairing(&c_r[i], &self.delta1[i]);        
-            if left != right {
-                println!("$$$$$$$$$num_proofs: {}", i);
-            }
+        for i in 0..num_real_proofs {
             debug_assert_eq!(
                 E::pairing(&a_r[i], &b_vals[i]),
-                E::pairing(&alpha_r[i], &self.beta[i])
+                E::pairing(&self.alpha, &self.beta) * twist_powers[i]
                     + E::pairing(&prepared_input_r[i], &self.h[i])
-                    + E::pairing(&d_r[i], &self.delta0[i])
-                    + E::pairing(&c_r[i], &self.delta1[i])
+                    + d_r
+                        .iter()
+                        .zip(&self.deltas)
+                        .map(|(dj, deltaj)| E::pairing(&dj[i], &deltaj[i]))
+                        .sum::<PairingOutput<E>>()
+                    + E::pairing(&c_r[i], &delta_last[i])
             );
         }
 
-        // Multiply every LHS with every RHS
-        let cross_terms = [&a_r, &prepared_input_r, &d_r, &c_r]
-            .into_par_iter()
+        // Multiply every LHS with every RHS. The LHSs are A, S, the Ds, and C. The RHSs are B, H,
+        // and the deltas
+        let lhs_vals = [&a_r, &prepared_input_r]
+            .into_iter()
+            .chain(&d_r)
+            .chain([&c_r])
+            .collect::<Vec<_>>();
+        let rhs_vals = [&b_vals, &self.h]
+            .into_iter()
+            .chain(&self.deltas)
+            .collect::<Vec<_>>();
+        let cross_terms = lhs_vals
+            .par_iter()
             .map(|lhs| {
-                [&b_vals, &self.h, &self.delta0, &self.delta1]
-                    .into_par_iter()
+                rhs_vals
+                    .par_iter()
                     .map(|rhs| pairing::<E>(lhs, rhs))
                     .collect::<Vec<_>>()
             })
             .collect::<Vec<_>>();
+        let num_terms = cross_terms.len();
 
         // Check that the pairing product equation holds with the r coeffs
-        let z_ab = cross_terms[0][0];
-        let z_sh = cross_terms[1][1];
-        let z_ddelta0 = cross_terms[2][2];
-        let z_cdelta1 = cross_terms[3][3];
         debug_assert_eq!(
-            z_ab,
-            pairing::<E>(&alpha_r, &self.beta) + z_sh + z_ddelta0 + z_cdelta1
+            cross_terms[0][0],
+            E::pairing(&self.alpha, &self.beta) * twist_powers_sum
+                + (1..num_terms)
+                    .map(|k| cross_terms[k][k])
+                    .sum::<PairingOutput<E>>()
         );
 
         // Get challenges s,t
         pt.append_serializable(b"cross-terms", &cross_terms);
         let s = pt.challenge_scalar::<E::ScalarField>(b"s-random-fiatshamir");
         let t = pt.challenge_scalar::<E::ScalarField>(b"t-random-fiatshamir");
-        // Compute squares and cubes
-        let s_sq = s * s;
-        let s_cube = s_sq * s;
-        let t_sq = t * t;
-        let t_cube = t_sq * t;
+        let s_powers = structured_scalar_power(num_terms, s);
+        let t_powers = structured_scalar_power(num_terms, t);
 
         // Now compute a combination wrt powers of s and t
         let left = {
-            // Compute L = A' · (S')^s · (D')^{s²} · (C')^{s³}
-            par! {
-                let s_to_the_s =
-                    scalar_pairing(&prepared_input, vec![s; num_proofs].as_slice());
-                let d_to_the_s2 = scalar_pairing(&d_vals, vec![s_sq; num_proofs].as_slice());
-                let c_to_the_s3 = scalar_pairing(&c_vals, vec![s_cube; num_proofs].as_slice())
-            };
-            a_vals
+            // Compute L = A' · (S')^s · (D₀')^{s²} · ... · (C')^{s^{n+2}}
+            let mut left = a_vals
                 .into_par_iter()
-                .zip(s_to_the_s)
-                .zip(d_to_the_s2)
-                .zip(c_to_the_s3)
-                .map(|(((a, s), d), c)| a + s + d + c)
-                .collect::<Vec<_>>()
+                .map(|a| a.into_group())
+                .collect::<Vec<_>>();
+            let other_lhs = [&prepared_input]
+                .into_iter()
+                .chain(&d_vals)
+                .chain([&c_vals]);
+            for (vals, s_pow) in other_lhs.zip(&s_powers[1..]) {
+                let scaled = scalar_pairing(vals, vec![*s_pow; num_proofs].as_slice());
+                left.par_iter_mut().zip(scaled).for_each(|(l, v)| *l += v);
+            }
+            left
         };
         let right = {
-            // Compute R = B · H^t · δ₀^{t²} · δ₁^{t³}
-            par! {
-                let h_to_the_t = scalar_pairing(&self.h, vec![t; num_proofs].as_slice());
-                let delta0_to_the_t2 =
-                    scalar_pairing(&self.delta0, vec![t_sq; num_proofs].as_slice());
-                let delta1_to_the_t3 =
-                    scalar_pairing(&self.delta1, vec![t_cube; num_proofs].as_slice())
-            };
-            b_vals
+            // Compute R = B · H^t · δ₀^{t²} · ... · δₙ^{t^{n+2}}
+            let mut right = b_vals
                 .into_par_iter()
-                .zip(h_to_the_t)
-                .zip(delta0_to_the_t2)
-                .zip(delta1_to_the_t3)
-                .map(|(((b, s), d), c)| b + s + d + c)
-                .collect::<Vec<_>>()
+                .map(|b| b.into_group())
+                .collect::<Vec<_>>();
+            let other_rhs = [&self.h].into_iter().chain(&self.deltas);
+            for (vals, t_pow) in other_rhs.zip(&t_powers[1..]) {
+                let scaled = scalar_pairing(vals, vec![*t_pow; num_proofs].as_slice());
+                right.par_iter_mut().zip(scaled).for_each(|(r, v)| *r += v);
+            }
+            right
         };
         // Compute the corresponding commitments
-        let com_lr = {
-            let s_partial_sum = com_ab + com_prepared_input * s + *com_d * s_sq + com_c * s_cube;
-            let t_partial_sum = self.com_h * t + self.com_delta0 * t_sq + self.com_delta1 * t_cube;
-            s_partial_sum + t_partial_sum
-        };
+        let com_lr = combine_lr_coms(
+            com_ab,
+            &[&[com_prepared_input][..], com_ds, &[com_c]].concat(),
+            &[&[self.com_h][..], &self.com_deltas].concat(),
+            &s_powers,
+            &t_powers,
+        );
         // Take the product of the left and right sides
         let z_lr = PairingInnerProduct::twisted_inner_product(&left, &right, twist).unwrap();
 
@@ -343,13 +1038,264 @@ impl<'b, E: Pairing> AggProvingKey<'b, E> {
         let witness = ark_ip_proofs::gipa::Witness { left, right };
 
         let tipp_proof = TIPA::<_, Sha256>::prove(&self.tipp_pk, &instance, &witness).unwrap();
-
-        let tipp_start = start_timer!(|| format!("Verifying TIPA for {num_proofs} proofs"));
-        assert!(TIPA::<_, Sha256>::verify(&self.tipp_pk.vk(), &instance, &tipp_proof).unwrap());
-        println!("verify!!");
-        end_timer!(tipp_start);
         end_timer!(start);
 
-        tipp_proof
+        AggProof {
+            com_ab,
+            com_c,
+            cross_terms,
+            tipp_proof,
+        }
     }
 }
+
+/// A set of aggregation proving keys, one per contiguous group of `group_size` subcircuits (the last
+/// group may be smaller). Each group is aggregated separately, e.g., by a group of workers, and the
+/// coordinator combines the group aggregates into a single [`HekatonProof`]. Every group MUST have
+/// its own TIPP key, since a group's super-commitment is only binding under its own key
+#[derive(Clone, CanonicalSerialize, CanonicalDeserialize)]
+pub struct GroupedAggProvingKey<'b, E: Pairing> {
+    group_size: usize,
+    groups: Vec<AggProvingKey<'b, E>>,
+}
+
+/// The key necessary to verify a proof made with a [`GroupedAggProvingKey`]
+#[derive(Clone, CanonicalSerialize, CanonicalDeserialize)]
+pub struct GroupedAggVerifyingKey<E: Pairing> {
+    groups: Vec<AggVerifyingKey<E>>,
+}
+
+impl<E: Pairing> GroupedAggVerifyingKey<E> {
+    /// The number of subcircuit proofs this key verifies
+    pub fn num_subcircuits(&self) -> usize {
+        self.groups.iter().map(|vk| vk.num_subcircuits()).sum()
+    }
+}
+
+impl<'b, E: Pairing> GroupedAggProvingKey<'b, E> {
+    /// Creates a grouped aggregation proving key for `num_subcircuits` subcircuits split into
+    /// groups of `group_size`. Every group gets a fresh TIPP key sampled from `rng`, and the
+    /// trapdoors are dropped as soon as the keys are made. `pk_fetcher` is as in
+    /// [`AggProvingKey::new`], and is indexed by the subcircuit's global index.
+    pub fn new<'a>(
+        num_subcircuits: usize,
+        group_size: usize,
+        rng: &mut impl Rng,
+        pk_fetcher: impl Fn(usize) -> &'a G16ProvingKey<E>,
+    ) -> Self {
+        assert!(group_size > 0, "group size must be nonzero");
+        assert!(num_subcircuits > 0, "need at least 1 subcircuit");
+
+        let groups = (0..num_subcircuits)
+            .step_by(group_size)
+            .map(|start| {
+                let len = group_size.min(num_subcircuits - start);
+                let (tipp_pk, _) = TIPA::<E, Sha256>::setup(tipp_size(len), &mut *rng).unwrap();
+                AggProvingKey::new(tipp_pk, len, |i| pk_fetcher(start + i))
+            })
+            .collect();
+
+        GroupedAggProvingKey { group_size, groups }
+    }
+
+    /// Derives the verifying key from this proving key
+    pub fn vk(&self) -> GroupedAggVerifyingKey<E> {
+        GroupedAggVerifyingKey {
+            groups: self.groups.iter().map(AggProvingKey::vk).collect(),
+        }
+    }
+
+    /// The number of groups
+    pub fn num_groups(&self) -> usize {
+        self.groups.len()
+    }
+
+    /// The maximum number of subcircuits in a group
+    pub fn group_size(&self) -> usize {
+        self.group_size
+    }
+
+    /// The aggregation proving key of the given group
+    pub fn group(&self, group_idx: usize) -> &AggProvingKey<'b, E> {
+        &self.groups[group_idx]
+    }
+
+    /// The indices of the subcircuits in the given group
+    pub fn group_range(&self, group_idx: usize) -> Range<usize> {
+        let start = group_idx * self.group_size;
+        start..start + self.groups[group_idx].num_subcircuits
+    }
+}
+
+impl<'b, E: Pairing> SuperComKey<E> for GroupedAggProvingKey<'b, E> {
+    /// The super-commitment is the sum of the super-commitments of each group
+    fn super_commit(&self, coms: &[G16Com<E>]) -> IppCom<E> {
+        let mut group_coms = self
+            .groups
+            .iter()
+            .zip(coms.chunks(self.group_size))
+            .map(|(group, chunk)| group.tipp_pk.super_commit(chunk));
+        let first = group_coms.next().unwrap();
+        group_coms.fold(first, |acc, com| acc + com)
+    }
+}
+
+/// Computes the commitment to the prepared public inputs, i.e., `com_s[0] · Π com_s[j]^pub_inputs[j-1]`
+fn prepare_input_com<E: Pairing>(com_s: &[IppCom<E>], pub_inputs: &[E::ScalarField]) -> IppCom<E> {
+    // The first public input is always 1, so com_s[0] gets no coeff
+    com_s[1..]
+        .iter()
+        .zip(pub_inputs)
+        .fold(com_s[0], |acc, (com, x)| acc + *com * x)
+}
+
+/// Computes the commitment to the prepared public inputs of every instance, i.e., the sum of each
+/// instance's [`prepare_input_com`]
+fn prepare_batch_input_com<E: Pairing>(
+    com_s: &[Vec<IppCom<E>>],
+    pub_inputs: &[Vec<E::ScalarField>],
+) -> IppCom<E> {
+    let mut instance_coms = com_s
+        .iter()
+        .zip(pub_inputs)
+        .map(|(com_sk, xk)| prepare_input_com(com_sk, xk));
+    let first = instance_coms.next().unwrap();
+    instance_coms.fold(first, |acc, com| acc + com)
+}
+
+/// Computes the commitment to L and R, given the commitment to A and B, the commitments to the
+/// rest of the LHSs and RHSs, and the powers of s and t they're raised to
+fn combine_lr_coms<E: Pairing>(
+    com_ab: IppCom<E>,
+    other_lhs_coms: &[IppCom<E>],
+    other_rhs_coms: &[IppCom<E>],
+    s_powers: &[E::ScalarField],
+    t_powers: &[E::ScalarField],
+) -> IppCom<E> {
+    let s_partial_sum = other_lhs_coms
+        .iter()
+        .zip(&s_powers[1..])
+        .fold(com_ab, |acc, (com, s_pow)| acc + *com * *s_pow);
+    other_rhs_coms
+        .iter()
+        .zip(&t_powers[1..])
+        .fold(s_partial_sum, |acc, (com, t_pow)| acc + *com * *t_pow)
+}
+
+/// Verifies an aggregate proof with respect to the given super-commitments, execution tree root,
+/// and Fiat-Shamir challenges. `pt` MUST be in the same state as the one given to
+/// [`AggProvingKey::agg_subcircuit_proofs`].
+pub fn verify_agg_proof<E: Pairing>(
+    vk: &AggVerifyingKey<E>,
+    pt: &mut ProtoTranscript,
+    super_coms: &[IppCom<E>],
+    exec_tree_root: &E::ScalarField,
+    challenges: &[E::ScalarField],
+    proof: &AggProof<E>,
+) -> bool {
+    // The public inputs to every subcircuit are the challenges followed by the root
+    let pub_inputs = [challenges, &[*exec_tree_root]].concat();
+    verify_batch_agg_proof(vk, pt, super_coms, &[pub_inputs], proof)
+}
+
+/// Verifies an aggregate proof over several instances with respect to the given super-commitments
+/// and the Groth16 public inputs of each instance. `pt` MUST be in the same state as the one given
+/// to [`AggProvingKey::agg_batch_proofs`].
+pub fn verify_batch_agg_proof<E: Pairing>(
+    vk: &AggVerifyingKey<E>,
+    pt: &mut ProtoTranscript,
+    super_coms: &[IppCom<E>],
+    pub_inputs: &[Vec<E::ScalarField>],
+    proof: &AggProof<E>,
+) -> bool {
+    let start = start_timer!(|| "Verifying aggregate proof");
+    let num_proofs = vk.padded_size;
+
+    // There must be public inputs for every instance
+    if pub_inputs.len() != vk.com_s.len()
+        || pub_inputs
+            .iter()
+            .zip(&vk.com_s)
+            .any(|(xk, com_sk)| xk.len() + 1 != com_sk.len())
+    {
+        return false;
+    }
+    // There must be a super-commitment per committed stage
+    if super_coms.len() + 1 != vk.com_deltas.len() {
+        return false;
+    }
+    // The cross terms must be a square matrix, with a row for each of A, S, the Ds, and C
+    let num_terms = super_coms.len() + 3;
+    if proof.cross_terms.len() != num_terms
+        || proof.cross_terms.iter().any(|row| row.len() != num_terms)
+    {
+        return false;
+    }
+
+    let AggProof {
+        com_ab,
+        com_c,
+        cross_terms,
+        tipp_proof,
+    } = proof;
+    let com_ds = super_coms;
+    let com_prepared_input = prepare_batch_input_com(&vk.com_s, pub_inputs);
+
+    // Recompute the random linear combination challenge
+    pt.append_serializable(b"AB-commitment", com_ab);
+    pt.append_serializable(b"C-commitment", com_c);
+    for com_d in com_ds {
+        pt.append_serializable(b"D-commitment", com_d);
+    }
+    let twist = pt.challenge_scalar::<E::ScalarField>(b"r-random-fiatshamir");
+
+    // Check that the pairing product equation holds with the r coeffs, i.e.,
+    // e(A', B) = e(α, β)^{Σ rⁱ} · e(S', H) · e(D₀', δ₀) · ... · e(C', δₙ), where the sum is over
+    // the real proofs. The padding proofs are all zeros
+    let num_real_proofs = vk.num_instances * vk.num_subcircuits;
+    let twist_powers_sum: E::ScalarField =
+        structured_scalar_power(num_real_proofs, twist).iter().sum();
+    let alpha_beta_r = vk.alpha_beta * twist_powers_sum;
+    let diagonal_sum: PairingOutput<E> = (1..num_terms).map(|k| cross_terms[k][k]).sum();
+    if cross_terms[0][0] != alpha_beta_r + diagonal_sum {
+        return false;
+    }
+
+    // Get challenges s,t
+    pt.append_serializable(b"cross-terms", cross_terms);
+    let s = pt.challenge_scalar::<E::ScalarField>(b"s-random-fiatshamir");
+    let t = pt.challenge_scalar::<E::ScalarField>(b"t-random-fiatshamir");
+    let s_powers = structured_scalar_power(num_terms, s);
+    let t_powers = structured_scalar_power(num_terms, t);
+
+    // The product of the left and right sides is the combination of the cross terms wrt powers of
+    // s and t
+    let z_lr: PairingOutput<E> = cross_terms
+        .iter()
+        .zip(&s_powers)
+        .flat_map(|(row, s_pow)| {
+            row.iter()
+                .zip(&t_powers)
+                .map(move |(term, t_pow)| *term * (*s_pow * t_pow))
+        })
+        .sum();
+    // Compute the corresponding commitment
+    let com_lr = combine_lr_coms(
+        *com_ab,
+        &[&[com_prepared_input][..], com_ds, &[*com_c]].concat(),
+        &[&[vk.com_h][..], &vk.com_deltas].concat(),
+        &s_powers,
+        &t_powers,
+    );
+
+    let instance = ark_ip_proofs::gipa::Instance {
+        size: num_proofs,
+        output: z_lr,
+        commitment: com_lr,
+        twist,
+    };
+    let res = TIPA::<_, Sha256>::verify(&vk.tipp_vk, &instance, tipp_proof).unwrap_or(false);
+    end_timer!(start);
+
+    res
+}
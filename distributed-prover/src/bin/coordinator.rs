@@ -1,24 +1,31 @@
 use ark_ip_proofs::tipa::TIPA;
 use distributed_prover::{
-    aggregation::AggProvingKey,
+    aggregation::{tipp_size, AggProvingKey},
+    circuits::{
+        with_registered_circuit, RegisteredCircuit, SetupCommand, WithRegisteredCircuit,
+        WithSetupArgs,
+    },
     coordinator::{CoordinatorStage0State, FinalAggState, G16ProvingKeyGenerator},
-    poseidon_util::{
-        gen_merkle_params, PoseidonTreeConfig as TreeConfig, PoseidonTreeConfigVar as TreeConfigVar,
+    poseidon_util::{gen_merkle_params, PoseidonTreeConfig, PoseidonTreeConfigVar},
+    util::{
+        cli_filenames::*, deserialize_from_path, serialize_to_path, serialize_to_paths,
+        G16ProvingKey,
     },
-    tree_hash_circuit::{MerkleTreeCircuit, MerkleTreeCircuitParams},
-    util::{cli_filenames::*, deserialize_from_path, serialize_to_path, serialize_to_paths},
     worker::{Stage0Response, Stage1Response},
     CircuitWithPortals,
 };
 use sha2::Sha256;
 
-use std::{io, path::PathBuf};
+use std::{collections::BTreeMap, io, path::PathBuf};
 
 use ark_bls12_381::{Bls12_381 as E, Fr};
 use ark_std::{end_timer, start_timer};
-use clap::{Parser, Subcommand};
+use clap::{Args as ClapArgs, Parser, Subcommand};
 use rayon::prelude::*;
 
+type TreeConfig = PoseidonTreeConfig<Fr>;
+type TreeConfigVar = PoseidonTreeConfigVar<Fr>;
+
 #[derive(Parser)]
 struct Args {
     #[clap(subcommand)]
@@ -27,30 +34,11 @@ struct Args {
 
 #[derive(Subcommand)]
 enum Command {
-    /// Generates the Groth16 proving keys and aggregation key  for a test circuit consisting of
-    /// `n` subcircuits. Places them in coord-state-dir
-    /// cargo run  --bin coordinator gen-keys --g16-pk-dir ./pk-nc=4-ns=4-np=4  --coord-state-dir ./co-nc=4-ns=4-np=4 --num-subcircuits 2 --num-sha2-iters 4 --num-portals 4
-    GenKeys {
-        /// Directory where the Groth16 proving keys will be stored
-        #[clap(long, value_name = "DIR")]
-        g16_pk_dir: PathBuf,
-
-        /// Directory where the coordinator's intermediate state is stored.
-        #[clap(long, value_name = "DIR")]
-        coord_state_dir: PathBuf,
-
-        /// Test circuit param: Number of subcircuits. MUST be a power of two and greater than 1.
-        #[clap(long, value_name = "NUM")]
-        num_subcircuits: usize,
-
-        /// Test circuit param: Number of SHA256 iterations per subcircuit. MUST be at least 1.
-        #[clap(long, value_name = "NUM")]
-        num_sha2_iters: usize,
-
-        /// Test circuit param: Number of portal wire ops per subcircuit. MUST be at least 1.
-        #[clap(long, value_name = "NUM")]
-        num_portals: usize,
-    },
+    // Generates the Groth16 proving keys and aggregation key for a registered circuit. Places
+    // them in g16-pk-dir and coord-state-dir, along with the circuit's ID and parameters
+    // cargo run  --bin coordinator setup-big-merkle --g16-pk-dir ./pk-nc=4-ns=4-np=4  --coord-state-dir ./co-nc=4-ns=4-np=4 --num-subcircuits 2 --num-sha2-iters 4 --num-portals 4
+    #[command(flatten)]
+    Setup(SetupCommand<GenKeysArgs>),
 
     /// Begins stage0 for a random proof for a large circuit with the given parameters. This
     /// produces _worker request packages_ which are processed in parallel by worker nodes.
@@ -93,214 +81,156 @@ enum Command {
     },
 }
 
-// Checks the test circuit parameters and puts them in a struct
-fn gen_test_circuit_params(
-    num_subcircuits: usize,
-    num_sha_iterations: usize,
-    num_portals_per_subcircuit: usize,
-) -> MerkleTreeCircuitParams {
-    assert!(
-        num_subcircuits.is_power_of_two(),
-        "#subcircuits MUST be a power of 2"
-    );
-    assert!(num_subcircuits > 1, "num. of subcircuits MUST be > 1");
-    assert!(
-        num_sha_iterations > 0,
-        "num. of SHA256 iterations per subcircuit MUST be > 0"
-    );
-    assert!(
-        num_portals_per_subcircuit > 0,
-        "num. of portal ops per subcircuit MUST be > 0"
-    );
+/// The args of every setup subcommand, besides the circuit's own
+#[derive(ClapArgs)]
+struct GenKeysArgs {
+    /// Directory where the Groth16 proving keys will be stored
+    #[clap(long, value_name = "DIR")]
+    g16_pk_dir: PathBuf,
 
-    MerkleTreeCircuitParams {
-        num_leaves: num_subcircuits / 2,
-        num_sha_iters_per_subcircuit: num_sha_iterations,
-        num_portals_per_subcircuit,
+    /// Directory where the coordinator's intermediate state is stored.
+    #[clap(long, value_name = "DIR")]
+    coord_state_dir: PathBuf,
+}
+
+/// Runs a setup subcommand
+struct GenKeys;
+
+impl WithSetupArgs<GenKeysArgs> for GenKeys {
+    type Output = ();
+
+    fn call<C: RegisteredCircuit>(self, gen_keys_args: GenKeysArgs, args: C::Args) {
+        let GenKeysArgs {
+            g16_pk_dir,
+            coord_state_dir,
+        } = gen_keys_args;
+
+        // Make the circuit params and save them to disk, along with the circuit's ID. The workers
+        // find the circuit by the ID in the proving key dir
+        let circ_params = C::params_from_args::<Fr>(args);
+        let circ_id = C::ID.to_string();
+        serialize_to_path(&circ_id, &coord_state_dir, CIRC_ID_FILENAME_PREFIX, None).unwrap();
+        serialize_to_path(&circ_id, &g16_pk_dir, CIRC_ID_FILENAME_PREFIX, None).unwrap();
+        serialize_to_path(
+            &circ_params,
+            &coord_state_dir,
+            TEST_CIRC_PARAM_FILENAME_PREFIX,
+            None,
+        )
+        .unwrap();
+
+        // Now make the keys
+        generate_g16_pks::<C>(circ_params, &g16_pk_dir, &coord_state_dir);
     }
 }
 
+/// Runs a command other than setup on the given circuit
+struct RunCommand(Command);
+
+impl WithRegisteredCircuit for RunCommand {
+    type Output = ();
+
+    fn call<C: RegisteredCircuit>(self) {
+        match self.0 {
+            Command::StartStage0 {
+                req_dir,
+                coord_state_dir,
+            } => {
+                begin_stage0::<C>(&req_dir, &coord_state_dir).unwrap();
+            },
+
+            Command::StartStage1 {
+                resp_dir,
+                coord_state_dir,
+                req_dir,
+            } => {
+                process_stage0_resps::<C>(&coord_state_dir, &req_dir, &resp_dir);
+            },
+
+            Command::EndProof {
+                coord_state_dir,
+                resp_dir,
+            } => {
+                process_stage1_resps::<C>(&coord_state_dir, &resp_dir);
+            },
+
+            Command::Setup(_) => unreachable!("setup doesn't run on an existing circuit"),
+        }
+    }
+}
+
+/// Reads the parameters of the circuit that the keys were made for
+fn load_circ_params<C: RegisteredCircuit>(coord_state_dir: &PathBuf) -> C::Params {
+    deserialize_from_path::<C::Params>(coord_state_dir, TEST_CIRC_PARAM_FILENAME_PREFIX, None)
+        .unwrap()
+}
+
+/// Returns the number of subcircuits of the circuit `C` with the given parameters
+fn num_subcircuits<C: RegisteredCircuit>(circ_params: &C::Params) -> usize {
+    <C::Circuit<Fr> as CircuitWithPortals<Fr>>::new(circ_params).num_subcircuits()
+}
+
 /// Generates all the Groth16 proving and committing keys keys that the workers will use
-fn generate_g16_pks(
-    circ_params: MerkleTreeCircuitParams,
+fn generate_g16_pks<C: RegisteredCircuit>(
+    circ_params: C::Params,
     g16_pk_dir: &PathBuf,
     coord_state_dir: &PathBuf,
 ) {
     let mut rng = rand::thread_rng();
-    let tree_params = gen_merkle_params();
+    let tree_params = gen_merkle_params::<Fr>();
 
     // Make an empty circuit of the correct size
-    let circ = <MerkleTreeCircuit as CircuitWithPortals<Fr>>::new(&circ_params);
-    let num_subcircuits = <MerkleTreeCircuit as CircuitWithPortals<Fr>>::num_subcircuits(&circ);
+    let circ = <C::Circuit<Fr> as CircuitWithPortals<Fr>>::new(&circ_params);
+    let num_subcircuits = circ.num_subcircuits();
+    println!("Number of subcircuits: {num_subcircuits}");
 
     let generator = G16ProvingKeyGenerator::<TreeConfig, TreeConfigVar, E, _>::new(
+        &mut rng,
         circ.clone(),
         tree_params.clone(),
     );
 
-    // We don't actually have to generate every circuit proving key individually. Remember the test
+    // We don't actually have to generate every circuit proving key individually. Every subcircuit
+    // uses the key of its representative among the unique subcircuits, e.g., the Merkle tree
     // circuit only really has 5 subcircuits: the first leaf, the root, every other leaf, every
-    // other parent, and the final padding circuit. So we only have to generate 5 proving keys and
-    // copy them a bunch of times.
-
-    // First a special case: if there's just 4 subcircuits, generate them all and be done with it
-    if num_subcircuits <= 4 {
-        // Generate the subcircuit's G16 proving keys
-        let pks = (0..num_subcircuits)
-            .map(|subcircuit_idx| generator.gen_pk(&mut rng, subcircuit_idx))
-            .collect::<Vec<_>>();
-
-        // Save them and the corresponding committing key
-        for (subcircuit_idx, pk) in pks.iter().enumerate() {
-            serialize_to_path(pk, g16_pk_dir, G16_PK_FILENAME_PREFIX, Some(subcircuit_idx))
-                .unwrap();
-            // Save the corresponding committing key
-            serialize_to_path(
-                &pk.ck,
-                g16_pk_dir,
-                G16_CK_FILENAME_PREFIX,
-                Some(subcircuit_idx),
-            )
-            .unwrap();
-        }
-
-        let pk_fetcher = |subcircuit_idx: usize| &pks[subcircuit_idx];
-
-        // Construct the aggregator commitment key
-        let start =
-            start_timer!(|| format!("Generating aggregation key with params {circ_params}"));
-        let agg_ck = {
-            let (tipp_pk, _tipp_vk) = TIPA::<E, Sha256>::setup(num_subcircuits, &mut rng).unwrap();
-            AggProvingKey::new(tipp_pk, pk_fetcher)
-        };
-        end_timer!(start);
-
-        // Save the aggregator key
-        println!("Writing aggregation key");
-        serialize_to_path(&agg_ck, coord_state_dir, AGG_CK_FILENAME_PREFIX, None).unwrap();
+    // other parent, and the final padding circuit. So we only generate those keys and copy them a
+    // bunch of times.
+    let pks: BTreeMap<usize, G16ProvingKey<E>> = circ
+        .get_unique_subcircuits()
+        .into_iter()
+        .map(|subcircuit_idx| (subcircuit_idx, generator.gen_pk(&mut rng, subcircuit_idx)))
+        .collect();
+    let pk_fetcher = |subcircuit_idx: usize| &pks[&circ.representative_subcircuit(subcircuit_idx)];
+
+    // Save the proving keys and the corresponding committing keys. Consecutive subcircuits with
+    // the same key are written together, so the key is only serialized once
+    println!("Writing proving keys");
+    let mut start_idx = 0;
+    while start_idx < num_subcircuits {
+        let representative = circ.representative_subcircuit(start_idx);
+        let end_idx = (start_idx + 1..num_subcircuits)
+            .find(|&i| circ.representative_subcircuit(i) != representative)
+            .unwrap_or(num_subcircuits);
+
+        let pk = pk_fetcher(start_idx);
+        serialize_to_paths(pk, g16_pk_dir, G16_PK_FILENAME_PREFIX, start_idx..end_idx).unwrap();
+        serialize_to_paths(
+            &pk.ck,
+            g16_pk_dir,
+            G16_CK_FILENAME_PREFIX,
+            start_idx..end_idx,
+        )
+        .unwrap();
 
-        return;
+        start_idx = end_idx;
     }
 
-    // Now if there are more than 4 subcircuits:
-
-    // Generate the first leaf
-    let first_leaf_pk = generator.gen_pk(&mut rng, 0);
-    // Generate the second leaf
-    let second_leaf_pk = generator.gen_pk(&mut rng, 1);
-    // Generate the padding
-    let padding_pk = generator.gen_pk(&mut rng, num_subcircuits - 1);
-    // Generate the root
-    let root_pk = generator.gen_pk(&mut rng, num_subcircuits - 2);
-    // Generate the second to last parent
-    let parent_pk = generator.gen_pk(&mut rng, num_subcircuits - 3);
-
-    // Now save them
-
-    // Save the first leaf (proving key and committing key)
-    println!("Writing first leaf proving key");
-    serialize_to_path(&first_leaf_pk, g16_pk_dir, G16_PK_FILENAME_PREFIX, Some(0)).unwrap();
-    serialize_to_path(
-        &first_leaf_pk.ck,
-        g16_pk_dir,
-        G16_CK_FILENAME_PREFIX,
-        Some(0),
-    )
-    .unwrap();
-
-    // Save all the rest of the leaves
-    println!("Writing leaf proving keys");
-    let other_leaf_idxs = 1..(num_subcircuits / 2);
-    serialize_to_paths(
-        &second_leaf_pk,
-        g16_pk_dir,
-        G16_PK_FILENAME_PREFIX,
-        other_leaf_idxs.clone(),
-    )
-    .unwrap();
-    serialize_to_paths(
-        &second_leaf_pk.ck,
-        g16_pk_dir,
-        G16_CK_FILENAME_PREFIX,
-        other_leaf_idxs.clone(),
-    )
-    .unwrap();
-
-    // Save all the parents
-    println!("Writing parent proving keys");
-    let parent_idxs = (num_subcircuits / 2)..(num_subcircuits - 2);
-    serialize_to_paths(
-        &parent_pk,
-        g16_pk_dir,
-        G16_PK_FILENAME_PREFIX,
-        parent_idxs.clone(),
-    )
-    .unwrap();
-    serialize_to_paths(
-        &parent_pk.ck,
-        g16_pk_dir,
-        G16_CK_FILENAME_PREFIX,
-        parent_idxs.clone(),
-    )
-    .unwrap();
-
-    // Save the root
-    println!("Writing root proving key");
-    serialize_to_path(
-        &root_pk,
-        g16_pk_dir,
-        G16_PK_FILENAME_PREFIX,
-        Some(num_subcircuits - 2),
-    )
-    .unwrap();
-    serialize_to_path(
-        &root_pk.ck,
-        g16_pk_dir,
-        G16_CK_FILENAME_PREFIX,
-        Some(num_subcircuits - 2),
-    )
-    .unwrap();
-
-    // Save the padding
-    println!("Writing padding proving key");
-    serialize_to_path(
-        &padding_pk,
-        g16_pk_dir,
-        G16_PK_FILENAME_PREFIX,
-        Some(num_subcircuits - 1),
-    )
-    .unwrap();
-    serialize_to_path(
-        &padding_pk.ck,
-        g16_pk_dir,
-        G16_CK_FILENAME_PREFIX,
-        Some(num_subcircuits - 1),
-    )
-    .unwrap();
-
-    // To generate the aggregation key, we need an efficient G16 pk fetcher. Normally this hits
-    // disk, but this might take a long long time.
-    let pk_fetcher = |subcircuit_idx: usize| {
-        if subcircuit_idx == 0 {
-            &first_leaf_pk
-        } else if other_leaf_idxs.contains(&subcircuit_idx) {
-            &second_leaf_pk
-        } else if parent_idxs.contains(&subcircuit_idx) {
-            &parent_pk
-        } else if subcircuit_idx == num_subcircuits - 2 {
-            &root_pk
-        } else if subcircuit_idx == num_subcircuits - 1 {
-            &padding_pk
-        } else {
-            panic!("unexpected subcircuit index {subcircuit_idx}")
-        }
-    };
-
     // Construct the aggregator commitment key
-    let start = start_timer!(|| format!("Generating aggregation key with params {circ_params}"));
+    let start = start_timer!(|| format!("Generating aggregation key for the {}", C::ID));
     let agg_ck = {
-        let (tipp_pk, _tipp_vk) = TIPA::<E, Sha256>::setup(num_subcircuits, &mut rng).unwrap();
-        AggProvingKey::new(tipp_pk, pk_fetcher)
+        let (tipp_pk, _tipp_vk) =
+            TIPA::<E, Sha256>::setup(tipp_size(num_subcircuits), &mut rng).unwrap();
+        AggProvingKey::new(tipp_pk, num_subcircuits, pk_fetcher)
     };
     end_timer!(start);
 
@@ -309,42 +239,40 @@ fn generate_g16_pks(
     serialize_to_path(&agg_ck, coord_state_dir, AGG_CK_FILENAME_PREFIX, None).unwrap();
 }
 
-fn begin_stage0(worker_req_dir: &PathBuf, coord_state_dir: &PathBuf) -> io::Result<()> {
+fn begin_stage0<C: RegisteredCircuit>(
+    worker_req_dir: &PathBuf,
+    coord_state_dir: &PathBuf,
+) -> io::Result<()> {
     let mut rng = rand::thread_rng();
     let stage0_timer = start_timer!(|| "Begin Stage0");
 
     let circ_params_timer = start_timer!(|| "Deserializing circuit parameters");
     // Get the circuit parameters determined at Groth16 PK generation
-    let circ_params = deserialize_from_path::<MerkleTreeCircuitParams>(
-        &coord_state_dir,
-        TEST_CIRC_PARAM_FILENAME_PREFIX,
-        None,
-    )
-    .unwrap();
+    let circ_params = load_circ_params::<C>(coord_state_dir);
     end_timer!(circ_params_timer);
 
-    let num_subcircuits = 2 * circ_params.num_leaves;
+    let num_subcircuits = num_subcircuits::<C>(&circ_params);
+    C::print_info(&circ_params, num_subcircuits);
 
-    let merkle_tree_timer =
-        start_timer!(|| format!("Sampling a random MerkleTreeCircuit with parapms {circ_params}"));
+    let rand_circ_timer = start_timer!(|| format!("Sampling a random {}", C::ID));
     // Make a random circuit with the given parameters
     println!("Making a random circuit");
-    let circ = <MerkleTreeCircuit as CircuitWithPortals<Fr>>::rand(&mut rng, &circ_params);
-    end_timer!(merkle_tree_timer);
+    let circ = <C::Circuit<Fr> as CircuitWithPortals<Fr>>::rand(&mut rng, &circ_params);
+    end_timer!(rand_circ_timer);
 
     // Make the stage0 coordinator state
     println!("Building stage0 state");
     let stage0_state = CoordinatorStage0State::<E, _>::new::<TreeConfig>(circ);
 
     // Sender sends stage0 requests containing the subtraces. Workers will commit to these
-    let start = start_timer!(|| format!("Generating stage0 requests with params {circ_params}"));
+    let start = start_timer!(|| format!("Generating stage0 requests for the {}", C::ID));
     let reqs = (0..num_subcircuits)
         .into_par_iter()
         .map(|subcircuit_idx| stage0_state.gen_request(subcircuit_idx))
         .collect::<Vec<_>>();
     end_timer!(start);
 
-    let write_timer = start_timer!(|| format!("Writing stage0 requests with params {circ_params}"));
+    let write_timer = start_timer!(|| format!("Writing stage0 requests for the {}", C::ID));
     reqs.into_par_iter()
         .enumerate()
         .for_each(|(subcircuit_idx, req)| {
@@ -372,21 +300,19 @@ fn begin_stage0(worker_req_dir: &PathBuf, coord_state_dir: &PathBuf) -> io::Resu
     Ok(())
 }
 
-fn process_stage0_resps(coord_state_dir: &PathBuf, req_dir: &PathBuf, resp_dir: &PathBuf) {
-    let tree_params = gen_merkle_params();
+fn process_stage0_resps<C: RegisteredCircuit>(
+    coord_state_dir: &PathBuf,
+    req_dir: &PathBuf,
+    resp_dir: &PathBuf,
+) {
+    let tree_params = gen_merkle_params::<Fr>();
 
     // Get the circuit parameters determined at Groth16 PK generation
-    let circ_params = deserialize_from_path::<MerkleTreeCircuitParams>(
-        &coord_state_dir,
-        TEST_CIRC_PARAM_FILENAME_PREFIX,
-        None,
-    )
-    .unwrap();
-    // Num subcircuits is 2× num leaves
-    let num_subcircuits = 2 * circ_params.num_leaves;
+    let circ_params = load_circ_params::<C>(coord_state_dir);
+    let num_subcircuits = num_subcircuits::<C>(&circ_params);
 
     // Deserialize the coordinator's state and the aggregation key
-    let coord_state = deserialize_from_path::<CoordinatorStage0State<E, MerkleTreeCircuit>>(
+    let coord_state = deserialize_from_path::<CoordinatorStage0State<E, C::Circuit<Fr>>>(
         coord_state_dir,
         STAGE0_COORD_STATE_FILENAME_PREFIX,
         None,
@@ -420,9 +346,7 @@ fn process_stage0_resps(coord_state_dir: &PathBuf, req_dir: &PathBuf, resp_dir:
         coord_state.process_stage0_responses(&super_com_key, tree_params, &stage0_resps);
 
     // Create all the stage1 requests
-    let start = start_timer!(|| format!(
-        "Generating stage1 requests for circuit with params {circ_params}"
-    ));
+    let start = start_timer!(|| format!("Generating stage1 requests for the {}", C::ID));
     let reqs = (0..num_subcircuits)
         .into_par_iter()
         .map(|subcircuit_idx| new_coord_state.gen_request(subcircuit_idx))
@@ -452,24 +376,18 @@ fn process_stage0_resps(coord_state_dir: &PathBuf, req_dir: &PathBuf, resp_dir:
     .unwrap();
 }
 
-fn process_stage1_resps(coord_state_dir: &PathBuf, resp_dir: &PathBuf) {
+fn process_stage1_resps<C: RegisteredCircuit>(coord_state_dir: &PathBuf, resp_dir: &PathBuf) {
     // Get the circuit parameters determined at Groth16 PK generation
-    let circ_params = deserialize_from_path::<MerkleTreeCircuitParams>(
-        &coord_state_dir,
-        TEST_CIRC_PARAM_FILENAME_PREFIX,
-        None,
-    )
-    .unwrap();
-    // Num subcircuits is 2× num leaves
-    let num_subcircuits = 2 * circ_params.num_leaves;
+    let circ_params = load_circ_params::<C>(coord_state_dir);
+    let num_subcircuits = num_subcircuits::<C>(&circ_params);
 
     // Deserialize the coordinator's final state, the aggregation key
-    let final_agg_state = deserialize_from_path::<FinalAggState<E>>(
+    let final_agg_state = deserialize_from_path::<FinalAggState<TreeConfig, E>>(
         coord_state_dir,
         FINAL_AGG_STATE_FILENAME_PREFIX,
         None,
     )
-    .unwrap(); 
+    .unwrap();
     let agg_ck =
         deserialize_from_path::<AggProvingKey<E>>(coord_state_dir, AGG_CK_FILENAME_PREFIX, None)
             .unwrap();
@@ -489,8 +407,7 @@ fn process_stage1_resps(coord_state_dir: &PathBuf, resp_dir: &PathBuf) {
         .collect::<Vec<_>>();
 
     // Compute the aggregate
-    let start =
-        start_timer!(|| format!("Aggregating proofs for circuit with params {circ_params}"));
+    let start = start_timer!(|| format!("Aggregating proofs for the {}", C::ID));
     let agg_proof = final_agg_state.gen_agg_proof(&agg_ck, &stage1_resps);
     end_timer!(start);
     // Save the proof
@@ -504,47 +421,26 @@ fn main() {
     let start = start_timer!(|| format!("Running coordinator"));
 
     match args.command {
-        Command::GenKeys {
-            g16_pk_dir,
-            coord_state_dir,
-            num_subcircuits,
-            num_sha2_iters,
-            num_portals,
-        } => {
-            // Make the circuit params and save them to disk
-            let circ_params = gen_test_circuit_params(num_subcircuits, num_sha2_iters, num_portals);
-            serialize_to_path(
-                &circ_params,
-                &coord_state_dir,
-                TEST_CIRC_PARAM_FILENAME_PREFIX,
-                None,
-            )
-            .unwrap();
-
-            // Now run the subcommand
-            generate_g16_pks(circ_params, &g16_pk_dir, &coord_state_dir);
-        },
-
-        Command::StartStage0 {
-            req_dir,
-            coord_state_dir,
-        } => {
-            begin_stage0(&req_dir, &coord_state_dir).unwrap();
-        },
-
-        Command::StartStage1 {
-            resp_dir,
-            coord_state_dir,
-            req_dir,
-        } => {
-            process_stage0_resps(&coord_state_dir, &req_dir, &resp_dir);
-        },
-
-        Command::EndProof {
-            coord_state_dir,
-            resp_dir,
-        } => {
-            process_stage1_resps(&coord_state_dir, &resp_dir);
+        Command::Setup(setup) => setup.run(GenKeys),
+
+        // The other commands run on the circuit that the keys were made for
+        command => {
+            let coord_state_dir = match &command {
+                Command::StartStage0 {
+                    coord_state_dir, ..
+                }
+                | Command::StartStage1 {
+                    coord_state_dir, ..
+                }
+                | Command::EndProof {
+                    coord_state_dir, ..
+                } => coord_state_dir,
+                Command::Setup(_) => unreachable!(),
+            };
+            let circ_id =
+                deserialize_from_path::<String>(coord_state_dir, CIRC_ID_FILENAME_PREFIX, None)
+                    .unwrap();
+            with_registered_circuit(&circ_id, RunCommand(command));
         },
     }
 
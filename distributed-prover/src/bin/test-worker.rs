@@ -1,6 +1,6 @@
 use distributed_prover::{
     coordinator::{Stage0Request, Stage1Request},
-    poseidon_util::{gen_merkle_params, PoseidonTreeConfigVar as TreeConfigVar},
+    poseidon_util::{gen_merkle_params, PoseidonTreeConfigVar},
     util::{cli_filenames::*, deserialize_from_path, serialize_to_path, G16ProvingKey},
     worker::Stage0Response,
     test_circuit::ZkDbSqlCircuit,
@@ -12,6 +12,8 @@ use ark_bls12_381::{Bls12_381 as E, Fr};
 use ark_std::{end_timer, start_timer};
 use clap::{Parser, Subcommand};
 
+type TreeConfigVar = PoseidonTreeConfigVar<Fr>;
+
 #[derive(Parser)]
 struct Args {
     #[clap(subcommand)]
@@ -72,7 +74,7 @@ fn process_stage0_request(
     out_dir: &PathBuf,
 ) {
     let mut rng = rand::thread_rng();
-    let tree_params = gen_merkle_params();
+    let tree_params = gen_merkle_params::<Fr>();
 
     // Deserialize the appropriate committing key and request
     let start = start_timer!(|| "Deserializing g16 com key");
@@ -125,7 +127,7 @@ fn process_stage1_request(
     out_dir: &PathBuf,
 ) {
     let mut rng = rand::thread_rng();
-    let tree_params = gen_merkle_params();
+    let tree_params = gen_merkle_params::<Fr>();
 
     // Deserialize the appropriate proving key, old request, old response, and new request
     let g16_pk = deserialize_from_path::<G16ProvingKey<E>>(
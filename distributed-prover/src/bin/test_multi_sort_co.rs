@@ -1,10 +1,8 @@
 use ark_ip_proofs::tipa::TIPA;
 use distributed_prover::{
-    aggregation::AggProvingKey,
+    aggregation::{tipp_size, AggProvingKey},
     coordinator::{CoordinatorStage0State, FinalAggState, G16ProvingKeyGenerator},
-    poseidon_util::{
-        gen_merkle_params, PoseidonTreeConfig as TreeConfig, PoseidonTreeConfigVar as TreeConfigVar,
-    },
+    poseidon_util::{gen_merkle_params, PoseidonTreeConfig, PoseidonTreeConfigVar},
     test_circuit::{ZkDbSqlCircuit, ZkDbSqlCircuitParams},
     util::{cli_filenames::*, deserialize_from_path, serialize_to_path, serialize_to_paths},
     worker::{Stage0Response, Stage1Response},
@@ -19,6 +17,9 @@ use ark_std::{end_timer, start_timer};
 use clap::{Parser, Subcommand};
 use rayon::prelude::*;
 
+type TreeConfig = PoseidonTreeConfig<Fr>;
+type TreeConfigVar = PoseidonTreeConfigVar<Fr>;
+
 #[derive(Parser)]
 struct Args {
     #[clap(subcommand)]
@@ -105,13 +106,14 @@ fn generate_g16_pks(
 ) {
     
     let mut rng = rand::thread_rng();
-    let tree_params = gen_merkle_params();
+    let tree_params = gen_merkle_params::<Fr>();
 
     // Make an empty circuit of the correct size
     let circ = <ZkDbSqlCircuit<Fr> as CircuitWithPortals<Fr>>::new(&circ_params);
     let num_subcircuits = <ZkDbSqlCircuit<Fr> as CircuitWithPortals<Fr>>::num_subcircuits(&circ);
     println!("Making a test circuit with {num_subcircuits} subcircuits");
     let generator = G16ProvingKeyGenerator::<TreeConfig, TreeConfigVar, E, _>::new(
+        &mut rng,
         circ.clone(),
         tree_params.clone(),
     );
@@ -164,8 +166,9 @@ fn generate_g16_pks(
     // Construct the aggregator commitment key
     let agg_start = Instant::now();
     let agg_ck = {
-        let (tipp_pk, _tipp_vk) = TIPA::<E, Sha256>::setup(num_subcircuits, &mut rng).unwrap();
-        AggProvingKey::new(tipp_pk, pk_fetcher)
+        let (tipp_pk, _tipp_vk) =
+            TIPA::<E, Sha256>::setup(tipp_size(num_subcircuits), &mut rng).unwrap();
+        AggProvingKey::new(tipp_pk, num_subcircuits, pk_fetcher)
     };
     let elapsed = agg_start.elapsed();
     println!("agg_ck construct Elapsed time: {:?}", elapsed);
@@ -239,7 +242,7 @@ fn begin_stage0(worker_req_dir: &PathBuf, coord_state_dir: &PathBuf) -> io::Resu
 }
 
 fn process_stage0_resps(coord_state_dir: &PathBuf, req_dir: &PathBuf, resp_dir: &PathBuf) {
-    let tree_params = gen_merkle_params();
+    let tree_params = gen_merkle_params::<Fr>();
 
     // Get the circuit parameters determined at Groth16 PK generation
     let circ_params = deserialize_from_path::<ZkDbSqlCircuitParams>(
@@ -329,7 +332,7 @@ fn process_stage1_resps(coord_state_dir: &PathBuf, resp_dir: &PathBuf) {
     let num_subcircuits = (circ_params.num_rows+63)/64;
 
     // Deserialize the coordinator's final state, the aggregation key
-    let final_agg_state = deserialize_from_path::<FinalAggState<E>>(
+    let final_agg_state = deserialize_from_path::<FinalAggState<TreeConfig, E>>(
         coord_state_dir,
         FINAL_AGG_STATE_FILENAME_PREFIX,
         None,
@@ -1,7 +1,7 @@
 use distributed_prover::{
+    circuits::{with_registered_circuit, RegisteredCircuit, WithRegisteredCircuit},
     coordinator::{Stage0Request, Stage1Request},
-    poseidon_util::{gen_merkle_params, PoseidonTreeConfigVar as TreeConfigVar},
-    tree_hash_circuit::MerkleTreeCircuit,
+    poseidon_util::{gen_merkle_params, PoseidonTreeConfigVar},
     util::{cli_filenames::*, deserialize_from_path, serialize_to_path, G16ProvingKey},
     worker::Stage0Response,
 };
@@ -12,6 +12,8 @@ use ark_bls12_381::{Bls12_381 as E, Fr};
 use ark_std::{end_timer, start_timer};
 use clap::{Parser, Subcommand};
 
+type TreeConfigVar = PoseidonTreeConfigVar<Fr>;
+
 #[derive(Parser)]
 struct Args {
     #[clap(subcommand)]
@@ -65,14 +67,46 @@ enum Command {
     },
 }
 
-fn process_stage0_request(
+/// Runs a command on the given circuit
+struct RunCommand(Command);
+
+impl WithRegisteredCircuit for RunCommand {
+    type Output = ();
+
+    fn call<C: RegisteredCircuit>(self) {
+        match self.0 {
+            Command::ProcessStage0Request {
+                g16_pk_dir,
+                req_dir,
+                out_dir,
+                subcircuit_index,
+            } => process_stage0_request::<C>(subcircuit_index, &g16_pk_dir, &req_dir, &out_dir),
+
+            Command::ProcessStage1Request {
+                g16_pk_dir,
+                req_dir,
+                resp_dir,
+                out_dir,
+                subcircuit_index,
+            } => process_stage1_request::<C>(
+                subcircuit_index,
+                &g16_pk_dir,
+                &req_dir,
+                &resp_dir,
+                &out_dir,
+            ),
+        }
+    }
+}
+
+fn process_stage0_request<C: RegisteredCircuit>(
     subcircuit_idx: usize,
     g16_pk_dir: &PathBuf,
     req_dir: &PathBuf,
     out_dir: &PathBuf,
 ) {
     let mut rng = rand::thread_rng();
-    let tree_params = gen_merkle_params();
+    let tree_params = gen_merkle_params::<Fr>();
 
     // Deserialize the appropriate committing key and request
     let start = start_timer!(|| "Deserializing g16 com key");
@@ -101,7 +135,7 @@ fn process_stage0_request(
         _,
         TreeConfigVar,
         _,
-        MerkleTreeCircuit,
+        C::Circuit<Fr>,
         _,
     >(&mut rng, tree_params, &g16_pk, stage0_req);
     end_timer!(start);
@@ -116,7 +150,7 @@ fn process_stage0_request(
     .unwrap();
 }
 
-fn process_stage1_request(
+fn process_stage1_request<C: RegisteredCircuit>(
     subcircuit_idx: usize,
     g16_pk_dir: &PathBuf,
     req_dir: &PathBuf,
@@ -124,7 +158,7 @@ fn process_stage1_request(
     out_dir: &PathBuf,
 ) {
     let mut rng = rand::thread_rng();
-    let tree_params = gen_merkle_params();
+    let tree_params = gen_merkle_params::<Fr>();
 
     // Deserialize the appropriate proving key, old request, old response, and new request
     let g16_pk = deserialize_from_path::<G16ProvingKey<E>>(
@@ -145,7 +179,7 @@ fn process_stage1_request(
         Some(subcircuit_idx),
     )
     .unwrap();
-    let stage1_req = deserialize_from_path::<Stage1Request<_, _, MerkleTreeCircuit>>(
+    let stage1_req = deserialize_from_path::<Stage1Request<_, _, C::Circuit<Fr>>>(
         req_dir,
         STAGE1_REQ_FILENAME_PREFIX,
         Some(subcircuit_idx),
@@ -180,22 +214,14 @@ fn main() {
     let args = Args::parse();
     let start = start_timer!(|| format!("Running worker"));
 
-    match args.command {
-        Command::ProcessStage0Request {
-            g16_pk_dir,
-            req_dir,
-            out_dir,
-            subcircuit_index,
-        } => process_stage0_request(subcircuit_index, &g16_pk_dir, &req_dir, &out_dir),
-
-        Command::ProcessStage1Request {
-            g16_pk_dir,
-            req_dir,
-            resp_dir,
-            out_dir,
-            subcircuit_index,
-        } => process_stage1_request(subcircuit_index, &g16_pk_dir, &req_dir, &resp_dir, &out_dir),
-    }
+    // Run the command on the circuit that the keys were made for
+    let g16_pk_dir = match &args.command {
+        Command::ProcessStage0Request { g16_pk_dir, .. }
+        | Command::ProcessStage1Request { g16_pk_dir, .. } => g16_pk_dir,
+    };
+    let circ_id =
+        deserialize_from_path::<String>(g16_pk_dir, CIRC_ID_FILENAME_PREFIX, None).unwrap();
+    with_registered_circuit(&circ_id, RunCommand(args.command));
 
     end_timer!(start);
 }
@@ -0,0 +1,451 @@
+use crate::{
+    test_circuit::{ZkDbSqlCircuit, ZkDbSqlCircuitParams},
+    tpch::{TpchQ1Circuit, TpchQ1Params, Q1_MAX_GROUPS},
+    tree_hash_circuit::{MerkleTreeCircuit, MerkleTreeCircuitParams},
+    vkd::{
+        MerkleTreeConcreteParameters, SparseMerkleTree, VerifiableKeyDirectoryCircuit,
+        VerifiableKeyDirectoryCircuitParams, DEPTH, HASH_TYPE,
+    },
+    vm::{VirtualMachine, VirtualMachineParameters, MERKLE_MEMORY_DEPTH, REGISTER_NUM},
+    CircuitWithPortals,
+};
+
+use ark_crypto_primitives::sponge::Absorb;
+use ark_ff::PrimeField;
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+use clap::{error::ErrorKind, ArgMatches, Args, FromArgMatches, Subcommand};
+use std::marker::PhantomData;
+
+/// A circuit that the binaries can make proving keys for and prove. A circuit is registered by
+/// implementing this and adding it to [`visit_circuits`]. Every binary then picks it up: the
+/// setup subcommands come from [`SetupCommand`], and a circuit is looked up by its ID with
+/// [`with_registered_circuit`].
+pub trait RegisteredCircuit {
+    /// The ID recorded in the proving keys. This MUST be unique among the registered circuits.
+    const ID: &'static str;
+
+    /// The name of the subcommand that makes the proving keys, e.g., `setup-vkd`
+    const SETUP_COMMAND: &'static str;
+
+    /// The CLI args of the setup subcommand, other than the ones every binary adds to it
+    type Args: Args;
+
+    /// The circuit parameters. These are the same over every field.
+    type Params: Clone + CanonicalSerialize + CanonicalDeserialize + Send;
+
+    /// The circuit over the field `F`
+    type Circuit<F: PrimeField + Absorb>: CircuitWithPortals<F, Parameters = Self::Params>;
+
+    /// Checks the CLI args of the setup subcommand, and makes the circuit parameters over `F`.
+    /// Panics if the args are invalid.
+    fn params_from_args<F: PrimeField + Absorb>(args: Self::Args) -> Self::Params;
+
+    /// Prints whatever is worth knowing about a circuit with the given parameters before proving
+    /// it
+    fn print_info(_params: &Self::Params, _num_subcircuits: usize) {}
+}
+
+/// Something to do with every registered circuit
+pub trait CircuitVisitor {
+    fn visit<C: RegisteredCircuit>(&mut self);
+}
+
+/// Calls `v` on every registered circuit. This is the registry: a new circuit only has to be
+/// added here.
+pub fn visit_circuits(v: &mut impl CircuitVisitor) {
+    v.visit::<TestCircuit>();
+    v.visit::<BigMerkle>();
+    v.visit::<Vkd>();
+    v.visit::<Vm>();
+    v.visit::<TpchQ1>();
+}
+
+/// Something to do with a registered circuit
+pub trait WithRegisteredCircuit {
+    type Output;
+
+    fn call<C: RegisteredCircuit>(self) -> Self::Output;
+}
+
+/// Calls `f` with the registered circuit of the given ID. Panics if no circuit has that ID.
+pub fn with_registered_circuit<W: WithRegisteredCircuit>(id: &str, f: W) -> W::Output {
+    struct Finder<'a, W: WithRegisteredCircuit> {
+        id: &'a str,
+        f: Option<W>,
+        output: Option<W::Output>,
+    }
+
+    impl<W: WithRegisteredCircuit> CircuitVisitor for Finder<'_, W> {
+        fn visit<C: RegisteredCircuit>(&mut self) {
+            if self.id == C::ID {
+                self.output = Some(self.f.take().unwrap().call::<C>());
+            }
+        }
+    }
+
+    let mut finder = Finder {
+        id,
+        f: Some(f),
+        output: None,
+    };
+    visit_circuits(&mut finder);
+    finder
+        .output
+        .unwrap_or_else(|| panic!("unknown circuit ID {id}"))
+}
+
+/// Something to do with the args of a setup subcommand, given the circuit it's for. `A` are the
+/// args the binary adds to every setup subcommand.
+pub trait WithSetupArgs<A> {
+    type Output;
+
+    fn call<C: RegisteredCircuit>(self, common_args: A, args: C::Args) -> Self::Output;
+}
+
+/// The setup subcommands of all the registered circuits, each with the args `A` as well as the
+/// circuit's own. Flatten this into a binary's subcommands to give it a `setup-*` subcommand for
+/// every circuit.
+pub struct SetupCommand<A: Args> {
+    name: String,
+    matches: ArgMatches,
+    _common_args: PhantomData<A>,
+}
+
+impl<A: Args> SetupCommand<A> {
+    /// Parses the args of the subcommand, and calls `f` with them and the circuit they're for
+    pub fn run<W: WithSetupArgs<A>>(self, f: W) -> W::Output {
+        struct Runner<A: Args, W: WithSetupArgs<A>> {
+            cmd: SetupCommand<A>,
+            f: Option<W>,
+            output: Option<W::Output>,
+        }
+
+        impl<A: Args, W: WithSetupArgs<A>> CircuitVisitor for Runner<A, W> {
+            fn visit<C: RegisteredCircuit>(&mut self) {
+                if self.cmd.name != C::SETUP_COMMAND {
+                    return;
+                }
+
+                let common_args =
+                    A::from_arg_matches(&self.cmd.matches).unwrap_or_else(|e| e.exit());
+                let args =
+                    C::Args::from_arg_matches(&self.cmd.matches).unwrap_or_else(|e| e.exit());
+                self.output = Some(self.f.take().unwrap().call::<C>(common_args, args));
+            }
+        }
+
+        let mut runner = Runner {
+            cmd: self,
+            f: Some(f),
+            output: None,
+        };
+        visit_circuits(&mut runner);
+        let name = runner.cmd.name;
+        runner
+            .output
+            .unwrap_or_else(|| panic!("unknown setup command {name}"))
+    }
+}
+
+impl<A: Args> FromArgMatches for SetupCommand<A> {
+    fn from_arg_matches(matches: &ArgMatches) -> Result<Self, clap::Error> {
+        match matches.subcommand() {
+            Some((name, sub_matches)) if Self::has_subcommand(name) => Ok(SetupCommand {
+                name: name.to_string(),
+                matches: sub_matches.clone(),
+                _common_args: PhantomData,
+            }),
+            _ => Err(clap::Error::raw(
+                ErrorKind::MissingSubcommand,
+                "expected a setup subcommand",
+            )),
+        }
+    }
+
+    fn update_from_arg_matches(&mut self, matches: &ArgMatches) -> Result<(), clap::Error> {
+        *self = Self::from_arg_matches(matches)?;
+        Ok(())
+    }
+}
+
+impl<A: Args> Subcommand for SetupCommand<A> {
+    fn augment_subcommands(cmd: clap::Command) -> clap::Command {
+        struct Augmenter<A: Args>(Option<clap::Command>, PhantomData<A>);
+
+        impl<A: Args> CircuitVisitor for Augmenter<A> {
+            fn visit<C: RegisteredCircuit>(&mut self) {
+                let setup_cmd = clap::Command::new(C::SETUP_COMMAND)
+                    .about(format!("Makes the proving keys of the {}", C::ID));
+                let setup_cmd = C::Args::augment_args(A::augment_args(setup_cmd));
+                self.0 = self.0.take().map(|cmd| cmd.subcommand(setup_cmd));
+            }
+        }
+
+        let mut augmenter = Augmenter::<A>(Some(cmd), PhantomData);
+        visit_circuits(&mut augmenter);
+        augmenter.0.unwrap()
+    }
+
+    fn augment_subcommands_for_update(cmd: clap::Command) -> clap::Command {
+        Self::augment_subcommands(cmd)
+    }
+
+    fn has_subcommand(name: &str) -> bool {
+        struct Finder<'a>(&'a str, bool);
+
+        impl CircuitVisitor for Finder<'_> {
+            fn visit<C: RegisteredCircuit>(&mut self) {
+                self.1 |= C::SETUP_COMMAND == self.0;
+            }
+        }
+
+        let mut finder = Finder(name, false);
+        visit_circuits(&mut finder);
+        finder.1
+    }
+}
+
+/// The sorting circuit of `crate::test_circuit`
+pub struct TestCircuit;
+
+#[derive(Args)]
+pub struct TestCircuitArgs {
+    /// Test circuit param: Number of rows of the table. MUST be a power of two and greater than 1.
+    #[clap(long, value_name = "NUM")]
+    num_rows: usize,
+
+    /// Test circuit param: The column to sort the table by
+    #[clap(long, value_name = "NUM", default_value_t = 0)]
+    sort_column_idx: usize,
+}
+
+impl RegisteredCircuit for TestCircuit {
+    const ID: &'static str = "test circuit";
+    const SETUP_COMMAND: &'static str = "setup-test";
+
+    type Args = TestCircuitArgs;
+    type Params = ZkDbSqlCircuitParams;
+    type Circuit<F: PrimeField + Absorb> = ZkDbSqlCircuit<F>;
+
+    fn params_from_args<F: PrimeField + Absorb>(args: TestCircuitArgs) -> ZkDbSqlCircuitParams {
+        ZkDbSqlCircuitParams {
+            num_rows: args.num_rows,
+            sort_column_idx: args.sort_column_idx,
+        }
+    }
+}
+
+/// The Merkle tree circuit used for the main scalability experiments
+pub struct BigMerkle;
+
+#[derive(Args)]
+pub struct BigMerkleArgs {
+    /// Test circuit param: Number of subcircuits. The tree is the smallest one with at least
+    /// this many subcircuits. A tree with `l` leaves has `2l` subcircuits, and `l` is a power of
+    /// two.
+    #[clap(long, value_name = "NUM")]
+    num_subcircuits: usize,
+
+    /// Test circuit param: Number of SHA256 iterations per subcircuit. MUST be at least 1.
+    #[clap(long, value_name = "NUM")]
+    num_sha2_iters: usize,
+
+    /// Test circuit param: Number of portal wire ops per subcircuit. MUST be at least 1.
+    #[clap(long, value_name = "NUM")]
+    num_portals: usize,
+}
+
+impl RegisteredCircuit for BigMerkle {
+    const ID: &'static str = "BigMerkle circuit";
+    const SETUP_COMMAND: &'static str = "setup-big-merkle";
+
+    type Args = BigMerkleArgs;
+    type Params = MerkleTreeCircuitParams;
+    type Circuit<F: PrimeField + Absorb> = MerkleTreeCircuit;
+
+    fn params_from_args<F: PrimeField + Absorb>(args: BigMerkleArgs) -> MerkleTreeCircuitParams {
+        assert!(
+            args.num_sha2_iters > 0,
+            "num. of SHA256 iterations per subcircuit MUST be > 0"
+        );
+        assert!(
+            args.num_portals > 0,
+            "num. of portal ops per subcircuit MUST be > 0"
+        );
+
+        MerkleTreeCircuitParams {
+            num_leaves: MerkleTreeCircuitParams::num_leaves_for(args.num_subcircuits),
+            num_sha_iters_per_subcircuit: args.num_sha2_iters,
+            num_portals_per_subcircuit: args.num_portals,
+        }
+    }
+}
+
+/// The verifiable key directory circuit
+pub struct Vkd;
+
+#[derive(Args)]
+pub struct VkdArgs {
+    /// Test circuit param: Number of subcircuits. The VKD is the smallest one with at least this
+    /// many subcircuits. A VKD has a power of two number of subcircuits, and at least 16.
+    #[clap(long, value_name = "NUM")]
+    num_subcircuits: usize,
+}
+
+impl RegisteredCircuit for Vkd {
+    const ID: &'static str = "VKD circuit";
+    const SETUP_COMMAND: &'static str = "setup-vkd";
+
+    type Args = VkdArgs;
+    type Params = VerifiableKeyDirectoryCircuitParams;
+    type Circuit<F: PrimeField + Absorb> = VerifiableKeyDirectoryCircuit<F>;
+
+    fn params_from_args<F: PrimeField + Absorb>(
+        args: VkdArgs,
+    ) -> VerifiableKeyDirectoryCircuitParams {
+        // A VKD with fewer than 16 subcircuits has no room for updates
+        let log_num_subcircuits = (ark_std::log2(args.num_subcircuits) as usize).max(4);
+        let tree = SparseMerkleTree::<MerkleTreeConcreteParameters, F>::new().unwrap();
+
+        VerifiableKeyDirectoryCircuitParams {
+            log_num_subcircuits,
+            null_leaf: tree.sparse_initial_hashes[DEPTH],
+        }
+    }
+
+    fn print_info(_params: &VerifiableKeyDirectoryCircuitParams, num_subcircuits: usize) {
+        // Taken from VerifiableKeyDirectoryCircuit::random(). This is how many
+        // update operations we can fit in a VKD circuit that was generated with random(),
+        // with a given num_subcircuits value, which is a power of two
+        let num_updates = ((num_subcircuits - 8) / 8) - 1;
+        println!("Number of VKD updates: {num_updates}");
+        println!("VKD depth: {}", DEPTH);
+        println!("VKD hash function: {:?}", HASH_TYPE);
+    }
+}
+
+/// The virtual machine circuit
+pub struct Vm;
+
+#[derive(Args)]
+pub struct VmArgs {
+    /// Test circuit param: Number of subcircuits. MUST be at least 1.
+    #[clap(long, value_name = "NUM")]
+    num_subcircuits: usize,
+
+    /// If on, uses a Merkle tree for memory
+    #[clap(long, value_name = "")]
+    use_merkle_memory: bool,
+
+    /// Number of cycles per subcircuit. MUST be at least 1.
+    #[clap(long, value_name = "NUM")]
+    num_cycles_per_subcircuit: usize,
+
+    /// Number of instructions in the program ROM
+    #[clap(long, value_name = "NUM", default_value_t = 64)]
+    program_len: usize,
+
+    /// Number of words of data memory
+    #[clap(long, value_name = "NUM", default_value_t = 64)]
+    memory_size: usize,
+}
+
+impl RegisteredCircuit for Vm {
+    const ID: &'static str = "VM circuit";
+    const SETUP_COMMAND: &'static str = "setup-vm";
+
+    type Args = VmArgs;
+    type Params = VirtualMachineParameters;
+    type Circuit<F: PrimeField + Absorb> = VirtualMachine<F>;
+
+    fn params_from_args<F: PrimeField + Absorb>(args: VmArgs) -> VirtualMachineParameters {
+        let VmArgs {
+            num_subcircuits,
+            use_merkle_memory,
+            num_cycles_per_subcircuit: operations_per_chunk,
+            program_len,
+            memory_size,
+        } = args;
+
+        assert!(num_subcircuits > 0, "num. of subcircuits MUST be > 0");
+        assert!(operations_per_chunk > 0, "num. ops per chunk MUST be > 0");
+        assert!(program_len > 0, "program length MUST be > 0");
+        assert!(memory_size > 0, "memory size MUST be > 0");
+
+        VirtualMachineParameters {
+            use_merkle_memory,
+            num_subcircuits,
+            operations_per_chunk,
+            program_len,
+            memory_size,
+        }
+    }
+
+    fn print_info(params: &VirtualMachineParameters, num_subcircuits: usize) {
+        println!("Number of VM cycle chunks: {}", num_subcircuits);
+        println!(
+            "Number of cycles per chunk: {}",
+            params.operations_per_chunk,
+        );
+        println!("Length of VM program: {}", params.program_len);
+        println!("Size of VM data memory: {}", params.memory_size);
+        println!("VM using Merkle memory: {}", params.use_merkle_memory);
+        println!("Depth of Merkle memory: {}", MERKLE_MEMORY_DEPTH);
+        println!("Number of VM CPU registers: {}", REGISTER_NUM);
+    }
+}
+
+/// The circuit for query 1 of TPC-H
+pub struct TpchQ1;
+
+#[derive(Args)]
+pub struct TpchQ1Args {
+    /// Number of rows of the lineitem table. MUST be at least 1. The table is padded with invalid
+    /// rows to a power of two.
+    #[clap(long, value_name = "NUM")]
+    num_rows: usize,
+
+    /// Number of rows per subcircuit. This is rounded up to a power of two, and to at least 2, and
+    /// is at most the padded number of rows.
+    #[clap(long, value_name = "NUM")]
+    rows_per_subcircuit: usize,
+
+    /// The DELTA parameter of the query, in days
+    #[clap(long, value_name = "NUM", default_value_t = 90)]
+    delta: u64,
+}
+
+impl RegisteredCircuit for TpchQ1 {
+    const ID: &'static str = "TPC-H Q1 circuit";
+    const SETUP_COMMAND: &'static str = "setup-tpch-q1";
+
+    type Args = TpchQ1Args;
+    type Params = TpchQ1Params;
+    type Circuit<F: PrimeField + Absorb> = TpchQ1Circuit<F>;
+
+    fn params_from_args<F: PrimeField + Absorb>(args: TpchQ1Args) -> TpchQ1Params {
+        let TpchQ1Args {
+            num_rows,
+            rows_per_subcircuit,
+            delta,
+        } = args;
+
+        assert!(num_rows > 0, "#rows MUST be > 0");
+
+        TpchQ1Params {
+            num_rows,
+            rows_per_subcircuit,
+            delta,
+        }
+    }
+
+    fn print_info(params: &TpchQ1Params, _num_subcircuits: usize) {
+        println!("Number of lineitem rows: {}", params.num_rows);
+        println!(
+            "Number of rows per subcircuit: {}",
+            params.rows_per_subcircuit
+        );
+        println!("Q1 DELTA: {}", params.delta);
+        println!("Max number of Q1 groups: {}", Q1_MAX_GROUPS);
+    }
+}
@@ -1,34 +1,45 @@
-use crate::transcript::{RunningEvaluation, TranscriptEntry};
+use crate::transcript::{round_challenges, RunningEvaluation, TranscriptEntry};
 use crate::{
-    aggregation::{AggProvingKey, IppCom},
+    aggregation::{
+        new_agg_transcript, AggProvingKey, GroupAggProof, GroupedAggProvingKey, HekatonBatchProof,
+        HekatonProof, IppCom, SuperComKey,
+    },
     eval_tree::{
-        ExecTreeLeaf, ExecTreeParams, MerkleRoot, SerializedLeaf, SerializedLeafVar, TreeConfig,
-        TreeConfigGadget,
+        exec_tree_num_leaves, ExecTreeLeaf, ExecTreeParams, MerkleRoot, SerializedLeaf,
+        SerializedLeafVar, TreeConfig, TreeConfigGadget,
     },
     subcircuit_circuit::SubcircuitWithPortalsProver,
+    subcircuit_store::SubcircuitStore,
     transcript::MemType,
-    util::{G16Com, G16ComSeed, G16ProvingKey},
-    worker::{Stage0Response, Stage1Response},
+    util::{
+        digest_circ_params, CircParamsDigest, G16Com, G16ComSeed, G16PreparedVerifyingKey,
+        G16Proof, G16ProvingKey,
+    },
+    worker::{RoundResponse, Stage0Response, Stage1Response},
     CircuitWithPortals,
 };
 
 use core::marker::PhantomData;
+use std::{borrow::Cow, io, path::Path};
 
-use ark_cp_groth16::r1cs_to_qap::LibsnarkReduction as QAP;
+use ark_cp_groth16::{
+    generator::{generate_parameters_with_trapdoor, SharedTrapdoor},
+    r1cs_to_qap::LibsnarkReduction as QAP,
+    verifier::verify_proof,
+    MultiStageConstraintSynthesizer, MultiStageConstraintSystem,
+};
 use ark_crypto_primitives::merkle_tree::{MerkleTree, Path as MerklePath};
 use ark_ec::pairing::Pairing;
 use ark_ff::{PrimeField, ToConstraintField};
-use ark_ip_proofs::{
-    ip_commitment::{snarkpack::TIPPCommitment, IPCommitment},
-    tipa::{Proof, ProverKey},
-};
+use ark_relations::r1cs::{OptimizationGoal, SynthesisMode};
 use ark_serialize::{
     CanonicalDeserialize, CanonicalSerialize, Compress, SerializationError, Write,
 };
 use ark_std::{end_timer, start_timer};
 use rand::RngCore;
 
-/// Generates Groth16 proving keys
+/// Generates Groth16 proving keys. All the keys it generates share alpha, beta, and gamma, so that
+/// their proofs can be aggregated with a single `e(α, β)`
 pub struct G16ProvingKeyGenerator<C, CG, E, P>
 where
     E: Pairing,
@@ -39,6 +50,8 @@ where
     tree_params: ExecTreeParams<C>,
     circ: P,
     time_ordered_subtraces: Vec<Vec<TranscriptEntry<E::ScalarField>>>,
+    /// The toxic waste common to every key
+    trapdoor: SharedTrapdoor<E>,
     _marker: PhantomData<(C, CG)>,
 }
 
@@ -49,7 +62,7 @@ where
     C: TreeConfig<Leaf = SerializedLeaf<E::ScalarField>>,
     CG: TreeConfigGadget<C, E::ScalarField, Leaf = SerializedLeafVar<E::ScalarField>>,
 {
-    pub fn new(circ: P, tree_params: ExecTreeParams<C>) -> Self {
+    pub fn new<R: RngCore>(mut rng: R, circ: P, tree_params: ExecTreeParams<C>) -> Self {
         // Generate the traces. Do not bother to check whether the constraints are satisfied. This
         // circuit's contents might be placeholder values.
         let time_ordered_subtraces = circ.get_portal_subtraces();
@@ -58,76 +71,83 @@ where
             tree_params,
             circ,
             time_ordered_subtraces,
+            trapdoor: SharedTrapdoor::rand(&mut rng),
             _marker: PhantomData,
         }
     }
 
     pub fn gen_pk<R: RngCore>(&self, mut rng: R, subcircuit_idx: usize) -> G16ProvingKey<E> {
+        let subcirc = self.subcircuit(subcircuit_idx);
+
+        // Generate the CRS. Only the deltas and the circuit-specific values are new
+        generate_parameters_with_trapdoor::<_, E, QAP>(subcirc, &self.trapdoor, &mut rng).unwrap()
+    }
+
+    /// Returns the number of constraints of the given subcircuit, across all its stages. This is
+    /// a rough measure of how long the subcircuit takes to prove.
+    pub fn num_constraints(&self, subcircuit_idx: usize) -> usize {
+        let mut subcirc = self.subcircuit(subcircuit_idx);
+
+        // Synthesize the way the generator does, so the count matches the key's
+        let mut mscs = MultiStageConstraintSystem::default();
+        mscs.set_optimization_goal(OptimizationGoal::Constraints);
+        mscs.set_mode(SynthesisMode::Setup);
+        for stage in 0..subcirc.total_num_stages() {
+            subcirc.generate_constraints(stage, &mut mscs).unwrap();
+        }
+        mscs.num_constraints()
+    }
+
+    /// Makes the placeholder subcircuit that keys are generated from
+    fn subcircuit(
+        &self,
+        subcircuit_idx: usize,
+    ) -> SubcircuitWithPortalsProver<E::ScalarField, P, C, CG> {
         let num_subcircuits = self.circ.num_subcircuits();
+        let public_inputs = self.circ.public_inputs();
+        let num_tree_leaves = exec_tree_num_leaves(num_subcircuits, !public_inputs.is_empty());
 
         // Create a Groth16 instance for each subcircuit
         let subtrace = &self.time_ordered_subtraces[subcircuit_idx];
         // TODO: Avoid the clones here
         let mut subcirc = SubcircuitWithPortalsProver::<_, P, _, CG>::new(
             self.tree_params.clone(),
-            num_subcircuits,
+            num_tree_leaves,
         );
 
         // Set the index and the underlying circuit
         subcirc.subcircuit_idx = subcircuit_idx;
         subcirc.circ = Some(self.circ.clone());
+        // The public inputs are placeholders too. They just have to be the right length.
+        subcirc.public_inputs = public_inputs;
 
         // Make the subtraces the same. These are just placeholders anyway. They just have to be
         // the right length.
         subcirc.time_ordered_subtrace = subtrace.clone();
         subcirc.addr_ordered_subtrace = subtrace.clone();
 
-        // Generate the CRS
-        ark_cp_groth16::generator::generate_parameters::<_, E, QAP>(subcirc, &mut rng).unwrap()
+        subcirc
     }
 }
 
-/// Flattens the subtraces into one big trace, sorts it by address, and chunks it back into the
-/// same-sized subtraces
-fn sort_subtraces_by_addr<F: PrimeField>(
-    time_ordered_subtraces: &[Vec<TranscriptEntry<F>>],
-) -> Vec<Vec<TranscriptEntry<F>>> {
-    // Make the (flattened) address-sorted trace
-    // Flatten the trace
-    let mut flat_trace = time_ordered_subtraces
-        .iter()
-        .flat_map(|st| st)
-        .collect::<Vec<_>>();
-    // Sort by address, i.e., the hash of the name
-    match flat_trace[0] {
-        TranscriptEntry::Rom(_) => {
-            flat_trace.sort_by_key(|entry| entry.addr());
-        },
-        TranscriptEntry::Ram(_) => {
-            flat_trace.sort_by_key(|entry| (entry.addr(), entry.timestamp()));
-        },
-    }
-
-    // Chunk back up
-    let mut out = Vec::new();
-    let flat_iter = &mut flat_trace.into_iter();
-    for chunk_size in time_ordered_subtraces.iter().map(|st| st.len()) {
-        let chunk = flat_iter.take(chunk_size).cloned().collect();
-        out.push(chunk);
-    }
-    out
+/// The key the trace is sorted by to make the address-ordered subtraces, i.e., the address, which
+/// is the hash of the name, and then the timestamp. ROM entries have no timestamp, so they keep
+/// their time order within an address.
+fn addr_order_key<F: PrimeField>(entry: &TranscriptEntry<F>) -> (u64, u32) {
+    (entry.addr(), entry.timestamp())
 }
 
 /// Generates a Merkle tree whose i-th leaf is `(time_eval, addr_eval, last_trace_elem)` where
 /// time_eval and addr_eval are the time- and address-ordered evals AFTER running subcircuit i, and
-/// where `last_trace_elem` is the last element of the i-th address-ordered subtrace. Returns the
-/// computed tree and its leaves
+/// where `last_trace_elem` is the last element of the i-th address-ordered subtrace. If there are
+/// public inputs, they are the leaf following the last subcircuit's leaf. Returns the computed tree
+/// and its subcircuit leaves
 fn generate_exec_tree<E, C>(
     mem_type: MemType,
     tree_params: &ExecTreeParams<C>,
     super_com: &IppCom<E>,
-    time_ordered_subtraces: &[Vec<TranscriptEntry<E::ScalarField>>],
-    addr_ordered_subtraces: &[Vec<TranscriptEntry<E::ScalarField>>],
+    subcircuits: &SubcircuitStore<E::ScalarField>,
+    public_inputs: &[E::ScalarField],
 ) -> (MerkleTree<C>, Vec<ExecTreeLeaf<E::ScalarField>>)
 where
     E: Pairing,
@@ -140,11 +160,10 @@ where
 
     // Every leaf conttains the last entry of the addr-ordered subtrace
     let mut last_subtrace_entry = TranscriptEntry::<E::ScalarField>::padding(mem_type);
-    for (time_st, addr_st) in time_ordered_subtraces
-        .iter()
-        .zip(addr_ordered_subtraces.iter())
-    {
-        for (time_entry, addr_entry) in time_st.iter().zip(addr_st) {
+    for subcircuit_idx in 0..subcircuits.num_subcircuits() {
+        let time_st = subcircuits.time_ordered_subtrace(subcircuit_idx);
+        let addr_st = subcircuits.addr_ordered_subtrace(subcircuit_idx);
+        for (time_entry, addr_entry) in time_st.iter().zip(addr_st.iter()) {
             // Eval everything in this subtrace
             evals.update_time_ordered(time_entry);
             evals.update_addr_ordered(addr_entry);
@@ -160,7 +179,20 @@ where
         leaves.push(leaf);
     }
 
-    let serialized_leaves = leaves.iter().map(|leaf| leaf.to_field_elements().unwrap());
+    let mut serialized_leaves = leaves
+        .iter()
+        .map(|leaf| leaf.to_field_elements().unwrap())
+        .collect::<Vec<_>>();
+
+    // Put the public inputs in their own leaf, and pad the rest of the tree
+    let num_tree_leaves = exec_tree_num_leaves(leaves.len(), !public_inputs.is_empty());
+    if !public_inputs.is_empty() {
+        serialized_leaves.push(public_inputs.to_vec());
+    }
+    let padding_leaf = ExecTreeLeaf::<E::ScalarField>::padding(mem_type)
+        .to_field_elements()
+        .unwrap();
+    serialized_leaves.resize(num_tree_leaves, padding_leaf);
 
     (
         MerkleTree::new(
@@ -182,10 +214,10 @@ where
     E: Pairing,
     P: CircuitWithPortals<E::ScalarField>,
 {
-    time_ordered_subtraces: Vec<Vec<TranscriptEntry<E::ScalarField>>>,
-    addr_ordered_subtraces: Vec<Vec<TranscriptEntry<E::ScalarField>>>,
-    all_serialized_witnesses: Vec<Vec<u8>>,
+    subcircuits: SubcircuitStore<E::ScalarField>,
     circ_params: P::Parameters,
+    public_inputs: Vec<E::ScalarField>,
+    public_input_subcircuit: usize,
 }
 
 /// This is sent to every worker at the beginning of every distributed proof. It contains
@@ -210,8 +242,8 @@ impl<F: PrimeField> Stage0Request<F> {
     pub fn to_ref<'a>(&'a self) -> Stage0RequestRef<'a, F> {
         Stage0RequestRef {
             subcircuit_idx: self.subcircuit_idx,
-            time_ordered_subtrace: &self.time_ordered_subtrace,
-            addr_ordered_subtrace: &self.addr_ordered_subtrace,
+            time_ordered_subtrace: Cow::Borrowed(&self.time_ordered_subtrace),
+            addr_ordered_subtrace: Cow::Borrowed(&self.addr_ordered_subtrace),
         }
     }
 }
@@ -219,8 +251,8 @@ impl<F: PrimeField> Stage0Request<F> {
 #[derive(Clone)]
 pub struct Stage0RequestRef<'a, F: PrimeField> {
     pub subcircuit_idx: usize,
-    pub time_ordered_subtrace: &'a Vec<TranscriptEntry<F>>,
-    pub addr_ordered_subtrace: &'a Vec<TranscriptEntry<F>>,
+    pub time_ordered_subtrace: Cow<'a, [TranscriptEntry<F>]>,
+    pub addr_ordered_subtrace: Cow<'a, [TranscriptEntry<F>]>,
 }
 
 // We need to manually implement this because CanonicalSerialize isn't implemented for &T
@@ -254,8 +286,8 @@ impl<'a, F: PrimeField> Stage0RequestRef<'a, F> {
     pub fn to_owned(&self) -> Stage0Request<F> {
         Stage0Request {
             subcircuit_idx: self.subcircuit_idx,
-            time_ordered_subtrace: self.time_ordered_subtrace.clone(),
-            addr_ordered_subtrace: self.addr_ordered_subtrace.clone(),
+            time_ordered_subtrace: self.time_ordered_subtrace.to_vec(),
+            addr_ordered_subtrace: self.addr_ordered_subtrace.to_vec(),
         }
     }
 }
@@ -266,84 +298,136 @@ where
     P: CircuitWithPortals<E::ScalarField>,
 {
     pub fn new<C: TreeConfig>(circ: P) -> Self {
+        let subcircuits = SubcircuitStore::in_memory(circ.num_subcircuits());
+        Self::with_store::<C>(circ, subcircuits).unwrap()
+    }
+
+    /// Like `new`, but keeps the subtraces and witnesses in files in `dir` instead of in memory.
+    /// The circuit's witnesses and subtraces are written out as they're generated, so they're
+    /// never all in memory at once, as long as the circuit overrides
+    /// `CircuitWithPortals::portal_subtraces_iter`. The address-ordered subtraces are made by an
+    /// external merge sort of the subtraces in `dir`.
+    pub fn new_on_disk<C: TreeConfig>(circ: P, dir: &Path) -> io::Result<Self> {
+        let subcircuits = SubcircuitStore::on_disk(circ.num_subcircuits(), dir)?;
+        Self::with_store::<C>(circ, subcircuits)
+    }
+
+    fn with_store<C: TreeConfig>(
+        circ: P,
+        mut subcircuits: SubcircuitStore<E::ScalarField>,
+    ) -> io::Result<Self> {
         let timer = start_timer!(|| "CoordinatorStage0State::new");
         // Extract everything we need to know from the circuit
         let circ_params = circ.get_params();
 
         let witness_timer = start_timer!(|| "Get serialized witnesses");
         // Serialize the circuit's witnesses
-        let all_serialized_witnesses = (0..circ.num_subcircuits())
-            .map(|idx| circ.get_serialized_witnesses(idx))
-            .collect();
+        for (idx, serialized_witnesses) in circ.serialized_witnesses_iter().enumerate() {
+            subcircuits.put_serialized_witnesses(idx, serialized_witnesses)?;
+        }
         end_timer!(witness_timer);
 
         let subtrace_timer = start_timer!(|| "Get subtraces");
-        // Run the circuit and collect the execution trace. Check that constraints are satisfied.
-        // TODO: Hossein: I modified this
-        let time_ordered_subtraces = circ.get_portal_subtraces();
-        let addr_ordered_subtraces = sort_subtraces_by_addr(&time_ordered_subtraces);
+        // Run the circuit and collect the execution trace, one subcircuit at a time
+        for (idx, subtrace) in circ.portal_subtraces_iter().enumerate() {
+            subcircuits.put_time_ordered_subtrace(idx, subtrace)?;
+        }
         end_timer!(subtrace_timer);
 
+        // The address-ordered subtraces are the whole trace sorted by address. An on-disk store
+        // sorts it on disk
+        let sort_timer = start_timer!(|| "Sort trace by address");
+        subcircuits.put_addr_ordered_subtraces_sorted_by(addr_order_key)?;
+        end_timer!(sort_timer);
+
         end_timer!(timer);
 
-        CoordinatorStage0State {
-            time_ordered_subtraces,
-            addr_ordered_subtraces,
-            all_serialized_witnesses,
+        Ok(CoordinatorStage0State {
+            subcircuits,
             circ_params,
-        }
+            public_inputs: circ.public_inputs(),
+            public_input_subcircuit: circ.public_input_subcircuit(),
+        })
     }
 
     /// Creates a stage0 request for commitment for the given set of subcircuits
     pub fn gen_request(&self, subcircuit_idx: usize) -> Stage0RequestRef<E::ScalarField> {
-        Stage0RequestRef {
-            subcircuit_idx,
-            time_ordered_subtrace: self
-                .time_ordered_subtraces
-                .get(subcircuit_idx)
-                .as_ref()
-                .unwrap(),
-            addr_ordered_subtrace: self
-                .addr_ordered_subtraces
-                .get(subcircuit_idx)
-                .as_ref()
-                .unwrap(),
-        }
+        gen_stage0_request(&self.subcircuits, subcircuit_idx)
     }
 
-    /// Processes the stage 0 repsonses and move to stage 1
-    pub fn process_stage0_responses<C>(
+    /// Processes the stage 0 repsonses and move to stage 1. `super_com_key` is the TIPP key, or the
+    /// grouped aggregation key if the proofs will be aggregated in groups
+    pub fn process_stage0_responses<C, K>(
         self,
-        tipp_pk: &ProverKey<E>,
+        super_com_key: &K,
         tree_params: ExecTreeParams<C>,
         responses: &[Stage0Response<E>],
     ) -> CoordinatorStage1State<C, E, P>
     where
         C: TreeConfig<Leaf = SerializedLeaf<E::ScalarField>, InnerDigest = E::ScalarField>,
+        K: SuperComKey<E> + ?Sized,
     {
-        let (coms, com_seeds) = {
-            // Sort responses by subcircuit idx
-            let mut buf = responses.to_vec();
-            buf.sort_by_key(|res| res.subcircuit_idx);
-
-            // Extract the coms and the seeds separately
-            (
-                buf.iter().map(|res| res.com).collect::<Vec<_>>(),
-                buf.iter().map(|res| res.com_seed).collect(),
-            )
-        };
+        let (coms, com_seeds) = sort_stage0_responses(responses);
 
-        // Commit to the commitments. These are in G1, so it's a "left" commitment. Don't worry
-        // about what that means
-        let coms_group = coms.iter().map(|&com| com.into()).collect::<Vec<_>>();
-        let super_com = TIPPCommitment::commit_only_left(&tipp_pk.pk.ck, &coms_group).unwrap();
+        // Commit to the commitments
+        let super_com = super_com_key.super_commit(&coms);
 
+        self.into_stage1(tree_params, coms, com_seeds, super_com)
+    }
+
+    /// Processes the stage 0 responses of several instances of the same circuit, and moves them all
+    /// to stage 1. `responses[k]` are the responses of the k-th instance. The instances share a
+    /// single super-commitment to the commitments of every instance, in order, and thus share
+    /// their challenges. This is what lets `FinalAggState::gen_batch_agg_proof` aggregate them all
+    /// at once. `super_com_key` is the TIPP key of the batch aggregation key.
+    pub fn process_batch_stage0_responses<C, K>(
+        states: Vec<Self>,
+        super_com_key: &K,
+        tree_params: ExecTreeParams<C>,
+        responses: &[Vec<Stage0Response<E>>],
+    ) -> Vec<CoordinatorStage1State<C, E, P>>
+    where
+        C: TreeConfig<Leaf = SerializedLeaf<E::ScalarField>, InnerDigest = E::ScalarField>,
+        K: SuperComKey<E> + ?Sized,
+    {
+        assert_eq!(states.len(), responses.len(), "wrong number of instances");
+        let sorted_resps = responses
+            .iter()
+            .map(|resps| sort_stage0_responses(resps))
+            .collect::<Vec<_>>();
+
+        // Commit to the commitments of every instance at once
+        let all_coms = sorted_resps
+            .iter()
+            .flat_map(|(coms, _)| coms.iter().copied())
+            .collect::<Vec<_>>();
+        let super_com = super_com_key.super_commit(&all_coms);
+
+        states
+            .into_iter()
+            .zip(sorted_resps)
+            .map(|(state, (coms, com_seeds))| {
+                state.into_stage1(tree_params.clone(), coms, com_seeds, super_com)
+            })
+            .collect()
+    }
+
+    fn into_stage1<C>(
+        self,
+        tree_params: ExecTreeParams<C>,
+        coms: Vec<G16Com<E>>,
+        com_seeds: Vec<G16ComSeed>,
+        super_com: IppCom<E>,
+    ) -> CoordinatorStage1State<C, E, P>
+    where
+        C: TreeConfig<Leaf = SerializedLeaf<E::ScalarField>, InnerDigest = E::ScalarField>,
+    {
         CoordinatorStage1State::new(
             tree_params,
-            self.time_ordered_subtraces,
-            self.addr_ordered_subtraces,
-            self.all_serialized_witnesses,
+            self.subcircuits,
             self.circ_params,
+            self.public_inputs,
+            self.public_input_subcircuit,
             coms,
             com_seeds,
             super_com,
@@ -351,6 +435,37 @@ where
     }
 }
 
+/// Sorts the stage 0 responses by subcircuit idx, and extracts the coms and the seeds separately
+fn sort_stage0_responses<E: Pairing>(
+    responses: &[Stage0Response<E>],
+) -> (Vec<G16Com<E>>, Vec<G16ComSeed>) {
+    let mut buf = responses.to_vec();
+    buf.sort_by_key(|res| res.subcircuit_idx);
+
+    (
+        buf.iter().map(|res| res.com).collect(),
+        buf.iter().map(|res| res.com_seed).collect(),
+    )
+}
+
+/// Sorts the responses of an extra round by subcircuit idx, and extracts the coms
+fn sort_round_responses<E: Pairing>(responses: &[RoundResponse<E>]) -> Vec<G16Com<E>> {
+    let mut buf = responses.to_vec();
+    buf.sort_by_key(|res| res.subcircuit_idx);
+    buf.iter().map(|res| res.com).collect()
+}
+
+fn gen_stage0_request<F: PrimeField>(
+    subcircuits: &SubcircuitStore<F>,
+    subcircuit_idx: usize,
+) -> Stage0RequestRef<F> {
+    Stage0RequestRef {
+        subcircuit_idx,
+        time_ordered_subtrace: subcircuits.time_ordered_subtrace(subcircuit_idx),
+        addr_ordered_subtrace: subcircuits.addr_ordered_subtrace(subcircuit_idx),
+    }
+}
+
 #[derive(CanonicalSerialize, CanonicalDeserialize)]
 pub struct CoordinatorStage1State<C, E, P>
 where
@@ -358,20 +473,27 @@ where
     E: Pairing,
     P: CircuitWithPortals<E::ScalarField>,
 {
-    /// All the time-ordered subtraces
-    time_ordered_subtraces: Vec<Vec<TranscriptEntry<E::ScalarField>>>,
-    /// All the addr-ordered subtraces
-    addr_ordered_subtraces: Vec<Vec<TranscriptEntry<E::ScalarField>>>,
-    /// The list of serialized witnesses, ordered by subcircuit
-    all_serialized_witnesses: Vec<Vec<u8>>,
+    /// The subtraces and serialized witnesses of every subcircuit
+    subcircuits: SubcircuitStore<E::ScalarField>,
     /// Circuit metadata
     circ_params: P::Parameters,
+    /// The public inputs to the circuit
+    public_inputs: Vec<E::ScalarField>,
+    /// The subcircuit that receives the public inputs
+    public_input_subcircuit: usize,
     /// The commitments to all the Groth16 inputs
     coms: Vec<G16Com<E>>,
     /// The associated seeds for the randomness to the above commitments
     seeds: Vec<G16ComSeed>,
     /// The inner-pairing commitment to the above commitments
     pub super_com: IppCom<E>,
+    /// The commitments of every subcircuit in each extra round done so far. `round_coms[k][i]` is
+    /// the commitment of subcircuit i in extra round k
+    round_coms: Vec<Vec<G16Com<E>>>,
+    /// The inner-pairing commitments to the above, one per extra round
+    round_super_coms: Vec<IppCom<E>>,
+    /// The challenges derived in the extra rounds so far, in order
+    round_challenges: Vec<E::ScalarField>,
     // We can't store the exec tree directly because it's not CanonicalSerialize :shrug:
     /// The list of execution leaves. Index i contains the ith leaf in the exec tree.
     exec_tree_leaves: Vec<ExecTreeLeaf<E::ScalarField>>,
@@ -380,40 +502,233 @@ where
     /// The list of auth paths of the execution leaves that provers compute as output. Index i
     /// contains the auth path for the ith leaf in the exec tree.
     exec_tree_leaf_auth_paths: Vec<MerklePath<C>>,
+    /// The auth path of the public input leaf, if there are public inputs
+    public_input_membership: Option<MerklePath<C>>,
 }
 
 /// The state necessary to aggregate the stage1 responses
 #[derive(CanonicalSerialize, CanonicalDeserialize)]
-pub struct FinalAggState<E: Pairing> {
+pub struct FinalAggState<C: TreeConfig, E: Pairing> {
+    pub(crate) challenges: Vec<E::ScalarField>,
+    pub(crate) exec_tree_root: E::ScalarField,
+    pub(crate) super_coms: Vec<IppCom<E>>,
+    pub(crate) circ_params_digest: CircParamsDigest,
     pub(crate) public_inputs: Vec<E::ScalarField>,
-    pub(crate) super_com: IppCom<E>,
+    pub(crate) public_input_membership: Option<MerklePath<C>>,
 }
 
-impl<E: Pairing> FinalAggState<E> {
-    /// Compute the aggregate proof
+impl<C: TreeConfig, E: Pairing> FinalAggState<C, E> {
+    /// The Groth16 public inputs of every subcircuit. These are the challenges followed by the
+    /// root
+    pub(crate) fn g16_public_inputs(&self) -> Vec<E::ScalarField> {
+        [self.challenges.as_slice(), &[self.exec_tree_root]].concat()
+    }
+
+    /// Compute the aggregate proof. This aggregates all the proofs as a single group
     pub fn gen_agg_proof(
         &self,
         agg_ck: &AggProvingKey<E>,
         resps: &[Stage1Response<E>],
-    ) -> Proof<E> {
-        // Collect the Groth16 proofs
-        let g16_proofs = {
-            // Sort responses by subcircuit idx
-            let mut buf = resps.to_vec();
-            buf.sort_by_key(|res| res.subcircuit_idx);
-
-            // Extract the proofs
-            buf.into_iter().map(|res| res.proof).collect::<Vec<_>>()
-        };
+    ) -> HekatonProof<C, E> {
+        let group_proof = self.agg_group(agg_ck, 0, self.super_coms.clone(), &sorted_proofs(resps));
+        self.combine_group_agg_proofs(vec![group_proof])
+    }
 
-        // Aggregate the proofs
-        agg_ck.agg_subcircuit_proofs(
-            &mut crate::util::ProtoTranscript::new(b"test-e2e"),
-            &self.super_com,
-            &g16_proofs,
-            &self.public_inputs,
-        )
+    /// Computes the aggregate proof of the given group of subcircuits. `group_ck` is the group's
+    /// key in the `GroupedAggProvingKey` used during stage 0, and `resps` are the responses of
+    /// exactly the subcircuits in the group. This doesn't need the responses of any other group.
+    pub fn gen_group_agg_proof(
+        &self,
+        group_ck: &AggProvingKey<E>,
+        group_idx: usize,
+        resps: &[Stage1Response<E>],
+    ) -> GroupAggProof<E> {
+        // The group's super-commitments are to the group's commitments of each stage, which are
+        // in the proofs themselves
+        let proofs = sorted_proofs(resps);
+        let super_coms = (0..self.super_coms.len())
+            .map(|k| {
+                let coms = proofs.iter().map(|proof| proof.ds[k]).collect::<Vec<_>>();
+                group_ck.tipp_pk.super_commit(&coms)
+            })
+            .collect();
+
+        self.agg_group(group_ck, group_idx, super_coms, &proofs)
+    }
+
+    /// Makes the request to aggregate the given group of subcircuits, e.g., on a worker. `resps`
+    /// are the responses of every subcircuit, in order of subcircuit.
+    pub fn gen_group_request<'a>(
+        &'a self,
+        grouped_ck: &'a GroupedAggProvingKey<'a, E>,
+        group_idx: usize,
+        resps: &'a [Stage1Response<E>],
+    ) -> GroupAggRequestRef<'a, C, E> {
+        GroupAggRequestRef {
+            group_idx,
+            group_ck: grouped_ck.group(group_idx),
+            agg_state: self,
+            resps: &resps[grouped_ck.group_range(group_idx)],
+        }
+    }
+
+    /// Computes a single aggregate proof of several instances of the same circuit. The states MUST
+    /// come from `CoordinatorStage0State::process_batch_stage0_responses`, `agg_ck` MUST be made
+    /// with `AggProvingKey::new_batch`, and `resps[k]` are the responses of the k-th instance.
+    pub fn gen_batch_agg_proof(
+        states: &[Self],
+        agg_ck: &AggProvingKey<E>,
+        resps: &[Vec<Stage1Response<E>>],
+    ) -> HekatonBatchProof<C, E> {
+        assert_eq!(states.len(), resps.len(), "wrong number of instances");
+        let first = &states[0];
+        assert!(
+            states
+                .iter()
+                .all(|state| state.super_coms == first.super_coms
+                    && state.circ_params_digest == first.circ_params_digest),
+            "instances weren't processed as a batch"
+        );
+
+        // Aggregate the proofs of every instance, in order
+        let proofs = resps
+            .iter()
+            .flat_map(|instance_resps| sorted_proofs(instance_resps))
+            .collect::<Vec<_>>();
+        let pub_inputs = states
+            .iter()
+            .map(|state| state.g16_public_inputs())
+            .collect::<Vec<_>>();
+        let agg_proof = agg_ck.agg_batch_proofs(
+            &mut new_agg_transcript(&first.circ_params_digest, 0),
+            &first.super_coms,
+            &proofs,
+            &pub_inputs,
+        );
+
+        HekatonBatchProof {
+            super_coms: first.super_coms.clone(),
+            challenges: first.challenges.clone(),
+            exec_tree_roots: states.iter().map(|state| state.exec_tree_root).collect(),
+            agg_proof,
+            circ_params_digest: first.circ_params_digest,
+            public_inputs: states
+                .iter()
+                .map(|state| state.public_inputs.clone())
+                .collect(),
+            public_input_memberships: states
+                .iter()
+                .map(|state| state.public_input_membership.clone())
+                .collect(),
+        }
+    }
+
+    /// Combines the aggregate proofs of every group, in order, into a single proof
+    pub fn combine_group_agg_proofs(
+        &self,
+        group_proofs: Vec<GroupAggProof<E>>,
+    ) -> HekatonProof<C, E> {
+        HekatonProof {
+            super_coms: self.super_coms.clone(),
+            exec_tree_root: self.exec_tree_root,
+            challenges: self.challenges.clone(),
+            group_proofs,
+            circ_params_digest: self.circ_params_digest,
+            public_inputs: self.public_inputs.clone(),
+            public_input_membership: self.public_input_membership.clone(),
+        }
     }
+
+    /// Aggregates the (sorted) proofs of one group with respect to the group's super-commitments
+    fn agg_group(
+        &self,
+        agg_ck: &AggProvingKey<E>,
+        group_idx: usize,
+        super_coms: Vec<IppCom<E>>,
+        proofs: &[G16Proof<E>],
+    ) -> GroupAggProof<E> {
+        let agg_proof = agg_ck.agg_subcircuit_proofs(
+            &mut new_agg_transcript(&self.circ_params_digest, group_idx),
+            &super_coms,
+            proofs,
+            &self.g16_public_inputs(),
+        );
+
+        GroupAggProof {
+            super_coms,
+            agg_proof,
+        }
+    }
+}
+
+/// A request to aggregate one group of subcircuit proofs. It has everything needed to compute the
+/// group's aggregate, so it can be handled by someone without the rest of the proofs or keys.
+#[derive(CanonicalSerialize, CanonicalDeserialize)]
+pub struct GroupAggRequest<'b, C: TreeConfig, E: Pairing> {
+    pub group_idx: usize,
+    group_ck: AggProvingKey<'b, E>,
+    agg_state: FinalAggState<C, E>,
+    resps: Vec<Stage1Response<E>>,
+}
+
+impl<'b, C: TreeConfig, E: Pairing> GroupAggRequest<'b, C, E> {
+    /// Computes the group's aggregate proof
+    pub fn aggregate(&self) -> GroupAggResponse<E> {
+        GroupAggResponse {
+            group_idx: self.group_idx,
+            proof: self
+                .agg_state
+                .gen_group_agg_proof(&self.group_ck, self.group_idx, &self.resps),
+        }
+    }
+}
+
+pub struct GroupAggRequestRef<'a, C: TreeConfig, E: Pairing> {
+    pub group_idx: usize,
+    group_ck: &'a AggProvingKey<'a, E>,
+    agg_state: &'a FinalAggState<C, E>,
+    resps: &'a [Stage1Response<E>],
+}
+
+// We need to manually implement this because CanonicalSerialize isn't implemented for &T
+// where T: CanonicalSerialize
+impl<'a, C: TreeConfig, E: Pairing> CanonicalSerialize for GroupAggRequestRef<'a, C, E> {
+    #[inline]
+    fn serialize_with_mode<W: Write>(
+        &self,
+        mut writer: W,
+        compress: Compress,
+    ) -> Result<(), SerializationError> {
+        self.group_idx.serialize_with_mode(&mut writer, compress)?;
+        self.group_ck.serialize_with_mode(&mut writer, compress)?;
+        self.agg_state.serialize_with_mode(&mut writer, compress)?;
+        self.resps.serialize_with_mode(&mut writer, compress)?;
+
+        Ok(())
+    }
+
+    #[inline]
+    fn serialized_size(&self, compress: Compress) -> usize {
+        self.group_idx.serialized_size(compress)
+            + self.group_ck.serialized_size(compress)
+            + self.agg_state.serialized_size(compress)
+            + self.resps.serialized_size(compress)
+    }
+}
+
+/// The aggregate proof of one group, in response to a [`GroupAggRequest`]. Combine the groups'
+/// aggregates with `FinalAggState::combine_group_agg_proofs`.
+#[derive(Clone, CanonicalSerialize, CanonicalDeserialize)]
+pub struct GroupAggResponse<E: Pairing> {
+    pub group_idx: usize,
+    pub proof: GroupAggProof<E>,
+}
+
+/// Collects the Groth16 proofs of the given responses, sorted by subcircuit idx
+fn sorted_proofs<E: Pairing>(resps: &[Stage1Response<E>]) -> Vec<G16Proof<E>> {
+    let mut buf = resps.to_vec();
+    buf.sort_by_key(|res| res.subcircuit_idx);
+    buf.into_iter().map(|res| res.proof).collect()
 }
 
 impl<C, E, P> CoordinatorStage1State<C, E, P>
@@ -424,10 +739,10 @@ where
 {
     fn new(
         tree_params: ExecTreeParams<C>,
-        time_ordered_subtraces: Vec<Vec<TranscriptEntry<E::ScalarField>>>,
-        addr_ordered_subtraces: Vec<Vec<TranscriptEntry<E::ScalarField>>>,
-        all_serialized_witnesses: Vec<Vec<u8>>,
+        subcircuits: SubcircuitStore<E::ScalarField>,
         circ_params: P::Parameters,
+        public_inputs: Vec<E::ScalarField>,
+        public_input_subcircuit: usize,
         coms: Vec<G16Com<E>>,
         seeds: Vec<G16ComSeed>,
         super_com: IppCom<E>,
@@ -437,12 +752,12 @@ where
             P::MEM_TYPE,
             &tree_params,
             &super_com,
-            &time_ordered_subtraces,
-            &addr_ordered_subtraces,
+            &subcircuits,
+            &public_inputs,
         );
 
         // Make the authentication paths
-        let num_subcircuits = time_ordered_subtraces.len();
+        let num_subcircuits = subcircuits.num_subcircuits();
         let tree_leaf_auth_paths = (0..num_subcircuits)
             .map(|subcircuit_idx| {
                 exec_tree
@@ -450,22 +765,128 @@ where
                     .expect("invalid subcircuit idx")
             })
             .collect();
+        // The public input leaf comes right after the last subcircuit's leaf
+        let public_input_membership = if public_inputs.is_empty() {
+            None
+        } else {
+            Some(
+                exec_tree
+                    .generate_proof(num_subcircuits)
+                    .expect("invalid public input leaf idx"),
+            )
+        };
 
         CoordinatorStage1State {
-            time_ordered_subtraces,
-            addr_ordered_subtraces,
-            all_serialized_witnesses,
+            subcircuits,
             circ_params,
+            public_inputs,
+            public_input_subcircuit,
             coms,
             seeds,
             super_com,
+            round_coms: Vec::new(),
+            round_super_coms: Vec::new(),
+            round_challenges: Vec::new(),
             exec_tree_leaves: tree_leaves,
             exec_tree_root: exec_tree.root(),
             exec_tree_leaf_auth_paths: tree_leaf_auth_paths,
+            public_input_membership,
+        }
+    }
+
+    /// The number of extra rounds whose responses have been processed
+    pub fn num_rounds_done(&self) -> usize {
+        self.round_super_coms.len()
+    }
+
+    /// The challenges derived so far, i.e., the memory-checking challenges followed by the
+    /// challenges of the extra rounds done so far
+    fn challenges(&self) -> Vec<E::ScalarField> {
+        [
+            self.exec_tree_leaves[0].evals.challenges(),
+            self.round_challenges.clone(),
+        ]
+        .concat()
+    }
+
+    /// Creates the request for the next extra round of the given subcircuit. This MUST only be
+    /// called while there are extra rounds left.
+    pub fn gen_round_request(&self, subcircuit_idx: usize) -> RoundRequest<E::ScalarField, P> {
+        let round = self.num_rounds_done();
+        assert!(round < P::NUM_EXTRA_ROUNDS, "all the extra rounds are done");
+
+        RoundRequest {
+            subcircuit_idx,
+            round,
+            challenges: self.challenges(),
+            serialized_witnesses: self
+                .subcircuits
+                .serialized_witnesses(subcircuit_idx)
+                .into_owned(),
+            circ_params: self.circ_params.clone(),
         }
     }
 
+    /// Processes the responses of the current extra round. This commits to the round's commitments
+    /// and derives the round's challenges from the super-commitments of every round so far.
+    /// `super_com_key` MUST be the same key given to `process_stage0_responses`
+    pub fn process_round_responses<K: SuperComKey<E> + ?Sized>(
+        &mut self,
+        super_com_key: &K,
+        responses: &[RoundResponse<E>],
+    ) {
+        let coms = sort_round_responses(responses);
+
+        // Commit to the commitments, same as in stage 0
+        let super_com = super_com_key.super_commit(&coms);
+        self.push_round(coms, super_com);
+    }
+
+    /// Processes the responses of the current extra round of several instances. `responses[k]` are
+    /// the responses of the k-th instance. The states MUST come from
+    /// `CoordinatorStage0State::process_batch_stage0_responses`, and `super_com_key` MUST be the
+    /// same key given to it
+    pub fn process_batch_round_responses<K: SuperComKey<E> + ?Sized>(
+        states: &mut [Self],
+        super_com_key: &K,
+        responses: &[Vec<RoundResponse<E>>],
+    ) {
+        assert_eq!(states.len(), responses.len(), "wrong number of instances");
+        let coms = responses
+            .iter()
+            .map(|resps| sort_round_responses(resps))
+            .collect::<Vec<_>>();
+
+        // Commit to the commitments of every instance at once, same as in stage 0
+        let super_com = super_com_key.super_commit(&coms.concat());
+        for (state, coms) in states.iter_mut().zip(coms) {
+            state.push_round(coms, super_com);
+        }
+    }
+
+    /// Records the commitments and super-commitment of the current extra round, and derives the
+    /// round's challenges from the super-commitments of every round so far
+    fn push_round(&mut self, coms: Vec<G16Com<E>>, super_com: IppCom<E>) {
+        assert!(
+            self.num_rounds_done() < P::NUM_EXTRA_ROUNDS,
+            "all the extra rounds are done"
+        );
+
+        self.round_coms.push(coms);
+        self.round_super_coms.push(super_com);
+
+        let super_coms = [&[self.super_com][..], &self.round_super_coms].concat();
+        self.round_challenges
+            .extend(round_challenges::<E>(&super_coms, P::NUM_ROUND_CHALLENGES));
+    }
+
     pub fn gen_request(&self, subcircuit_idx: usize) -> Stage1RequestRef<C, E::ScalarField, P> {
+        assert_eq!(
+            self.num_rounds_done(),
+            P::NUM_EXTRA_ROUNDS,
+            "the extra rounds must be done before stage 1"
+        );
+
         // The current leaf is the input to this subcircuit. This occurs at
         // self.exec_tree_leaves[idx-1]
         let cur_leaf = if subcircuit_idx > 0 {
@@ -486,32 +907,87 @@ where
         // Fetch the auth path
         let next_leaf_membership = self.exec_tree_leaf_auth_paths[subcircuit_idx].clone();
 
+        // Only the subcircuit that receives the public inputs needs them
+        let (public_inputs, public_input_membership) =
+            if subcircuit_idx == self.public_input_subcircuit {
+                (
+                    self.public_inputs.as_slice(),
+                    self.public_input_membership.clone(),
+                )
+            } else {
+                (&[][..], None)
+            };
+
         Stage1RequestRef {
             subcircuit_idx,
             cur_leaf,
             next_leaf_membership,
             root: self.exec_tree_root.clone(),
-            serialized_witnesses: self
-                .all_serialized_witnesses
-                .get(subcircuit_idx)
-                .as_ref()
-                .unwrap(),
+            serialized_witnesses: self.subcircuits.serialized_witnesses(subcircuit_idx),
             circ_params: &self.circ_params,
+            public_inputs,
+            public_input_membership,
+            round_challenges: self.round_challenges.as_slice(),
         }
     }
 
+    /// Checks the given stage1 response. That is, checks that its proof verifies wrt `pvk`, and that
+    /// the proof's commitments are the ones that went into the super-commitments. `pvk` MUST be the
+    /// verifying key of the response's subcircuit, i.e., of its representative.
+    pub fn verify_stage1_response(
+        &self,
+        pvk: &G16PreparedVerifyingKey<E>,
+        resp: &Stage1Response<E>,
+    ) -> bool {
+        // A worker could prove the subcircuit wrt a different commitment than it sent in stage 0 or
+        // in an extra round
+        if self.expected_ds(resp.subcircuit_idx).as_deref() != Some(resp.proof.ds.as_slice()) {
+            return false;
+        }
+
+        // The public inputs are the same for every subcircuit. They're the challenges followed by
+        // the root
+        let public_inputs = [self.challenges().as_slice(), &[self.exec_tree_root]].concat();
+        verify_proof(pvk, &resp.proof, &public_inputs).unwrap_or(false)
+    }
+
+    /// Returns the commitments that a stage1 proof of the given subcircuit must be made wrt, i.e.,
+    /// its stage0 commitment followed by its commitment in each extra round. Returns `None` if
+    /// there's no such subcircuit.
+    pub fn expected_ds(&self, subcircuit_idx: usize) -> Option<Vec<G16Com<E>>> {
+        core::iter::once(&self.coms)
+            .chain(&self.round_coms)
+            .map(|coms| coms.get(subcircuit_idx).copied())
+            .collect()
+    }
+
+    /// Goes back to stage 0, e.g., because a stage0 response turned out to be bad. The stage0
+    /// requests stay the same, but the commitments and everything derived from them are dropped.
+    pub fn into_stage0_state(self) -> CoordinatorStage0State<E, P> {
+        CoordinatorStage0State {
+            subcircuits: self.subcircuits,
+            circ_params: self.circ_params,
+            public_inputs: self.public_inputs,
+            public_input_subcircuit: self.public_input_subcircuit,
+        }
+    }
+
+    /// Creates the stage0 request of the given subcircuit again. A worker that does stage 1 of a
+    /// subcircuit needs it to recompute the stage0 commitment.
+    pub fn gen_stage0_request(&self, subcircuit_idx: usize) -> Stage0RequestRef<E::ScalarField> {
+        gen_stage0_request(&self.subcircuits, subcircuit_idx)
+    }
+
     /// Consumes this stage1 request generator and outputs all the state necessary to aggregate the
     /// resulting responses
-    pub fn into_agg_state(self) -> FinalAggState<E> {
-        let public_inputs: Vec<E::ScalarField> = [
-            self.exec_tree_leaves[0].evals.challenges(),
-            self.exec_tree_root.to_field_elements().unwrap(),
-        ]
-        .concat();
-
+    pub fn into_agg_state(self) -> FinalAggState<C, E> {
         FinalAggState {
-            public_inputs,
-            super_com: self.super_com,
+            challenges: self.challenges(),
+            exec_tree_root: self.exec_tree_root,
+            super_coms: [&[self.super_com][..], &self.round_super_coms].concat(),
+            circ_params_digest: digest_circ_params(&self.circ_params),
+            public_inputs: self.public_inputs,
+            public_input_membership: self.public_input_membership,
         }
     }
 }
@@ -529,6 +1005,9 @@ where
     pub(crate) root: MerkleRoot<C>,
     pub(crate) serialized_witnesses: Vec<u8>,
     pub(crate) circ_params: P::Parameters,
+    pub(crate) public_inputs: Vec<F>,
+    pub(crate) public_input_membership: Option<MerklePath<C>>,
+    pub(crate) round_challenges: Vec<F>,
 }
 
 impl<C, F, P> Stage1Request<C, F, P>
@@ -543,8 +1022,11 @@ where
             cur_leaf: self.cur_leaf.clone(),
             next_leaf_membership: self.next_leaf_membership.clone(),
             root: self.root.clone(),
-            serialized_witnesses: self.serialized_witnesses.as_slice(),
+            serialized_witnesses: Cow::Borrowed(&self.serialized_witnesses),
             circ_params: &self.circ_params,
+            public_inputs: self.public_inputs.as_slice(),
+            public_input_membership: self.public_input_membership.clone(),
+            round_challenges: self.round_challenges.as_slice(),
         }
     }
 }
@@ -560,8 +1042,11 @@ where
     pub(crate) cur_leaf: ExecTreeLeaf<F>,
     pub(crate) next_leaf_membership: MerklePath<C>,
     pub(crate) root: MerkleRoot<C>,
-    pub(crate) serialized_witnesses: &'a [u8],
+    pub(crate) serialized_witnesses: Cow<'a, [u8]>,
     pub(crate) circ_params: &'a P::Parameters,
+    pub(crate) public_inputs: &'a [F],
+    pub(crate) public_input_membership: Option<MerklePath<C>>,
+    pub(crate) round_challenges: &'a [F],
 }
 
 // We need to manually implement this because CanonicalSerialize isn't implemented for &T
@@ -588,6 +1073,12 @@ where
             .serialize_with_mode(&mut writer, compress)?;
         self.circ_params
             .serialize_with_mode(&mut writer, compress)?;
+        self.public_inputs
+            .serialize_with_mode(&mut writer, compress)?;
+        self.public_input_membership
+            .serialize_with_mode(&mut writer, compress)?;
+        self.round_challenges
+            .serialize_with_mode(&mut writer, compress)?;
 
         Ok(())
     }
@@ -600,6 +1091,9 @@ where
             + self.root.serialized_size(compress)
             + self.serialized_witnesses.serialized_size(compress)
             + self.circ_params.serialized_size(compress)
+            + self.public_inputs.serialized_size(compress)
+            + self.public_input_membership.serialized_size(compress)
+            + self.round_challenges.serialized_size(compress)
     }
 }
 
@@ -617,6 +1111,25 @@ where
             root: self.root.clone(),
             serialized_witnesses: self.serialized_witnesses.to_vec(),
             circ_params: self.circ_params.clone(),
+            public_inputs: self.public_inputs.to_vec(),
+            public_input_membership: self.public_input_membership.clone(),
+            round_challenges: self.round_challenges.to_vec(),
         }
     }
 }
+
+/// A request for the commitment of a subcircuit in an extra round of the circuit. See
+/// `CircuitWithPortals::NUM_EXTRA_ROUNDS`.
+#[derive(Clone, CanonicalSerialize, CanonicalDeserialize)]
+pub struct RoundRequest<F, P>
+where
+    F: PrimeField,
+    P: CircuitWithPortals<F>,
+{
+    pub subcircuit_idx: usize,
+    pub round: usize,
+    /// The memory-checking challenges followed by the challenges of the earlier extra rounds
+    pub(crate) challenges: Vec<F>,
+    pub(crate) serialized_witnesses: Vec<u8>,
+    pub(crate) circ_params: P::Parameters,
+}
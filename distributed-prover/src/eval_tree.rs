@@ -116,6 +116,16 @@ pub(crate) type SerializedLeaf<F> = [F];
 /// The ZK version of `SerializedLeaf`
 pub(crate) type SerializedLeafVar<F> = [FpVar<F>];
 
+/// Returns the number of leaves in the execution tree. Leaf i is the output of subcircuit i. If
+/// the circuit has public inputs, they go in an extra leaf at index `num_subcircuits`. The tree is
+/// padded with padding leaves to the next power of two, and to at least 2 leaves so that it has a
+/// root above the leaves.
+pub(crate) fn exec_tree_num_leaves(num_subcircuits: usize, has_public_inputs: bool) -> usize {
+    (num_subcircuits + has_public_inputs as usize)
+        .next_power_of_two()
+        .max(2)
+}
+
 impl<F: PrimeField> R1CSVar<F> for ExecTreeLeafVar<F> {
     type Value = ExecTreeLeaf<F>;
 
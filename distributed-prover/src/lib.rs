@@ -1,21 +1,25 @@
 extern crate core;
 
 use ark_ff::PrimeField;
+use ark_r1cs_std::fields::fp::FpVar;
 use ark_relations::r1cs::{ConstraintSystemRef, SynthesisError};
 use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
 use rand::Rng;
 use transcript::{MemType, TranscriptEntry};
 
 pub mod aggregation;
+pub mod circuits;
 pub mod coordinator;
 pub mod eval_tree;
 pub mod pairing_ops;
 pub mod poseidon_util;
+pub mod sql;
 pub mod subcircuit_circuit;
+pub mod subcircuit_store;
 pub mod tree_hash_circuit;
 pub mod util;
 pub mod worker;
-mod tpch;
+pub mod tpch;
 pub mod test_circuit;
 pub mod single_tuple_filter_circuit;
 
@@ -56,6 +60,16 @@ pub trait CircuitWithPortals<F: PrimeField>: Clone {
 
     const MEM_TYPE: MemType;
 
+    /// The number of commit-then-challenge rounds this circuit needs after the memory-checking
+    /// one, e.g., 1 for a lookup argument whose witnesses depend on the memory-checking
+    /// challenges. In extra round `k`, every subcircuit commits to the witnesses allocated by
+    /// `generate_round_witnesses(.., k, ..)`, and then `NUM_ROUND_CHALLENGES` challenges are
+    /// derived from the commitments of all the rounds so far.
+    const NUM_EXTRA_ROUNDS: usize = 0;
+
+    /// The number of challenges derived in each extra round
+    const NUM_ROUND_CHALLENGES: usize = 1;
+
     /// Makes a random instance of this circuit with teh given parameters
     fn rand(rng: &mut impl Rng, params: &Self::Parameters) -> Self;
 
@@ -65,6 +79,13 @@ pub trait CircuitWithPortals<F: PrimeField>: Clone {
     /// Gets all the subtraces of the portal wires used in this circuit instantiation
     fn get_portal_subtraces(&self) -> Vec<Vec<TranscriptEntry<F>>>;
 
+    /// Like `get_portal_subtraces`, but yields the subtraces one subcircuit at a time. Circuits
+    /// that can compute their trace incrementally should override this, so the coordinator never
+    /// has to hold the whole thing.
+    fn portal_subtraces_iter(&self) -> Box<dyn Iterator<Item = Vec<TranscriptEntry<F>>> + '_> {
+        Box::new(self.get_portal_subtraces().into_iter())
+    }
+
     /// The number of subcircuits in this circuit
     fn num_subcircuits(&self) -> usize;
 
@@ -80,6 +101,11 @@ pub trait CircuitWithPortals<F: PrimeField>: Clone {
     /// Gets the list of witnesses that belong to the given subcircuit
     fn get_serialized_witnesses(&self, subcircuit_idx: usize) -> Vec<u8>;
 
+    /// Yields the serialized witnesses of every subcircuit, in order
+    fn serialized_witnesses_iter(&self) -> Box<dyn Iterator<Item = Vec<u8>> + '_> {
+        Box::new((0..self.num_subcircuits()).map(|idx| self.get_serialized_witnesses(idx)))
+    }
+
     /// Sets the list of witnesses that belong to the given subcircuit
     fn set_serialized_witnesses(&mut self, subcircuit_idx: usize, bytes: &[u8]);
 
@@ -92,6 +118,62 @@ pub trait CircuitWithPortals<F: PrimeField>: Clone {
         subcircuit_idx: usize,
         pm: &mut P,
     ) -> Result<(), SynthesisError>;
+
+    /// Allocates the witnesses of the given subcircuit that are committed to in extra round
+    /// `round`, where `round < NUM_EXTRA_ROUNDS`. `challenges` are the memory-checking challenges
+    /// followed by the challenges of the earlier extra rounds. The circuit must keep whatever
+    /// variables it allocates here, to use them in `generate_constraints`. Like there, the ONLY
+    /// witnesses it may use are the subcircuit's.
+    fn generate_round_witnesses(
+        &mut self,
+        cs: ConstraintSystemRef<F>,
+        subcircuit_idx: usize,
+        round: usize,
+        challenges: &[F],
+    ) -> Result<(), SynthesisError> {
+        let _ = (cs, subcircuit_idx, round, challenges);
+        Ok(())
+    }
+
+    /// Gives the circuit the challenges of all the extra rounds, in order, as public inputs. This
+    /// is called right before `generate_constraints`.
+    fn set_round_challenges(&mut self, challenges: &[FpVar<F>]) {
+        let _ = challenges;
+    }
+
+    /// The public inputs to this circuit. If nonempty, these are hashed into a special leaf of the
+    /// execution tree at index `num_subcircuits()`, and the membership proof of that leaf is part
+    /// of the final proof.
+    ///
+    /// The NUMBER of public inputs MUST depend only on the circuit's parameters. Key generation,
+    /// proving, and verification each get it from a different instance with the same parameters
+    /// (the verifier uses `Self::new(params)`), and keys and proofs don't match if these differ.
+    fn public_inputs(&self) -> Vec<F> {
+        Vec::new()
+    }
+
+    /// The index of the subcircuit that receives the public inputs
+    fn public_input_subcircuit(&self) -> usize {
+        0
+    }
+
+    /// Generates constraints for the subcircuit at `self.public_input_subcircuit()`. By the time
+    /// this is called, `public_inputs` have been checked against the execution tree. Circuits with
+    /// public inputs MUST override this. The default panics if there are any, since it would leave
+    /// them unconstrained.
+    fn generate_constraints_with_public_inputs<P: PortalManager<F>>(
+        &mut self,
+        cs: ConstraintSystemRef<F>,
+        subcircuit_idx: usize,
+        pm: &mut P,
+        public_inputs: &[FpVar<F>],
+    ) -> Result<(), SynthesisError> {
+        assert!(
+            public_inputs.is_empty(),
+            "a circuit with public inputs must override generate_constraints_with_public_inputs"
+        );
+        self.generate_constraints(cs, subcircuit_idx, pm)
+    }
 }
 
 pub trait CircuitWithRamPortals<F: PrimeField> {
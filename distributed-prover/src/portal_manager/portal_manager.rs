@@ -12,6 +12,26 @@ pub trait PortalManager<F: PrimeField> {
 
     fn set(&mut self, name: String, val: &FpVar<F>) -> Result<(), SynthesisError>;
 
+    /// Gets the portal wire of the given name, and checks that it lives at memory address `addr`.
+    /// This is for wires whose name depends on the witness, e.g., a VM memory cell. Portal
+    /// managers that don't track addresses ignore `addr`.
+    fn get_at(&mut self, name: &str, addr: &FpVar<F>) -> Result<FpVar<F>, SynthesisError> {
+        let _ = addr;
+        self.get(name)
+    }
+
+    /// Sets the portal wire of the given name, and checks that it lives at memory address `addr`.
+    /// See [`PortalManager::get_at`].
+    fn set_at(
+        &mut self,
+        name: String,
+        addr: &FpVar<F>,
+        val: &FpVar<F>,
+    ) -> Result<(), SynthesisError> {
+        let _ = addr;
+        self.set(name, val)
+    }
+
     fn running_evals(&self) -> RunningEvaluationVar<F>;
 }
 
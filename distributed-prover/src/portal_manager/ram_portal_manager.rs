@@ -85,6 +85,19 @@ impl<F: PrimeField> SetupRamPortalManager<F> {
         self.subtraces.push(Vec::new());
         self.cs = cs;
     }
+
+    /// Panics if the wire `name` does not live at address `addr`. Addresses are handed out in
+    /// order of first use, so callers of `get_at`/`set_at` must use their wires in address order
+    /// the first time around.
+    fn check_addr(&mut self, name: &str, addr: &FpVar<F>) -> Result<(), SynthesisError> {
+        let expected_addr = F::from(self.address.name_to_addr(name));
+        assert_eq!(
+            expected_addr,
+            addr.value()?,
+            "portal wire '{name}' is not at the given address"
+        );
+        Ok(())
+    }
 }
 
 impl<F: PrimeField> PortalManager<F> for SetupRamPortalManager<F> {
@@ -142,6 +155,23 @@ impl<F: PrimeField> PortalManager<F> for SetupRamPortalManager<F> {
         Ok(())
     }
 
+    /// Sanity-checks that `name` is at address `addr`, then does a `get`
+    fn get_at(&mut self, name: &str, addr: &FpVar<F>) -> Result<FpVar<F>, SynthesisError> {
+        self.check_addr(name, addr)?;
+        self.get(name)
+    }
+
+    /// Sanity-checks that `name` is at address `addr`, then does a `set`
+    fn set_at(
+        &mut self,
+        name: String,
+        addr: &FpVar<F>,
+        val: &FpVar<F>,
+    ) -> Result<(), SynthesisError> {
+        self.check_addr(&name, addr)?;
+        self.set(name, val)
+    }
+
     /// 在“设置期”对应的 PortalManager 并不真正维护“多项式累积”(RunningEvaluationVar)
     fn running_evals(&self) -> RunningEvaluationVar<F> {
         // A setup portal manager does not have running evals
@@ -237,6 +267,31 @@ impl<F: PrimeField> PortalManager<F> for RamProverPortalManager<F> {
         Ok(())
     }
 
+    /// Checks that the next entry is at address `addr`, then does a `get`
+    fn get_at(&mut self, name: &str, addr: &FpVar<F>) -> Result<FpVar<F>, SynthesisError> {
+        self.time_ordered_subtrace
+            .get(self.next_entry_idx)
+            .expect("ran out of time-ordered subtrace entries")
+            .addr
+            .enforce_equal(addr)?;
+        self.get(name)
+    }
+
+    /// Checks that the next entry is at address `addr`, then does a `set`
+    fn set_at(
+        &mut self,
+        name: String,
+        addr: &FpVar<F>,
+        val: &FpVar<F>,
+    ) -> Result<(), SynthesisError> {
+        self.time_ordered_subtrace
+            .get(self.next_entry_idx)
+            .expect("ran out of time-ordered subtrace entries")
+            .addr
+            .enforce_equal(addr)?;
+        self.set(name, val)
+    }
+
     fn running_evals(&self) -> RunningEvaluationVar<F> {
         RunningEvaluationVar::Ram(self.running_evals.clone())
     }
@@ -60,6 +60,14 @@ impl<F: PrimeField> SetupRomPortalManager<F> {
         self.subtraces.push(Vec::new());
         self.cs = cs;
     }
+
+    /// Removes and returns the subtrace in progress, so that a caller who makes the subtraces one
+    /// at a time doesn't have to keep them all. The portal wires set so far can still be gotten.
+    pub fn take_subtrace(&mut self) -> Vec<RomTranscriptEntry<F>> {
+        self.subtraces
+            .pop()
+            .expect("must run start_subtrace() before using SetupPortalManager")
+    }
 }
 
 impl<F: PrimeField> PortalManager<F> for SetupRomPortalManager<F> {
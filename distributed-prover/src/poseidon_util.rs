@@ -1,6 +1,5 @@
 use crate::eval_tree::{ExecTreeParams, SerializedLeaf, SerializedLeafVar};
 
-use ark_bls12_381::Fr;
 use ark_crypto_primitives::{
     crh::{
         constraints::{CRHSchemeGadget, TwoToOneCRHSchemeGadget},
@@ -10,47 +9,81 @@ use ark_crypto_primitives::{
         constraints::ConfigGadget as TreeConfigGadget, Config as TreeConfig,
         IdentityDigestConverter,
     },
-    sponge::poseidon::{find_poseidon_ark_and_mds, PoseidonConfig, PoseidonDefaultConfigEntry},
+    sponge::{
+        poseidon::{find_poseidon_ark_and_mds, PoseidonConfig, PoseidonDefaultConfigEntry},
+        Absorb,
+    },
 };
 use ark_ff::PrimeField;
+use lazy_static::lazy_static;
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    marker::PhantomData,
+    sync::{Arc, Mutex},
+};
 
 // Define all the leaf and two-to-one hashes for Poseidon
-pub type LeafH = poseidon::CRH<Fr>;
-pub type LeafHG = poseidon::constraints::CRHGadget<Fr>;
-pub type CompressH = poseidon::TwoToOneCRH<Fr>;
-pub type CompressHG = poseidon::constraints::TwoToOneCRHGadget<Fr>;
+pub type LeafH<F> = poseidon::CRH<F>;
+pub type LeafHG<F> = poseidon::constraints::CRHGadget<F>;
+pub type CompressH<F> = poseidon::TwoToOneCRH<F>;
+pub type CompressHG<F> = poseidon::constraints::TwoToOneCRHGadget<F>;
 
 // Define the structs necessary to make a Merkle tree over the Poseidon hash
 
 #[derive(Clone)]
-pub struct PoseidonTreeConfig;
-impl TreeConfig for PoseidonTreeConfig {
-    type Leaf = SerializedLeaf<Fr>;
+pub struct PoseidonTreeConfig<F>(PhantomData<F>);
+impl<F: PrimeField + Absorb> TreeConfig for PoseidonTreeConfig<F> {
+    type Leaf = SerializedLeaf<F>;
 
-    type LeafHash = LeafH;
-    type TwoToOneHash = CompressH;
+    type LeafHash = LeafH<F>;
+    type TwoToOneHash = CompressH<F>;
 
-    type LeafDigest = <LeafH as CRHScheme>::Output;
+    type LeafDigest = <LeafH<F> as CRHScheme>::Output;
     type LeafInnerDigestConverter = IdentityDigestConverter<Self::LeafDigest>;
-    type InnerDigest = <CompressH as TwoToOneCRHScheme>::Output;
+    type InnerDigest = <CompressH<F> as TwoToOneCRHScheme>::Output;
 }
 
-pub struct PoseidonTreeConfigVar;
-impl TreeConfigGadget<PoseidonTreeConfig, Fr> for PoseidonTreeConfigVar {
-    type Leaf = SerializedLeafVar<Fr>;
+pub struct PoseidonTreeConfigVar<F>(PhantomData<F>);
+impl<F: PrimeField + Absorb> TreeConfigGadget<PoseidonTreeConfig<F>, F>
+    for PoseidonTreeConfigVar<F>
+{
+    type Leaf = SerializedLeafVar<F>;
 
-    type LeafDigest = <LeafHG as CRHSchemeGadget<LeafH, Fr>>::OutputVar;
+    type LeafDigest = <LeafHG<F> as CRHSchemeGadget<LeafH<F>, F>>::OutputVar;
     type LeafInnerConverter = IdentityDigestConverter<Self::LeafDigest>;
-    type InnerDigest = <CompressHG as TwoToOneCRHSchemeGadget<CompressH, Fr>>::OutputVar;
-    type LeafHash = LeafHG;
-    type TwoToOneHash = CompressHG;
+    type InnerDigest = <CompressHG<F> as TwoToOneCRHSchemeGadget<CompressH<F>, F>>::OutputVar;
+    type LeafHash = LeafHG<F>;
+    type TwoToOneHash = CompressHG<F>;
+}
+
+lazy_static! {
+    // The Poseidon params made so far, keyed by field and rate. Each is a PoseidonConfig<F>
+    static ref POSEIDON_PARAMS: Mutex<HashMap<(TypeId, usize), Arc<dyn Any + Send + Sync>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Returns the (unoptimized) Poseidon params of the given rate over `F`. Making them is slow, so
+/// they're made once per field and rate, and then reused.
+pub(crate) fn poseidon_params<F: PrimeField>(rate: usize) -> Arc<PoseidonConfig<F>> {
+    let mut cache = POSEIDON_PARAMS.lock().unwrap();
+    cache
+        .entry((TypeId::of::<F>(), rate))
+        .or_insert_with(|| Arc::new(gen_poseidon_params::<F>(rate, false)))
+        .clone()
+        .downcast()
+        .unwrap()
 }
 
-// Generates Poseidon params for BLS12-381. This is copied from
+// Generates Poseidon params for the given field. The parameter sets are the ones for BLS12-381, and
+// they work for any field whose modulus is about as big, e.g., BN254. This is copied from
 //     https://github.com/arkworks-rs/crypto-primitives/blob/54b3ac24b8943fbd984863558c749997e96ff399/src/sponge/poseidon/traits.rs#L69
 // and
 //     https://github.com/arkworks-rs/crypto-primitives/blob/54b3ac24b8943fbd984863558c749997e96ff399/src/sponge/test.rs
-pub(crate) fn gen_poseidon_params(rate: usize, optimized_for_weights: bool) -> PoseidonConfig<Fr> {
+pub(crate) fn gen_poseidon_params<F: PrimeField>(
+    rate: usize,
+    optimized_for_weights: bool,
+) -> PoseidonConfig<F> {
     let params_set = if !optimized_for_weights {
         [
             PoseidonDefaultConfigEntry::new(2, 17, 8, 31, 0),
@@ -75,8 +108,8 @@ pub(crate) fn gen_poseidon_params(rate: usize, optimized_for_weights: bool) -> P
 
     for param in params_set.iter() {
         if param.rate == rate {
-            let (ark, mds) = find_poseidon_ark_and_mds::<Fr>(
-                Fr::MODULUS_BIT_SIZE as u64,
+            let (ark, mds) = find_poseidon_ark_and_mds::<F>(
+                F::MODULUS_BIT_SIZE as u64,
                 rate,
                 param.full_rounds as u64,
                 param.partial_rounds as u64,
@@ -98,8 +131,8 @@ pub(crate) fn gen_poseidon_params(rate: usize, optimized_for_weights: bool) -> P
     panic!("could not generate poseidon params");
 }
 
-/// Returns leaf and two-to-one params for a Poseidon Merkle tree
-pub fn gen_merkle_params() -> ExecTreeParams<PoseidonTreeConfig> {
+/// Returns leaf and two-to-one params for a Poseidon Merkle tree over `F`
+pub fn gen_merkle_params<F: PrimeField + Absorb>() -> ExecTreeParams<PoseidonTreeConfig<F>> {
     ExecTreeParams {
         leaf_params: gen_poseidon_params(3, false),
         two_to_one_params: gen_poseidon_params(2, false),
@@ -0,0 +1,247 @@
+use ark_ff::PrimeField;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+/// The type of a column of a text table, which determines how a value is encoded as a field
+/// element. Every encoding is a nonnegative integer that preserves the order of the values, so
+/// the encoded columns can be compared and sorted in a query.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColumnType {
+    /// A nonnegative integer, encoded as itself
+    Int,
+    /// A nonnegative decimal with at most the given number of digits after the point, encoded as
+    /// an integer in units of 10^-digits. For example, `17.5` with 2 digits is 1750.
+    Decimal(u32),
+    /// A date of the form YYYY-MM-DD, encoded as in [`date`]
+    Date,
+    /// A single ASCII character, encoded as its code. Flags like `A < N < R` keep their order.
+    Char,
+    /// A column that is not loaded
+    Skip,
+}
+
+/// How the rows of a text table are laid out
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TableFormat {
+    pub delimiter: char,
+    /// Whether every field is followed by the delimiter, including the last one
+    pub trailing_delimiter: bool,
+    /// Whether the first line holds the column names, and should be skipped
+    pub has_header: bool,
+}
+
+impl TableFormat {
+    /// The format of the `.tbl` files made by the TPC-H dbgen tool
+    pub const TBL: TableFormat = TableFormat {
+        delimiter: '|',
+        trailing_delimiter: true,
+        has_header: false,
+    };
+
+    /// Comma-separated values with a header line. Quoting is not supported, so fields can't
+    /// contain commas.
+    pub const CSV: TableFormat = TableFormat {
+        delimiter: ',',
+        trailing_delimiter: false,
+        has_header: true,
+    };
+}
+
+/// Reads a text table with the given column types. Every row has one field element per column
+/// that is not skipped, in order. Empty lines are ignored.
+pub fn read_table<F: PrimeField>(
+    reader: impl BufRead,
+    format: TableFormat,
+    schema: &[ColumnType],
+) -> io::Result<Vec<Vec<F>>> {
+    let mut rows = Vec::new();
+    for (line_idx, line) in reader.lines().enumerate() {
+        let line = line?;
+        if (format.has_header && line_idx == 0) || line.trim().is_empty() {
+            continue;
+        }
+
+        let line = match format.trailing_delimiter {
+            true => line.strip_suffix(format.delimiter).unwrap_or(&line),
+            false => &line,
+        };
+        let fields = line.split(format.delimiter).collect::<Vec<_>>();
+        if fields.len() != schema.len() {
+            return Err(invalid_data(format!(
+                "line {}: expected {} fields, found {}",
+                line_idx + 1,
+                schema.len(),
+                fields.len()
+            )));
+        }
+
+        let row = fields
+            .iter()
+            .zip(schema)
+            .filter(|(_, ty)| **ty != ColumnType::Skip)
+            .map(|(field, ty)| {
+                encode(field.trim(), *ty).map(F::from).ok_or_else(|| {
+                    invalid_data(format!("line {}: {field:?} is not a {ty:?}", line_idx + 1))
+                })
+            })
+            .collect::<io::Result<Vec<F>>>()?;
+        rows.push(row);
+    }
+
+    Ok(rows)
+}
+
+/// Reads a text table from the given file. See [`read_table`]
+pub fn load_table<F: PrimeField>(
+    path: impl AsRef<Path>,
+    format: TableFormat,
+    schema: &[ColumnType],
+) -> io::Result<Vec<Vec<F>>> {
+    read_table(BufReader::new(File::open(path)?), format, schema)
+}
+
+/// Encodes a value of the given type. Returns `None` if it doesn't parse.
+fn encode(value: &str, ty: ColumnType) -> Option<u64> {
+    match ty {
+        ColumnType::Int => parse_digits(value),
+        ColumnType::Decimal(digits) => {
+            let (int_part, frac_part) = value.split_once('.').unwrap_or((value, ""));
+            let frac_len = u32::try_from(frac_part.len()).ok()?;
+            if frac_len > digits {
+                return None;
+            }
+            let frac = match frac_part {
+                "" => 0,
+                _ => parse_digits(frac_part)?,
+            };
+            parse_digits(int_part)?
+                .checked_mul(10u64.checked_pow(digits)?)?
+                .checked_add(frac * 10u64.pow(digits - frac_len))
+        },
+        ColumnType::Date => {
+            let mut parts = value.split('-').map(parse_digits);
+            let (year, month, day) = (parts.next()??, parts.next()??, parts.next()??);
+            if parts.next().is_some() {
+                return None;
+            }
+            let (year, month, day) = (
+                year.try_into().ok()?,
+                month.try_into().ok()?,
+                day.try_into().ok()?,
+            );
+            is_valid_date(year, month, day).then(|| date(year, month, day))
+        },
+        ColumnType::Char => match value.as_bytes() {
+            &[c] if c.is_ascii() => Some(u64::from(c)),
+            _ => None,
+        },
+        ColumnType::Skip => Some(0),
+    }
+}
+
+/// Parses a nonempty string of ASCII digits
+fn parse_digits(s: &str) -> Option<u64> {
+    match !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit()) {
+        true => s.parse().ok(),
+        false => None,
+    }
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Returns whether the given day exists and isn't before 1970-01-01
+fn is_valid_date(year: u32, month: u32, day: u32) -> bool {
+    let is_leap_year = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days_in_month = match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if is_leap_year => 29,
+        2 => 28,
+        _ => return false,
+    };
+    year >= 1970 && (1..=days_in_month).contains(&day)
+}
+
+/// Encodes a date as the number of days since 1970-01-01. Dates before that are not supported.
+pub fn date(year: u32, month: u32, day: u32) -> u64 {
+    assert!(
+        is_valid_date(year, month, day),
+        "invalid date {year}-{month}-{day}"
+    );
+
+    // Years start in March here, so that the leap day is the last day of the year. This is the
+    // days_from_civil algorithm from http://howardhinnant.github.io/date_algorithms.html
+    let year = u64::from(if month <= 2 { year - 1 } else { year });
+    let (month, day) = (u64::from(month), u64::from(day));
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{date, is_valid_date, read_table, ColumnType, TableFormat};
+    use ark_bls12_381::Fr;
+
+    #[test]
+    fn test_date() {
+        assert_eq!(date(1970, 1, 1), 0);
+        assert_eq!(date(1970, 3, 1), 59);
+        assert_eq!(date(1992, 1, 1), 8035);
+        // 1996 is a leap year, 2100 is not
+        assert_eq!(date(1996, 3, 1) - date(1996, 2, 28), 2);
+        assert_eq!(date(2100, 3, 1) - date(2100, 2, 28), 1);
+        assert_eq!(date(1998, 12, 1) - 90, date(1998, 9, 2));
+        assert_eq!(date(2000, 3, 1) - date(2000, 2, 29), 1);
+    }
+
+    // Days past the end of the month don't exist
+    #[test]
+    fn test_invalid_dates() {
+        assert!(is_valid_date(1996, 2, 29));
+        assert!(is_valid_date(2000, 2, 29));
+        assert!(is_valid_date(1998, 12, 31));
+        assert!(!is_valid_date(1996, 2, 30));
+        assert!(!is_valid_date(1997, 2, 29));
+        assert!(!is_valid_date(2100, 2, 29));
+        assert!(!is_valid_date(1998, 4, 31));
+        assert!(!is_valid_date(1998, 1, 0));
+        assert!(!is_valid_date(1969, 12, 31));
+    }
+
+    // Reads a small CSV file with every column type, and checks that malformed values are
+    // rejected
+    #[test]
+    fn test_read_table() {
+        use ColumnType::*;
+        let schema = [Int, Decimal(2), Date, Char, Skip];
+        let csv = "id,price,day,flag,comment\n\
+                   7,17.5,1970-01-02,A,first\n\
+                   \n\
+                   0,0.04,1996-03-01,N,\n";
+
+        let rows = read_table::<Fr>(csv.as_bytes(), TableFormat::CSV, &schema).unwrap();
+        let expected = [[7, 1750, 1, 65], [0, 4, date(1996, 3, 1), 78]]
+            .map(|row| row.map(Fr::from).to_vec())
+            .to_vec();
+        assert_eq!(rows, expected);
+
+        for bad_row in [
+            "1,2.345,1970-01-01,A,",
+            "1,-2,1970-01-01,A,",
+            "1,2,1970-13-01,A,",
+            "1,2,1996-02-31,A,",
+            "1,2,1970-01-01,AB,",
+            "x,2,1970-01-01,A,",
+            "1,2,1970-01-01,A",
+        ] {
+            let csv = format!("header\n{bad_row}");
+            assert!(read_table::<Fr>(csv.as_bytes(), TableFormat::CSV, &schema).is_err());
+        }
+    }
+}
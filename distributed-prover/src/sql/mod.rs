@@ -0,0 +1,10 @@
+mod loader;
+mod plan;
+mod sql_circuit;
+mod sql_constraints;
+mod table;
+
+pub use loader::*;
+pub use plan::*;
+pub use sql_circuit::*;
+pub use table::*;
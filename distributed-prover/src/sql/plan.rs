@@ -0,0 +1,716 @@
+use ark_ff::PrimeField;
+use ark_serialize::{
+    CanonicalDeserialize, CanonicalSerialize, Compress, Read, SerializationError, Valid, Validate,
+    Write,
+};
+
+/// A comparison operator used in filter predicates
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CmpOp {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+}
+
+impl CmpOp {
+    /// Evaluates `lhs op rhs`, where field elements are compared as integers
+    pub fn eval<F: PrimeField>(&self, lhs: F, rhs: F) -> bool {
+        match self {
+            CmpOp::Lt => lhs < rhs,
+            CmpOp::Le => lhs <= rhs,
+            CmpOp::Gt => lhs > rhs,
+            CmpOp::Ge => lhs >= rhs,
+            CmpOp::Eq => lhs == rhs,
+            CmpOp::Ne => lhs != rhs,
+        }
+    }
+}
+
+/// A filter predicate of the form `col op value`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CanonicalSerialize, CanonicalDeserialize)]
+pub struct Comparison {
+    pub col: usize,
+    pub op: CmpOp,
+    pub value: u64,
+}
+
+impl Comparison {
+    pub fn new(col: usize, op: CmpOp, value: u64) -> Self {
+        Comparison { col, op, value }
+    }
+}
+
+/// The deepest `Expr` that can be deserialized. This keeps a malicious plan from overflowing the
+/// stack.
+pub const MAX_EXPR_DEPTH: usize = 64;
+
+/// An arithmetic expression over the columns of a row. Arithmetic is over the scalar field, so a
+/// subtraction that goes below 0 wraps around.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Expr {
+    Col(usize),
+    Const(u64),
+    Add(Box<Expr>, Box<Expr>),
+    Sub(Box<Expr>, Box<Expr>),
+    Mul(Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    pub fn col(col: usize) -> Self {
+        Expr::Col(col)
+    }
+
+    pub fn constant(value: u64) -> Self {
+        Expr::Const(value)
+    }
+
+    /// Evaluates this expression on the given row
+    pub fn eval<F: PrimeField>(&self, row: &[F]) -> F {
+        match self {
+            Expr::Col(c) => row[*c],
+            Expr::Const(v) => F::from(*v),
+            Expr::Add(a, b) => a.eval(row) + b.eval(row),
+            Expr::Sub(a, b) => a.eval(row) - b.eval(row),
+            Expr::Mul(a, b) => a.eval(row) * b.eval(row),
+        }
+    }
+
+    /// The largest column index this expression refers to, if any
+    fn max_col(&self) -> Option<usize> {
+        match self {
+            Expr::Col(c) => Some(*c),
+            Expr::Const(_) => None,
+            Expr::Add(a, b) | Expr::Sub(a, b) | Expr::Mul(a, b) => a.max_col().max(b.max_col()),
+        }
+    }
+}
+
+impl core::ops::Add for Expr {
+    type Output = Expr;
+
+    fn add(self, rhs: Expr) -> Expr {
+        Expr::Add(Box::new(self), Box::new(rhs))
+    }
+}
+
+impl core::ops::Sub for Expr {
+    type Output = Expr;
+
+    fn sub(self, rhs: Expr) -> Expr {
+        Expr::Sub(Box::new(self), Box::new(rhs))
+    }
+}
+
+impl core::ops::Mul for Expr {
+    type Output = Expr;
+
+    fn mul(self, rhs: Expr) -> Expr {
+        Expr::Mul(Box::new(self), Box::new(rhs))
+    }
+}
+
+/// An aggregate function computed by a group-by
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Aggregate {
+    /// The sum of the given column
+    Sum(usize),
+    /// The number of rows in the group
+    Count,
+    /// The average of the given column, rounded down
+    Avg(usize),
+}
+
+impl Aggregate {
+    /// The column this aggregate reads, if any
+    pub fn col(&self) -> Option<usize> {
+        match self {
+            Aggregate::Sum(c) | Aggregate::Avg(c) => Some(*c),
+            Aggregate::Count => None,
+        }
+    }
+}
+
+/// A sort key. Rows are compared on their sort keys in order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CanonicalSerialize, CanonicalDeserialize)]
+pub struct SortKey {
+    pub col: usize,
+    pub descending: bool,
+}
+
+impl SortKey {
+    pub fn asc(col: usize) -> Self {
+        SortKey {
+            col,
+            descending: false,
+        }
+    }
+
+    pub fn desc(col: usize) -> Self {
+        SortKey {
+            col,
+            descending: true,
+        }
+    }
+}
+
+/// A relational operator. Every operator takes a table and produces a table with the same number
+/// of rows, where some rows may be marked invalid, i.e., not part of the table.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Operator {
+    /// Keeps the rows that satisfy all of the given comparisons
+    Filter(Vec<Comparison>),
+    /// Replaces every row with the given expressions evaluated on it
+    Project(Vec<Expr>),
+    /// Sorts the rows by the given keys. Valid rows come before invalid rows. The sort is not
+    /// stable.
+    Sort(Vec<SortKey>),
+    /// Groups rows by the given key columns. The output has the key columns followed by one
+    /// column per aggregate, and has one valid row per group.
+    GroupBy {
+        keys: Vec<usize>,
+        aggregates: Vec<Aggregate>,
+    },
+}
+
+/// A query over a single table. The query starts with a scan of the input table, then applies
+/// the operators in order. Finally, the first `num_output_rows` rows of the result are made
+/// public, and the result must not have any more valid rows than that. If the last operator is
+/// not a sort, the result is sorted first so that the valid rows come first.
+///
+/// Comparisons treat field elements as integers, and only work on values up to `(p-1)/2`.
+#[derive(Clone, Debug, PartialEq, Eq, CanonicalSerialize, CanonicalDeserialize)]
+pub struct QueryPlan {
+    /// The number of columns of the input table
+    pub num_cols: usize,
+    pub ops: Vec<Operator>,
+    pub num_output_rows: usize,
+}
+
+impl QueryPlan {
+    /// Starts a query that scans a table with `num_cols` columns and outputs one row
+    pub fn scan(num_cols: usize) -> Self {
+        QueryPlan {
+            num_cols,
+            ops: Vec::new(),
+            num_output_rows: 1,
+        }
+    }
+
+    pub fn filter(mut self, predicates: Vec<Comparison>) -> Self {
+        self.ops.push(Operator::Filter(predicates));
+        self
+    }
+
+    pub fn project(mut self, exprs: Vec<Expr>) -> Self {
+        self.ops.push(Operator::Project(exprs));
+        self
+    }
+
+    pub fn sort(mut self, keys: Vec<SortKey>) -> Self {
+        self.ops.push(Operator::Sort(keys));
+        self
+    }
+
+    pub fn group_by(mut self, keys: Vec<usize>, aggregates: Vec<Aggregate>) -> Self {
+        self.ops.push(Operator::GroupBy { keys, aggregates });
+        self
+    }
+
+    /// Sets the number of result rows that are made public
+    pub fn limit(mut self, num_output_rows: usize) -> Self {
+        self.num_output_rows = num_output_rows;
+        self
+    }
+
+    /// The number of columns of the result
+    pub fn output_width(&self) -> usize {
+        self.ops.iter().fold(self.num_cols, |width, op| match op {
+            Operator::Filter(_) | Operator::Sort(_) => width,
+            Operator::Project(exprs) => exprs.len(),
+            Operator::GroupBy { keys, aggregates } => keys.len() + aggregates.len(),
+        })
+    }
+
+    /// Breaks this plan into the stages that get turned into subcircuits. Panics if the plan
+    /// refers to a column that doesn't exist.
+    pub(crate) fn compile(&self, num_rows: usize) -> Vec<CompiledStage> {
+        assert!(
+            self.num_output_rows > 0 && self.num_output_rows <= num_rows,
+            "number of output rows must be between 1 and the number of rows"
+        );
+
+        let check_col = |col: usize, width: usize| {
+            assert!(
+                col < width,
+                "column {col} is out of range for a table with {width} columns"
+            )
+        };
+
+        let mut stages = Vec::new();
+        let mut width = self.num_cols;
+        push_stage(&mut stages, Stage::Scan, 0);
+
+        for op in &self.ops {
+            match op {
+                Operator::Filter(preds) => {
+                    preds.iter().for_each(|p| check_col(p.col, width));
+                    push_stage(&mut stages, Stage::Filter(preds.clone()), width);
+                },
+                Operator::Project(exprs) => {
+                    exprs
+                        .iter()
+                        .filter_map(Expr::max_col)
+                        .for_each(|c| check_col(c, width));
+                    push_stage(&mut stages, Stage::Project(exprs.clone()), width);
+                    width = exprs.len();
+                },
+                Operator::Sort(keys) => {
+                    keys.iter().for_each(|k| check_col(k.col, width));
+                    push_sort(&mut stages, keys, width, num_rows);
+                },
+                Operator::GroupBy { keys, aggregates } => {
+                    keys.iter().for_each(|&k| check_col(k, width));
+                    aggregates
+                        .iter()
+                        .filter_map(Aggregate::col)
+                        .for_each(|c| check_col(c, width));
+
+                    // Sort by the keys so that groups are contiguous, then aggregate
+                    let sort_keys = keys.iter().map(|&k| SortKey::asc(k)).collect::<Vec<_>>();
+                    push_sort(&mut stages, &sort_keys, width, num_rows);
+                    let stage = Stage::Aggregate {
+                        keys: keys.clone(),
+                        aggregates: aggregates.clone(),
+                    };
+                    push_stage(&mut stages, stage, width);
+                    width = keys.len() + aggregates.len();
+                },
+            }
+        }
+
+        // Move the valid rows to the front, unless the query already did
+        if !matches!(self.ops.last(), Some(Operator::Sort(_))) {
+            push_sort(&mut stages, &[], width, num_rows);
+        }
+        push_stage(&mut stages, Stage::Output, width);
+
+        stages
+    }
+}
+
+/// A step of a compiled query. Stage `i` reads table `i - 1` and writes table `i`, except for the
+/// scan, which reads the input, and the output, which writes nothing.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Stage {
+    Scan,
+    Filter(Vec<Comparison>),
+    Project(Vec<Expr>),
+    /// One layer of a sorting network. Row `i` is compared with row `i ^ mask`, and the smaller
+    /// of the two goes to the lower index.
+    SortLayer {
+        keys: Vec<SortKey>,
+        mask: usize,
+    },
+    /// Aggregates the groups of a table that is sorted by the group keys
+    Aggregate {
+        keys: Vec<usize>,
+        aggregates: Vec<Aggregate>,
+    },
+    /// Makes the first rows of the table public
+    Output,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct CompiledStage {
+    pub(crate) stage: Stage,
+    /// The number of columns of the table this stage reads
+    pub(crate) in_width: usize,
+    /// Stages in the same class produce subcircuits of the same shape. This is the index of the
+    /// first stage in the class.
+    pub(crate) class: usize,
+}
+
+/// Appends a stage that is in a class of its own
+fn push_stage(stages: &mut Vec<CompiledStage>, stage: Stage, in_width: usize) {
+    let class = stages.len();
+    stages.push(CompiledStage {
+        stage,
+        in_width,
+        class,
+    });
+}
+
+/// Appends one stage per layer of the sorting network. All the layers look the same, so they
+/// share a class.
+fn push_sort(stages: &mut Vec<CompiledStage>, keys: &[SortKey], width: usize, num_rows: usize) {
+    let class = stages.len();
+    for mask in sort_layers(num_rows) {
+        stages.push(CompiledStage {
+            stage: Stage::SortLayer {
+                keys: keys.to_vec(),
+                mask,
+            },
+            in_width: width,
+            class,
+        });
+    }
+}
+
+/// The layers of a sorting network on `n` rows. This is the variant of bitonic sort where every
+/// comparator puts the smaller value first. A layer is described by the mask that maps a row to
+/// the row it's compared with. If `n` is not a power of two, the network is the one for the next
+/// power of two, where the missing rows count as larger than everything. Those rows never move, so
+/// their comparators are left out, see `sort_pairs`.
+pub(crate) fn sort_layers(n: usize) -> Vec<usize> {
+    let mut layers = Vec::new();
+    let mut block = 2;
+    while block < 2 * n {
+        // First compare mirrored pairs within the block, then merge the halves
+        layers.push(block - 1);
+        let mut dist = block / 4;
+        while dist > 0 {
+            layers.push(dist);
+            dist /= 2;
+        }
+        block *= 2;
+    }
+    layers
+}
+
+/// The pairs of rows `(i, j)` that a layer of the sorting network on `n` rows compares, where
+/// `i < j`. The smaller row goes to `i`.
+pub(crate) fn sort_pairs(n: usize, mask: usize) -> impl Iterator<Item = (usize, usize)> {
+    (0..n)
+        .map(move |i| (i, i ^ mask))
+        .filter(move |&(i, j)| i < j && j < n)
+}
+
+impl CanonicalSerialize for CmpOp {
+    fn serialize_with_mode<W: Write>(
+        &self,
+        writer: W,
+        compress: Compress,
+    ) -> Result<(), SerializationError> {
+        let tag = match self {
+            CmpOp::Lt => 0u8,
+            CmpOp::Le => 1,
+            CmpOp::Gt => 2,
+            CmpOp::Ge => 3,
+            CmpOp::Eq => 4,
+            CmpOp::Ne => 5,
+        };
+        tag.serialize_with_mode(writer, compress)
+    }
+
+    fn serialized_size(&self, _compress: Compress) -> usize {
+        1
+    }
+}
+
+impl Valid for CmpOp {
+    fn check(&self) -> Result<(), SerializationError> {
+        Ok(())
+    }
+}
+
+impl CanonicalDeserialize for CmpOp {
+    fn deserialize_with_mode<R: Read>(
+        reader: R,
+        compress: Compress,
+        validate: Validate,
+    ) -> Result<Self, SerializationError> {
+        let tag = u8::deserialize_with_mode(reader, compress, validate)?;
+        match tag {
+            0u8 => Ok(CmpOp::Lt),
+            1u8 => Ok(CmpOp::Le),
+            2u8 => Ok(CmpOp::Gt),
+            3u8 => Ok(CmpOp::Ge),
+            4u8 => Ok(CmpOp::Eq),
+            5u8 => Ok(CmpOp::Ne),
+            _ => Err(SerializationError::InvalidData),
+        }
+    }
+}
+
+impl CanonicalSerialize for Expr {
+    fn serialize_with_mode<W: Write>(
+        &self,
+        mut writer: W,
+        compress: Compress,
+    ) -> Result<(), SerializationError> {
+        match self {
+            Expr::Col(c) => {
+                writer.write_all(&[0u8])?;
+                c.serialize_with_mode(writer, compress)
+            },
+            Expr::Const(v) => {
+                writer.write_all(&[1u8])?;
+                v.serialize_with_mode(writer, compress)
+            },
+            Expr::Add(a, b) | Expr::Sub(a, b) | Expr::Mul(a, b) => {
+                let tag = match self {
+                    Expr::Add(..) => 2u8,
+                    Expr::Sub(..) => 3u8,
+                    _ => 4u8,
+                };
+                writer.write_all(&[tag])?;
+                // Recurse through a trait object so the writer type doesn't grow at every level
+                let writer: &mut dyn Write = &mut writer;
+                a.serialize_with_mode(&mut *writer, compress)?;
+                b.serialize_with_mode(writer, compress)
+            },
+        }
+    }
+
+    fn serialized_size(&self, compress: Compress) -> usize {
+        match self {
+            Expr::Col(c) => 1 + c.serialized_size(compress),
+            Expr::Const(v) => 1 + v.serialized_size(compress),
+            Expr::Add(a, b) | Expr::Sub(a, b) | Expr::Mul(a, b) => {
+                1 + a.serialized_size(compress) + b.serialized_size(compress)
+            },
+        }
+    }
+}
+
+impl Valid for Expr {
+    fn check(&self) -> Result<(), SerializationError> {
+        Ok(())
+    }
+}
+
+impl Expr {
+    /// Deserializes an expression that can be at most `depth` levels deep
+    fn deserialize_with_depth(
+        reader: &mut dyn Read,
+        compress: Compress,
+        validate: Validate,
+        depth: usize,
+    ) -> Result<Self, SerializationError> {
+        if depth == 0 {
+            return Err(SerializationError::InvalidData);
+        }
+
+        let tag = u8::deserialize_with_mode(&mut *reader, compress, validate)?;
+        match tag {
+            0u8 => usize::deserialize_with_mode(reader, compress, validate).map(Expr::Col),
+            1u8 => u64::deserialize_with_mode(reader, compress, validate).map(Expr::Const),
+            2u8..=4u8 => {
+                let a = Expr::deserialize_with_depth(&mut *reader, compress, validate, depth - 1)?;
+                let b = Expr::deserialize_with_depth(reader, compress, validate, depth - 1)?;
+                Ok(match tag {
+                    2u8 => a + b,
+                    3u8 => a - b,
+                    _ => a * b,
+                })
+            },
+            _ => Err(SerializationError::InvalidData),
+        }
+    }
+}
+
+impl CanonicalDeserialize for Expr {
+    /// Fails if the expression is more than `MAX_EXPR_DEPTH` levels deep
+    fn deserialize_with_mode<R: Read>(
+        mut reader: R,
+        compress: Compress,
+        validate: Validate,
+    ) -> Result<Self, SerializationError> {
+        // Recurse through a trait object so the reader type doesn't grow at every level
+        Expr::deserialize_with_depth(&mut reader, compress, validate, MAX_EXPR_DEPTH)
+    }
+}
+
+impl CanonicalSerialize for Aggregate {
+    fn serialize_with_mode<W: Write>(
+        &self,
+        mut writer: W,
+        compress: Compress,
+    ) -> Result<(), SerializationError> {
+        match self {
+            Aggregate::Sum(c) => {
+                writer.write_all(&[0u8])?;
+                c.serialize_with_mode(writer, compress)
+            },
+            Aggregate::Count => writer.write_all(&[1u8]).map_err(Into::into),
+            Aggregate::Avg(c) => {
+                writer.write_all(&[2u8])?;
+                c.serialize_with_mode(writer, compress)
+            },
+        }
+    }
+
+    fn serialized_size(&self, compress: Compress) -> usize {
+        1 + self.col().map_or(0, |c| c.serialized_size(compress))
+    }
+}
+
+impl Valid for Aggregate {
+    fn check(&self) -> Result<(), SerializationError> {
+        Ok(())
+    }
+}
+
+impl CanonicalDeserialize for Aggregate {
+    fn deserialize_with_mode<R: Read>(
+        mut reader: R,
+        compress: Compress,
+        validate: Validate,
+    ) -> Result<Self, SerializationError> {
+        let tag = u8::deserialize_with_mode(&mut reader, compress, validate)?;
+        match tag {
+            0u8 => usize::deserialize_with_mode(reader, compress, validate).map(Aggregate::Sum),
+            1u8 => Ok(Aggregate::Count),
+            2u8 => usize::deserialize_with_mode(reader, compress, validate).map(Aggregate::Avg),
+            _ => Err(SerializationError::InvalidData),
+        }
+    }
+}
+
+impl CanonicalSerialize for Operator {
+    fn serialize_with_mode<W: Write>(
+        &self,
+        mut writer: W,
+        compress: Compress,
+    ) -> Result<(), SerializationError> {
+        match self {
+            Operator::Filter(preds) => {
+                writer.write_all(&[0u8])?;
+                preds.serialize_with_mode(writer, compress)
+            },
+            Operator::Project(exprs) => {
+                writer.write_all(&[1u8])?;
+                exprs.serialize_with_mode(writer, compress)
+            },
+            Operator::Sort(keys) => {
+                writer.write_all(&[2u8])?;
+                keys.serialize_with_mode(writer, compress)
+            },
+            Operator::GroupBy { keys, aggregates } => {
+                writer.write_all(&[3u8])?;
+                keys.serialize_with_mode(&mut writer, compress)?;
+                aggregates.serialize_with_mode(writer, compress)
+            },
+        }
+    }
+
+    fn serialized_size(&self, compress: Compress) -> usize {
+        1 + match self {
+            Operator::Filter(preds) => preds.serialized_size(compress),
+            Operator::Project(exprs) => exprs.serialized_size(compress),
+            Operator::Sort(keys) => keys.serialized_size(compress),
+            Operator::GroupBy { keys, aggregates } => {
+                keys.serialized_size(compress) + aggregates.serialized_size(compress)
+            },
+        }
+    }
+}
+
+impl Valid for Operator {
+    fn check(&self) -> Result<(), SerializationError> {
+        Ok(())
+    }
+}
+
+impl CanonicalDeserialize for Operator {
+    fn deserialize_with_mode<R: Read>(
+        mut reader: R,
+        compress: Compress,
+        validate: Validate,
+    ) -> Result<Self, SerializationError> {
+        let tag = u8::deserialize_with_mode(&mut reader, compress, validate)?;
+        match tag {
+            0u8 => Vec::deserialize_with_mode(reader, compress, validate).map(Operator::Filter),
+            1u8 => Vec::deserialize_with_mode(reader, compress, validate).map(Operator::Project),
+            2u8 => Vec::deserialize_with_mode(reader, compress, validate).map(Operator::Sort),
+            3u8 => {
+                let keys = Vec::deserialize_with_mode(&mut reader, compress, validate)?;
+                let aggregates = Vec::deserialize_with_mode(reader, compress, validate)?;
+                Ok(Operator::GroupBy { keys, aggregates })
+            },
+            _ => Err(SerializationError::InvalidData),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sql::plan::{
+        sort_layers, sort_pairs, Aggregate, CmpOp, Comparison, Expr, QueryPlan, SortKey,
+        MAX_EXPR_DEPTH,
+    };
+    use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+
+    // Checks that the sorting network sorts every 0/1 sequence, which by the 0-1 principle means
+    // it sorts everything. This includes lengths that aren't powers of two
+    #[test]
+    fn test_sort_layers() {
+        for n in 1..=16 {
+            for bits in 0u32..(1 << n) {
+                let mut seq = (0..n).map(|i| (bits >> i) & 1).collect::<Vec<_>>();
+                for mask in sort_layers(n) {
+                    for (i, j) in sort_pairs(n, mask) {
+                        if seq[j] < seq[i] {
+                            seq.swap(i, j);
+                        }
+                    }
+                }
+                assert!(
+                    seq.windows(2).all(|w| w[0] <= w[1]),
+                    "n = {n}, bits = {bits}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_plan_serialization() {
+        let plan = QueryPlan::scan(4)
+            .filter(vec![Comparison::new(0, CmpOp::Le, 10)])
+            .project(vec![
+                Expr::col(0),
+                Expr::col(1) * (Expr::constant(100) - Expr::col(2)),
+                Expr::col(3),
+            ])
+            .group_by(
+                vec![2],
+                vec![Aggregate::Sum(1), Aggregate::Count, Aggregate::Avg(0)],
+            )
+            .sort(vec![SortKey::desc(1)])
+            .limit(3);
+
+        let mut buf = Vec::new();
+        plan.serialize_compressed(&mut buf).unwrap();
+        assert_eq!(buf.len(), plan.compressed_size());
+        let deserialized = QueryPlan::deserialize_compressed(buf.as_slice()).unwrap();
+        assert_eq!(plan, deserialized);
+    }
+
+    // Expressions up to MAX_EXPR_DEPTH deep deserialize, and deeper ones are rejected
+    #[test]
+    fn test_expr_depth_limit() {
+        let nested = |depth: usize| (1..depth).fold(Expr::col(0), |e, _| e + Expr::constant(1));
+
+        let mut buf = Vec::new();
+        nested(MAX_EXPR_DEPTH)
+            .serialize_compressed(&mut buf)
+            .unwrap();
+        assert_eq!(
+            Expr::deserialize_compressed(buf.as_slice()).unwrap(),
+            nested(MAX_EXPR_DEPTH)
+        );
+
+        let mut buf = Vec::new();
+        nested(MAX_EXPR_DEPTH + 1)
+            .serialize_compressed(&mut buf)
+            .unwrap();
+        assert!(Expr::deserialize_compressed(buf.as_slice()).is_err());
+
+        // A long run of Add tags with nothing after them is rejected, rather than overflowing the
+        // stack
+        assert!(Expr::deserialize_compressed(vec![2u8; 1 << 20].as_slice()).is_err());
+    }
+}
@@ -0,0 +1,198 @@
+use crate::sql::plan::{sort_pairs, CompiledStage, QueryPlan, Stage};
+use crate::sql::table::Table;
+
+use ark_ff::PrimeField;
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+use rand::Rng;
+use std::ops::Range;
+use std::sync::OnceLock;
+
+#[derive(Clone, CanonicalSerialize, CanonicalDeserialize)]
+pub struct SqlCircuitParams {
+    // number of rows of the input table. the tables in the query are padded with invalid rows to
+    // padded_num_rows()
+    pub num_rows: usize,
+
+    // number of rows each subcircuit works on. this is rounded up to chunk_size()
+    pub rows_per_subcircuit: usize,
+
+    pub plan: QueryPlan,
+}
+
+impl SqlCircuitParams {
+    /// The number of rows of every table in the query. This is `num_rows` rounded up to a power of
+    /// two, and to at least 2, so that the table splits into chunks evenly
+    pub(crate) fn padded_num_rows(&self) -> usize {
+        self.num_rows.max(2).next_power_of_two()
+    }
+
+    /// The number of rows each subcircuit works on. This is `rows_per_subcircuit` rounded up to a
+    /// power of two, and to at least 2, so that a chunk of a sorting network layer is a whole
+    /// number of pairs. It's at most `padded_num_rows()`.
+    pub(crate) fn chunk_size(&self) -> usize {
+        let chunk_size = self.rows_per_subcircuit.max(2).next_power_of_two();
+        chunk_size.min(self.padded_num_rows())
+    }
+}
+
+impl std::fmt::Display for SqlCircuitParams {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "[nr={},rps={},ops={}]",
+            self.num_rows,
+            self.rows_per_subcircuit,
+            self.plan.ops.len()
+        )
+    }
+}
+
+// Names of the portal wires that hold the tables. Table `t` is the output of stage `t`.
+pub(crate) fn valid_name(table: usize, row: usize) -> String {
+    format!("t{table} r{row} valid")
+}
+
+pub(crate) fn cell_name(table: usize, row: usize, col: usize) -> String {
+    format!("t{table} r{row} c{col}")
+}
+
+/// The running aggregates that a group-by subcircuit hands to the next one. `row` is the last row
+/// of the subcircuit.
+pub(crate) fn carry_name(table: usize, row: usize, k: usize) -> String {
+    format!("t{table} r{row} carry{k}")
+}
+
+/// A query over a single private table, compiled into subcircuits. Every stage of the compiled
+/// query is split into subcircuits that each handle `params.chunk_size()` rows, and the
+/// tables in between stages are passed along in portal wires. The output subcircuit makes the
+/// query result public.
+#[derive(Clone)]
+pub struct SqlCircuit<F: PrimeField> {
+    /// The input table, padded with invalid rows to `params.padded_num_rows()` rows
+    pub table: Table<F>,
+    pub params: SqlCircuitParams,
+    pub(crate) stages: Vec<CompiledStage>,
+    /// The (stage, chunk) that each subcircuit handles
+    pub(crate) subcircuits: Vec<(usize, usize)>,
+    /// The public output of the query. This is computed on first use, or filled in by
+    /// `set_serialized_witnesses` for the last subcircuit.
+    pub(crate) output: OnceLock<Vec<F>>,
+}
+
+impl<F: PrimeField> SqlCircuit<F> {
+    /// Makes a query over an empty table
+    pub fn new(params: &SqlCircuitParams) -> Self {
+        Self::with_table(params, Vec::new())
+    }
+
+    /// Makes a query over the given rows, of which there are at most `params.num_rows`. The table
+    /// is padded with invalid rows.
+    pub fn with_table(params: &SqlCircuitParams, rows: Vec<Vec<F>>) -> Self {
+        assert!(
+            rows.len() <= params.num_rows,
+            "table has more than {} rows",
+            params.num_rows
+        );
+        let num_rows = params.padded_num_rows();
+
+        let stages = params.plan.compile(num_rows);
+        let num_chunks = num_rows / params.chunk_size();
+        let subcircuits = stages
+            .iter()
+            .enumerate()
+            .flat_map(|(s, stage)| {
+                let chunks = match stage.stage {
+                    Stage::Output => 1,
+                    _ => num_chunks,
+                };
+                (0..chunks).map(move |c| (s, c))
+            })
+            .collect();
+
+        SqlCircuit {
+            table: Table::padded(rows, params.plan.num_cols, num_rows),
+            params: params.clone(),
+            stages,
+            subcircuits,
+            output: OnceLock::new(),
+        }
+    }
+
+    /// Makes a query over a full table of small random values, so that groups and ties occur
+    pub fn rand(rng: &mut impl Rng, params: &SqlCircuitParams) -> Self {
+        let rows = (0..params.num_rows)
+            .map(|_| {
+                (0..params.plan.num_cols)
+                    .map(|_| F::from(rng.gen_range(0u64..8)))
+                    .collect()
+            })
+            .collect();
+        Self::with_table(params, rows)
+    }
+
+    /// The public output of the query. See [`Table::output_values`]
+    pub(crate) fn output(&self) -> &[F] {
+        self.output.get_or_init(|| {
+            self.table
+                .query(&self.params.plan)
+                .output_values(self.params.plan.num_output_rows)
+        })
+    }
+
+    /// The valid rows of the public output
+    pub fn output_rows(&self) -> Vec<Vec<F>> {
+        let width = self.params.plan.output_width();
+        self.output()
+            .chunks(width + 1)
+            .filter(|row| row[0] == F::ONE)
+            .map(|row| row[1..].to_vec())
+            .collect()
+    }
+
+    /// The rows that the given chunk of a stage works on. For a layer of a sorting network, these
+    /// are the smaller rows of the compared pairs.
+    pub(crate) fn chunk_rows(&self, stage: &Stage, chunk: usize) -> Vec<usize> {
+        match stage {
+            Stage::SortLayer { mask, .. } => {
+                let num_pairs = self.params.chunk_size() / 2;
+                sort_pairs(self.params.padded_num_rows(), *mask)
+                    .map(|(i, _)| i)
+                    .skip(chunk * num_pairs)
+                    .take(num_pairs)
+                    .collect()
+            },
+            _ => self.chunk_range(chunk).collect(),
+        }
+    }
+
+    /// The (stage, chunk) that the given subcircuit handles
+    pub(crate) fn subcircuit(&self, subcircuit_idx: usize) -> (usize, usize) {
+        self.subcircuits[subcircuit_idx]
+    }
+
+    pub(crate) fn chunk_range(&self, chunk: usize) -> Range<usize> {
+        let rows = self.params.chunk_size();
+        chunk * rows..(chunk + 1) * rows
+    }
+
+    /// Subcircuits with the same shape key have the same constraints
+    fn shape_key(&self, subcircuit_idx: usize) -> (usize, bool, bool) {
+        let (s, chunk) = self.subcircuit(subcircuit_idx);
+        let stage = &self.stages[s];
+        match stage.stage {
+            // The first and last chunks of an aggregation have no previous and next rows
+            Stage::Aggregate { .. } => {
+                let last_chunk = self.params.padded_num_rows() / self.params.chunk_size() - 1;
+                (stage.class, chunk == 0, chunk == last_chunk)
+            },
+            _ => (stage.class, false, false),
+        }
+    }
+
+    pub(crate) fn representative(&self, subcircuit_idx: usize) -> usize {
+        let key = self.shape_key(subcircuit_idx);
+        (0..=subcircuit_idx)
+            .find(|&i| self.shape_key(i) == key)
+            .unwrap()
+    }
+}
@@ -0,0 +1,705 @@
+use crate::portal_manager::{PortalManager, RomProverPortalManager, SetupRomPortalManager};
+use crate::sql::plan::{Aggregate, CmpOp, Comparison, Expr, SortKey, Stage};
+use crate::sql::sql_circuit::{carry_name, cell_name, valid_name, SqlCircuit, SqlCircuitParams};
+use crate::sql::table::floor_div;
+use crate::transcript::{MemType, TranscriptEntry};
+use crate::CircuitWithPortals;
+
+use ark_ff::PrimeField;
+use ark_r1cs_std::alloc::AllocVar;
+use ark_r1cs_std::boolean::Boolean;
+use ark_r1cs_std::eq::EqGadget;
+use ark_r1cs_std::fields::fp::FpVar;
+use ark_r1cs_std::fields::FieldVar;
+use ark_r1cs_std::R1CSVar;
+use ark_relations::ns;
+use ark_relations::r1cs::{ConstraintSystem, ConstraintSystemRef, SynthesisError, SynthesisMode};
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+use core::cmp::Ordering;
+use rand::Rng;
+
+impl<F: PrimeField> CircuitWithPortals<F> for SqlCircuit<F> {
+    type Parameters = SqlCircuitParams;
+    type ProverPortalManager = RomProverPortalManager<F>;
+    const MEM_TYPE: MemType = MemType::Rom;
+
+    fn rand(rng: &mut impl Rng, params: &SqlCircuitParams) -> Self {
+        SqlCircuit::rand(rng, params)
+    }
+
+    fn get_params(&self) -> SqlCircuitParams {
+        self.params.clone()
+    }
+
+    /// Runs every subcircuit against a setup portal manager. Only the witness values are
+    /// computed. The constraints are thrown away.
+    fn get_portal_subtraces(&self) -> Vec<Vec<TranscriptEntry<F>>> {
+        let mut pm = SetupRomPortalManager::new(ConstraintSystem::new_ref());
+        let mut circ = self.clone();
+        for subcircuit_idx in 0..self.num_subcircuits() {
+            let cs = ConstraintSystem::new_ref();
+            cs.set_mode(SynthesisMode::Prove {
+                construct_matrices: false,
+            });
+            pm.start_subtrace(cs.clone());
+            circ.generate_constraints(cs, subcircuit_idx, &mut pm)
+                .unwrap();
+        }
+
+        pm.subtraces
+            .into_iter()
+            .map(|subtrace| subtrace.into_iter().map(TranscriptEntry::Rom).collect())
+            .collect()
+    }
+
+    fn num_subcircuits(&self) -> usize {
+        self.subcircuits.len()
+    }
+
+    fn get_unique_subcircuits(&self) -> Vec<usize> {
+        (0..self.num_subcircuits())
+            .filter(|&i| self.representative(i) == i)
+            .collect()
+    }
+
+    fn representative_subcircuit(&self, subcircuit_idx: usize) -> usize {
+        self.representative(subcircuit_idx)
+    }
+
+    fn new(params: &Self::Parameters) -> Self {
+        SqlCircuit::new(params)
+    }
+
+    /// The query result is public. See [`crate::sql::Table::output_values`] for the format.
+    fn public_inputs(&self) -> Vec<F> {
+        self.output().to_vec()
+    }
+
+    /// The output subcircuit, which is the last one
+    fn public_input_subcircuit(&self) -> usize {
+        self.subcircuits.len() - 1
+    }
+
+    /// Scan subcircuits get their rows of the input table, and the last subcircuit gets the query
+    /// result. Nothing else has witnesses, since everything else is computed from portal wires.
+    fn get_serialized_witnesses(&self, subcircuit_idx: usize) -> Vec<u8> {
+        let mut out_buf = Vec::new();
+        let (s, chunk) = self.subcircuit(subcircuit_idx);
+        match self.stages[s].stage {
+            Stage::Scan => {
+                let range = self.chunk_range(chunk);
+                self.table.rows[range.clone()]
+                    .serialize_uncompressed(&mut out_buf)
+                    .unwrap();
+                self.table.valid[range]
+                    .serialize_uncompressed(&mut out_buf)
+                    .unwrap();
+            },
+            Stage::Output => self.output().serialize_uncompressed(&mut out_buf).unwrap(),
+            _ => (),
+        }
+        out_buf
+    }
+
+    fn set_serialized_witnesses(&mut self, subcircuit_idx: usize, mut bytes: &[u8]) {
+        let (s, chunk) = self.subcircuit(subcircuit_idx);
+        match self.stages[s].stage {
+            Stage::Scan => {
+                let range = self.chunk_range(chunk);
+                let rows = Vec::<Vec<F>>::deserialize_uncompressed_unchecked(&mut bytes).unwrap();
+                let valid = Vec::<bool>::deserialize_uncompressed_unchecked(&mut bytes).unwrap();
+                self.table.rows.splice(range.clone(), rows);
+                self.table.valid.splice(range, valid);
+            },
+            Stage::Output => {
+                let output = Vec::<F>::deserialize_uncompressed_unchecked(&mut bytes).unwrap();
+                self.output = output.into();
+            },
+            _ => (),
+        }
+    }
+
+    fn generate_constraints<P: PortalManager<F>>(
+        &mut self,
+        cs: ConstraintSystemRef<F>,
+        subcircuit_idx: usize,
+        pm: &mut P,
+    ) -> Result<(), SynthesisError> {
+        let (s, chunk) = self.subcircuit(subcircuit_idx);
+
+        let stage = self.stages[s].clone();
+        let rows = self.chunk_rows(&stage.stage, chunk);
+        // Stage s reads table s - 1 and writes table s
+        let (t_in, t_out) = (s.wrapping_sub(1), s);
+
+        match &stage.stage {
+            Stage::Scan => {
+                for i in rows {
+                    let valid = Boolean::new_witness(ns!(cs, "valid"), || Ok(self.table.valid[i]))?;
+                    let row = self.table.rows[i]
+                        .iter()
+                        .map(|x| FpVar::new_witness(ns!(cs, "cell"), || Ok(x)))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    write_row(pm, t_out, i, &valid.into(), &row)?;
+                }
+            },
+            Stage::Filter(preds) => {
+                for i in rows {
+                    let valid = pm.get(&valid_name(t_in, i))?;
+                    let row = read_cells(pm, t_in, i, 0..stage.in_width)?;
+                    let new_valid = filter_row(&valid, &row, preds)?;
+                    write_row(pm, t_out, i, &new_valid, &row)?;
+                }
+            },
+            Stage::Project(exprs) => {
+                for i in rows {
+                    let valid = pm.get(&valid_name(t_in, i))?;
+                    let row = read_cells(pm, t_in, i, 0..stage.in_width)?;
+                    // Zero out invalid rows
+                    let new_row = exprs
+                        .iter()
+                        .map(|e| &valid * expr_var(e, &row))
+                        .collect::<Vec<_>>();
+                    write_row(pm, t_out, i, &valid, &new_row)?;
+                }
+            },
+            Stage::SortLayer { keys, mask } => {
+                for i in rows {
+                    let j = i ^ mask;
+                    let valid_i = pm.get(&valid_name(t_in, i))?;
+                    let row_i = read_cells(pm, t_in, i, 0..stage.in_width)?;
+                    let valid_j = pm.get(&valid_name(t_in, j))?;
+                    let row_j = read_cells(pm, t_in, j, 0..stage.in_width)?;
+
+                    // Swap the rows if row j goes first
+                    let swap = row_less(&valid_j, &row_j, &valid_i, &row_i, keys)?;
+                    let cond_swap = |a: &FpVar<F>, b: &FpVar<F>| {
+                        let lo = a + &swap * (b - a);
+                        let hi = a + b - &lo;
+                        (lo, hi)
+                    };
+                    let (valid_lo, valid_hi) = cond_swap(&valid_i, &valid_j);
+                    let (row_lo, row_hi): (Vec<_>, Vec<_>) = row_i
+                        .iter()
+                        .zip(&row_j)
+                        .map(|(a, b)| cond_swap(a, b))
+                        .unzip();
+
+                    write_row(pm, t_out, i, &valid_lo, &row_lo)?;
+                    write_row(pm, t_out, j, &valid_hi, &row_hi)?;
+                }
+            },
+            Stage::Aggregate { keys, aggregates } => {
+                self.aggregate_constraints(cs.clone(), pm, (t_in, t_out), rows, keys, aggregates)?
+            },
+            Stage::Output => {
+                let output = self.output().to_vec();
+                let output_vars = output
+                    .iter()
+                    .map(|x| FpVar::new_witness(ns!(cs, "output"), || Ok(x)))
+                    .collect::<Result<Vec<_>, _>>()?;
+                self.generate_constraints_with_public_inputs(
+                    cs.clone(),
+                    subcircuit_idx,
+                    pm,
+                    &output_vars,
+                )?;
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the first rows of the final table match the public output, and that the row
+    /// after them is invalid. Since the final table is sorted, this means the public output holds
+    /// every valid row, up to the row limit.
+    fn generate_constraints_with_public_inputs<P: PortalManager<F>>(
+        &mut self,
+        _cs: ConstraintSystemRef<F>,
+        subcircuit_idx: usize,
+        pm: &mut P,
+        public_inputs: &[FpVar<F>],
+    ) -> Result<(), SynthesisError> {
+        let (s, _) = self.subcircuits[subcircuit_idx];
+        let width = self.stages[s].in_width;
+        let t_in = s - 1;
+        let num_output_rows = self.params.plan.num_output_rows;
+        assert_eq!(public_inputs.len(), num_output_rows * (width + 1));
+
+        for (i, expected) in public_inputs.chunks(width + 1).enumerate() {
+            let valid = pm.get(&valid_name(t_in, i))?;
+            let row = read_cells(pm, t_in, i, 0..width)?;
+            valid.enforce_equal(&expected[0])?;
+            for (x, y) in row.iter().zip(&expected[1..]) {
+                (&valid * x).enforce_equal(y)?;
+            }
+        }
+        if num_output_rows < self.params.padded_num_rows() {
+            pm.get(&valid_name(t_in, num_output_rows))?
+                .enforce_equal(&FpVar::zero())?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<F: PrimeField> SqlCircuit<F> {
+    /// Computes the running aggregates of the given rows of a table that's sorted by `keys`. The
+    /// aggregates of the row before `rows` are read from the previous subcircuit, and the ones of
+    /// the last row are handed to the next subcircuit.
+    fn aggregate_constraints<P: PortalManager<F>>(
+        &self,
+        cs: ConstraintSystemRef<F>,
+        pm: &mut P,
+        (t_in, t_out): (usize, usize),
+        rows: Vec<usize>,
+        keys: &[usize],
+        aggregates: &[Aggregate],
+    ) -> Result<(), SynthesisError> {
+        let n = self.params.padded_num_rows();
+        let agg_cols = aggregates
+            .iter()
+            .filter_map(Aggregate::col)
+            .collect::<Vec<_>>();
+        let (first, last) = (rows[0], rows[rows.len() - 1]);
+
+        // The previous row, along with its running aggregates
+        let mut prev = None;
+        if first > 0 {
+            prev = Some(GroupRowVar {
+                valid: pm.get(&valid_name(t_in, first - 1))?,
+                keys: read_cells(pm, t_in, first - 1, keys.iter().copied())?,
+                count: pm.get(&carry_name(t_out, first - 1, 0))?,
+                sums: (0..agg_cols.len())
+                    .map(|k| pm.get(&carry_name(t_out, first - 1, k + 1)))
+                    .collect::<Result<Vec<_>, _>>()?,
+            });
+        }
+
+        for i in rows {
+            let valid = pm.get(&valid_name(t_in, i))?;
+            let row_keys = read_cells(pm, t_in, i, keys.iter().copied())?;
+            let vals = read_cells(pm, t_in, i, agg_cols.iter().copied())?;
+
+            // Now that we know whether this row continues the previous row's group, the previous
+            // row can be output. If the previous subcircuit had it, it's already been output
+            let (count, sums) = match &prev {
+                Some(p) => {
+                    let same = same_group(&p.valid, &p.keys, &valid, &row_keys)?;
+                    if i > first {
+                        p.emit(cs.clone(), pm, t_out, i - 1, aggregates, &same)?;
+                    }
+                    let count = FpVar::one() + &same * &p.count;
+                    let sums = vals
+                        .iter()
+                        .zip(&p.sums)
+                        .map(|(v, sum)| v + &same * sum)
+                        .collect();
+                    (count, sums)
+                },
+                None => (FpVar::one(), vals),
+            };
+            prev = Some(GroupRowVar {
+                valid,
+                keys: row_keys,
+                count,
+                sums,
+            });
+        }
+
+        // Look at the next row to see whether the last row ends its group. Then hand off the
+        // running aggregates
+        let p = prev.unwrap();
+        if last + 1 < n {
+            let next_valid = pm.get(&valid_name(t_in, last + 1))?;
+            let next_keys = read_cells(pm, t_in, last + 1, keys.iter().copied())?;
+            let next_same = same_group(&p.valid, &p.keys, &next_valid, &next_keys)?;
+            p.emit(cs, pm, t_out, last, aggregates, &next_same)?;
+
+            pm.set(carry_name(t_out, last, 0), &p.count)?;
+            for (k, sum) in p.sums.iter().enumerate() {
+                pm.set(carry_name(t_out, last, k + 1), sum)?;
+            }
+        } else {
+            p.emit(cs, pm, t_out, last, aggregates, &FpVar::zero())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A row of a table that's being grouped, along with the running aggregates of its group
+struct GroupRowVar<F: PrimeField> {
+    valid: FpVar<F>,
+    keys: Vec<FpVar<F>>,
+    count: FpVar<F>,
+    /// One running sum for every aggregate that reads a column
+    sums: Vec<FpVar<F>>,
+}
+
+impl<F: PrimeField> GroupRowVar<F> {
+    /// Writes this row of the grouped table. The row is valid iff the row after it is not in the
+    /// same group.
+    fn emit<P: PortalManager<F>>(
+        &self,
+        cs: ConstraintSystemRef<F>,
+        pm: &mut P,
+        table: usize,
+        row: usize,
+        aggregates: &[Aggregate],
+        next_same: &FpVar<F>,
+    ) -> Result<(), SynthesisError> {
+        let valid = &self.valid * (FpVar::one() - next_same);
+        let mut cells = self.keys.clone();
+        let mut sums = self.sums.iter();
+        for agg in aggregates {
+            cells.push(match agg {
+                Aggregate::Sum(_) => sums.next().unwrap().clone(),
+                Aggregate::Count => self.count.clone(),
+                Aggregate::Avg(_) => floor_div_var(cs.clone(), sums.next().unwrap(), &self.count)?,
+            });
+        }
+        write_row(pm, table, row, &valid, &cells)
+    }
+}
+
+/// Reads the given columns of a row
+fn read_cells<F: PrimeField>(
+    pm: &mut impl PortalManager<F>,
+    table: usize,
+    row: usize,
+    cols: impl IntoIterator<Item = usize>,
+) -> Result<Vec<FpVar<F>>, SynthesisError> {
+    cols.into_iter()
+        .map(|c| pm.get(&cell_name(table, row, c)))
+        .collect()
+}
+
+fn write_row<F: PrimeField>(
+    pm: &mut impl PortalManager<F>,
+    table: usize,
+    row: usize,
+    valid: &FpVar<F>,
+    cells: &[FpVar<F>],
+) -> Result<(), SynthesisError> {
+    pm.set(valid_name(table, row), valid)?;
+    for (c, cell) in cells.iter().enumerate() {
+        pm.set(cell_name(table, row, c), cell)?;
+    }
+    Ok(())
+}
+
+fn expr_var<F: PrimeField>(expr: &Expr, row: &[FpVar<F>]) -> FpVar<F> {
+    match expr {
+        Expr::Col(c) => row[*c].clone(),
+        Expr::Const(v) => FpVar::constant(F::from(*v)),
+        Expr::Add(a, b) => expr_var(a, row) + expr_var(b, row),
+        Expr::Sub(a, b) => expr_var(a, row) - expr_var(b, row),
+        Expr::Mul(a, b) => expr_var(a, row) * expr_var(b, row),
+    }
+}
+
+/// Returns the new valid bit of a row after filtering on the given predicates
+fn filter_row<F: PrimeField>(
+    valid: &FpVar<F>,
+    row: &[FpVar<F>],
+    preds: &[Comparison],
+) -> Result<FpVar<F>, SynthesisError> {
+    preds.iter().try_fold(valid.clone(), |acc, pred| {
+        let lhs = &row[pred.col];
+        let rhs = FpVar::constant(F::from(pred.value));
+        let res = match pred.op {
+            CmpOp::Lt => lhs.is_cmp(&rhs, Ordering::Less, false)?,
+            CmpOp::Le => lhs.is_cmp(&rhs, Ordering::Less, true)?,
+            CmpOp::Gt => lhs.is_cmp(&rhs, Ordering::Greater, false)?,
+            CmpOp::Ge => lhs.is_cmp(&rhs, Ordering::Greater, true)?,
+            CmpOp::Eq => lhs.is_eq(&rhs)?,
+            CmpOp::Ne => lhs.is_neq(&rhs)?,
+        };
+        Ok(acc * FpVar::from(res))
+    })
+}
+
+/// Returns 1 if row `x` goes before row `y` when sorting by `keys`, and 0 otherwise. Valid rows
+/// go before invalid ones.
+fn row_less<F: PrimeField>(
+    valid_x: &FpVar<F>,
+    x: &[FpVar<F>],
+    valid_y: &FpVar<F>,
+    y: &[FpVar<F>],
+    keys: &[SortKey],
+) -> Result<FpVar<F>, SynthesisError> {
+    // Compare lexicographically, starting from the last key
+    let mut less = FpVar::zero();
+    for key in keys.iter().rev() {
+        let (a, b) = (&x[key.col], &y[key.col]);
+        let ordering = if key.descending {
+            Ordering::Greater
+        } else {
+            Ordering::Less
+        };
+        let lt = FpVar::from(a.is_cmp(b, ordering, false)?);
+        let eq = FpVar::from(a.is_eq(b)?);
+        less = lt + eq * less;
+    }
+
+    // The valid bit comes before all the keys
+    let both_valid = valid_x * valid_y;
+    let lt = valid_x - &both_valid;
+    let eq = FpVar::one() - valid_x - valid_y + both_valid.double()?;
+    Ok(lt + eq * less)
+}
+
+/// Returns 1 if both rows are valid and have the same keys, and 0 otherwise
+fn same_group<F: PrimeField>(
+    valid_x: &FpVar<F>,
+    x: &[FpVar<F>],
+    valid_y: &FpVar<F>,
+    y: &[FpVar<F>],
+) -> Result<FpVar<F>, SynthesisError> {
+    x.iter().zip(y).try_fold(valid_x * valid_y, |acc, (a, b)| {
+        Ok(acc * FpVar::from(a.is_eq(b)?))
+    })
+}
+
+/// Divides `num` by `den` as integers, rounding down. `den` must be nonzero.
+fn floor_div_var<F: PrimeField>(
+    cs: ConstraintSystemRef<F>,
+    num: &FpVar<F>,
+    den: &FpVar<F>,
+) -> Result<FpVar<F>, SynthesisError> {
+    let quotient = FpVar::new_witness(ns!(cs, "quotient"), || {
+        Ok(floor_div(num.value()?, den.value()?))
+    })?;
+    let remainder = FpVar::new_witness(ns!(cs, "remainder"), || {
+        Ok(num.value()? - quotient.value()? * den.value()?)
+    })?;
+
+    // num = quotient * den + remainder, where remainder < den, and quotient <= num so that
+    // nothing wraps around
+    (&quotient * den + &remainder).enforce_equal(num)?;
+    remainder.enforce_cmp(den, Ordering::Less, false)?;
+    quotient.enforce_cmp(num, Ordering::Less, true)?;
+
+    Ok(quotient)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::portal_manager::SetupRomPortalManager;
+    use crate::sql::{
+        Aggregate, CmpOp, Comparison, Expr, QueryPlan, SortKey, SqlCircuit, SqlCircuitParams,
+    };
+    use crate::transcript::TranscriptEntry;
+    use crate::CircuitWithPortals;
+    use ark_bls12_381::Fr;
+    use ark_ff::{Field, PrimeField};
+    use ark_r1cs_std::alloc::AllocVar;
+    use ark_r1cs_std::fields::fp::FpVar;
+    use ark_relations::r1cs::ConstraintSystem;
+    use ark_std::test_rng;
+    use std::collections::BTreeMap;
+
+    // Runs every subcircuit of the query on a pared-down copy of the circuit, and checks that the
+    // constraints are satisfied, the subtraces match the natively computed ones, and subcircuits
+    // have the same number of constraints as their representatives
+    fn check_sql(circ: &SqlCircuit<Fr>) {
+        let expected_subtraces = circ.get_portal_subtraces();
+
+        let mut pm: SetupRomPortalManager<Fr> =
+            SetupRomPortalManager::new(ConstraintSystem::new_ref());
+        let cs = pm.cs.clone();
+        let mut num_constraints = Vec::new();
+        for subcircuit_idx in 0..circ.num_subcircuits() {
+            pm.start_subtrace(cs.clone());
+
+            let mut circ_copy = SqlCircuit::<Fr>::new(&circ.params);
+            let wits = circ.get_serialized_witnesses(subcircuit_idx);
+            circ_copy.set_serialized_witnesses(subcircuit_idx, &wits);
+
+            let before = cs.num_constraints();
+            circ_copy
+                .generate_constraints(cs.clone(), subcircuit_idx, &mut pm)
+                .unwrap();
+            num_constraints.push(cs.num_constraints() - before);
+        }
+        assert!(cs.is_satisfied().unwrap());
+
+        for subcircuit_idx in 0..circ.num_subcircuits() {
+            let rep = circ.representative_subcircuit(subcircuit_idx);
+            assert_eq!(num_constraints[subcircuit_idx], num_constraints[rep]);
+        }
+
+        // Compare the subtraces
+        let wrapped_subtraces = pm
+            .subtraces
+            .into_iter()
+            .map(|st| st.into_iter().map(TranscriptEntry::Rom).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(wrapped_subtraces, expected_subtraces);
+    }
+
+    fn to_u64(row: &[Fr]) -> Vec<u64> {
+        row.iter().map(|x| x.into_bigint().0[0]).collect::<Vec<_>>()
+    }
+
+    // Filters and projects a table, and compares with the straightforward computation
+    #[test]
+    fn test_filter_project() {
+        let mut rng = test_rng();
+        let params = SqlCircuitParams {
+            num_rows: 8,
+            rows_per_subcircuit: 4,
+            plan: QueryPlan::scan(3)
+                .filter(vec![
+                    Comparison::new(0, CmpOp::Le, 4),
+                    Comparison::new(1, CmpOp::Ne, 2),
+                ])
+                .project(vec![
+                    Expr::col(0),
+                    Expr::col(1) * (Expr::constant(10) - Expr::col(2)) + Expr::col(0),
+                ])
+                .limit(8),
+        };
+        let circ = SqlCircuit::<Fr>::rand(&mut rng, &params);
+
+        let mut expected = circ
+            .table
+            .valid_rows()
+            .iter()
+            .map(|row| to_u64(row))
+            .filter(|row| row[0] <= 4 && row[1] != 2)
+            .map(|row| vec![row[0], row[1] * (10 - row[2]) + row[0]])
+            .collect::<Vec<_>>();
+        let mut output = circ
+            .output_rows()
+            .iter()
+            .map(|row| to_u64(row))
+            .collect::<Vec<_>>();
+        // The final sort is not stable, so compare as multisets
+        expected.sort();
+        output.sort();
+        assert_eq!(output, expected);
+
+        check_sql(&circ);
+    }
+
+    // Groups a partly filled table, and compares with the straightforward computation
+    #[test]
+    fn test_group_by() {
+        let mut rng = test_rng();
+        let params = SqlCircuitParams {
+            num_rows: 16,
+            rows_per_subcircuit: 4,
+            plan: QueryPlan::scan(3)
+                .group_by(
+                    vec![0, 1],
+                    vec![Aggregate::Sum(2), Aggregate::Count, Aggregate::Avg(2)],
+                )
+                .sort(vec![SortKey::asc(0), SortKey::desc(1)])
+                .limit(16),
+        };
+        let full = SqlCircuit::<Fr>::rand(&mut rng, &params);
+        let circ = SqlCircuit::with_table(&params, full.table.rows[..13].to_vec());
+
+        let mut groups = BTreeMap::new();
+        for row in circ.table.valid_rows().iter().map(|row| to_u64(row)) {
+            let (sum, count) = groups.entry((row[0], row[1])).or_insert((0, 0));
+            *sum += row[2];
+            *count += 1;
+        }
+        let mut expected = groups
+            .into_iter()
+            .map(|((a, b), (sum, count))| vec![a, b, sum, count, sum / count])
+            .collect::<Vec<_>>();
+        expected.sort_by_key(|row| (row[0], u64::MAX - row[1]));
+
+        let output = circ
+            .output_rows()
+            .iter()
+            .map(|row| to_u64(row))
+            .collect::<Vec<_>>();
+        assert_eq!(output, expected);
+
+        check_sql(&circ);
+    }
+
+    // Groups tables whose number of rows, and rows per subcircuit, aren't powers of two
+    #[test]
+    fn test_non_power_of_two_rows() {
+        let mut rng = test_rng();
+        for (num_rows, rows_per_subcircuit) in [(1, 1), (3, 2), (5, 3), (6, 8)] {
+            let params = SqlCircuitParams {
+                num_rows,
+                rows_per_subcircuit,
+                plan: QueryPlan::scan(2)
+                    .group_by(vec![0], vec![Aggregate::Sum(1)])
+                    .limit(num_rows),
+            };
+            let circ = SqlCircuit::<Fr>::rand(&mut rng, &params);
+            assert_eq!(circ.table.valid_rows().len(), num_rows);
+
+            let mut groups = BTreeMap::new();
+            for row in circ.table.valid_rows().iter().map(|row| to_u64(row)) {
+                *groups.entry(row[0]).or_insert(0) += row[1];
+            }
+            let expected = groups
+                .into_iter()
+                .map(|(a, sum)| vec![a, sum])
+                .collect::<Vec<_>>();
+
+            let mut output = circ
+                .output_rows()
+                .iter()
+                .map(|row| to_u64(row))
+                .collect::<Vec<_>>();
+            output.sort();
+            assert_eq!(output, expected);
+
+            check_sql(&circ);
+        }
+    }
+
+    // Checks that the output subcircuit rejects a wrong query result
+    #[test]
+    fn test_wrong_output() {
+        let mut rng = test_rng();
+        let params = SqlCircuitParams {
+            num_rows: 4,
+            rows_per_subcircuit: 2,
+            plan: QueryPlan::scan(2)
+                .group_by(vec![0], vec![Aggregate::Sum(1)])
+                .limit(2),
+        };
+        let circ = SqlCircuit::<Fr>::rand(&mut rng, &params);
+        let mut bad_output = circ.public_inputs();
+        bad_output[2] += Fr::ONE;
+
+        let mut pm = SetupRomPortalManager::new(ConstraintSystem::new_ref());
+        let cs = pm.cs.clone();
+        let mut circ_copy = circ.clone();
+        for subcircuit_idx in 0..circ.public_input_subcircuit() {
+            pm.start_subtrace(cs.clone());
+            circ_copy
+                .generate_constraints(cs.clone(), subcircuit_idx, &mut pm)
+                .unwrap();
+        }
+        assert!(cs.is_satisfied().unwrap());
+
+        pm.start_subtrace(cs.clone());
+        let bad_output_vars = bad_output
+            .iter()
+            .map(|x| FpVar::new_input(cs.clone(), || Ok(x)))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        circ_copy
+            .generate_constraints_with_public_inputs(
+                cs.clone(),
+                circ.public_input_subcircuit(),
+                &mut pm,
+                &bad_output_vars,
+            )
+            .unwrap();
+        assert!(!cs.is_satisfied().unwrap());
+    }
+}
@@ -0,0 +1,176 @@
+use crate::sql::plan::{sort_pairs, Aggregate, CompiledStage, QueryPlan, SortKey, Stage};
+
+use ark_ff::PrimeField;
+use num_bigint::BigUint;
+
+/// A table of field elements. All the tables in a query have the same number of rows. Rows that
+/// are not part of the table are marked invalid.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Table<F: PrimeField> {
+    pub rows: Vec<Vec<F>>,
+    pub valid: Vec<bool>,
+}
+
+impl<F: PrimeField> Table<F> {
+    /// Makes a table with `num_rows` rows, where the first rows are `rows` and the rest are
+    /// invalid rows of 0s
+    pub fn padded(mut rows: Vec<Vec<F>>, width: usize, num_rows: usize) -> Self {
+        assert!(rows.len() <= num_rows, "table has too many rows");
+        assert!(
+            rows.iter().all(|row| row.len() == width),
+            "every row must have {width} columns"
+        );
+
+        let mut valid = vec![true; rows.len()];
+        valid.resize(num_rows, false);
+        rows.resize(num_rows, vec![F::ZERO; width]);
+        Table { rows, valid }
+    }
+
+    pub fn num_rows(&self) -> usize {
+        self.rows.len()
+    }
+
+    /// The valid rows, in order
+    pub fn valid_rows(&self) -> Vec<Vec<F>> {
+        self.rows
+            .iter()
+            .zip(&self.valid)
+            .filter(|(_, &valid)| valid)
+            .map(|(row, _)| row.clone())
+            .collect()
+    }
+
+    /// Runs the query natively, up to but not including the output stage. This has exactly the
+    /// same result as the query circuit.
+    pub fn query(&self, plan: &QueryPlan) -> Table<F> {
+        plan.compile(self.num_rows())
+            .iter()
+            .fold(self.clone(), |table, stage| table.apply(stage))
+    }
+
+    /// Runs a single stage natively. The output stage does nothing.
+    pub(crate) fn apply(&self, stage: &CompiledStage) -> Table<F> {
+        let n = self.num_rows();
+        match &stage.stage {
+            Stage::Scan | Stage::Output => self.clone(),
+            Stage::Filter(preds) => {
+                let valid = (0..n)
+                    .map(|i| {
+                        self.valid[i]
+                            && preds
+                                .iter()
+                                .all(|p| p.op.eval(self.rows[i][p.col], F::from(p.value)))
+                    })
+                    .collect();
+                Table {
+                    rows: self.rows.clone(),
+                    valid,
+                }
+            },
+            Stage::Project(exprs) => {
+                // Invalid rows are zeroed out
+                let rows = (0..n)
+                    .map(|i| {
+                        exprs
+                            .iter()
+                            .map(|e| match self.valid[i] {
+                                true => e.eval(&self.rows[i]),
+                                false => F::ZERO,
+                            })
+                            .collect()
+                    })
+                    .collect();
+                Table {
+                    rows,
+                    valid: self.valid.clone(),
+                }
+            },
+            Stage::SortLayer { keys, mask } => {
+                let mut out = self.clone();
+                for (i, j) in sort_pairs(n, *mask) {
+                    if self.row_less(j, i, keys) {
+                        out.rows.swap(i, j);
+                        out.valid.swap(i, j);
+                    }
+                }
+                out
+            },
+            Stage::Aggregate { keys, aggregates } => self.aggregate(keys, aggregates),
+        }
+    }
+
+    /// Returns whether row `i` goes before row `j` when sorting by `keys`. Valid rows go before
+    /// invalid ones.
+    fn row_less(&self, i: usize, j: usize, keys: &[SortKey]) -> bool {
+        if self.valid[i] != self.valid[j] {
+            return self.valid[i];
+        }
+        for key in keys {
+            let (a, b) = (self.rows[i][key.col], self.rows[j][key.col]);
+            if a != b {
+                return if key.descending { a > b } else { a < b };
+            }
+        }
+        false
+    }
+
+    /// Returns whether rows `i` and `i - 1` are valid and in the same group
+    pub(crate) fn same_group(&self, i: usize, keys: &[usize]) -> bool {
+        i > 0
+            && self.valid[i]
+            && self.valid[i - 1]
+            && keys.iter().all(|&k| self.rows[i][k] == self.rows[i - 1][k])
+    }
+
+    /// Aggregates the groups of this table, which must be sorted by `keys`. Every row gets the
+    /// running aggregates of its group, and only the last row of each group is valid.
+    fn aggregate(&self, keys: &[usize], aggregates: &[Aggregate]) -> Table<F> {
+        let n = self.num_rows();
+        let mut rows = Vec::with_capacity(n);
+        let mut valid = Vec::with_capacity(n);
+
+        let mut count = F::ZERO;
+        let mut sums = vec![F::ZERO; aggregates.len()];
+        for i in 0..n {
+            let same = self.same_group(i, keys);
+            count = if same { count + F::ONE } else { F::ONE };
+            for (sum, agg) in sums.iter_mut().zip(aggregates) {
+                if let Some(col) = agg.col() {
+                    let val = self.rows[i][col];
+                    *sum = if same { *sum + val } else { val };
+                }
+            }
+
+            let mut row = keys.iter().map(|&k| self.rows[i][k]).collect::<Vec<_>>();
+            row.extend(aggregates.iter().zip(&sums).map(|(agg, sum)| match agg {
+                Aggregate::Sum(_) => *sum,
+                Aggregate::Count => count,
+                Aggregate::Avg(_) => floor_div(*sum, count),
+            }));
+            rows.push(row);
+            valid.push(self.valid[i] && !(i + 1 < n && self.same_group(i + 1, keys)));
+        }
+
+        Table { rows, valid }
+    }
+
+    /// The public output of a query whose final table is this one. This is the first
+    /// `num_output_rows` rows, each written as the valid bit followed by the row. Invalid rows are
+    /// all 0s.
+    pub(crate) fn output_values(&self, num_output_rows: usize) -> Vec<F> {
+        (0..num_output_rows)
+            .flat_map(|i| {
+                let valid = F::from(self.valid[i]);
+                core::iter::once(valid).chain(self.rows[i].iter().map(move |x| valid * x))
+            })
+            .collect()
+    }
+}
+
+/// Divides two field elements as integers, rounding down
+pub(crate) fn floor_div<F: PrimeField>(num: F, den: F) -> F {
+    let num: BigUint = num.into();
+    let den: BigUint = den.into();
+    F::from(num / den)
+}
@@ -49,8 +49,13 @@ where
     // Stage 1 witnesses
     pub(crate) cur_leaf: ExecTreeLeaf<F>,
     pub next_leaf_membership: MerklePath<C>,
+    // Only used by the subcircuit at `P::public_input_subcircuit()`, and only if the circuit has
+    // public inputs
+    pub(crate) public_inputs: Vec<F>,
+    pub(crate) public_input_membership: MerklePath<C>,
 
-    // Stage 1 public inputs
+    // Stage 1 public inputs. The challenges are the memory-checking ones followed by the ones of
+    // the extra rounds. In an extra round, only the challenges of the earlier rounds are set.
     pub challenges: Vec<F>,
     pub root: MerkleRoot<C>,
 
@@ -75,6 +80,8 @@ where
             addr_ordered_subtrace_var: self.addr_ordered_subtrace_var.clone(),
             cur_leaf: self.cur_leaf.clone(),
             next_leaf_membership: self.next_leaf_membership.clone(),
+            public_inputs: self.public_inputs.clone(),
+            public_input_membership: self.public_input_membership.clone(),
             challenges: self.challenges.clone(),
             root: self.root.clone(),
             _marker: self._marker.clone(),
@@ -89,17 +96,16 @@ where
     C: TreeConfig,
     CG: TreeConfigGadget<C, F>,
 {
-    // Makes a new struct with subcircuit idx 0, no subtraces, and an empty Merkle auth path
-    pub fn new(tree_params: ExecTreeParams<C>, num_subcircuits: usize) -> Self {
+    // Makes a new struct with subcircuit idx 0, no subtraces, no public inputs, and empty Merkle
+    // auth paths. `num_leaves` is the number of leaves in the execution tree
+    pub fn new(tree_params: ExecTreeParams<C>, num_leaves: usize) -> Self {
         // Create an auth path of the correct length
-        let auth_path_len = log2(num_subcircuits) - 1;
+        let auth_path_len = log2(num_leaves) - 1;
         let mut auth_path = MerklePath::default();
         auth_path.auth_path = vec![C::InnerDigest::default(); auth_path_len];
 
-        let challenges_len = match P::MEM_TYPE {
-            MemType::Rom => 2,
-            MemType::Ram => 4,
-        };
+        let challenges_len =
+            P::MEM_TYPE.num_challenges() + P::NUM_EXTRA_ROUNDS * P::NUM_ROUND_CHALLENGES;
 
         SubcircuitWithPortalsProver {
             subcircuit_idx: 0,
@@ -110,7 +116,9 @@ where
             time_ordered_subtrace_var: Vec::new(),
             addr_ordered_subtrace_var: Vec::new(),
             cur_leaf: ExecTreeLeaf::padding(P::MEM_TYPE),
-            next_leaf_membership: auth_path,
+            next_leaf_membership: auth_path.clone(),
+            public_inputs: Vec::new(),
+            public_input_membership: auth_path,
             challenges: vec![F::zero(); challenges_len],
             root: MerkleRoot::<C>::default(),
             _marker: PhantomData,
@@ -125,9 +133,9 @@ where
     C: TreeConfig,
     CG: TreeConfigGadget<C, F, Leaf = SerializedLeafVar<F>>,
 {
-    /// Two stages: Subtrace commit, and the rest
+    /// Subtrace commit, one stage per extra round of the circuit, and the rest
     fn total_num_stages(&self) -> usize {
-        2
+        P::NUM_EXTRA_ROUNDS + 2
     }
 
     /// Generates constraints for the i-th stage.
@@ -158,11 +166,29 @@ where
                 Ok(())
             });
         }
-        if stage > 1 {
-            panic!("there are only two stages in the subcircuit prover");
+        // At the stages of the extra rounds, let the circuit witness whatever it needs
+        if stage <= P::NUM_EXTRA_ROUNDS {
+            let subcircuit_idx = self.subcircuit_idx;
+            // Only the challenges of the earlier rounds are known by now
+            let num_known_challenges =
+                P::MEM_TYPE.num_challenges() + (stage - 1) * P::NUM_ROUND_CHALLENGES;
+            let challenges = &self.challenges[..num_known_challenges];
+            let circ = self
+                .circ
+                .as_mut()
+                .expect("must provide circuit for extra round computation");
+            return cs.synthesize_with(|c| {
+                circ.generate_round_witnesses(c, subcircuit_idx, stage - 1, challenges)
+            });
+        }
+        if stage > self.last_stage() {
+            panic!(
+                "there are only {} stages in the subcircuit prover",
+                self.total_num_stages()
+            );
         }
 
-        // Everything below is stage 1
+        // Everything below is the last stage
         cs.synthesize_with(|c| {
             // Witness all the necessary variables
             // This does NOT witness the RunningEvals challenges. That must be done separately
@@ -208,9 +234,12 @@ where
                     .enforce_equal(&Boolean::TRUE)?;
             }
 
-            // Set the challenge values so the running evals knows how to update itself
+            // Set the challenge values so the running evals knows how to update itself. The rest
+            // of the challenges are the circuit's
+            let (mem_challenge_vars, round_challenge_vars) =
+                challenge_vars.split_at(P::MEM_TYPE.num_challenges());
             let mut running_evals_var = cur_leaf_var.evals.clone();
-            running_evals_var.set_challenges(&challenge_vars);
+            running_evals_var.set_challenges(mem_challenge_vars);
 
             // Prepend the last subtrace entry to the addr-ordered subtrace. This necessary for the
             // consistency check.
@@ -228,11 +257,53 @@ where
                 running_evals_var,
             );
 
-            // Run the specific subcircuit and give it the prepared portal manager
-            self.circ
+            let circ = self
+                .circ
                 .as_mut()
-                .expect("must provide circuit for stage 1 computation")
-                .generate_constraints(c.clone(), self.subcircuit_idx, &mut pm)?;
+                .expect("must provide circuit for stage 1 computation");
+            circ.set_round_challenges(round_challenge_vars);
+
+            // Run the specific subcircuit and give it the prepared portal manager. If this is the
+            // subcircuit that uses the public inputs, then also witness them and prove that they
+            // are in the leaf right after the last subcircuit's leaf
+            if !self.public_inputs.is_empty()
+                && self.subcircuit_idx == circ.public_input_subcircuit()
+            {
+                let public_input_vars = self
+                    .public_inputs
+                    .iter()
+                    .map(|x| FpVar::new_witness(ns!(c, "pub input"), || Ok(x)))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let mut public_input_membership_var =
+                    MerklePathVar::<_, _, CG>::new_witness(ns!(c, "pub input path"), || {
+                        Ok(&self.public_input_membership)
+                    })?;
+
+                // Fix the position of the leaf. Positions are little-endian
+                let pos = circ.num_subcircuits();
+                let pos_bits = (0..=self.public_input_membership.auth_path.len())
+                    .map(|i| Boolean::constant((pos >> i) & 1 == 1))
+                    .collect();
+                public_input_membership_var.set_leaf_position(pos_bits);
+
+                public_input_membership_var
+                    .verify_membership(
+                        &leaf_params_var,
+                        &two_to_one_params_var,
+                        &root_var,
+                        &public_input_vars,
+                    )?
+                    .enforce_equal(&Boolean::TRUE)?;
+
+                circ.generate_constraints_with_public_inputs(
+                    c.clone(),
+                    self.subcircuit_idx,
+                    &mut pm,
+                    &public_input_vars,
+                )?;
+            } else {
+                circ.generate_constraints(c.clone(), self.subcircuit_idx, &mut pm)?;
+            }
 
             // Sanity checks: make sure all the subtraces were used. The addr-ordered one has 1
             // remaining because it starts with 1 extra. The last one is used, but it's not popped.
@@ -284,33 +355,257 @@ mod test {
     use super::*;
 
     use crate::{
-        aggregation::AggProvingKey,
-        coordinator::{CoordinatorStage0State, G16ProvingKeyGenerator, Stage1Request},
-        poseidon_util::{
-            gen_merkle_params, PoseidonTreeConfig as TestParams,
-            PoseidonTreeConfigVar as TestParamsVar,
+        aggregation::{
+            tipp_size, AggProvingKey, GroupedAggProvingKey, HekatonBatchProof, HekatonProof,
+            SuperComKey,
         },
+        coordinator::{
+            CoordinatorStage0State, CoordinatorStage1State, FinalAggState, G16ProvingKeyGenerator,
+            Stage1Request,
+        },
+        portal_manager::{RomProverPortalManager, SetupRomPortalManager},
+        poseidon_util::{gen_merkle_params, PoseidonTreeConfig, PoseidonTreeConfigVar},
+        sql::{Aggregate, QueryPlan, SqlCircuit, SqlCircuitParams},
+        tpch::{TpchQ1Circuit, TpchQ1Params},
         tree_hash_circuit::*,
         util::{G16Com, G16ComSeed, G16ProvingKey},
         vkd::{VerifiableKeyDirectoryCircuit, VerifiableKeyDirectoryCircuitParams},
         vm::VirtualMachine,
-        worker::{process_stage0_request, process_stage1_request, Stage0Response},
+        worker::{
+            process_round_request, process_stage0_request, process_stage1_request_with_rounds,
+            RoundResponse, Stage0Response,
+        },
     };
     use sha2::Sha256;
 
     use crate::vkd::{MerkleTreeConcreteParameters, SparseMerkleTree, DEPTH};
     use crate::vm::VirtualMachineParameters;
     use ark_bls12_381::{Bls12_381 as E, Fr};
+    use ark_bn254::Bn254;
     use ark_cp_groth16::verifier::prepare_verifying_key;
+    use ark_crypto_primitives::sponge::Absorb;
+    use ark_ec::pairing::Pairing;
     use ark_ff::UniformRand;
     use ark_ip_proofs::tipa::TIPA;
+    use ark_r1cs_std::R1CSVar;
+    use ark_relations::r1cs::{ConstraintSystem, ConstraintSystemRef};
+    use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
     use ark_std::test_rng;
 
+    type TestParams<F> = PoseidonTreeConfig<F>;
+    type TestParamsVar<F> = PoseidonTreeConfigVar<F>;
+    type TestProof<E> = HekatonProof<TestParams<<E as Pairing>::ScalarField>, E>;
+
+    /// A MerkleTreeCircuit with two extra rounds. In round 0, a subcircuit witnesses w₀ = idx + 1.
+    /// In round 1, it witnesses w₁ = w₀ · r₀, where r₀ is the challenge of round 0. The last stage
+    /// checks w₁ against the r₀ it's given as a public input.
+    #[derive(Clone)]
+    struct TwoRoundCircuit<F: PrimeField> {
+        inner: MerkleTreeCircuit,
+        round_vars: Vec<FpVar<F>>,
+        round_challenge_vars: Vec<FpVar<F>>,
+    }
+
+    impl<F: PrimeField> CircuitWithPortals<F> for TwoRoundCircuit<F> {
+        type Parameters = <MerkleTreeCircuit as CircuitWithPortals<F>>::Parameters;
+        type ProverPortalManager =
+            <MerkleTreeCircuit as CircuitWithPortals<F>>::ProverPortalManager;
+        const MEM_TYPE: MemType = <MerkleTreeCircuit as CircuitWithPortals<F>>::MEM_TYPE;
+        const NUM_EXTRA_ROUNDS: usize = 2;
+
+        fn rand(rng: &mut impl rand::Rng, params: &Self::Parameters) -> Self {
+            TwoRoundCircuit {
+                inner: <MerkleTreeCircuit as CircuitWithPortals<F>>::rand(rng, params),
+                round_vars: Vec::new(),
+                round_challenge_vars: Vec::new(),
+            }
+        }
+
+        fn get_params(&self) -> Self::Parameters {
+            <MerkleTreeCircuit as CircuitWithPortals<F>>::get_params(&self.inner)
+        }
+
+        fn get_portal_subtraces(&self) -> Vec<Vec<TranscriptEntry<F>>> {
+            self.inner.get_portal_subtraces()
+        }
+
+        fn num_subcircuits(&self) -> usize {
+            <MerkleTreeCircuit as CircuitWithPortals<F>>::num_subcircuits(&self.inner)
+        }
+
+        fn get_unique_subcircuits(&self) -> Vec<usize> {
+            <MerkleTreeCircuit as CircuitWithPortals<F>>::get_unique_subcircuits(&self.inner)
+        }
+
+        fn representative_subcircuit(&self, subcircuit_idx: usize) -> usize {
+            <MerkleTreeCircuit as CircuitWithPortals<F>>::representative_subcircuit(
+                &self.inner,
+                subcircuit_idx,
+            )
+        }
+
+        fn new(params: &Self::Parameters) -> Self {
+            TwoRoundCircuit {
+                inner: <MerkleTreeCircuit as CircuitWithPortals<F>>::new(params),
+                round_vars: Vec::new(),
+                round_challenge_vars: Vec::new(),
+            }
+        }
+
+        fn get_serialized_witnesses(&self, subcircuit_idx: usize) -> Vec<u8> {
+            <MerkleTreeCircuit as CircuitWithPortals<F>>::get_serialized_witnesses(
+                &self.inner,
+                subcircuit_idx,
+            )
+        }
+
+        fn set_serialized_witnesses(&mut self, subcircuit_idx: usize, bytes: &[u8]) {
+            <MerkleTreeCircuit as CircuitWithPortals<F>>::set_serialized_witnesses(
+                &mut self.inner,
+                subcircuit_idx,
+                bytes,
+            )
+        }
+
+        fn generate_round_witnesses(
+            &mut self,
+            cs: ConstraintSystemRef<F>,
+            subcircuit_idx: usize,
+            round: usize,
+            challenges: &[F],
+        ) -> Result<(), SynthesisError> {
+            let var = if round == 0 {
+                FpVar::new_witness(ns!(cs, "w0"), || Ok(F::from(subcircuit_idx as u64 + 1)))?
+            } else {
+                let w0 = &self.round_vars[0];
+                let r0 = *challenges.last().unwrap();
+                FpVar::new_witness(ns!(cs, "w1"), || Ok(w0.value()? * r0))?
+            };
+            self.round_vars.push(var);
+            Ok(())
+        }
+
+        fn set_round_challenges(&mut self, challenges: &[FpVar<F>]) {
+            self.round_challenge_vars = challenges.to_vec();
+        }
+
+        fn generate_constraints<PM: PortalManager<F>>(
+            &mut self,
+            cs: ConstraintSystemRef<F>,
+            subcircuit_idx: usize,
+            pm: &mut PM,
+        ) -> Result<(), SynthesisError> {
+            let w1 = &self.round_vars[0] * &self.round_challenge_vars[0];
+            w1.enforce_equal(&self.round_vars[1])?;
+            self.inner.generate_constraints(cs, subcircuit_idx, pm)
+        }
+    }
+
+    /// A chain of any number of subcircuits. Subcircuit 0 witnesses x₀ and reads it back.
+    /// Subcircuit i > 0 reads xᵢ₋₁ and sets xᵢ = xᵢ₋₁ + 1. The parameter is the number of
+    /// subcircuits.
+    #[derive(Clone)]
+    struct ChainCircuit<F: PrimeField> {
+        num_subcircuits: usize,
+        start: F,
+    }
+
+    impl<F: PrimeField> CircuitWithPortals<F> for ChainCircuit<F> {
+        type Parameters = usize;
+        type ProverPortalManager = RomProverPortalManager<F>;
+        const MEM_TYPE: MemType = MemType::Rom;
+
+        fn rand(rng: &mut impl rand::Rng, &num_subcircuits: &usize) -> Self {
+            ChainCircuit {
+                num_subcircuits,
+                start: F::rand(rng),
+            }
+        }
+
+        fn get_params(&self) -> usize {
+            self.num_subcircuits
+        }
+
+        fn get_portal_subtraces(&self) -> Vec<Vec<TranscriptEntry<F>>> {
+            let mut pm = SetupRomPortalManager::new(ConstraintSystem::new_ref());
+            let mut circ = self.clone();
+            for subcircuit_idx in 0..self.num_subcircuits {
+                let cs = ConstraintSystem::new_ref();
+                pm.start_subtrace(cs.clone());
+                circ.generate_constraints(cs, subcircuit_idx, &mut pm)
+                    .unwrap();
+            }
+
+            pm.subtraces
+                .into_iter()
+                .map(|subtrace| subtrace.into_iter().map(TranscriptEntry::Rom).collect())
+                .collect()
+        }
+
+        fn num_subcircuits(&self) -> usize {
+            self.num_subcircuits
+        }
+
+        // The first subcircuit, a middle one, and the last one, which also does the final check
+        fn get_unique_subcircuits(&self) -> Vec<usize> {
+            let mut idxs = vec![0, 1, self.num_subcircuits - 1];
+            idxs.retain(|&i| i < self.num_subcircuits);
+            idxs.dedup();
+            idxs
+        }
+
+        fn representative_subcircuit(&self, subcircuit_idx: usize) -> usize {
+            if subcircuit_idx == 0 || subcircuit_idx == self.num_subcircuits - 1 {
+                subcircuit_idx
+            } else {
+                1
+            }
+        }
+
+        fn new(&num_subcircuits: &usize) -> Self {
+            ChainCircuit {
+                num_subcircuits,
+                start: F::ZERO,
+            }
+        }
+
+        fn get_serialized_witnesses(&self, subcircuit_idx: usize) -> Vec<u8> {
+            let mut out_buf = Vec::new();
+            if subcircuit_idx == 0 {
+                self.start.serialize_uncompressed(&mut out_buf).unwrap();
+            }
+            out_buf
+        }
+
+        fn set_serialized_witnesses(&mut self, subcircuit_idx: usize, bytes: &[u8]) {
+            if subcircuit_idx == 0 {
+                self.start = F::deserialize_uncompressed_unchecked(bytes).unwrap();
+            }
+        }
+
+        fn generate_constraints<PM: PortalManager<F>>(
+            &mut self,
+            cs: ConstraintSystemRef<F>,
+            subcircuit_idx: usize,
+            pm: &mut PM,
+        ) -> Result<(), SynthesisError> {
+            if subcircuit_idx == 0 {
+                let x = FpVar::new_witness(ns!(cs, "x0"), || Ok(self.start))?;
+                pm.set("x0".to_string(), &x)?;
+                pm.get("x0")?.enforce_equal(&x)?;
+            } else {
+                let prev = pm.get(&format!("x{}", subcircuit_idx - 1))?;
+                pm.set(format!("x{subcircuit_idx}"), &(prev + FpVar::one()))?;
+            }
+            Ok(())
+        }
+    }
+
     // Checks that the SubcircuitWithPortalsProver is satisfied when the correct inputs are given
     #[test]
     fn test_subcircuit_portal_prover_satisfied() {
         let mut rng = test_rng();
-        let tree_params = gen_merkle_params();
+        let tree_params = gen_merkle_params::<Fr>();
 
         // Make a random Merkle tree
         let circ_params = MerkleTreeCircuitParams {
@@ -323,8 +618,9 @@ mod test {
 
         // Make the stage0 coordinator state. The value of the commitment key doesn't really matter
         // since we don't test aggregation here.
-        let (tipp_pk, _tipp_vk) = TIPA::<_, Sha256>::setup(num_subcircuits, &mut rng).unwrap();
-        let stage0_state = CoordinatorStage0State::new::<TestParams>(circ);
+        let (tipp_pk, _tipp_vk) =
+            TIPA::<_, Sha256>::setup(tipp_size(num_subcircuits), &mut rng).unwrap();
+        let stage0_state = CoordinatorStage0State::new::<TestParams<Fr>>(circ);
         let all_subcircuit_indices = (0..num_subcircuits).collect::<Vec<_>>();
 
         // Worker receives a stage0 package containing all the subtraces it will need for this run.
@@ -379,15 +675,17 @@ mod test {
                 subcircuit_idx,
                 circ: Some(partial_circ),
                 tree_params: tree_params.clone(),
-                time_ordered_subtrace: stage0_req.time_ordered_subtrace.clone(),
-                addr_ordered_subtrace: stage0_req.addr_ordered_subtrace.clone(),
+                time_ordered_subtrace: stage0_req.time_ordered_subtrace.to_vec(),
+                addr_ordered_subtrace: stage0_req.addr_ordered_subtrace.to_vec(),
                 time_ordered_subtrace_var: Vec::new(),
                 addr_ordered_subtrace_var: Vec::new(),
                 cur_leaf: stage1_req.cur_leaf,
                 next_leaf_membership: stage1_req.next_leaf_membership,
+                public_inputs: stage1_req.public_inputs.to_vec(),
+                public_input_membership: stage1_req.public_input_membership.unwrap_or_default(),
                 challenges,
                 root: stage1_req.root,
-                _marker: PhantomData::<TestParamsVar>,
+                _marker: PhantomData::<TestParamsVar<Fr>>,
             };
 
             // Run both stages
@@ -408,13 +706,40 @@ mod test {
             num_sha_iters_per_subcircuit: 1,
             num_portals_per_subcircuit: 1,
         };
-        run_e2e_prover::<MerkleTreeCircuit>(circ_params);
+
+        // The root hash is public, so this also checks that tampered public inputs are rejected
+        let circ = <MerkleTreeCircuit as CircuitWithPortals<Fr>>::new(&circ_params);
+        assert!(!circ.public_inputs().is_empty());
+
+        run_e2e_prover::<E, MerkleTreeCircuit>(circ_params);
+    }
+
+    // Runs a full proof of the MerkleTreeCircuit over BN254
+    #[test]
+    fn test_merkle_e2e_prover_bn254() {
+        let circ_params = MerkleTreeCircuitParams {
+            num_leaves: 2,
+            num_sha_iters_per_subcircuit: 1,
+            num_portals_per_subcircuit: 1,
+        };
+        run_e2e_prover::<Bn254, MerkleTreeCircuit>(circ_params);
+    }
+
+    // Runs a full proof of a circuit with extra commit-then-challenge rounds
+    #[test]
+    fn test_extra_rounds_e2e_prover() {
+        let circ_params = MerkleTreeCircuitParams {
+            num_leaves: 2,
+            num_sha_iters_per_subcircuit: 1,
+            num_portals_per_subcircuit: 1,
+        };
+        run_e2e_prover::<E, TwoRoundCircuit<Fr>>(circ_params);
     }
 
     // Runs a full proof of the VKD circuit
     #[test]
     fn test_vkd_e2e_prover() {
-        type TestMerkleTree = SparseMerkleTree<MerkleTreeConcreteParameters>;
+        type TestMerkleTree = SparseMerkleTree<MerkleTreeConcreteParameters, Fr>;
         let tree = TestMerkleTree::new().unwrap();
 
         let circ_params = VerifiableKeyDirectoryCircuitParams {
@@ -422,7 +747,7 @@ mod test {
             log_num_subcircuits: 10,
             null_leaf: tree.sparse_initial_hashes[DEPTH],
         };
-        run_e2e_prover::<VerifiableKeyDirectoryCircuit>(circ_params);
+        run_e2e_prover::<E, VerifiableKeyDirectoryCircuit<Fr>>(circ_params);
     }
 
     // Runs a full proof of the VM circuit
@@ -430,21 +755,283 @@ mod test {
     fn test_vm_e2e_prover() {
         let virtual_machine_parameter = VirtualMachineParameters {
             use_merkle_memory: false,
-            log_num_subcircuit: 3,
-            dummy_constraint_num: 30,
+            num_subcircuits: 8,
             operations_per_chunk: 2,
+            program_len: 8,
+            memory_size: 4,
         };
-        run_e2e_prover::<VirtualMachine<Fr>>(virtual_machine_parameter);
+        run_e2e_prover::<E, VirtualMachine<Fr>>(virtual_machine_parameter);
     }
 
-    // Runs a full prover for the given CircuitWithPortals type and parameters
-    fn run_e2e_prover<P>(circ_params: P::Parameters)
+    // Runs a full proof of TPC-H Q1
+    #[test]
+    fn test_tpch_q1_e2e_prover() {
+        let circ_params = TpchQ1Params {
+            num_rows: 4,
+            rows_per_subcircuit: 2,
+            delta: 90,
+        };
+        run_e2e_prover::<E, TpchQ1Circuit<Fr>>(circ_params);
+    }
+
+    // Runs a full proof of a query over a table whose number of rows is not a power of two
+    #[test]
+    fn test_sql_non_power_of_two_e2e_prover() {
+        let circ_params = SqlCircuitParams {
+            num_rows: 5,
+            rows_per_subcircuit: 3,
+            plan: QueryPlan::scan(2)
+                .group_by(vec![0], vec![Aggregate::Sum(1)])
+                .limit(5),
+        };
+        run_e2e_prover::<E, SqlCircuit<Fr>>(circ_params);
+    }
+
+    // Runs full proofs of circuits whose number of subcircuits is not a power of two, including a
+    // circuit with just 1 subcircuit
+    #[test]
+    fn test_non_power_of_two_e2e_prover() {
+        for num_subcircuits in [1, 3, 5] {
+            run_e2e_prover::<E, ChainCircuit<Fr>>(num_subcircuits);
+        }
+    }
+
+    // Runs full proofs whose subcircuits are aggregated in groups, including groups of different
+    // sizes and a circuit with extra rounds
+    #[test]
+    fn test_grouped_agg_e2e_prover() {
+        run_e2e_prover_with_groups::<E, ChainCircuit<Fr>>(5, Some(2));
+        let circ_params = MerkleTreeCircuitParams {
+            num_leaves: 2,
+            num_sha_iters_per_subcircuit: 1,
+            num_portals_per_subcircuit: 1,
+        };
+        run_e2e_prover_with_groups::<E, TwoRoundCircuit<Fr>>(circ_params, Some(3));
+    }
+
+    // Runs full proofs of several instances of the same circuit, both independently and as a single
+    // batch, including a circuit with extra rounds
+    #[test]
+    fn test_batch_e2e_prover() {
+        run_batch_e2e_prover::<E, ChainCircuit<Fr>>(3, 3);
+        let circ_params = MerkleTreeCircuitParams {
+            num_leaves: 2,
+            num_sha_iters_per_subcircuit: 1,
+            num_portals_per_subcircuit: 1,
+        };
+        run_batch_e2e_prover::<E, TwoRoundCircuit<Fr>>(circ_params, 2);
+    }
+
+    // Proves num_instances random instances of the given circuit, reusing the same proving keys and
+    // aggregation keys for all of them. First every instance gets its own aggregate proof, then all
+    // the instances get a single aggregate proof
+    fn run_batch_e2e_prover<E, P>(circ_params: P::Parameters, num_instances: usize)
+    where
+        E: Pairing,
+        E::ScalarField: Absorb,
+        P: CircuitWithPortals<E::ScalarField> + Clone,
+    {
+        let mut rng = test_rng();
+        let tree_params = gen_merkle_params::<E::ScalarField>();
+
+        let circs = (0..num_instances)
+            .map(|_| P::rand(&mut rng, &circ_params))
+            .collect::<Vec<_>>();
+        let num_subcircuits = P::num_subcircuits(&circs[0]);
+
+        // The instances all have the same shape, so the proving keys are shared
+        let minimal_proving_keys: HashMap<usize, Rc<G16ProvingKey<E>>> = {
+            let generator = G16ProvingKeyGenerator::<_, TestParamsVar<E::ScalarField>, E, _>::new(
+                &mut rng,
+                circs[0].clone(),
+                tree_params.clone(),
+            );
+            P::get_unique_subcircuits(&circs[0])
+                .into_iter()
+                .map(|i| (i, Rc::new(generator.gen_pk(&mut rng, i))))
+                .collect()
+        };
+        let proving_keys: Vec<Rc<G16ProvingKey<E>>> = (0..num_subcircuits)
+            .map(|i| minimal_proving_keys[&P::representative_subcircuit(&circs[0], i)].clone())
+            .collect();
+
+        // Make the aggregation keys. One aggregates a single instance, and the other aggregates
+        // all of them
+        let (tipp_pk, _) = TIPA::<E, Sha256>::setup(tipp_size(num_subcircuits), &mut rng).unwrap();
+        let agg_ck = AggProvingKey::new(tipp_pk, num_subcircuits, |i| &proving_keys[i]);
+        let agg_vk = agg_ck.vk();
+        let (batch_tipp_pk, _) =
+            TIPA::<E, Sha256>::setup(tipp_size(num_instances * num_subcircuits), &mut rng).unwrap();
+        let batch_agg_ck =
+            AggProvingKey::new_batch(batch_tipp_pk, num_subcircuits, num_instances, |i| {
+                &proving_keys[i]
+            });
+        let batch_agg_vk = batch_agg_ck.vk();
+
+        for batch in [false, true] {
+            // Do stage 0 for every instance
+            let stage0_states = circs
+                .iter()
+                .map(|circ| {
+                    CoordinatorStage0State::<E, _>::new::<TestParams<E::ScalarField>>(circ.clone())
+                })
+                .collect::<Vec<_>>();
+            let stage0_reqs = stage0_states
+                .iter()
+                .map(|state| {
+                    (0..num_subcircuits)
+                        .map(|idx| state.gen_request(idx).to_owned())
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>();
+            let stage0_resps = stage0_reqs
+                .iter()
+                .map(|reqs| {
+                    reqs.iter()
+                        .zip(proving_keys.iter())
+                        .map(|(req, pk)| {
+                            process_stage0_request::<_, TestParamsVar<_>, _, P, _>(
+                                &mut rng,
+                                tree_params.clone(),
+                                pk,
+                                req.clone(),
+                            )
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>();
+            let mut stage1_states: Vec<CoordinatorStage1State<TestParams<_>, _, P>> = if batch {
+                CoordinatorStage0State::process_batch_stage0_responses(
+                    stage0_states,
+                    &batch_agg_ck.tipp_pk,
+                    tree_params.clone(),
+                    &stage0_resps,
+                )
+            } else {
+                stage0_states
+                    .into_iter()
+                    .zip(stage0_resps.iter())
+                    .map(|(state, resps)| {
+                        state.process_stage0_responses(&agg_ck.tipp_pk, tree_params.clone(), resps)
+                    })
+                    .collect()
+            };
+
+            // Do the extra rounds, if any. round_resps[k][i] holds subcircuit i's responses from
+            // every round in instance k
+            let mut round_resps: Vec<Vec<Vec<RoundResponse<E>>>> =
+                vec![vec![Vec::new(); num_subcircuits]; num_instances];
+            for _ in 0..P::NUM_EXTRA_ROUNDS {
+                let resps = (0..num_instances)
+                    .map(|k| {
+                        (0..num_subcircuits)
+                            .map(|idx| {
+                                process_round_request::<_, TestParamsVar<_>, _, P, _>(
+                                    &mut rng,
+                                    tree_params.clone(),
+                                    &proving_keys[idx],
+                                    stage0_reqs[k][idx].clone(),
+                                    &stage0_resps[k][idx],
+                                    &round_resps[k][idx],
+                                    stage1_states[k].gen_round_request(idx),
+                                )
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                    .collect::<Vec<_>>();
+                if batch {
+                    CoordinatorStage1State::process_batch_round_responses(
+                        &mut stage1_states,
+                        &batch_agg_ck.tipp_pk,
+                        &resps,
+                    );
+                } else {
+                    for (state, resps) in stage1_states.iter_mut().zip(resps.iter()) {
+                        state.process_round_responses(&agg_ck.tipp_pk, resps);
+                    }
+                }
+                for (k, resps) in resps.into_iter().enumerate() {
+                    for resp in resps {
+                        round_resps[k][resp.subcircuit_idx].push(resp);
+                    }
+                }
+            }
+
+            // Do stage 1 for every instance
+            let stage1_resps = (0..num_instances)
+                .map(|k| {
+                    (0..num_subcircuits)
+                        .map(|idx| {
+                            process_stage1_request_with_rounds::<_, TestParamsVar<_>, _, _, _>(
+                                &mut rng,
+                                tree_params.clone(),
+                                &proving_keys[idx],
+                                stage0_reqs[k][idx].clone(),
+                                &stage0_resps[k][idx],
+                                &round_resps[k][idx],
+                                stage1_states[k].gen_request(idx).to_owned(),
+                            )
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>();
+            let final_agg_states = stage1_states
+                .into_iter()
+                .map(|state| state.into_agg_state())
+                .collect::<Vec<_>>();
+
+            if !batch {
+                // Every instance gets its own aggregate proof, using the same key
+                for (state, resps) in final_agg_states.iter().zip(stage1_resps.iter()) {
+                    let agg_proof = state.gen_agg_proof(&agg_ck, resps);
+                    assert!(agg_proof.verify::<P>(&agg_vk, &tree_params, &circ_params));
+                }
+                continue;
+            }
+
+            // Aggregate all the instances at once. Verify before and after a serialization round
+            // trip
+            let agg_proof =
+                FinalAggState::gen_batch_agg_proof(&final_agg_states, &batch_agg_ck, &stage1_resps);
+            assert!(agg_proof.verify::<P>(&batch_agg_vk, &tree_params, &circ_params));
+            let mut buf = Vec::new();
+            agg_proof.serialize_compressed(&mut buf).unwrap();
+            let agg_proof =
+                HekatonBatchProof::<TestParams<E::ScalarField>, E>::deserialize_compressed(
+                    buf.as_slice(),
+                )
+                .unwrap();
+            assert!(agg_proof.verify::<P>(&batch_agg_vk, &tree_params, &circ_params));
+
+            // Mixing up the instances should make verification fail
+            if agg_proof.exec_tree_roots[0] != agg_proof.exec_tree_roots[1] {
+                let mut bad_proof = agg_proof.clone();
+                bad_proof.exec_tree_roots.swap(0, 1);
+                assert!(!bad_proof.verify::<P>(&batch_agg_vk, &tree_params, &circ_params));
+            }
+        }
+    }
+
+    // Runs a full prover over the given curve for the given CircuitWithPortals type and parameters
+    fn run_e2e_prover<E, P>(circ_params: P::Parameters)
     where
-        P: CircuitWithPortals<Fr> + Clone,
+        E: Pairing,
+        E::ScalarField: Absorb,
+        P: CircuitWithPortals<E::ScalarField> + Clone,
+    {
+        run_e2e_prover_with_groups::<E, P>(circ_params, None)
+    }
+
+    // Same as above, but if group_size is given, aggregates the proofs in groups of that size
+    fn run_e2e_prover_with_groups<E, P>(circ_params: P::Parameters, group_size: Option<usize>)
+    where
+        E: Pairing,
+        E::ScalarField: Absorb,
+        P: CircuitWithPortals<E::ScalarField> + Clone,
     {
         let start_a = Instant::now();
         let mut rng = test_rng();
-        let tree_params = gen_merkle_params();
+        let tree_params = gen_merkle_params::<E::ScalarField>();
 
         let circ = P::rand(&mut rng, &circ_params);
         let num_subcircuits = P::num_subcircuits(&circ);
@@ -452,7 +1039,8 @@ mod test {
 
         // Coordinator generates all the proving keys. We only need to generate the proving keys for the minimal set of unique subcircuits
         let minimal_proving_keys: HashMap<usize, Rc<G16ProvingKey<E>>> = {
-            let generator = G16ProvingKeyGenerator::<TestParams, TestParamsVar, _, _>::new(
+            let generator = G16ProvingKeyGenerator::<_, TestParamsVar<E::ScalarField>, E, _>::new(
+                &mut rng,
                 circ.clone(),
                 tree_params.clone(),
             );
@@ -480,7 +1068,7 @@ mod test {
         let start_b = Instant::now();
 
         // Make the stage0 coordinator state
-        let stage0_state = CoordinatorStage0State::new::<TestParams>(circ);
+        let stage0_state = CoordinatorStage0State::<E, _>::new::<TestParams<E::ScalarField>>(circ);
 
         // Workers receives stage0 packages containing the subtraces it will need for this run. We
         // imagine the worker saves their package to disk.
@@ -494,7 +1082,7 @@ mod test {
             .iter()
             .zip(proving_keys.iter())
             .map(|(req, pk)| {
-                process_stage0_request::<_, TestParamsVar, _, P, _>(
+                process_stage0_request::<_, TestParamsVar<_>, _, P, _>(
                     &mut rng,
                     tree_params.clone(),
                     &pk,
@@ -508,24 +1096,58 @@ mod test {
 
         let start_c = Instant::now();
 
-        // Move on to stage 1. Make the coordinator state
-        let (tipp_pk, _tipp_vk) = TIPA::<E, Sha256>::setup(num_subcircuits, &mut rng).unwrap();
-        let stage1_state =
-            stage0_state.process_stage0_responses(&tipp_pk, tree_params.clone(), &stage0_resps);
+        // Move on to stage 1. Make the coordinator state. If we're aggregating in groups, every
+        // group gets its own TIPP key, and the super-commitments are made wrt the grouped key
+        let (tipp_pk, _tipp_vk) =
+            TIPA::<E, Sha256>::setup(tipp_size(num_subcircuits), &mut rng).unwrap();
+        let grouped_ck = group_size.map(|group_size| {
+            GroupedAggProvingKey::new(num_subcircuits, group_size, &mut rng, |i| &proving_keys[i])
+        });
+        let super_com_key: &dyn SuperComKey<E> = match &grouped_ck {
+            Some(ck) => ck,
+            None => &tipp_pk,
+        };
+        let mut stage1_state = stage0_state.process_stage0_responses(
+            super_com_key,
+            tree_params.clone(),
+            &stage0_resps,
+        );
+
+        // Do the extra rounds, if any. Index i holds subcircuit i's responses from every round
+        let mut round_resps: Vec<Vec<RoundResponse<E>>> = vec![Vec::new(); num_subcircuits];
+        for _ in 0..P::NUM_EXTRA_ROUNDS {
+            let resps = all_subcircuit_indices
+                .iter()
+                .zip(proving_keys.iter())
+                .map(|(&idx, pk)| {
+                    process_round_request::<_, TestParamsVar<_>, _, P, _>(
+                        &mut rng,
+                        tree_params.clone(),
+                        &pk,
+                        stage0_reqs[idx].clone(),
+                        &stage0_resps[idx],
+                        &round_resps[idx],
+                        stage1_state.gen_round_request(idx),
+                    )
+                })
+                .collect::<Vec<_>>();
+            stage1_state.process_round_responses(super_com_key, &resps);
+            for resp in resps {
+                round_resps[resp.subcircuit_idx].push(resp);
+            }
+        }
 
         // Compute the values needed to prove stage1 for all subcircuits
-        let stage1_reqs: Vec<Stage1Request<TestParams, _, _>> = all_subcircuit_indices
-            .iter()
-            .map(|idx| stage1_state.gen_request(*idx).to_owned())
-            .collect();
+        let stage1_reqs: Vec<Stage1Request<TestParams<E::ScalarField>, _, _>> =
+            all_subcircuit_indices
+                .iter()
+                .map(|idx| stage1_state.gen_request(*idx).to_owned())
+                .collect();
 
         let duration_c = start_c.elapsed();
         println!("Part C took: {:?}", duration_c);
 
         let start_d = Instant::now();
-        // Convert the coordinator state into a final aggregator state. We can throw away most of
-        // our circuit data now
-        let final_agg_state = stage1_state.into_agg_state();
 
         // Now compute all the proofs, check them, and collect them for aggregation
         let stage1_resps = stage0_reqs
@@ -533,41 +1155,108 @@ mod test {
             .zip(stage0_resps.into_iter())
             .zip(stage1_reqs.into_iter())
             .zip(proving_keys.iter())
-            .map(|(((stage0_req, stage0_resp), stage1_req), pk)| {
-                // Compute the proof
-                let resp = process_stage1_request::<_, TestParamsVar, _, _, _>(
-                    &mut rng,
-                    tree_params.clone(),
-                    &pk,
-                    stage0_req,
-                    &stage0_resp,
-                    stage1_req,
-                );
-
-                // Verify
-
-                let _public_inputs = &final_agg_state.public_inputs;
-                let _pvk = prepare_verifying_key(&pk.vk());
-                // assert!(verify_proof(&pvk, &resp.proof, &public_inputs).unwrap());
-
-                resp
-            })
+            .zip(round_resps.iter())
+            .map(
+                |((((stage0_req, stage0_resp), stage1_req), pk), round_resps)| {
+                    // Compute the proof
+                    let resp = process_stage1_request_with_rounds::<_, TestParamsVar<_>, _, _, _>(
+                        &mut rng,
+                        tree_params.clone(),
+                        &pk,
+                        stage0_req,
+                        &stage0_resp,
+                        round_resps,
+                        stage1_req,
+                    );
+
+                    // Verify
+                    let pvk = prepare_verifying_key(&pk.vk());
+                    assert!(stage1_state.verify_stage1_response(&pvk, &resp));
+
+                    // A proof for a different subcircuit must be rejected
+                    if num_subcircuits > 1 {
+                        let mut bad_resp = resp.clone();
+                        bad_resp.subcircuit_idx = (resp.subcircuit_idx + 1) % num_subcircuits;
+                        assert!(!stage1_state.verify_stage1_response(&pvk, &bad_resp));
+                    }
+
+                    resp
+                },
+            )
             .collect::<Vec<_>>();
 
+        // Convert the coordinator state into a final aggregator state. We can throw away most of
+        // our circuit data now
+        let final_agg_state = stage1_state.into_agg_state();
+
         let duration_d = start_d.elapsed();
         println!("Part D took: {:?}", duration_d);
 
         let start_e = Instant::now();
 
-        // Do aggregation. Make up whatever keys are necessary
-        let agg_ck = AggProvingKey::new(tipp_pk, |i| &proving_keys[i]);
-
-        // Compute the aggregate proof
-        final_agg_state.gen_agg_proof(&agg_ck, &stage1_resps);
+        // Do aggregation, either all at once or one group at a time. Make up whatever keys are
+        // necessary. Also make the corresponding verifier
+        let (tree_params, circ_params) = (&tree_params, &circ_params);
+        let (agg_proof, verify) = match &grouped_ck {
+            Some(ck) => {
+                let group_proofs = (0..ck.num_groups())
+                    .map(|g| {
+                        let resps = &stage1_resps[ck.group_range(g)];
+                        final_agg_state.gen_group_agg_proof(ck.group(g), g, resps)
+                    })
+                    .collect();
+                let agg_proof = final_agg_state.combine_group_agg_proofs(group_proofs);
+                let agg_vk = ck.vk();
+                let verify: Box<dyn Fn(&TestProof<E>) -> bool + '_> =
+                    Box::new(move |proof: &TestProof<E>| {
+                        proof.verify_grouped::<P>(&agg_vk, tree_params, circ_params)
+                    });
+                (agg_proof, verify)
+            },
+            None => {
+                let agg_ck = AggProvingKey::new(tipp_pk, num_subcircuits, |i| &proving_keys[i]);
+                let agg_proof = final_agg_state.gen_agg_proof(&agg_ck, &stage1_resps);
+                let agg_vk = agg_ck.vk();
+                let verify: Box<dyn Fn(&TestProof<E>) -> bool + '_> =
+                    Box::new(move |proof: &TestProof<E>| {
+                        proof.verify::<P>(&agg_vk, tree_params, circ_params)
+                    });
+                (agg_proof, verify)
+            },
+        };
 
         let duration_e = start_e.elapsed();
         println!("Part E took: {:?}", duration_e);
 
-        // TODO: Check verification
+        // Verify the aggregate proof, both before and after a serialization round trip
+        assert!(verify(&agg_proof));
+        let mut buf = Vec::new();
+        agg_proof.serialize_compressed(&mut buf).unwrap();
+        let agg_proof = TestProof::<E>::deserialize_compressed(buf.as_slice()).unwrap();
+        assert!(verify(&agg_proof));
+
+        // Challenges that aren't derived from the super-commitments should make verification fail
+        let mut bad_proof = agg_proof.clone();
+        bad_proof.challenges[0] += E::ScalarField::from(1u8);
+        assert!(!verify(&bad_proof));
+
+        // The proof has every public input of the circuit, and changing any of them should make
+        // verification fail
+        assert_eq!(
+            agg_proof.public_inputs.len(),
+            P::new(circ_params).public_inputs().len()
+        );
+        for i in 0..agg_proof.public_inputs.len() {
+            let mut bad_proof = agg_proof.clone();
+            bad_proof.public_inputs[i] += E::ScalarField::from(1u8);
+            assert!(!verify(&bad_proof));
+        }
+
+        // Reordering the groups should make verification fail
+        if agg_proof.group_proofs.len() > 1 {
+            let mut bad_proof = agg_proof.clone();
+            bad_proof.group_proofs.swap(0, 1);
+            assert!(!verify(&bad_proof));
+        }
     }
 }
@@ -0,0 +1,414 @@
+use crate::transcript::TranscriptEntry;
+
+use ark_ff::PrimeField;
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+use std::{
+    borrow::Cow,
+    cmp::Reverse,
+    collections::BinaryHeap,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+const TIME_SUBTRACE_FILENAME_PREFIX: &str = "time_ordered_subtrace";
+const ADDR_SUBTRACE_FILENAME_PREFIX: &str = "addr_ordered_subtrace";
+const WITNESSES_FILENAME_PREFIX: &str = "serialized_witnesses";
+const SORT_RUN_FILENAME_PREFIX: &str = "addr_sort_run";
+
+/// The number of sorted runs an on-disk store merges at once when it sorts the trace. Each run
+/// being merged holds a read buffer and one entry in memory.
+const MERGE_FAN_IN: usize = 64;
+
+/// The subtraces and serialized witnesses of every subcircuit. The coordinator needs these in both
+/// stages. For circuits with many subcircuits they don't fit in memory, so they can also be kept
+/// on disk, in one file per subcircuit and kind of data.
+#[derive(Clone, CanonicalSerialize, CanonicalDeserialize)]
+pub struct SubcircuitStore<F: PrimeField> {
+    num_subcircuits: usize,
+    /// The directory the data lives in, if it's on disk. This is a string because paths aren't
+    /// `CanonicalSerialize`.
+    dir: Option<String>,
+    /// The data, if it's in memory
+    time_ordered_subtraces: Vec<Vec<TranscriptEntry<F>>>,
+    addr_ordered_subtraces: Vec<Vec<TranscriptEntry<F>>>,
+    all_serialized_witnesses: Vec<Vec<u8>>,
+}
+
+impl<F: PrimeField> SubcircuitStore<F> {
+    /// Makes an empty store that keeps everything in memory
+    pub fn in_memory(num_subcircuits: usize) -> Self {
+        SubcircuitStore {
+            num_subcircuits,
+            dir: None,
+            time_ordered_subtraces: Vec::with_capacity(num_subcircuits),
+            addr_ordered_subtraces: Vec::with_capacity(num_subcircuits),
+            all_serialized_witnesses: Vec::with_capacity(num_subcircuits),
+        }
+    }
+
+    /// Makes an empty store that keeps everything in files in `dir`. The directory is created if
+    /// it doesn't exist.
+    pub fn on_disk(num_subcircuits: usize, dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        // Store the absolute path, so a serialized store can be used from any working directory
+        let dir = fs::canonicalize(dir)?;
+        let dir = dir
+            .to_str()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "non-UTF-8 store path"))?;
+
+        Ok(SubcircuitStore {
+            num_subcircuits,
+            dir: Some(dir.to_string()),
+            time_ordered_subtraces: Vec::new(),
+            addr_ordered_subtraces: Vec::new(),
+            all_serialized_witnesses: Vec::new(),
+        })
+    }
+
+    pub fn num_subcircuits(&self) -> usize {
+        self.num_subcircuits
+    }
+
+    /// Stores the time-ordered subtrace of the given subcircuit. Subtraces must be put in order.
+    pub(crate) fn put_time_ordered_subtrace(
+        &mut self,
+        subcircuit_idx: usize,
+        subtrace: Vec<TranscriptEntry<F>>,
+    ) -> io::Result<()> {
+        match self.path(TIME_SUBTRACE_FILENAME_PREFIX, subcircuit_idx) {
+            Some(path) => write_to_path(&path, &subtrace),
+            None => {
+                push_in_order(&mut self.time_ordered_subtraces, subcircuit_idx, subtrace);
+                Ok(())
+            },
+        }
+    }
+
+    /// Stores the address-ordered subtrace of the given subcircuit. Subtraces must be put in order.
+    pub(crate) fn put_addr_ordered_subtrace(
+        &mut self,
+        subcircuit_idx: usize,
+        subtrace: Vec<TranscriptEntry<F>>,
+    ) -> io::Result<()> {
+        match self.path(ADDR_SUBTRACE_FILENAME_PREFIX, subcircuit_idx) {
+            Some(path) => write_to_path(&path, &subtrace),
+            None => {
+                push_in_order(&mut self.addr_ordered_subtraces, subcircuit_idx, subtrace);
+                Ok(())
+            },
+        }
+    }
+
+    /// Makes the address-ordered subtraces by sorting the whole trace, i.e., the time-ordered
+    /// subtraces, which must all be stored, by `key`. Entries with the same key keep their time
+    /// order, and each address-ordered subtrace is as long as the time-ordered one of its
+    /// subcircuit. An in-memory store sorts the trace in memory. An on-disk store does an external
+    /// merge sort: every time-ordered subtrace is sorted into a run file, and the runs are merged
+    /// `MERGE_FAN_IN` at a time, so the whole trace is never in memory at once.
+    pub(crate) fn put_addr_ordered_subtraces_sorted_by<K: Ord>(
+        &mut self,
+        key: impl Fn(&TranscriptEntry<F>) -> K,
+    ) -> io::Result<()> {
+        if self.dir.is_none() {
+            // The sort is stable, so entries with the same key keep their time order
+            let mut flat_trace = self.time_ordered_subtraces.concat();
+            flat_trace.sort_by_key(|entry| key(entry));
+
+            let mut flat_iter = flat_trace.into_iter();
+            self.addr_ordered_subtraces = self
+                .time_ordered_subtraces
+                .iter()
+                .map(|subtrace| flat_iter.by_ref().take(subtrace.len()).collect())
+                .collect();
+            return Ok(());
+        }
+
+        // Sort each subtrace into its own run. The runs are in time order, and the merge breaks
+        // ties by run, so entries with the same key keep their time order
+        let mut subtrace_lens = Vec::with_capacity(self.num_subcircuits);
+        let mut runs = Vec::with_capacity(self.num_subcircuits);
+        for idx in 0..self.num_subcircuits {
+            let mut subtrace = self.time_ordered_subtrace(idx).into_owned();
+            subtrace.sort_by_key(|entry| key(entry));
+            subtrace_lens.push(subtrace.len());
+
+            let run = self.run_path(0, idx);
+            let mut writer = BufWriter::new(File::create(&run)?);
+            for entry in &subtrace {
+                write_entry(&mut writer, entry)?;
+            }
+            writer.flush()?;
+            runs.push((run, subtrace.len()));
+        }
+
+        // Merge consecutive runs until there are few enough to merge at once
+        let mut pass = 0;
+        while runs.len() > MERGE_FAN_IN {
+            pass += 1;
+            runs = runs
+                .chunks(MERGE_FAN_IN)
+                .enumerate()
+                .map(|(j, chunk)| {
+                    let merged = self.run_path(pass, j);
+                    let mut writer = BufWriter::new(File::create(&merged)?);
+                    merge_runs(chunk, &key, |entry| write_entry(&mut writer, &entry))?;
+                    writer.flush()?;
+                    Ok((merged, chunk.iter().map(|(_, len)| len).sum()))
+                })
+                .collect::<io::Result<Vec<_>>>()?;
+        }
+
+        // The last merge is chunked back up into subtraces as it goes
+        let mut idx = 0;
+        let mut subtrace = Vec::new();
+        let mut put_full_subtraces =
+            |store: &mut Self, buf: &mut Vec<TranscriptEntry<F>>| -> io::Result<()> {
+                // A subtrace may be empty, so this can put more than one
+                while idx < subtrace_lens.len() && buf.len() == subtrace_lens[idx] {
+                    store.put_addr_ordered_subtrace(idx, std::mem::take(buf))?;
+                    idx += 1;
+                }
+                Ok(())
+            };
+        put_full_subtraces(self, &mut subtrace)?;
+        merge_runs(&runs, &key, |entry| {
+            subtrace.push(entry);
+            put_full_subtraces(self, &mut subtrace)
+        })?;
+        assert_eq!(idx, self.num_subcircuits, "the sorted trace is too short");
+
+        Ok(())
+    }
+
+    /// Stores the serialized witnesses of the given subcircuit. Witnesses must be put in order.
+    pub(crate) fn put_serialized_witnesses(
+        &mut self,
+        subcircuit_idx: usize,
+        serialized_witnesses: Vec<u8>,
+    ) -> io::Result<()> {
+        match self.path(WITNESSES_FILENAME_PREFIX, subcircuit_idx) {
+            Some(path) => write_to_path(&path, &serialized_witnesses),
+            None => {
+                push_in_order(
+                    &mut self.all_serialized_witnesses,
+                    subcircuit_idx,
+                    serialized_witnesses,
+                );
+                Ok(())
+            },
+        }
+    }
+
+    /// Gets the time-ordered subtrace of the given subcircuit. Panics if it can't be read.
+    pub fn time_ordered_subtrace(&self, subcircuit_idx: usize) -> Cow<[TranscriptEntry<F>]> {
+        match self.path(TIME_SUBTRACE_FILENAME_PREFIX, subcircuit_idx) {
+            Some(path) => Cow::Owned(read_from_path(&path)),
+            None => Cow::Borrowed(&self.time_ordered_subtraces[subcircuit_idx]),
+        }
+    }
+
+    /// Gets the address-ordered subtrace of the given subcircuit. Panics if it can't be read.
+    pub fn addr_ordered_subtrace(&self, subcircuit_idx: usize) -> Cow<[TranscriptEntry<F>]> {
+        match self.path(ADDR_SUBTRACE_FILENAME_PREFIX, subcircuit_idx) {
+            Some(path) => Cow::Owned(read_from_path(&path)),
+            None => Cow::Borrowed(&self.addr_ordered_subtraces[subcircuit_idx]),
+        }
+    }
+
+    /// Gets the serialized witnesses of the given subcircuit. Panics if they can't be read.
+    pub fn serialized_witnesses(&self, subcircuit_idx: usize) -> Cow<[u8]> {
+        match self.path(WITNESSES_FILENAME_PREFIX, subcircuit_idx) {
+            Some(path) => Cow::Owned(read_from_path(&path)),
+            None => Cow::Borrowed(&self.all_serialized_witnesses[subcircuit_idx]),
+        }
+    }
+
+    /// Returns the file that holds the `j`-th sorted run of the given merge pass of the trace sort.
+    /// Panics if the store is in memory.
+    fn run_path(&self, pass: usize, j: usize) -> PathBuf {
+        let dir = self
+            .dir
+            .as_ref()
+            .expect("only an on-disk store makes sorted runs");
+        Path::new(dir).join(format!("{SORT_RUN_FILENAME_PREFIX}_{pass}_{j}.bin"))
+    }
+
+    /// Returns the file that holds the given data, or `None` if the store is in memory
+    fn path(&self, filename_prefix: &str, subcircuit_idx: usize) -> Option<PathBuf> {
+        assert!(
+            subcircuit_idx < self.num_subcircuits,
+            "subcircuit {subcircuit_idx} is out of range"
+        );
+        self.dir
+            .as_ref()
+            .map(|dir| Path::new(dir).join(format!("{filename_prefix}_{subcircuit_idx}.bin")))
+    }
+}
+
+fn push_in_order<T>(items: &mut Vec<T>, idx: usize, item: T) {
+    assert_eq!(idx, items.len(), "subcircuit data must be stored in order");
+    items.push(item);
+}
+
+/// Merges the given sorted runs, and their lengths, by `key`, and passes the entries to `emit` in
+/// order. Entries with the same key come out in the order of their runs. The runs are removed
+/// once they're merged.
+fn merge_runs<F: PrimeField, K: Ord>(
+    runs: &[(PathBuf, usize)],
+    key: impl Fn(&TranscriptEntry<F>) -> K,
+    mut emit: impl FnMut(TranscriptEntry<F>) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut readers = runs
+        .iter()
+        .map(|(path, len)| Ok((BufReader::new(File::open(path)?), *len)))
+        .collect::<io::Result<Vec<_>>>()?;
+    // Reads the next entry of the given run, if there is one
+    let mut next_entry = |r: usize| -> io::Result<Option<TranscriptEntry<F>>> {
+        let (reader, remaining) = &mut readers[r];
+        if *remaining == 0 {
+            return Ok(None);
+        }
+        *remaining -= 1;
+        TranscriptEntry::deserialize_uncompressed_unchecked(reader)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+    };
+
+    // The heap holds the key and run of every run's next entry. The entries themselves are in
+    // `heads`, indexed by run
+    let mut heads = Vec::with_capacity(runs.len());
+    let mut heap = BinaryHeap::with_capacity(runs.len());
+    for r in 0..runs.len() {
+        let head = next_entry(r)?;
+        if let Some(entry) = &head {
+            heap.push(Reverse((key(entry), r)));
+        }
+        heads.push(head);
+    }
+    while let Some(Reverse((_, r))) = heap.pop() {
+        let next = next_entry(r)?;
+        if let Some(entry) = &next {
+            heap.push(Reverse((key(entry), r)));
+        }
+        emit(std::mem::replace(&mut heads[r], next).unwrap())?;
+    }
+
+    for (path, _) in runs {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+fn write_entry<F: PrimeField>(writer: impl Write, entry: &TranscriptEntry<F>) -> io::Result<()> {
+    entry
+        .serialize_uncompressed(writer)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+}
+
+fn write_to_path(path: &Path, val: &impl CanonicalSerialize) -> io::Result<()> {
+    let mut f = BufWriter::new(File::create(path)?);
+    val.serialize_uncompressed(&mut f)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    f.into_inner()?;
+    Ok(())
+}
+
+fn read_from_path<T: CanonicalDeserialize>(path: &Path) -> T {
+    let f = File::open(path).unwrap_or_else(|e| panic!("couldn't open {path:?}: {e}"));
+    T::deserialize_uncompressed_unchecked(BufReader::new(f))
+        .unwrap_or_else(|e| panic!("couldn't deserialize {path:?}: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transcript::RomTranscriptEntry;
+
+    use ark_bls12_381::Fr;
+
+    // Puts some data in the store and checks that it comes back out
+    fn roundtrip(mut store: SubcircuitStore<Fr>) {
+        let num_subcircuits = store.num_subcircuits();
+        let subtrace =
+            |i: usize| vec![TranscriptEntry::<Fr>::padding(crate::transcript::MemType::Rom); i];
+
+        for i in 0..num_subcircuits {
+            store.put_time_ordered_subtrace(i, subtrace(i)).unwrap();
+            store.put_addr_ordered_subtrace(i, subtrace(i + 1)).unwrap();
+            store.put_serialized_witnesses(i, vec![i as u8; i]).unwrap();
+        }
+
+        for i in 0..num_subcircuits {
+            assert_eq!(
+                store.time_ordered_subtrace(i).as_ref(),
+                subtrace(i).as_slice()
+            );
+            assert_eq!(
+                store.addr_ordered_subtrace(i).as_ref(),
+                subtrace(i + 1).as_slice()
+            );
+            assert_eq!(
+                store.serialized_witnesses(i).as_ref(),
+                vec![i as u8; i].as_slice()
+            );
+        }
+    }
+
+    #[test]
+    fn test_in_memory_store() {
+        roundtrip(SubcircuitStore::in_memory(4));
+    }
+
+    #[test]
+    fn test_on_disk_store() {
+        let dir =
+            std::env::temp_dir().join(format!("subcircuit_store_test_{}", std::process::id()));
+        roundtrip(SubcircuitStore::on_disk(4, &dir).unwrap());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // Sorts a trace in memory and on disk, and checks that they agree. There are more subcircuits
+    // than MERGE_FAN_IN, so the on-disk sort takes more than one merge pass. Every entry has a
+    // distinct value, so this also checks that the sorts are stable.
+    #[test]
+    fn test_on_disk_sort() {
+        let num_subcircuits = 3 * MERGE_FAN_IN;
+        let dir =
+            std::env::temp_dir().join(format!("subcircuit_store_sort_test_{}", std::process::id()));
+        let mut in_memory = SubcircuitStore::<Fr>::in_memory(num_subcircuits);
+        let mut on_disk = SubcircuitStore::<Fr>::on_disk(num_subcircuits, &dir).unwrap();
+
+        // Some subtraces are empty
+        let mut val = 0u64;
+        for i in 0..num_subcircuits {
+            let subtrace: Vec<_> = (0..i % 5)
+                .map(|j| {
+                    val += 1;
+                    TranscriptEntry::Rom(RomTranscriptEntry {
+                        addr: ((7 * i + j) % 11) as u64,
+                        val: Fr::from(val),
+                    })
+                })
+                .collect();
+            in_memory
+                .put_time_ordered_subtrace(i, subtrace.clone())
+                .unwrap();
+            on_disk.put_time_ordered_subtrace(i, subtrace).unwrap();
+        }
+        in_memory
+            .put_addr_ordered_subtraces_sorted_by(TranscriptEntry::addr)
+            .unwrap();
+        on_disk
+            .put_addr_ordered_subtraces_sorted_by(TranscriptEntry::addr)
+            .unwrap();
+
+        for i in 0..num_subcircuits {
+            assert_eq!(
+                in_memory.addr_ordered_subtrace(i),
+                on_disk.addr_ordered_subtrace(i)
+            );
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -392,6 +392,23 @@ impl<F: PrimeField> CircuitWithPortals<F> for ZkDbSqlCircuit<F> {
     }
 }
 
+impl<F: PrimeField> ZkDbSqlCircuit<F> {
+    /// Makes a circuit over the given table, e.g., a lineitem table read with
+    /// [`crate::tpch::load_lineitem_table`]
+    pub fn with_table(params: &ZkDbSqlCircuitParams, table_data: Vec<Vec<F>>) -> Self {
+        assert_eq!(
+            table_data.len(),
+            params.num_rows,
+            "table must have exactly num_rows rows"
+        );
+        ZkDbSqlCircuit {
+            table_data,
+            sorted_indices: (0..params.num_rows).collect(),
+            params: *params,
+        }
+    }
+}
+
 // // 辅助函数：基于索引进行归并排序
 // fn merge_sort<F: PrimeField>(circuit: &mut ZkDbSqlCircuit<F>) {
 //     let num_rows = circuit.params.num_rows;
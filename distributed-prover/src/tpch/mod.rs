@@ -1 +1,3 @@
-mod tpch_1;
\ No newline at end of file
+mod tpch_1;
+
+pub use tpch_1::*;
@@ -1,282 +1,537 @@
-// use ark_ff::PrimeField;
-// use ark_relations::r1cs::{
-//     ConstraintSystemRef, Namespace, SynthesisError,
-// };
-// use ark_r1cs_std::{
-//     alloc::AllocVar,
-//     eq::EqGadget,
-//     fields::fp::FpVar,
-//     boolean::Boolean,
-// };
-// use ark_serialize::{CanonicalSerialize,CanonicalDeserialize};
-// use ark_std::{
-//     rand::Rng,
-//     vec::Vec,
-//     format,
-// };
-// use crate::{
-//     portal_manager::{PortalManager, RomProverPortalManager, SetupRomPortalManager},
-//     transcript::MemType,
-//     CircuitWithPortals,
-// };
-
-
-// #[derive(Copy, Clone, CanonicalSerialize, CanonicalDeserialize)]
-// pub struct ZkDbSqlCircuitParams {
-//     pub num_rows: usize,
-//     // 你可以根据需要增加更多字段，例如 groupby 字段数、阈值设定、是否做多列筛选 等
-// }
-
-// impl std::fmt::Display for ZkDbSqlCircuitParams {
-//     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-//         write!(f, "ZkDbSqlCircuitParams {{ num_rows: {} }}", self.num_rows)
-//     }
-// }
-
-
-// #[derive(Clone)]
-// pub struct ZkDbSqlCircuit<F: PrimeField> {
-//     /// 从 lineitem 表读入的数据，比如 7 列：quantity, extendedprice, discount, tax, returnflag, linestatus, shipdate
-//     pub lineitem_table: Vec<[F; 7]>,
-//     /// 过滤比较的右侧 shipdate
-//     pub right_shipdate: F,
-//     /// 记录下电路参数
-//     pub params: ZkDbSqlCircuitParams,
-// }
-
-// impl<F: PrimeField> ZkDbSqlCircuit<F> {
-//     /// 新建一个空电路
-//     pub fn new(params: &ZkDbSqlCircuitParams) -> Self {
-//         Self {
-//             lineitem_table: vec![[F::zero(); 7]; params.num_rows],
-//             right_shipdate: F::zero(),
-//             params: params.clone(),
-//         }
-//     }
-
-//     /// **生成随机电路**，可用于测试
-//     pub fn rand<R: Rng>(mut rng: R, params: &ZkDbSqlCircuitParams) -> Self {
-//         // 随机生成 lineitem_table
-//         let mut lineitem_table = Vec::with_capacity(params.num_rows);
-//         for _ in 0..params.num_rows {
-//             let row = [
-//                 F::rand(&mut rng), // quantity
-//                 F::rand(&mut rng), // extendedprice
-//                 F::rand(&mut rng), // discount
-//                 F::rand(&mut rng), // tax
-//                 F::rand(&mut rng), // returnflag
-//                 F::rand(&mut rng), // linestatus
-//                 F::rand(&mut rng), // shipdate
-//             ];
-//             lineitem_table.push(row);
-//         }
-//         let right_shipdate = F::rand(&mut rng);
-
-//         Self {
-//             lineitem_table,
-//             right_shipdate,
-//             params: params.clone(),
-//         }
-//     }
-// }
-
-// /// 为了让 node.rs 能够并行、分配子电路计算，需要实现 CircuitWithPortals
-// impl<F: PrimeField> CircuitWithPortals<F> for ZkDbSqlCircuit<F> {
-//     type Parameters = ZkDbSqlCircuitParams;
-//     const MEM_TYPE: MemType = MemType::Rom;
-//     type ProverPortalManager = RomProverPortalManager<F>;
-
-//     /// 子电路总数量（一个最简单的做法：每行 1 个 subcircuit，最后再加一个 padding）
-//     fn num_subcircuits(&self) -> usize {
-//         self.params.num_rows + 1  // +1 作为占位padding 
-//     }
-
-//     /// 返回一个“最小子电路集合”的索引，用于 CRS 生成等，这里 demo 返回几行
-//     fn get_unique_subcircuits(&self) -> Vec<usize> {
-//         // 例如第一行、第二行、最后一行、以及 padding
-//         let n = self.num_subcircuits();
-//         if n == 1 {
-//             return vec![0];
-//         }
-//         vec![0, 1, n - 1]
-//     }
-
-//     /// 将任意 subcircuit_idx 映射为 get_unique_subcircuits 里的代表索引
-//     fn representative_subcircuit(&self, subcircuit_idx: usize) -> usize {
-//         let n = self.num_subcircuits();
-//         if subcircuit_idx == 0 {
-//             0
-//         } else if subcircuit_idx == n - 1 {
-//             n - 1
-//         } else {
-//             // demo：除开首尾，其余都映射到 1
-//             1
-//         }
-//     }
-
-//     /// 返回电路参数
-//     fn get_params(&self) -> Self::Parameters {
-//         self.params.clone()
-//     }
-
-//     /// 构造一个随机电路，用于测试
-//     fn rand(rng: &mut impl Rng, params: &Self::Parameters) -> Self {
-//         Self::rand(rng, params)
-//     }
-
-//     /// 构造一个空电路
-//     fn new(params: &Self::Parameters) -> Self {
-//         Self::new(params)
-//     }
-
-//     /// 读取某个 subcircuit 的所有 witness 并序列化输出。  
-//     /// 类似参考 Merkle 代码中那样，这里可以根据 subcircuit 的含义来决定拿哪些字段做 witness。
-//     fn get_serialized_witnesses(&self, subcircuit_idx: usize) -> Vec<u8> {
-//         let num_rows = self.params.num_rows;
-//         let total_subcircuits = self.num_subcircuits();
-
-//         // padding subcircuit 不返回
-//         if subcircuit_idx == total_subcircuits - 1 {
-//             return vec![];
-//         }
-
-//         // demo：将第 i 行转为 bytes
-//         // 如果 subcircuit_idx >= num_rows，也就是 padding，多余行都不返回
-//         if subcircuit_idx < num_rows {
-//             // 这里随意用 Ark 自身序列化，或者 bincode、postcard 等
-//             let row = &self.lineitem_table[subcircuit_idx];
-//             let mut buf = Vec::new();
-//             // row[7], plus 1shipdate
-//             // 这里只是演示把 [F; 7] 转成 bytes
-//             for &field in row.iter() {
-//                 field.serialize_compressed(&mut buf).unwrap();
-//             }
-
-//             let mut right_ship_buf = Vec::new();
-//             self.right_shipdate.serialize_compressed(&mut right_ship_buf).unwrap();
-
-//             // 也把 right_shipdate 放进去
-//             buf.extend_from_slice(&right_ship_buf);
-
-//             buf
-//         } else {
-//             vec![]
-//         }
-//     }
-
-//     /// 从字节反序列化某个 subcircuit 的 witness
-//     fn set_serialized_witnesses(&mut self, subcircuit_idx: usize, bytes: &[u8]) {
-//         let num_rows = self.params.num_rows;
-//         let total_subcircuits = self.num_subcircuits();
-//         // padding 跳过
-//         if subcircuit_idx == total_subcircuits - 1 {
-//             return;
-//         }
-//         if subcircuit_idx >= num_rows {
-//             return;
-//         }
-
-//         // demo: 从 bytes 中依次读出 7 个 F & 1 个 right_shipdate
-//         let mut cursor = bytes;
-//         let mut row = [F::zero(); 7];
-//         for i in 0..7 {
-//             row[i] = F::deserialize_compressed_unchecked(&mut cursor).unwrap();
-//         }
-//         self.lineitem_table[subcircuit_idx] = row;
-//         let rs = F::deserialize_compressed_unchecked(&mut cursor).unwrap();
-//         self.right_shipdate = rs;
-//     }
-
-//     /// **核心**：在 Ark R1CS 下实现电路的约束逻辑。node.rs 会在多进程中，对每个 subcircuit_idx 调用一次。
-//     fn generate_constraints<P: PortalManager<F>>(
-//         &mut self,
-//         cs: ConstraintSystemRef<F>,
-//         subcircuit_idx: usize,
-//         pm: &mut P,
-//     ) -> Result<(), SynthesisError> {
-//         let nrows = self.params.num_rows;
-//         let total_subcircuits = self.num_subcircuits();
-
-//         // 如果是 padding，什么也不做
-//         if subcircuit_idx == total_subcircuits - 1 {
-//             // 占位操作
-//             // 也可以像 MerkleTreeCircuit 那样做 dummy hash
-//             return Ok(());
-//         }
-
-//         // 取出本 subcircuit 的那一行（或几行）
-//         // 这里 demo: 每个 subcircuit_idx 就是 1 行
-//         if subcircuit_idx >= nrows {
-//             return Ok(());
-//         }
-
-//         let row = self.lineitem_table[subcircuit_idx];
-//         // row 依次: [quantity, extendedprice, discount, tax, returnflag, linestatus, shipdate]
-//         let quantity_f = row[0];
-//         let extendedprice_f = row[1];
-//         let discount_f = row[2];
-//         let tax_f = row[3];
-//         let returnflag_f = row[4];
-//         let linestatus_f = row[5];
-//         let shipdate_f = row[6];
-
-//         // 分配到 R1CS witness
-//         let quantity_var = FpVar::new_witness(ark_relations::ns!(cs, "quantity"), || Ok(quantity_f))?;
-//         let extendedprice_var = FpVar::new_witness(ark_relations::ns!(cs, "extprice"), || Ok(extendedprice_f))?;
-//         let discount_var = FpVar::new_witness(ark_relations::ns!(cs, "discount"), || Ok(discount_f))?;
-//         let tax_var = FpVar::new_witness(ark_relations::ns!(cs, "tax"), || Ok(tax_f))?;
-//         let returnflag_var = FpVar::new_witness(ark_relations::ns!(cs, "returnflag"), || Ok(returnflag_f))?;
-//         let linestatus_var = FpVar::new_witness(ark_relations::ns!(cs, "linestatus"), || Ok(linestatus_f))?;
-//         let shipdate_var = FpVar::new_witness(ark_relations::ns!(cs, "shipdate"), || Ok(shipdate_f))?;
-
-//         let right_shipdate_var = FpVar::new_witness(ark_relations::ns!(cs, "rightship"), || {
-//             Ok(self.right_shipdate)
-//         })?;
-
-//         // 1) 筛选条件: shipdate <= right_shipdate ?
-//         //    这里为了演示，只写了一个“shipdate <= right_shipdate -> bool” 的做法
-//         //    Ark 没有内置的“<=” gadget，需要你自己实现一个 comparator gadget。
-//         //    此处只演示一个“如果 shipdate > right_shipdate，就记 is_valid = 0，否则 1” 的简易做法
-//         let is_valid = {
-//             // 替代做法：定义一个 (shipdate - right_shipdate) >= 0 判断
-//             // 如果你有一个更完善的 compare_gadget，可以直接调用
-//             let diff = &shipdate_var - &right_shipdate_var;
-//             // 这里我们只演示如何做一个“是否相等”的布尔约束
-//             // 真正要做 <= 需要多字节/多比特分解
-//             // demo: 简化写法——如果 diff 为负，则标记 is_valid = 1，否则 = 0
-//             // 你需要实现 is_non_positive(diff)
-//             let bool_var = Boolean::constant(false); 
-//             // TODO: 使用自定义 comparator 实现 (请根据自身需求做)
-//             bool_var
-//         };
-
-//         // 2) 仅当 is_valid=1 时，把 (quantity, extendedprice, discount, ...) 加到 groupby 结果
-//         //    我们模拟 MerkleCircuit 中的 portal，在 pm 里存储中间结果。
-//         //    在多行执行后（多 subcircuit），我们可以在“父 subcircuit”或“最终汇总 subcircuit”里把它们 sum 上去。
-//         //    这里 demo：把 “quantity” 的值存到 "row_{i}_quantity" portal 里，如果 is_valid=0，那就存 0。
-//         let quantity_true_var = quantity_var.clone() * is_valid.clone().into();  // 这里 is_valid.into() -> FpVar<F>
-//         pm.set(format!("row_{}_quantity", subcircuit_idx), &quantity_true_var)?;
-
-//         // 你可以将多列分别 set，也可以把它们 concat 成一个 FpVar 整理
-//         // ...
-
-//         // 3) 如果我们有“父 subcircuit”要把相邻行 returnflag 对比做排序校验
-//         //    就可以在此把 returnflag_var 设到 Portal
-//         pm.set(format!("row_{}_returnflag", subcircuit_idx), &returnflag_var)?;
-
-//         // 4) 还可以做更多诸如 groupby 统计、count、discount 累加的逻辑
-
-//         // (demo END) 
-//         // 实际生产建议把Halo2 等逻辑：ls_returnflag 排序、linestatus 排序、sum_qty、sum_base_price、sum_disc_price等
-//         // 在Ark中也要做相应R1CS 约束，这里限于篇幅不一一实现。
-
-//         Ok(())
-//     }
-
-//     // 如果还需要自定义一份 Portal trace（可选）
-//     fn get_portal_subtraces(&self) -> Vec<Vec<crate::transcript::TranscriptEntry<F>>> {
-//         // 类似 MerkleTreeCircuit::get_portal_subtraces() 的实现
-//         // 如果不需要，也可直接返回空
-//         vec![]
-//     }
-// }
\ No newline at end of file
+// TPC-H Q1, the pricing summary report:
+//
+//   select
+//       l_returnflag,
+//       l_linestatus,
+//       sum(l_quantity) as sum_qty,
+//       sum(l_extendedprice) as sum_base_price,
+//       sum(l_extendedprice * (1 - l_discount)) as sum_disc_price,
+//       sum(l_extendedprice * (1 - l_discount) * (1 + l_tax)) as sum_charge,
+//       avg(l_quantity) as avg_qty,
+//       avg(l_extendedprice) as avg_price,
+//       avg(l_discount) as avg_disc,
+//       count(*) as count_order
+//   from
+//       lineitem
+//   where
+//       l_shipdate <= date '1998-12-01' - interval ':1' day
+//   group by
+//       l_returnflag,
+//       l_linestatus
+//   order by
+//       l_returnflag,
+//       l_linestatus;
+
+use crate::{
+    portal_manager::{PortalManager, RomProverPortalManager},
+    sql::{
+        date, load_table, Aggregate, CmpOp, ColumnType, Comparison, Expr, QueryPlan, SortKey,
+        SqlCircuit, SqlCircuitParams, TableFormat,
+    },
+    transcript::{MemType, TranscriptEntry},
+    CircuitWithPortals,
+};
+
+use ark_ff::PrimeField;
+use ark_r1cs_std::fields::fp::FpVar;
+use ark_relations::r1cs::{ConstraintSystemRef, SynthesisError};
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+use rand::Rng;
+use std::io;
+use std::path::Path;
+
+// The columns of the lineitem table that Q1 uses
+pub const L_QUANTITY: usize = 0;
+pub const L_EXTENDEDPRICE: usize = 1;
+pub const L_DISCOUNT: usize = 2;
+pub const L_TAX: usize = 3;
+pub const L_RETURNFLAG: usize = 4;
+pub const L_LINESTATUS: usize = 5;
+pub const L_SHIPDATE: usize = 6;
+pub const LINEITEM_NUM_COLS: usize = 7;
+
+/// The columns of a lineitem `.tbl` file made by dbgen. Only the columns above are loaded, so a
+/// loaded row has them in the same order. Decimals are in hundredths, flags are ASCII codes, and
+/// dates are days since 1970-01-01.
+pub const LINEITEM_TBL_SCHEMA: [ColumnType; 16] = [
+    ColumnType::Skip,       // l_orderkey
+    ColumnType::Skip,       // l_partkey
+    ColumnType::Skip,       // l_suppkey
+    ColumnType::Skip,       // l_linenumber
+    ColumnType::Decimal(2), // l_quantity
+    ColumnType::Decimal(2), // l_extendedprice
+    ColumnType::Decimal(2), // l_discount
+    ColumnType::Decimal(2), // l_tax
+    ColumnType::Char,       // l_returnflag
+    ColumnType::Char,       // l_linestatus
+    ColumnType::Date,       // l_shipdate
+    ColumnType::Skip,       // l_commitdate
+    ColumnType::Skip,       // l_receiptdate
+    ColumnType::Skip,       // l_shipinstruct
+    ColumnType::Skip,       // l_shipmode
+    ColumnType::Skip,       // l_comment
+];
+
+/// Reads the rows of a lineitem `.tbl` file. See [`LINEITEM_TBL_SCHEMA`] for the encoding.
+pub fn load_lineitem_table<F: PrimeField>(path: impl AsRef<Path>) -> io::Result<Vec<Vec<F>>> {
+    load_table(path, TableFormat::TBL, &LINEITEM_TBL_SCHEMA)
+}
+
+/// Q1 has one group per (return flag, line status). There are 3 return flags and 2 line statuses.
+pub const Q1_MAX_GROUPS: usize = 6;
+
+/// A row of the lineitem table, encoded as in [`LINEITEM_TBL_SCHEMA`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LineItem {
+    pub quantity: u64,
+    pub extended_price: u64,
+    pub discount: u64,
+    pub tax: u64,
+    pub return_flag: u8,
+    pub line_status: u8,
+    pub ship_date: u64,
+}
+
+impl LineItem {
+    /// Makes a random line item following the TPC-H data generation rules
+    pub fn rand(rng: &mut impl Rng) -> Self {
+        let current_date = date(1995, 6, 17);
+        let quantity = rng.gen_range(1..=50);
+        let retail_price = rng.gen_range(90000..=209900);
+        let ship_date = rng.gen_range(date(1992, 1, 2)..=date(1998, 12, 1));
+        let receipt_date = ship_date + rng.gen_range(1..=30);
+
+        let return_flag = match receipt_date <= current_date {
+            true if rng.gen::<bool>() => b'R',
+            true => b'A',
+            false => b'N',
+        };
+        let line_status = match ship_date > current_date {
+            true => b'O',
+            false => b'F',
+        };
+
+        LineItem {
+            quantity: quantity * 100,
+            extended_price: quantity * retail_price,
+            discount: rng.gen_range(0..=10),
+            tax: rng.gen_range(0..=8),
+            return_flag,
+            line_status,
+            ship_date,
+        }
+    }
+
+    /// This line item as a row of the lineitem table
+    pub fn to_row<F: PrimeField>(&self) -> Vec<F> {
+        let mut row = vec![F::ZERO; LINEITEM_NUM_COLS];
+        row[L_QUANTITY] = F::from(self.quantity);
+        row[L_EXTENDEDPRICE] = F::from(self.extended_price);
+        row[L_DISCOUNT] = F::from(self.discount);
+        row[L_TAX] = F::from(self.tax);
+        row[L_RETURNFLAG] = F::from(self.return_flag);
+        row[L_LINESTATUS] = F::from(self.line_status);
+        row[L_SHIPDATE] = F::from(self.ship_date);
+        row
+    }
+}
+
+#[derive(Clone, Copy, CanonicalSerialize, CanonicalDeserialize)]
+pub struct TpchQ1Params {
+    // number of rows of the lineitem table. the table is padded with invalid rows to a power of two
+    pub num_rows: usize,
+
+    // number of rows each subcircuit works on. this is rounded up to a power of two, at least 2,
+    // and at most the padded number of rows
+    pub rows_per_subcircuit: usize,
+
+    // the DELTA substitution parameter of the query, in days. TPC-H picks it between 60 and 120
+    pub delta: u64,
+}
+
+impl std::fmt::Display for TpchQ1Params {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "[nr={},rps={},delta={}]",
+            self.num_rows, self.rows_per_subcircuit, self.delta
+        )
+    }
+}
+
+impl TpchQ1Params {
+    pub fn sql_params(&self) -> SqlCircuitParams {
+        SqlCircuitParams {
+            num_rows: self.num_rows,
+            rows_per_subcircuit: self.rows_per_subcircuit,
+            plan: q1_plan(self.delta, self.num_rows),
+        }
+    }
+}
+
+/// The plan of Q1 over a lineitem table with `num_rows` rows. The result columns are the ones of
+/// the query, where
+///   * `sum_disc_price` is in units of 1/10^4 and `sum_charge` is in units of 1/10^6,
+///   * averages are rounded down, and
+///   * every other decimal is in hundredths.
+pub fn q1_plan(delta: u64, num_rows: usize) -> QueryPlan {
+    let cutoff = date(1998, 12, 1) - delta;
+    let price = || Expr::col(L_EXTENDEDPRICE);
+    let disc_price = || price() * (Expr::constant(100) - Expr::col(L_DISCOUNT));
+
+    QueryPlan::scan(LINEITEM_NUM_COLS)
+        .filter(vec![Comparison::new(L_SHIPDATE, CmpOp::Le, cutoff)])
+        .project(vec![
+            Expr::col(L_RETURNFLAG),
+            Expr::col(L_LINESTATUS),
+            Expr::col(L_QUANTITY),
+            price(),
+            disc_price(),
+            disc_price() * (Expr::constant(100) + Expr::col(L_TAX)),
+            Expr::col(L_DISCOUNT),
+        ])
+        .group_by(
+            vec![0, 1],
+            vec![
+                Aggregate::Sum(2),
+                Aggregate::Sum(3),
+                Aggregate::Sum(4),
+                Aggregate::Sum(5),
+                Aggregate::Avg(2),
+                Aggregate::Avg(3),
+                Aggregate::Avg(6),
+                Aggregate::Count,
+            ],
+        )
+        .sort(vec![SortKey::asc(0), SortKey::asc(1)])
+        .limit(Q1_MAX_GROUPS.min(num_rows))
+}
+
+/// TPC-H Q1 over a private lineitem table. The query result is public.
+#[derive(Clone)]
+pub struct TpchQ1Circuit<F: PrimeField> {
+    pub params: TpchQ1Params,
+    pub sql: SqlCircuit<F>,
+}
+
+impl<F: PrimeField> TpchQ1Circuit<F> {
+    /// Makes a query over an empty lineitem table
+    pub fn new(params: &TpchQ1Params) -> Self {
+        Self::with_lineitems(params, &[])
+    }
+
+    /// Makes a query over the given line items. The table is padded with invalid rows.
+    pub fn with_lineitems(params: &TpchQ1Params, items: &[LineItem]) -> Self {
+        Self::with_table(params, items.iter().map(LineItem::to_row).collect())
+    }
+
+    /// Makes a query over the given lineitem rows, e.g., ones read with [`load_lineitem_table`].
+    /// The table is padded with invalid rows.
+    pub fn with_table(params: &TpchQ1Params, rows: Vec<Vec<F>>) -> Self {
+        TpchQ1Circuit {
+            params: *params,
+            sql: SqlCircuit::with_table(&params.sql_params(), rows),
+        }
+    }
+
+    /// Makes a query over a full table of random line items
+    pub fn rand(rng: &mut impl Rng, params: &TpchQ1Params) -> Self {
+        let items = (0..params.num_rows)
+            .map(|_| LineItem::rand(rng))
+            .collect::<Vec<_>>();
+        Self::with_lineitems(params, &items)
+    }
+
+    /// The rows of the query result, in order
+    pub fn output_rows(&self) -> Vec<Vec<F>> {
+        self.sql.output_rows()
+    }
+}
+
+impl<F: PrimeField> CircuitWithPortals<F> for TpchQ1Circuit<F> {
+    type Parameters = TpchQ1Params;
+    type ProverPortalManager = RomProverPortalManager<F>;
+    const MEM_TYPE: MemType = MemType::Rom;
+
+    fn rand(rng: &mut impl Rng, params: &TpchQ1Params) -> Self {
+        TpchQ1Circuit::rand(rng, params)
+    }
+
+    fn get_params(&self) -> TpchQ1Params {
+        self.params
+    }
+
+    fn get_portal_subtraces(&self) -> Vec<Vec<TranscriptEntry<F>>> {
+        self.sql.get_portal_subtraces()
+    }
+
+    fn num_subcircuits(&self) -> usize {
+        self.sql.num_subcircuits()
+    }
+
+    fn get_unique_subcircuits(&self) -> Vec<usize> {
+        self.sql.get_unique_subcircuits()
+    }
+
+    fn representative_subcircuit(&self, subcircuit_idx: usize) -> usize {
+        self.sql.representative_subcircuit(subcircuit_idx)
+    }
+
+    fn new(params: &TpchQ1Params) -> Self {
+        TpchQ1Circuit::new(params)
+    }
+
+    fn get_serialized_witnesses(&self, subcircuit_idx: usize) -> Vec<u8> {
+        self.sql.get_serialized_witnesses(subcircuit_idx)
+    }
+
+    fn set_serialized_witnesses(&mut self, subcircuit_idx: usize, bytes: &[u8]) {
+        self.sql.set_serialized_witnesses(subcircuit_idx, bytes)
+    }
+
+    fn generate_constraints<P: PortalManager<F>>(
+        &mut self,
+        cs: ConstraintSystemRef<F>,
+        subcircuit_idx: usize,
+        pm: &mut P,
+    ) -> Result<(), SynthesisError> {
+        self.sql.generate_constraints(cs, subcircuit_idx, pm)
+    }
+
+    fn public_inputs(&self) -> Vec<F> {
+        self.sql.public_inputs()
+    }
+
+    fn public_input_subcircuit(&self) -> usize {
+        self.sql.public_input_subcircuit()
+    }
+
+    fn generate_constraints_with_public_inputs<P: PortalManager<F>>(
+        &mut self,
+        cs: ConstraintSystemRef<F>,
+        subcircuit_idx: usize,
+        pm: &mut P,
+        public_inputs: &[FpVar<F>],
+    ) -> Result<(), SynthesisError> {
+        self.sql
+            .generate_constraints_with_public_inputs(cs, subcircuit_idx, pm, public_inputs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::portal_manager::SetupRomPortalManager;
+    use crate::sql::read_table;
+    use crate::util::CircuitInstance;
+
+    use ark_bls12_381::Fr;
+    use ark_ff::PrimeField;
+    use ark_r1cs_std::alloc::AllocVar;
+    use ark_relations::r1cs::ConstraintSystem;
+    use ark_std::test_rng;
+    use std::collections::BTreeMap;
+
+    // Q1 computed directly on the line items
+    fn native_q1(items: &[LineItem], delta: u64) -> Vec<Vec<u64>> {
+        let cutoff = date(1998, 12, 1) - delta;
+        let mut groups = BTreeMap::new();
+        for item in items.iter().filter(|item| item.ship_date <= cutoff) {
+            let key = (item.return_flag as u64, item.line_status as u64);
+            let sums = groups.entry(key).or_insert([0u64; 6]);
+            let disc_price = item.extended_price * (100 - item.discount);
+            sums[0] += item.quantity;
+            sums[1] += item.extended_price;
+            sums[2] += disc_price;
+            sums[3] += disc_price * (100 + item.tax);
+            sums[4] += item.discount;
+            sums[5] += 1;
+        }
+
+        groups
+            .into_iter()
+            .map(
+                |((flag, status), [qty, price, disc_price, charge, disc, count])| {
+                    vec![
+                        flag,
+                        status,
+                        qty,
+                        price,
+                        disc_price,
+                        charge,
+                        qty / count,
+                        price / count,
+                        disc / count,
+                        count,
+                    ]
+                },
+            )
+            .collect()
+    }
+
+    fn to_u64(row: &[Fr]) -> Vec<u64> {
+        row.iter().map(|x| x.into_bigint().0[0]).collect()
+    }
+
+    // Runs every subcircuit on a pared-down copy of the circuit, with the real query result as
+    // public input, and returns whether the constraints are satisfied
+    fn is_satisfied(circ: &TpchQ1Circuit<Fr>) -> bool {
+        let mut pm = SetupRomPortalManager::new(ConstraintSystem::new_ref());
+        let cs = pm.cs.clone();
+        for subcircuit_idx in 0..circ.num_subcircuits() {
+            pm.start_subtrace(cs.clone());
+
+            let mut circ_copy = TpchQ1Circuit::<Fr>::new(&circ.params);
+            let wits = circ.get_serialized_witnesses(subcircuit_idx);
+            circ_copy.set_serialized_witnesses(subcircuit_idx, &wits);
+
+            if subcircuit_idx == circ.public_input_subcircuit() {
+                let public_inputs = circ
+                    .public_inputs()
+                    .iter()
+                    .map(|x| FpVar::new_input(cs.clone(), || Ok(x)))
+                    .collect::<Result<Vec<_>, _>>()
+                    .unwrap();
+                circ_copy
+                    .generate_constraints_with_public_inputs(
+                        cs.clone(),
+                        subcircuit_idx,
+                        &mut pm,
+                        &public_inputs,
+                    )
+                    .unwrap();
+            } else {
+                circ_copy
+                    .generate_constraints(cs.clone(), subcircuit_idx, &mut pm)
+                    .unwrap();
+            }
+        }
+        cs.is_satisfied().unwrap()
+    }
+
+    // Runs Q1 on a partly filled lineitem table, and compares with the straightforward computation
+    #[test]
+    fn test_q1() {
+        let mut rng = test_rng();
+        let params = TpchQ1Params {
+            num_rows: 32,
+            rows_per_subcircuit: 8,
+            delta: 90,
+        };
+        let items = (0..27)
+            .map(|_| LineItem::rand(&mut rng))
+            .collect::<Vec<_>>();
+        let circ = TpchQ1Circuit::<Fr>::with_lineitems(&params, &items);
+
+        let expected = native_q1(&items, params.delta);
+        assert!(expected.len() > 1);
+        let output = circ
+            .output_rows()
+            .iter()
+            .map(|row| to_u64(row))
+            .collect::<Vec<_>>();
+        assert_eq!(output, expected);
+
+        assert!(is_satisfied(&circ));
+    }
+
+    // Checks a filter that drops everything, and a table with more groups than fit in the output
+    #[test]
+    fn test_q1_edge_cases() {
+        let mut rng = test_rng();
+        let params = TpchQ1Params {
+            num_rows: 4,
+            rows_per_subcircuit: 2,
+            delta: 90,
+        };
+        let mut items = (0..4).map(|_| LineItem::rand(&mut rng)).collect::<Vec<_>>();
+
+        for item in items.iter_mut() {
+            item.ship_date = date(1998, 11, 1);
+        }
+        let circ = TpchQ1Circuit::<Fr>::with_lineitems(&params, &items);
+        assert!(circ.output_rows().is_empty());
+        assert!(is_satisfied(&circ));
+
+        // Four line items that are all in different groups fill up the output
+        for (item, (flag, status)) in
+            items
+                .iter_mut()
+                .zip([(b'R', b'F'), (b'A', b'F'), (b'N', b'O'), (b'N', b'F')])
+        {
+            item.ship_date = date(1994, 1, 1);
+            item.return_flag = flag;
+            item.line_status = status;
+        }
+        let circ = TpchQ1Circuit::<Fr>::with_lineitems(&params, &items);
+        assert_eq!(circ.output_rows().len(), 4);
+        assert!(is_satisfied(&circ));
+    }
+
+    // Loads a few rows of a dbgen lineitem file, and runs Q1 on them
+    #[test]
+    fn test_q1_tbl() {
+        let tbl = "\
+            1|155190|7706|1|17|21168.23|0.04|0.02|N|O|1996-03-13|1996-02-12|1996-03-22|DELIVER IN PERSON|TRUCK|egular courts above the|
+            3|4297|1798|1|45|54058.05|0.06|0.00|R|F|1994-02-02|1994-01-04|1994-02-23|NONE|AIR|ongside of the furiously brave acco|
+            3|19036|6540|2|49|46796.47|0.10|0.00|R|F|1993-11-09|1993-12-20|1993-11-24|TAKE BACK RETURN|RAIL| unusual accounts. eve|
+            4|88035|5560|1|30|30690.90|0.03|0.08|N|O|1996-01-10|1995-12-14|1996-01-18|DELIVER IN PERSON|REG AIR|- quickly regular packages sleep. idly|
+        ";
+        let rows =
+            read_table::<Fr>(tbl.as_bytes(), TableFormat::TBL, &LINEITEM_TBL_SCHEMA).unwrap();
+
+        let item = |quantity, extended_price, discount, tax, flags: &[u8; 2], ship_date| LineItem {
+            quantity,
+            extended_price,
+            discount,
+            tax,
+            return_flag: flags[0],
+            line_status: flags[1],
+            ship_date,
+        };
+        let items = [
+            item(1700, 2116823, 4, 2, b"NO", date(1996, 3, 13)),
+            item(4500, 5405805, 6, 0, b"RF", date(1994, 2, 2)),
+            item(4900, 4679647, 10, 0, b"RF", date(1993, 11, 9)),
+            item(3000, 3069090, 3, 8, b"NO", date(1996, 1, 10)),
+        ];
+        let expected_rows = items.iter().map(LineItem::to_row).collect::<Vec<_>>();
+        assert_eq!(rows, expected_rows);
+
+        let params = TpchQ1Params {
+            num_rows: 4,
+            rows_per_subcircuit: 2,
+            delta: 90,
+        };
+        let circ = TpchQ1Circuit::<Fr>::with_table(&params, rows);
+        let output = circ
+            .output_rows()
+            .iter()
+            .map(|row| to_u64(row))
+            .collect::<Vec<_>>();
+        assert_eq!(output, native_q1(&items, params.delta));
+        assert!(is_satisfied(&circ));
+    }
+
+    // Writes out a Q1 instance and rebuilds the circuit from it
+    #[test]
+    fn test_q1_instance() {
+        let mut rng = test_rng();
+        let params = TpchQ1Params {
+            num_rows: 8,
+            rows_per_subcircuit: 4,
+            delta: 60,
+        };
+        let circ = TpchQ1Circuit::<Fr>::rand(&mut rng, &params);
+
+        let mut buf = Vec::new();
+        CircuitInstance::new(&circ)
+            .serialize_uncompressed(&mut buf)
+            .unwrap();
+        let instance = CircuitInstance::deserialize_uncompressed(buf.as_slice()).unwrap();
+        let rebuilt: TpchQ1Circuit<Fr> = instance.to_circuit();
+
+        assert_eq!(rebuilt.public_inputs(), circ.public_inputs());
+        assert_eq!(rebuilt.get_portal_subtraces(), circ.get_portal_subtraces());
+    }
+}
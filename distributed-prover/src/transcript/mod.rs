@@ -21,7 +21,10 @@ use ark_serialize::{
 pub use ram_transcript::*;
 pub use rom_transcript::*;
 
-use crate::aggregation::IppCom;
+use crate::{
+    aggregation::IppCom,
+    util::{ProtoTranscript, TranscriptProtocol},
+};
 
 #[derive(PartialEq, Eq, Copy, Clone)]
 pub enum MemType {
@@ -29,6 +32,33 @@ pub enum MemType {
     Rom,
 }
 
+impl MemType {
+    /// The number of memory-checking challenges, i.e., the length of
+    /// `RunningEvaluation::challenges`
+    pub fn num_challenges(&self) -> usize {
+        match self {
+            MemType::Ram => 4,
+            MemType::Rom => 2,
+        }
+    }
+}
+
+/// Derives the challenges of an extra round from the super-commitments of every round so far.
+/// `super_coms[0]` is the super-commitment to the subtraces, and `super_coms[k]` is the one of
+/// extra round `k - 1`.
+pub fn round_challenges<E: Pairing>(
+    super_coms: &[IppCom<E>],
+    num_challenges: usize,
+) -> Vec<E::ScalarField> {
+    let mut pt = ProtoTranscript::new(b"hekaton-round-challenges");
+    for com in super_coms {
+        pt.append_serializable(b"super-com", com);
+    }
+    (0..num_challenges)
+        .map(|_| pt.challenge_scalar(b"round-challenge"))
+        .collect()
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum TranscriptEntry<F: PrimeField> {
     Rom(RomTranscriptEntry<F>),
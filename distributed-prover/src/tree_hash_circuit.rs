@@ -25,13 +25,12 @@ use rand::Rng;
 pub(crate) type TestLeaf = [u8; 64];
 const EMPTY_LEAF: TestLeaf = [0u8; 64];
 
-/// Takes a digest as public input to the circuit
-/// Hossein: why comment says public input, but we have FpVar::new_witness
+/// Witnesses a digest. This is only used when the root is not given as a public input to the
+/// circuit, i.e., in tests that prove subcircuits in isolation
 fn input_digest<F: PrimeField>(
     cs: ConstraintSystemRef<F>,
     digest: InnerHash,
 ) -> Result<FpVar<F>, SynthesisError> {
-    // TODO: Make this an actual public input, not just a witness
     let fp = F::from_le_bytes_mod_order(&digest);
     FpVar::new_witness(ns!(cs, "elem"), || Ok(fp))
 }
@@ -83,6 +82,15 @@ pub struct MerkleTreeCircuitParams {
     pub num_portals_per_subcircuit: usize,
 }
 
+impl MerkleTreeCircuitParams {
+    /// The number of leaves of the smallest tree with at least `num_subcircuits` subcircuits. A
+    /// tree is complete and has one padding subcircuit, so it has twice as many subcircuits as
+    /// leaves.
+    pub fn num_leaves_for(num_subcircuits: usize) -> usize {
+        num_subcircuits.div_ceil(2).next_power_of_two()
+    }
+}
+
 impl std::fmt::Display for MerkleTreeCircuitParams {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
@@ -112,154 +120,16 @@ impl MerkleTreeCircuit {
         // Convert the final digest to a field element
         digest_to_fpvar(digest)
     }
-}
-
-impl<F: PrimeField> CircuitWithPortals<F> for MerkleTreeCircuit {
-    type Parameters = MerkleTreeCircuitParams;
-    const MEM_TYPE: MemType = MemType::Rom;
-    type ProverPortalManager = RomProverPortalManager<F>;
-
-    fn num_subcircuits(&self) -> usize {
-        // A tree has 2l - 1 nodes where l is the number of leaves. We pad with 1 extra circuit to
-        // get to a power of two
-        2 * self.leaves.len()
-    }
-
-    /// Returns a minimal set of the unique subcircuits in this circuit. This is for CRS generation.
-    fn get_unique_subcircuits(&self) -> Vec<usize> {
-        let n = CircuitWithPortals::<F>::num_subcircuits(self);
-
-        // Indices for first leaf, second leaf, padding, root, and second to last parent
-        vec![0, 1, n - 1, n - 2, n - 3]
-    }
-
-    /// Maps a subcircuit index to its canonical representative in the list of unique subcircuits returned by `get_unique_subcircuits`.
-    fn representative_subcircuit(&self, subcircuit_idx: usize) -> usize {
-        let n = CircuitWithPortals::<F>::num_subcircuits(self);
-
-        if subcircuit_idx == 0 {
-            0
-        } else if (1..n / 2).contains(&subcircuit_idx) {
-            1
-        } else if (n / 2..=n - 3).contains(&subcircuit_idx) {
-            n - 3
-        } else if subcircuit_idx == n - 1 {
-            n - 1
-        } else if subcircuit_idx == n - 2 {
-            n - 2
-        } else {
-            panic!("subcircuit index out of range: {subcircuit_idx}");
-        }
-    }
-
-    fn get_params(&self) -> MerkleTreeCircuitParams {
-        self.params
-    }
-
-    /// Makes a Merkle tree with a random set of leaves. The size is given by `params`
-    fn rand(rng: &mut impl Rng, &params: &MerkleTreeCircuitParams) -> Self {
-        let mut leaves = vec![EMPTY_LEAF; params.num_leaves];
-        leaves.iter_mut().for_each(|l| rng.fill(l));
-        let root_hash = calculate_root(&leaves, params);
-        MerkleTreeCircuit {
-            leaves,
-            root_hash,
-            params,
-        }
-    }
-
-    // Make a new empty merkle tree circuit
-    fn new(&params: &Self::Parameters) -> Self {
-        assert!(
-            params.num_sha_iters_per_subcircuit > 0,
-            "cannot have 0 SHA256 iterations in test circuit"
-        );
-
-        let leaves = vec![EMPTY_LEAF; params.num_leaves];
-        // Set the default root hash
-        let root_hash = InnerHash::default();
-
-        MerkleTreeCircuit {
-            leaves,
-            root_hash,
-            params,
-        }
-    }
-
-    /// 根据子电路编号，返回该子电路对应的 witness（序列化为字节）
-    fn get_serialized_witnesses(&self, subcircuit_idx: usize) -> Vec<u8> {
-        let num_leaves = self.leaves.len();
-        let num_subcircuits = <Self as CircuitWithPortals<F>>::num_subcircuits(&self);
-        let mut out_buf = Vec::new();
-
-        // If this is a padding circuit, return nothing
-        // 占位子电路什么也不返回
-        if subcircuit_idx == num_subcircuits - 1 {
-            return Vec::with_capacity(0);
-        }
 
-        // The witnesses for subcircuit i is either a leaf value (in the case this is a leaf) or a
-        // root hash (if this is the root)
-        // 根据节点在树中的位置判断：若为叶子节点，则取出对应的叶子数据；
-        // 若为根节点，则返回根哈希；
-
-        // The subcircuit ordering is level by level. Pick the right node idx
-        let node_idx = subcircuit_idx_to_node_idx(subcircuit_idx, num_leaves);
-
-        let is_leaf = level(node_idx) == 0;
-        let is_root = root_idx(num_leaves) == node_idx;
-
-        // If this is a leaf, return the serialized leaf val
-        if is_leaf {
-            // Which number leaf is it
-            let leaf_idx = (node_idx / 2) as usize;
-            let leaf = self.leaves.get(leaf_idx).unwrap();
-            leaf.serialize_uncompressed(&mut out_buf).unwrap();
-        } else if is_root {
-            self.root_hash.serialize_uncompressed(&mut out_buf).unwrap();
-        }
-        println!("######outbut:{:?}",out_buf);
-        out_buf
-    }
-
-    /// 根据提供的字节，设置相应子电路的 witness 状态
-    /// 同样根据节点属性（叶子或根），从序列化字节恢复出 witness，并更新到电路状态中。
-    fn set_serialized_witnesses(&mut self, subcircuit_idx: usize, bytes: &[u8]) {
-        let num_leaves = self.leaves.len();
-        let num_subcircuits = <Self as CircuitWithPortals<F>>::num_subcircuits(&self);
-
-        // If this is a padding circuit, do nothing
-        if subcircuit_idx == num_subcircuits - 1 {
-            return;
-        }
-
-        // The witnesses for subcircuit i is either a leaf value (in the case this is a leaf) or a
-        // root hash (if this is the root)
-
-        // The subcircuit ordering is level by level. Pick the right node idx
-        let node_idx = subcircuit_idx_to_node_idx(subcircuit_idx, num_leaves);
-
-        let is_leaf = level(node_idx) == 0;
-        let is_root = root_idx(num_leaves) == node_idx;
-
-        // If this is a leaf, return the serialized leaf val
-        if is_leaf {
-            // Which number leaf is it
-            let leaf_idx = (node_idx / 2) as usize;
-            let leaf = TestLeaf::deserialize_uncompressed_unchecked(bytes).unwrap();
-            self.leaves[leaf_idx] = leaf;
-        } else if is_root {
-            self.root_hash = InnerHash::deserialize_uncompressed_unchecked(bytes).unwrap();
-        }
-        println!("$$$$$leaf:{:?}",self.leaves);
-        println!("%%%%%root:{:?}",self.root_hash);
-    }
-
-    fn generate_constraints<P: PortalManager<F>>(
+    /// Generates constraints for the given subcircuit. If this is the root subcircuit, the computed
+    /// root is checked against `expected_root` if given, and against a witnessed `self.root_hash`
+    /// otherwise.
+    fn generate_constraints_with_root<F: PrimeField, P: PortalManager<F>>(
         &mut self,
         cs: ConstraintSystemRef<F>,
         subcircuit_idx: usize,
         pm: &mut P,
+        expected_root: Option<&FpVar<F>>,
     ) -> Result<(), SynthesisError> {
         let starting_num_constraints = cs.num_constraints();
 
@@ -298,6 +168,15 @@ impl<F: PrimeField> CircuitWithPortals<F> for MerkleTreeCircuit {
                 // Compute the leaf hash and store it in the portal manager
                 let leaf_hash = self.iterated_sha256(&leaf_var)?;
                 pm.set(format!("node {node_idx} hash"), &leaf_hash)?;
+
+                // A tree with a single leaf has the leaf as its root
+                if is_root {
+                    let expected_root_hash = match expected_root {
+                        Some(root) => root.clone(),
+                        None => input_digest(cs.clone(), self.root_hash)?,
+                    };
+                    leaf_hash.enforce_equal(&expected_root_hash)?;
+                }
             } else {
                 // 对于非根的父节点：
                 // 1. 从 portal manager 中获取左右孩子节点的哈希
@@ -322,7 +201,10 @@ impl<F: PrimeField> CircuitWithPortals<F> for MerkleTreeCircuit {
                 // value
                 // 如果当前子电路正好代表根节点，则 verify 计算出的父节点哈希必须等于公开的根哈希
                 if is_root {
-                    let expected_root_hash = input_digest(cs.clone(), self.root_hash)?;
+                    let expected_root_hash = match expected_root {
+                        Some(root) => root.clone(),
+                        None => input_digest(cs.clone(), self.root_hash)?,
+                    };
                     parent_hash.enforce_equal(&expected_root_hash)?;
                 }
             }
@@ -353,9 +235,14 @@ impl<F: PrimeField> CircuitWithPortals<F> for MerkleTreeCircuit {
         Ok(())
     }
 
-    // This produces the same portal trace as generate_constraints(0...num_circuits) would do, but
-    // without having to do all the ZK SHA2 computations
-    fn get_portal_subtraces(&self) -> Vec<Vec<TranscriptEntry<F>>> {
+    /// Computes the portal subtrace of the given subcircuit, like `get_portal_subtraces` does. The
+    /// subcircuits must be done in order, since a parent gets the hashes its children set in `pm`.
+    fn portal_subtrace<F: PrimeField>(
+        &self,
+        cs: &ConstraintSystemRef<F>,
+        pm: &mut SetupRomPortalManager<F>,
+        subcircuit_idx: usize,
+    ) -> Vec<TranscriptEntry<F>> {
         let num_leaves = self.leaves.len();
         let num_subcircuits = num_leaves * 2;
 
@@ -373,29 +260,18 @@ impl<F: PrimeField> CircuitWithPortals<F> for MerkleTreeCircuit {
             outbuf
         };
 
-        // Helper lambda to do the dummy operations at the end of every subcircuit
-        let do_dummy_ops = |pm: &mut SetupRomPortalManager<F>| {
-            // Now do the remaining placeholder ops
-            for _ in 0..self.params.num_portals_per_subcircuit - 1 {
-                let _ = pm.get("placeholder").unwrap();
-            }
-        };
-
-        // Make a portal manager to collect the subtraces
-        let cs = ConstraintSystem::new_ref();
-        let mut pm = SetupRomPortalManager::new(cs.clone());
+        // Every subcircuit gets its own subtrace
+        pm.start_subtrace(ConstraintSystem::new_ref());
 
-        // Hash every leaf and compute the SET operation for it
-        // 对所有叶子，依次启动一个新的子轨迹
-        // 对每个叶子，计算其哈希值，将值转换为有限域变量，并存储在 portal manager 中。同时执行占位的 set 和 dummy get 操作。
-        for (subcircuit_idx, leaf) in self.leaves.iter().enumerate() {
-            // Every leaf is its own subcircuit, so it gets its own subtrace
-            pm.start_subtrace(ConstraintSystem::new_ref());
-            let leaf_hash = iterated_sha256(leaf);
+        if subcircuit_idx < num_leaves {
+            // Hash the leaf and compute the SET operation for it
+            // 对每个叶子，计算其哈希值，将值转换为有限域变量，并存储在 portal manager 中。同时执行占位的 set 和 dummy get 操作。
+            let leaf_hash = iterated_sha256(&self.leaves[subcircuit_idx]);
 
             // Compute the label and value corresponding to this portal wire
             let node_idx: u32 = subcircuit_idx_to_node_idx(subcircuit_idx, num_leaves);
-            let leaf_hash_var: Vec<UInt8<F>> = UInt8::new_witness_vec(ns!(cs, "leaf hash"), &leaf_hash).unwrap();
+            let leaf_hash_var: Vec<UInt8<F>> =
+                UInt8::new_witness_vec(ns!(cs, "leaf hash"), &leaf_hash).unwrap();
             let leaf_hash_fpvar: FpVar<F> = digest_to_fpvar(DigestVar(leaf_hash_var)).unwrap();
 
             // Set the value
@@ -412,18 +288,12 @@ impl<F: PrimeField> CircuitWithPortals<F> for MerkleTreeCircuit {
                     )
                     .unwrap();
             }
-
-            do_dummy_ops(&mut pm);
-        }
-
-        // Now go through all the parents, including the root node
-        // 对中间节点（包括根）：
-        // 启动新子轨迹，通过 portal manager 获取左右子节点的哈希，
-        // 将左右子节点哈希转换回字节，再迭代计算父节点哈希，
-        // 将父节点的哈希存入 portal manager 中，并执行 dummy 占位操作
-        for subcircuit_idx in self.leaves.len()..(num_subcircuits - 1) {
-            pm.start_subtrace(ConstraintSystem::new_ref());
-
+        } else if subcircuit_idx < num_subcircuits - 1 {
+            // This is a parent, including the root node
+            // 对中间节点（包括根）：
+            // 通过 portal manager 获取左右子节点的哈希，
+            // 将左右子节点哈希转换回字节，再迭代计算父节点哈希，
+            // 将父节点的哈希存入 portal manager 中
             let node_idx = subcircuit_idx_to_node_idx(subcircuit_idx, num_leaves);
             let left = left_child(node_idx);
             let right = right_child(node_idx);
@@ -456,29 +326,221 @@ impl<F: PrimeField> CircuitWithPortals<F> for MerkleTreeCircuit {
             // Set the value in the portal manager
             pm.set(format!("node {node_idx} hash"), &parent_hash_fpvar)
                 .unwrap();
-
-            // Now do the dummy ops
-            do_dummy_ops(&mut pm);
         }
+        // The padding node is only dummy ops
 
-        // Now do the padding node. This is only dummy ops
-        // 将 padding 子电路的 dummy 操作也加入进去。
-        pm.start_subtrace(ConstraintSystem::new_ref());
-        do_dummy_ops(&mut pm);
+        // Now do the remaining placeholder ops at the end of every subcircuit
+        // 将 dummy 占位操作加入进去。
+        for _ in 0..self.params.num_portals_per_subcircuit - 1 {
+            let _ = pm.get("placeholder").unwrap();
+        }
 
-        // Return the subtraces, wrapped appropriately
-        pm.subtraces
+        // Return the subtrace, wrapped appropriately
+        pm.take_subtrace()
             .into_iter()
-            .map(|subtrace| {
-                subtrace
-                    .into_iter()
-                    .map(|e| TranscriptEntry::Rom(e))
-                    .collect()
-            })
+            .map(|e| TranscriptEntry::Rom(e))
             .collect()
     }
 }
 
+impl<F: PrimeField> CircuitWithPortals<F> for MerkleTreeCircuit {
+    type Parameters = MerkleTreeCircuitParams;
+    const MEM_TYPE: MemType = MemType::Rom;
+    type ProverPortalManager = RomProverPortalManager<F>;
+
+    fn num_subcircuits(&self) -> usize {
+        // A tree has 2l - 1 nodes where l is the number of leaves. We pad with 1 extra circuit to
+        // get to a power of two
+        2 * self.leaves.len()
+    }
+
+    /// Returns a minimal set of the unique subcircuits in this circuit. This is for CRS generation.
+    fn get_unique_subcircuits(&self) -> Vec<usize> {
+        let n = CircuitWithPortals::<F>::num_subcircuits(self);
+
+        // Indices for first leaf, second leaf, padding, root, and second to last parent. Small
+        // trees don't have all of these, so some of the indices coincide
+        let mut idxs = vec![0, 1, n - 1, n - 2, n.saturating_sub(3)];
+        idxs.sort_unstable();
+        idxs.dedup();
+        idxs
+    }
+
+    /// Maps a subcircuit index to its canonical representative in the list of unique subcircuits returned by `get_unique_subcircuits`.
+    fn representative_subcircuit(&self, subcircuit_idx: usize) -> usize {
+        let n = CircuitWithPortals::<F>::num_subcircuits(self);
+
+        if subcircuit_idx == 0 {
+            0
+        } else if subcircuit_idx == n - 1 {
+            n - 1
+        } else if subcircuit_idx == n - 2 {
+            n - 2
+        } else if (1..n / 2).contains(&subcircuit_idx) {
+            1
+        } else if (n / 2..n - 2).contains(&subcircuit_idx) {
+            n - 3
+        } else {
+            panic!("subcircuit index out of range: {subcircuit_idx}");
+        }
+    }
+
+    fn get_params(&self) -> MerkleTreeCircuitParams {
+        self.params
+    }
+
+    /// Makes a Merkle tree with a random set of leaves. The size is given by `params`
+    fn rand(rng: &mut impl Rng, &params: &MerkleTreeCircuitParams) -> Self {
+        let mut leaves = vec![EMPTY_LEAF; params.num_leaves];
+        leaves.iter_mut().for_each(|l| rng.fill(l));
+        let root_hash = calculate_root(&leaves, params);
+        MerkleTreeCircuit {
+            leaves,
+            root_hash,
+            params,
+        }
+    }
+
+    // Make a new empty merkle tree circuit
+    fn new(&params: &Self::Parameters) -> Self {
+        assert!(
+            params.num_sha_iters_per_subcircuit > 0,
+            "cannot have 0 SHA256 iterations in test circuit"
+        );
+
+        let leaves = vec![EMPTY_LEAF; params.num_leaves];
+        // Set the default root hash
+        let root_hash = InnerHash::default();
+
+        MerkleTreeCircuit {
+            leaves,
+            root_hash,
+            params,
+        }
+    }
+
+    /// 根据子电路编号，返回该子电路对应的 witness（序列化为字节）
+    fn get_serialized_witnesses(&self, subcircuit_idx: usize) -> Vec<u8> {
+        let num_leaves = self.leaves.len();
+        let num_subcircuits = <Self as CircuitWithPortals<F>>::num_subcircuits(&self);
+        let mut out_buf = Vec::new();
+
+        // If this is a padding circuit, return nothing
+        // 占位子电路什么也不返回
+        if subcircuit_idx == num_subcircuits - 1 {
+            return Vec::with_capacity(0);
+        }
+
+        // The witnesses for subcircuit i is either a leaf value (in the case this is a leaf) or a
+        // root hash (if this is the root)
+        // 根据节点在树中的位置判断：若为叶子节点，则取出对应的叶子数据；
+        // 若为根节点，则返回根哈希；
+
+        // The subcircuit ordering is level by level. Pick the right node idx
+        let node_idx = subcircuit_idx_to_node_idx(subcircuit_idx, num_leaves);
+
+        let is_leaf = level(node_idx) == 0;
+        let is_root = root_idx(num_leaves) == node_idx;
+
+        // If this is a leaf, return the serialized leaf val
+        if is_leaf {
+            // Which number leaf is it
+            let leaf_idx = (node_idx / 2) as usize;
+            let leaf = self.leaves.get(leaf_idx).unwrap();
+            leaf.serialize_uncompressed(&mut out_buf).unwrap();
+        }
+        // A tree with a single leaf has the leaf as its root, so it gets both
+        if is_root {
+            self.root_hash.serialize_uncompressed(&mut out_buf).unwrap();
+        }
+        println!("######outbut:{:?}", out_buf);
+        out_buf
+    }
+
+    /// 根据提供的字节，设置相应子电路的 witness 状态
+    /// 同样根据节点属性（叶子或根），从序列化字节恢复出 witness，并更新到电路状态中。
+    fn set_serialized_witnesses(&mut self, subcircuit_idx: usize, mut bytes: &[u8]) {
+        let num_leaves = self.leaves.len();
+        let num_subcircuits = <Self as CircuitWithPortals<F>>::num_subcircuits(&self);
+
+        // If this is a padding circuit, do nothing
+        if subcircuit_idx == num_subcircuits - 1 {
+            return;
+        }
+
+        // The witnesses for subcircuit i is either a leaf value (in the case this is a leaf) or a
+        // root hash (if this is the root)
+
+        // The subcircuit ordering is level by level. Pick the right node idx
+        let node_idx = subcircuit_idx_to_node_idx(subcircuit_idx, num_leaves);
+
+        let is_leaf = level(node_idx) == 0;
+        let is_root = root_idx(num_leaves) == node_idx;
+
+        // If this is a leaf, return the serialized leaf val
+        if is_leaf {
+            // Which number leaf is it
+            let leaf_idx = (node_idx / 2) as usize;
+            let leaf = TestLeaf::deserialize_uncompressed_unchecked(&mut bytes).unwrap();
+            self.leaves[leaf_idx] = leaf;
+        }
+        if is_root {
+            self.root_hash = InnerHash::deserialize_uncompressed_unchecked(&mut bytes).unwrap();
+        }
+        println!("$$$$$leaf:{:?}", self.leaves);
+        println!("%%%%%root:{:?}", self.root_hash);
+    }
+
+    fn generate_constraints<P: PortalManager<F>>(
+        &mut self,
+        cs: ConstraintSystemRef<F>,
+        subcircuit_idx: usize,
+        pm: &mut P,
+    ) -> Result<(), SynthesisError> {
+        self.generate_constraints_with_root(cs, subcircuit_idx, pm, None)
+    }
+
+    /// The public input is the root hash, interpreted as a field element
+    fn public_inputs(&self) -> Vec<F> {
+        vec![F::from_le_bytes_mod_order(&self.root_hash)]
+    }
+
+    /// The root subcircuit is the one that checks the root hash
+    fn public_input_subcircuit(&self) -> usize {
+        CircuitWithPortals::<F>::num_subcircuits(self) - 2
+    }
+
+    fn generate_constraints_with_public_inputs<P: PortalManager<F>>(
+        &mut self,
+        cs: ConstraintSystemRef<F>,
+        subcircuit_idx: usize,
+        pm: &mut P,
+        public_inputs: &[FpVar<F>],
+    ) -> Result<(), SynthesisError> {
+        self.generate_constraints_with_root(cs, subcircuit_idx, pm, Some(&public_inputs[0]))
+    }
+
+    // This produces the same portal trace as generate_constraints(0...num_circuits) would do, but
+    // without having to do all the ZK SHA2 computations
+    fn get_portal_subtraces(&self) -> Vec<Vec<TranscriptEntry<F>>> {
+        self.portal_subtraces_iter().collect()
+    }
+
+    /// Makes the subtraces one subcircuit at a time. A parent only needs the hashes its children
+    /// set, which the portal manager keeps, so a subtrace isn't kept once it's yielded.
+    fn portal_subtraces_iter(&self) -> Box<dyn Iterator<Item = Vec<TranscriptEntry<F>>> + '_> {
+        // Make a portal manager to collect the subtraces
+        let cs = ConstraintSystem::new_ref();
+        let mut pm = SetupRomPortalManager::new(cs.clone());
+
+        let num_subcircuits = CircuitWithPortals::<F>::num_subcircuits(self);
+        Box::new(
+            (0..num_subcircuits)
+                .map(move |subcircuit_idx| self.portal_subtrace(&cs, &mut pm, subcircuit_idx)),
+        )
+    }
+}
+
 // Calculates the Merkle tree root in the same way as is calculated above. That is, truncating each
 // hash to INNER_HASH_SIZE bytes, and computing parents as H(left || right).
 pub(crate) fn calculate_root(leaves: &[TestLeaf], params: MerkleTreeCircuitParams) -> InnerHash {
@@ -615,28 +677,34 @@ mod test {
     #[test]
     fn test_merkle_tree_correctness() {
         let mut rng = test_rng();
-        let circ_params = MerkleTreeCircuitParams {
-            num_leaves: 16,
-            num_sha_iters_per_subcircuit: 2,
-            num_portals_per_subcircuit: 7,
-        };
 
-        // Make a random Merkle tree
-        let mut circ = <MerkleTreeCircuit as CircuitWithPortals<Fr>>::rand(&mut rng, &circ_params);
+        // Include the trees with 1 and 2 leaves, where the root is a leaf or a parent of leaves
+        for num_leaves in [1, 2, 16] {
+            let circ_params = MerkleTreeCircuitParams {
+                num_leaves,
+                num_sha_iters_per_subcircuit: 2,
+                num_portals_per_subcircuit: 7,
+            };
+
+            // Make a random Merkle tree
+            let mut circ =
+                <MerkleTreeCircuit as CircuitWithPortals<Fr>>::rand(&mut rng, &circ_params);
+
+            // Make a fresh portal manager
+            let cs = ConstraintSystemRef::<Fr>::new(ConstraintSystem::default());
+            let mut pm = SetupRomPortalManager::new(cs.clone());
+            // Make it all one subtrace. We're not really testing this part
+            pm.start_subtrace(cs.clone());
 
-        // Make a fresh portal manager
-        let cs = ConstraintSystemRef::<Fr>::new(ConstraintSystem::default());
-        let mut pm = SetupRomPortalManager::new(cs.clone());
-        // Make it all one subtrace. We're not really testing this part
-        pm.start_subtrace(cs.clone());
+            let num_subcircuits =
+                <MerkleTreeCircuit as CircuitWithPortals<Fr>>::num_subcircuits(&circ);
+            for subcircuit_idx in 0..num_subcircuits {
+                circ.generate_constraints(cs.clone(), subcircuit_idx, &mut pm)
+                    .unwrap();
+            }
 
-        let num_subcircuits = <MerkleTreeCircuit as CircuitWithPortals<Fr>>::num_subcircuits(&circ);
-        for subcircuit_idx in 0..num_subcircuits {
-            circ.generate_constraints(cs.clone(), subcircuit_idx, &mut pm)
-                .unwrap();
+            assert!(cs.is_satisfied().unwrap());
         }
-
-        assert!(cs.is_satisfied().unwrap());
     }
 
     // The other way of getting the portal trace is by just running the full circuit. This is very
@@ -2,6 +2,8 @@ use std::{
     fs::File, io::{self, Read, Write}, os, path::PathBuf
 };
 
+use crate::CircuitWithPortals;
+
 use ark_ff::PrimeField;
 use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
 use ark_std::{end_timer, start_timer};
@@ -12,17 +14,78 @@ pub use ark_cp_groth16::{
     committer::CommitmentBuilder as G16CommitmentBuilder,
     data_structures::{
         Comm as G16Com, CommRandomness as G16ComRandomness, CommitterKey as G16ComKey,
-        Proof as G16Proof, ProvingKey as G16ProvingKey,
+        PreparedVerifyingKey as G16PreparedVerifyingKey, Proof as G16Proof,
+        ProvingKey as G16ProvingKey,
     },
     r1cs_to_qap::LibsnarkReduction as QAP,
+    verifier::prepare_verifying_key as prepare_g16_verifying_key,
 };
 pub use merlin::Transcript as ProtoTranscript;
 use rayon::iter::{IndexedParallelIterator, IntoParallelIterator, ParallelIterator};
+use sha2::{Digest, Sha256};
 
 /// A seed used for the RNG in stage 0 commitments. Each worker saves this and redoes the
 /// commitment once it's asked to do stage 1
 pub type G16ComSeed = [u8; 32];
 
+/// A SHA-256 digest of the parameters of a circuit. This binds a proof to the circuit it was made
+/// for
+pub type CircParamsDigest = [u8; 32];
+
+/// Computes the digest of the given circuit parameters
+pub fn digest_circ_params<S: CanonicalSerialize>(circ_params: &S) -> CircParamsDigest {
+    let mut buf = Vec::new();
+    circ_params
+        .serialize_uncompressed(&mut buf)
+        .expect("failed to serialize circuit params");
+    Sha256::digest(&buf).into()
+}
+
+/// A circuit with all of its witnesses filled in, in a form that can be written to a file. The
+/// witnesses are the ones given by `get_serialized_witnesses`, so the circuit is rebuilt with its
+/// own `set_serialized_witnesses`.
+#[derive(Clone, CanonicalSerialize, CanonicalDeserialize)]
+pub struct CircuitInstance {
+    pub serialized_circ_params: Vec<u8>,
+    pub all_serialized_witnesses: Vec<Vec<u8>>,
+}
+
+impl CircuitInstance {
+    pub fn new<F: PrimeField, P: CircuitWithPortals<F>>(circ: &P) -> Self {
+        let mut serialized_circ_params = Vec::new();
+        circ.get_params()
+            .serialize_uncompressed(&mut serialized_circ_params)
+            .unwrap();
+        let all_serialized_witnesses = (0..circ.num_subcircuits())
+            .map(|idx| circ.get_serialized_witnesses(idx))
+            .collect();
+
+        CircuitInstance {
+            serialized_circ_params,
+            all_serialized_witnesses,
+        }
+    }
+
+    /// Rebuilds the circuit. Panics if the instance is not one of a `P` circuit.
+    pub fn to_circuit<F: PrimeField, P: CircuitWithPortals<F>>(&self) -> P {
+        let circ_params = P::Parameters::deserialize_uncompressed_unchecked(
+            self.serialized_circ_params.as_slice(),
+        )
+        .expect("malformed circuit params in instance");
+        let mut circ = P::new(&circ_params);
+        assert_eq!(
+            circ.num_subcircuits(),
+            self.all_serialized_witnesses.len(),
+            "instance has the wrong number of subcircuits"
+        );
+
+        for (idx, wits) in self.all_serialized_witnesses.iter().enumerate() {
+            circ.set_serialized_witnesses(idx, wits);
+        }
+        circ
+    }
+}
+
 pub(crate) fn log2(x: usize) -> usize {
     // We set log2(0) == 0
     if x == 0 {
@@ -83,6 +146,7 @@ pub mod cli_filenames {
     pub const STAGE1_REQ_FILENAME_PREFIX: &str = "stage1_req";
     pub const STAGE1_RESP_FILENAME_PREFIX: &str = "stage1_resp";
     pub const TEST_CIRC_PARAM_FILENAME_PREFIX: &str = "test_circ_params";
+    pub const CIRC_ID_FILENAME_PREFIX: &str = "circ_id";
     pub const STAGE0_COORD_STATE_FILENAME_PREFIX: &str = "stage0_coordinator_state";
     pub const FINAL_AGG_STATE_FILENAME_PREFIX: &str = "final_aggregator_state";
     pub const FINAL_PROOF_PREFIX: &str = "agg_proof";
@@ -4,8 +4,8 @@ use std::{collections::HashMap, error::Error as ErrorTrait, fmt, marker::Phantom
 
 use crate::vkd::hash::*;
 use crate::vkd::util::split;
-use ark_bls12_381::Fr;
-use ark_crypto_primitives::Error;
+use ark_crypto_primitives::{sponge::Absorb, Error};
+use ark_ff::PrimeField;
 use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
 use num_bigint::BigUint;
 use num_traits::{One, Zero};
@@ -46,34 +46,34 @@ pub trait MerkleTreeParameters {
 }
 
 #[derive(Clone)]
-pub struct SparseMerkleTree<P: MerkleTreeParameters> {
+pub struct SparseMerkleTree<P: MerkleTreeParameters, F: PrimeField> {
     pub tree: HashMap<MerkleIndex, InnerHash>,
     pub leaves: HashMap<MerkleIndex, Vec<u8>>,
     pub root: InnerHash,
     pub sparse_initial_hashes: Vec<InnerHash>,
     _parameters1: PhantomData<P>,
-    _parameters2: PhantomData<Fr>,
+    _parameters2: PhantomData<F>,
 }
 
-impl<P: MerkleTreeParameters> Display for SparseMerkleTree<P> {
+impl<P: MerkleTreeParameters, F: PrimeField> Display for SparseMerkleTree<P, F> {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         write!(f, "(leaves: {:?})", self.leaves.keys())
     }
 }
 
-impl<P: MerkleTreeParameters> Default for SparseMerkleTree<P> {
+impl<P: MerkleTreeParameters, F: PrimeField + Absorb> Default for SparseMerkleTree<P, F> {
     fn default() -> Self {
         SparseMerkleTree::new().unwrap()
     }
 }
 
-impl<P: MerkleTreeParameters> SparseMerkleTree<P> {
+impl<P: MerkleTreeParameters, F: PrimeField + Absorb> SparseMerkleTree<P, F> {
     pub fn new() -> Result<Self, Error> {
         // Compute initial hashes for each depth of tree
-        let mut sparse_initial_hashes = vec![hash_leaf(&INITIAL_LEAF_VALUE)?];
+        let mut sparse_initial_hashes = vec![hash_leaf::<F>(&INITIAL_LEAF_VALUE)?];
         for i in 1..=P::DEPTH {
             let child_hash = sparse_initial_hashes[i - 1].clone();
-            sparse_initial_hashes.push(hash_inner_node(&child_hash, &child_hash)?);
+            sparse_initial_hashes.push(hash_inner_node::<F>(&child_hash, &child_hash)?);
         }
         sparse_initial_hashes.reverse();
 
@@ -107,7 +107,7 @@ impl<P: MerkleTreeParameters> SparseMerkleTree<P> {
             NodeType::InternalNode => InnerHash::try_from(value).unwrap(),
             NodeType::Leaf => {
                 self.leaves.insert(index.clone(), value.to_vec());
-                hash_leaf(value)?
+                hash_leaf::<F>(value)?
             },
         };
         // insert the node inside the tree
@@ -129,8 +129,10 @@ impl<P: MerkleTreeParameters> SparseMerkleTree<P> {
                 index: i.clone(),
                 depth: d,
             };
-            self.tree
-                .insert(temp.clone(), hash_inner_node(&(lc_hash.0), &(rc_hash.0))?);
+            self.tree.insert(
+                temp.clone(),
+                hash_inner_node::<F>(&(lc_hash.0), &(rc_hash.0))?,
+            );
         }
         let temp = MerkleIndex {
             index: BigUint::zero(),
@@ -163,7 +165,6 @@ impl<P: MerkleTreeParameters> SparseMerkleTree<P> {
         Ok(MerkleTreePath {
             path,
             _parameters1: PhantomData,
-            _parameters2: PhantomData,
         })
     }
 
@@ -181,7 +182,6 @@ pub struct MerkleTreePath<P: MerkleTreeParameters> {
     pub path: Vec<InnerHash>,
     // pretty smart to avoid multi-thread problems ==> https://stackoverflow.com/questions/50200197/how-do-i-share-a-struct-containing-a-phantom-pointer-among-threads
     pub _parameters1: PhantomData<fn() -> P>,
-    pub _parameters2: PhantomData<fn() -> Fr>,
 }
 
 impl<P: MerkleTreeParameters> Clone for MerkleTreePath<P> {
@@ -189,7 +189,6 @@ impl<P: MerkleTreeParameters> Clone for MerkleTreePath<P> {
         Self {
             path: self.path.clone(),
             _parameters1: PhantomData,
-            _parameters2: PhantomData,
         }
     }
 }
@@ -199,13 +198,13 @@ impl<P: MerkleTreeParameters> Default for MerkleTreePath<P> {
         Self {
             path: vec![InnerHash::default(); P::DEPTH],
             _parameters1: PhantomData,
-            _parameters2: PhantomData,
         }
     }
 }
 
 impl<P: MerkleTreeParameters> MerkleTreePath<P> {
-    pub fn compute_root(
+    /// Computes the root of the tree over `F` that this path is in
+    pub fn compute_root<F: PrimeField + Absorb>(
         &self,
         value: &[u8],
         index: &Vec<bool>,
@@ -213,25 +212,25 @@ impl<P: MerkleTreeParameters> MerkleTreePath<P> {
     ) -> Result<InnerHash, Error> {
         let mut current_hash = match node_type {
             NodeType::InternalNode => InnerHash::try_from(value).unwrap(),
-            NodeType::Leaf => hash_leaf(value)?,
+            NodeType::Leaf => hash_leaf::<F>(value)?,
         };
         for (i, sibling_hash) in self.path.iter().enumerate() {
             current_hash = match index[i] {
-                true => hash_inner_node(&current_hash, sibling_hash)?,
-                false => hash_inner_node(sibling_hash, &current_hash)?,
+                true => hash_inner_node::<F>(&current_hash, sibling_hash)?,
+                false => hash_inner_node::<F>(sibling_hash, &current_hash)?,
             };
         }
         Ok(current_hash)
     }
 
-    pub fn verify(
+    pub fn verify<F: PrimeField + Absorb>(
         &self,
         root: &InnerHash,
         value: &[u8],
         index: &Vec<bool>,
         node_type: NodeType,
     ) -> Result<bool, Error> {
-        Ok(self.compute_root(value, index, node_type)? == *root)
+        Ok(self.compute_root::<F>(value, index, node_type)? == *root)
     }
 }
 
@@ -243,7 +242,6 @@ impl<P: MerkleTreeParameters> MerkleTreePath<P> {
             .map(|path| MerkleTreePath {
                 path,
                 _parameters1: PhantomData,
-                _parameters2: PhantomData,
             })
             .collect())
     }
@@ -295,6 +293,7 @@ impl Display for MerkleTreeError {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use ark_bls12_381::Fr;
 
     #[derive(Clone)]
     pub struct MerkleTreeTestParameters;
@@ -303,17 +302,14 @@ mod tests {
         const DEPTH: usize = 64;
     }
 
-    type TestMerkleTree = SparseMerkleTree<MerkleTreeTestParameters>;
+    type TestMerkleTree = SparseMerkleTree<MerkleTreeTestParameters, Fr>;
 
     #[test]
     fn insert_test() {
         let mut merkle_tree = TestMerkleTree::new().unwrap();
         let first_leaf = [1_u8; 32];
-        let first_index = SparseMerkleTree::<MerkleTreeTestParameters>::get_index(
-            &first_leaf,
-            MerkleTreeTestParameters::DEPTH,
-        )
-        .unwrap();
+        let first_index =
+            TestMerkleTree::get_index(&first_leaf, MerkleTreeTestParameters::DEPTH).unwrap();
 
         // update the tree
         merkle_tree
@@ -321,7 +317,7 @@ mod tests {
             .expect("insertion error");
         let path_to_first_leaf = merkle_tree.lookup_path(&first_index).unwrap();
         assert!(path_to_first_leaf
-            .verify(
+            .verify::<Fr>(
                 &merkle_tree.root,
                 &first_leaf,
                 &first_index.to_bit_vector(),
@@ -330,11 +326,8 @@ mod tests {
             .expect("path verification error"));
 
         let second_leaf = [9_u8; 32];
-        let second_index = SparseMerkleTree::<MerkleTreeTestParameters>::get_index(
-            &second_leaf,
-            MerkleTreeTestParameters::DEPTH,
-        )
-        .unwrap();
+        let second_index =
+            TestMerkleTree::get_index(&second_leaf, MerkleTreeTestParameters::DEPTH).unwrap();
 
         // update the tree
         merkle_tree
@@ -342,7 +335,7 @@ mod tests {
             .expect("insertion error");
         let path_to_second_leaf = merkle_tree.lookup_path(&second_index).unwrap();
         assert!(path_to_second_leaf
-            .verify(
+            .verify::<Fr>(
                 &merkle_tree.root,
                 &second_leaf,
                 &second_index.to_bit_vector(),
@@ -351,12 +344,9 @@ mod tests {
             .expect("path verification error"));
 
         let third_leaf = [10_u8; 32];
-        let third_leaf_hash = hash_leaf(&third_leaf).unwrap();
-        let third_index = SparseMerkleTree::<MerkleTreeTestParameters>::get_index(
-            &third_leaf,
-            MerkleTreeTestParameters::DEPTH,
-        )
-        .unwrap();
+        let third_leaf_hash = hash_leaf::<Fr>(&third_leaf).unwrap();
+        let third_index =
+            TestMerkleTree::get_index(&third_leaf, MerkleTreeTestParameters::DEPTH).unwrap();
 
         // update the tree
         merkle_tree
@@ -366,7 +356,7 @@ mod tests {
         let path_to_third_leaf = merkle_tree.lookup_path(&third_index).unwrap();
 
         assert!(path_to_third_leaf
-            .verify(
+            .verify::<Fr>(
                 &merkle_tree.root,
                 &third_leaf_hash,
                 &third_index.to_bit_vector(),
@@ -380,11 +370,8 @@ mod tests {
     fn test_split() {
         let mut merkle_tree = TestMerkleTree::new().unwrap();
         let first_leaf = [1_u8; 32];
-        let first_index = SparseMerkleTree::<MerkleTreeTestParameters>::get_index(
-            &first_leaf,
-            MerkleTreeTestParameters::DEPTH,
-        )
-        .unwrap();
+        let first_index =
+            TestMerkleTree::get_index(&first_leaf, MerkleTreeTestParameters::DEPTH).unwrap();
         // update the tree
         merkle_tree
             .insert(first_index.clone(), &first_leaf, NodeType::Leaf)
@@ -12,27 +12,30 @@ use crate::vkd::sparse_tree::{MerkleTreeParameters, MerkleTreePath};
 
 use crate::vkd::hash::{hash_inner_node_var, hash_leaf_var};
 use crate::vkd::util::*;
-use ark_bls12_381::Fr;
+use ark_crypto_primitives::sponge::Absorb;
+use ark_ff::PrimeField;
 use std::{borrow::Borrow, marker::PhantomData};
 
 #[derive(Clone)]
-pub struct MerkleTreePathVar<P>
+pub struct MerkleTreePathVar<P, F>
 where
     P: MerkleTreeParameters,
+    F: PrimeField,
 {
-    path: Vec<FpVar<Fr>>,
+    path: Vec<FpVar<F>>,
     _parameters: PhantomData<P>,
 }
 
-impl<P> MerkleTreePathVar<P>
+impl<P, F> MerkleTreePathVar<P, F>
 where
     P: MerkleTreeParameters,
+    F: PrimeField + Absorb,
 {
     pub fn compute_root_var_from_leaf(
         &self,
-        leaf: &Vec<UInt8<Fr>>,
-        index: &Vec<Boolean<Fr>>,
-    ) -> Result<FpVar<Fr>, SynthesisError> {
+        leaf: &Vec<UInt8<F>>,
+        index: &Vec<Boolean<F>>,
+    ) -> Result<FpVar<F>, SynthesisError> {
         let mut current_hash = hash_leaf_var(leaf)?;
         for (i, b) in index.iter().take(self.path.len()).enumerate() {
             let lc = FpVar::conditionally_select(b, &self.path[i], &current_hash)?;
@@ -44,9 +47,9 @@ where
 
     pub fn compute_root_var_from_internal_node(
         &self,
-        internal_node: &FpVar<Fr>,
-        index: &Vec<Boolean<Fr>>,
-    ) -> Result<FpVar<Fr>, SynthesisError> {
+        internal_node: &FpVar<F>,
+        index: &Vec<Boolean<F>>,
+    ) -> Result<FpVar<F>, SynthesisError> {
         let mut current_hash = internal_node.clone();
         for (i, b) in index.iter().take(self.path.len()).enumerate() {
             let lc = FpVar::conditionally_select(b, &self.path[i], &current_hash)?;
@@ -58,18 +61,18 @@ where
 
     pub fn check_path_from_leaf(
         &self,
-        root: &FpVar<Fr>,
-        leaf: &Vec<UInt8<Fr>>,
-        index: &Vec<Boolean<Fr>>,
+        root: &FpVar<F>,
+        leaf: &Vec<UInt8<F>>,
+        index: &Vec<Boolean<F>>,
     ) -> Result<(), SynthesisError> {
         self.conditional_check_path_from_leaf(root, leaf, index, &Boolean::constant(true))
     }
 
     pub fn check_path_from_internal_node(
         &self,
-        root: &FpVar<Fr>,
-        internal_node: &FpVar<Fr>,
-        index: &Vec<Boolean<Fr>>,
+        root: &FpVar<F>,
+        internal_node: &FpVar<F>,
+        index: &Vec<Boolean<F>>,
     ) -> Result<(), SynthesisError> {
         self.conditional_check_path_from_internal_node(
             root,
@@ -81,10 +84,10 @@ where
 
     pub fn conditional_check_path_from_leaf(
         &self,
-        root: &FpVar<Fr>,
-        leaf: &Vec<UInt8<Fr>>,
-        index: &Vec<Boolean<Fr>>,
-        condition: &Boolean<Fr>,
+        root: &FpVar<F>,
+        leaf: &Vec<UInt8<F>>,
+        index: &Vec<Boolean<F>>,
+        condition: &Boolean<F>,
     ) -> Result<(), SynthesisError> {
         let computed_root = self.compute_root_var_from_leaf(leaf, index)?;
         root.conditional_enforce_equal(&computed_root, condition)
@@ -92,22 +95,23 @@ where
 
     pub fn conditional_check_path_from_internal_node(
         &self,
-        root: &FpVar<Fr>,
-        internal_node: &FpVar<Fr>,
-        index: &Vec<Boolean<Fr>>,
-        condition: &Boolean<Fr>,
+        root: &FpVar<F>,
+        internal_node: &FpVar<F>,
+        index: &Vec<Boolean<F>>,
+        condition: &Boolean<F>,
     ) -> Result<(), SynthesisError> {
         let computed_root = self.compute_root_var_from_internal_node(internal_node, index)?;
         root.conditional_enforce_equal(&computed_root, condition)
     }
 }
 
-impl<P> AllocVar<MerkleTreePath<P>, Fr> for MerkleTreePathVar<P>
+impl<P, F> AllocVar<MerkleTreePath<P>, F> for MerkleTreePathVar<P, F>
 where
     P: MerkleTreeParameters,
+    F: PrimeField + Absorb,
 {
     fn new_variable<T: Borrow<MerkleTreePath<P>>>(
-        cs: impl Into<Namespace<Fr>>,
+        cs: impl Into<Namespace<F>>,
         f: impl FnOnce() -> Result<T, SynthesisError>,
         mode: AllocationMode,
     ) -> Result<Self, SynthesisError> {
@@ -131,6 +135,7 @@ mod tests {
     use crate::vkd::hash::hash;
     use crate::vkd::sparse_tree::*;
     use crate::vkd::vkd::DEPTH;
+    use ark_bls12_381::Fr;
     use ark_r1cs_std::uint64::UInt64;
     use ark_relations::ns;
     use ark_relations::r1cs::ConstraintSystem;
@@ -142,18 +147,16 @@ mod tests {
         const DEPTH: usize = DEPTH;
     }
 
-    type TestMerkleTree = SparseMerkleTree<MerkleTreeTestParameters>;
+    type TestMerkleTree = SparseMerkleTree<MerkleTreeTestParameters, Fr>;
 
     #[test]
     fn valid_path_constraints_test() {
         let mut tree = TestMerkleTree::new().unwrap();
         let leaf = [1_u8; 32];
-        let leaf_hash = hash(&leaf).unwrap();
-        let index = SparseMerkleTree::<MerkleTreeTestParameters>::get_index(
-            leaf_hash.as_slice(),
-            MerkleTreeTestParameters::DEPTH,
-        )
-        .unwrap();
+        let leaf_hash = hash::<Fr>(&leaf).unwrap();
+        let index =
+            TestMerkleTree::get_index(leaf_hash.as_slice(), MerkleTreeTestParameters::DEPTH)
+                .unwrap();
 
         tree.insert(index.clone(), &leaf, NodeType::Leaf).unwrap();
 
@@ -161,23 +164,21 @@ mod tests {
         let path_first_half = MerkleTreePath {
             path: path.path[0..path.path.len() / 2].to_owned(),
             _parameters1: PhantomData,
-            _parameters2: PhantomData,
         };
         let path_second_half = MerkleTreePath {
             path: path.path[path.path.len() / 2..].to_owned(),
             _parameters1: PhantomData,
-            _parameters2: PhantomData,
         };
 
         let index_bool_vec = index.to_bit_vector();
         let (index_bool_vec_first_half, index_bool_vec_second_half) =
             index_bool_vec.split_at(path.path.len() / 2);
         let middle_root = path_first_half
-            .compute_root(&leaf, &index_bool_vec_first_half.to_vec(), NodeType::Leaf)
+            .compute_root::<Fr>(&leaf, &index_bool_vec_first_half.to_vec(), NodeType::Leaf)
             .unwrap();
 
         assert!(path_second_half
-            .verify(
+            .verify::<Fr>(
                 &tree.root,
                 &middle_root,
                 &index_bool_vec_second_half.to_vec(),
@@ -213,14 +214,14 @@ mod tests {
 
         // Allocate path first half
         let path_var_first_half =
-            MerkleTreePathVar::<MerkleTreeTestParameters>::new_witness(ns!(cs, "path"), || {
+            MerkleTreePathVar::<MerkleTreeTestParameters, Fr>::new_witness(ns!(cs, "path"), || {
                 Ok(path_first_half)
             })
             .unwrap();
 
         // Allocate path second half
         let path_var_second_half =
-            MerkleTreePathVar::<MerkleTreeTestParameters>::new_witness(ns!(cs, "path"), || {
+            MerkleTreePathVar::<MerkleTreeTestParameters, Fr>::new_witness(ns!(cs, "path"), || {
                 Ok(path_second_half)
             })
             .unwrap();
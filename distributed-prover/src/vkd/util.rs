@@ -2,7 +2,6 @@ use crate::vkd::{
     InnerHash, MerkleTreeError, MerkleTreeParameters, SparseMerkleTree, DEPTH, INNER_HASH_SIZE,
     PATH_LENGTH,
 };
-use ark_bls12_381::Fr;
 use ark_crypto_primitives::crh::sha256::constraints::DigestVar;
 use ark_crypto_primitives::sponge::Absorb;
 use ark_crypto_primitives::Error;
@@ -78,13 +77,13 @@ pub fn fpvar_to_boolean_index<F: PrimeField + Absorb>(
 
 // this function returns split index both in Vec<bool> and Vec<Bool>
 // [Bytes] ==> Fpvar ==> [Boolean] ==> this can be done for each part or a single part separately
-pub fn hash_leaf_to_split_index<P: MerkleTreeParameters>(
+pub fn hash_leaf_to_split_index<P: MerkleTreeParameters, F: PrimeField + Absorb>(
     leaf_hash: &[u8],
-    cs: ConstraintSystemRef<Fr>,
+    cs: ConstraintSystemRef<F>,
     mode: AllocationMode,
     split_factor: usize,
-) -> (Vec<FpVar<Fr>>, Vec<Vec<bool>>) {
-    let index = SparseMerkleTree::<P>::get_index(leaf_hash, P::DEPTH)
+) -> (Vec<FpVar<F>>, Vec<Vec<bool>>) {
+    let index = SparseMerkleTree::<P, F>::get_index(leaf_hash, P::DEPTH)
         .unwrap()
         .to_bit_vector();
     let split_index = split(index, split_factor).unwrap();
@@ -97,7 +96,7 @@ pub fn hash_leaf_to_split_index<P: MerkleTreeParameters>(
     for i in 0..split_factor {
         let mut fixed_size_array: [u8; PATH_LENGTH / 8] = [0u8; PATH_LENGTH / 8];
         fixed_size_array.copy_from_slice(&split_leah_hash[i].as_slice()[0..PATH_LENGTH / 8]);
-        let fp = Fr::from_le_bytes_mod_order(&fixed_size_array);
+        let fp = F::from_le_bytes_mod_order(&fixed_size_array);
         let fpvar = FpVar::new_variable(cs.clone(), || Ok(fp), mode).unwrap();
         split_leaf_hash_fpvar_vec.push(fpvar);
     }
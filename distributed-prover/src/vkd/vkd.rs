@@ -1,12 +1,14 @@
 use std::collections::HashMap;
 use std::fmt::Display;
+use std::marker::PhantomData;
 
 use crate::vkd::hash::hash;
 use crate::vkd::sparse_tree::{
     InnerHash, MerkleTreeParameters, MerkleTreePath, NodeType, SparseMerkleTree,
 };
 use crate::vkd::vkd_circuits::*;
-use ark_crypto_primitives::Error;
+use ark_crypto_primitives::{sponge::Absorb, Error};
+use ark_ff::PrimeField;
 use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
 use rand::seq::IteratorRandom;
 use rand::thread_rng;
@@ -38,14 +40,15 @@ impl MerkleTreeParameters for MerkleTreeConcreteParameters {
 }
 
 // Very high-level it seems VerifiableKeyDirectoryCircuit takes arguments for an initial root and final root and one update
-// It checks that the updates actually end us up with that final tree
+// It checks that the updates actually end us up with that final tree. The tree is hashed over F
 #[derive(Clone)]
-pub struct VerifiableKeyDirectoryCircuit {
+pub struct VerifiableKeyDirectoryCircuit<F> {
     pub(crate) initial_root: InnerHash,
     pub(crate) params: VerifiableKeyDirectoryCircuitParams,
     pub(crate) final_root: InnerHash,
     pub(crate) update: Vec<Update>,
     pub(crate) subcircuits: Vec<SubCircuit>,
+    pub(crate) _marker: PhantomData<F>,
 }
 
 #[derive(Clone, Debug)]
@@ -118,17 +121,19 @@ where
         .map(|(key, value)| (key.clone(), value.clone()))
 }
 
-impl VerifiableKeyDirectoryCircuit {
-    pub fn random(params: &VerifiableKeyDirectoryCircuitParams) -> VerifiableKeyDirectoryCircuit {
+impl<F: PrimeField + Absorb> VerifiableKeyDirectoryCircuit<F> {
+    pub fn random(
+        params: &VerifiableKeyDirectoryCircuitParams,
+    ) -> VerifiableKeyDirectoryCircuit<F> {
         // generate the initial tree from a random bytes
-        let mut tree = SparseMerkleTree::new().unwrap();
+        let mut tree = SparseMerkleTree::<MerkleTreeConcreteParameters, F>::new().unwrap();
         // usernames
         let mut users = HashMap::new();
         // add the initial (genesis) user
         users.insert(Username::default(), (0, Key::default()));
         let temp = concat(Username::default(), Key::default(), 0);
-        let leaf_h = hash(Username::default().as_slice()).unwrap();
-        let index = SparseMerkleTree::<MerkleTreeConcreteParameters>::get_index(
+        let leaf_h = hash::<F>(Username::default().as_slice()).unwrap();
+        let index = SparseMerkleTree::<MerkleTreeConcreteParameters, F>::get_index(
             leaf_h.as_slice(),
             MerkleTreeConcreteParameters::DEPTH,
         )
@@ -146,8 +151,8 @@ impl VerifiableKeyDirectoryCircuit {
         let key = [0u8; 32] as Key;
         users.insert(username, (0, key));
         // add it to the tree
-        let leaf_h = hash(username.as_slice()).unwrap();
-        let index = SparseMerkleTree::<MerkleTreeConcreteParameters>::get_index(
+        let leaf_h = hash::<F>(username.as_slice()).unwrap();
+        let index = SparseMerkleTree::<MerkleTreeConcreteParameters, F>::get_index(
             leaf_h.as_slice(),
             MerkleTreeConcreteParameters::DEPTH,
         )
@@ -173,8 +178,8 @@ impl VerifiableKeyDirectoryCircuit {
                 // randomly choose on of the usernames
                 let (counter, key1) = &users.get(&username).unwrap().clone();
                 // gets its path from the tree
-                let leaf_h = hash(username.as_slice()).unwrap();
-                let index = SparseMerkleTree::<MerkleTreeConcreteParameters>::get_index(
+                let leaf_h = hash::<F>(username.as_slice()).unwrap();
+                let index = SparseMerkleTree::<MerkleTreeConcreteParameters, F>::get_index(
                     leaf_h.as_slice(),
                     MerkleTreeConcreteParameters::DEPTH,
                 )
@@ -210,6 +215,7 @@ impl VerifiableKeyDirectoryCircuit {
             final_root: tree.root.clone(),
             update: updates.clone(),
             subcircuits: vkd_update_to_subcircuit(&updates),
+            _marker: PhantomData,
         }
     }
 
@@ -221,15 +227,15 @@ impl VerifiableKeyDirectoryCircuit {
             match u {
                 Update::Update(op) => {
                     // first key exists in the tree
-                    let leaf_h = hash(op.username.as_slice()).unwrap();
-                    let index = SparseMerkleTree::<MerkleTreeConcreteParameters>::get_index(
+                    let leaf_h = hash::<F>(op.username.as_slice()).unwrap();
+                    let index = SparseMerkleTree::<MerkleTreeConcreteParameters, F>::get_index(
                         leaf_h.as_slice(),
                         MerkleTreeConcreteParameters::DEPTH,
                     )
                     .unwrap();
                     res = res
                         & op.path
-                            .verify(
+                            .verify::<F>(
                                 &root,
                                 concat(op.username, op.key1, op.counter).as_slice(),
                                 &index.to_bit_vector(),
@@ -239,7 +245,7 @@ impl VerifiableKeyDirectoryCircuit {
                     // update the root to add the update
                     root = op
                         .path
-                        .compute_root(
+                        .compute_root::<F>(
                             concat(op.username, op.key2, op.counter + 1).as_slice(),
                             &index.to_bit_vector(),
                             NodeType::Leaf,
@@ -249,19 +255,19 @@ impl VerifiableKeyDirectoryCircuit {
                 },
                 Update::Append(op) => {
                     // append it to the tree
-                    let leaf_h = hash(op.username.as_slice()).unwrap();
-                    let index = SparseMerkleTree::<MerkleTreeConcreteParameters>::get_index(
+                    let leaf_h = hash::<F>(op.username.as_slice()).unwrap();
+                    let index = SparseMerkleTree::<MerkleTreeConcreteParameters, F>::get_index(
                         leaf_h.as_slice(),
                         MerkleTreeConcreteParameters::DEPTH,
                     )
                     .unwrap();
                     res = res
                         & op.path
-                            .verify(&root, &pp, &index.to_bit_vector(), NodeType::InternalNode)
+                            .verify::<F>(&root, &pp, &index.to_bit_vector(), NodeType::InternalNode)
                             .unwrap();
                     root = op
                         .path
-                        .compute_root(
+                        .compute_root::<F>(
                             concat(op.username, op.key, 0).as_slice(),
                             &index.to_bit_vector(),
                             NodeType::Leaf,
@@ -622,10 +628,11 @@ mod tests {
         MerkleTreeConcreteParameters, SparseMerkleTree, VerifiableKeyDirectoryCircuit,
         VerifiableKeyDirectoryCircuitParams, DEPTH,
     };
+    use ark_bls12_381::Fr;
 
     #[test]
     fn test_vkd_rand() {
-        type TestMerkleTree = SparseMerkleTree<MerkleTreeConcreteParameters>;
+        type TestMerkleTree = SparseMerkleTree<MerkleTreeConcreteParameters, Fr>;
         let tree = TestMerkleTree::new().unwrap();
 
         let circ_params = VerifiableKeyDirectoryCircuitParams {
@@ -633,7 +640,7 @@ mod tests {
             log_num_subcircuits: 5,
             null_leaf: tree.sparse_initial_hashes[DEPTH],
         };
-        let vkd: VerifiableKeyDirectoryCircuit =
+        let vkd: VerifiableKeyDirectoryCircuit<Fr> =
             VerifiableKeyDirectoryCircuit::random(&circ_params);
         assert!(vkd.verify(tree.sparse_initial_hashes[DEPTH]).unwrap());
     }
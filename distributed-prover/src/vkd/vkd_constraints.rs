@@ -9,8 +9,9 @@ use crate::vkd::{
     VerifiableKeyDirectoryCircuitParams, DEPTH, SPLIT_FACTOR,
 };
 use crate::CircuitWithPortals;
-use ark_bls12_381::Fr;
 use ark_crypto_primitives::crh::sha256::constraints::DigestVar;
+use ark_crypto_primitives::sponge::Absorb;
+use ark_ff::PrimeField;
 use ark_r1cs_std::alloc::{AllocVar, AllocationMode};
 use ark_r1cs_std::boolean::Boolean;
 use ark_r1cs_std::eq::EqGadget;
@@ -20,11 +21,13 @@ use ark_relations::r1cs::{ConstraintSystem, ConstraintSystemRef, SynthesisError}
 use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
 use lazy_static::lazy_static;
 use rand::Rng;
+use std::any::{Any, TypeId};
 use std::collections::HashMap;
 use std::sync::Mutex;
 
+/// The circuits made so far, keyed by field and params. Each is a `VerifiableKeyDirectoryCircuit<F>`
 pub struct VKDCache {
-    circuits: Mutex<HashMap<VerifiableKeyDirectoryCircuitParams, VerifiableKeyDirectoryCircuit>>,
+    circuits: Mutex<HashMap<(TypeId, VerifiableKeyDirectoryCircuitParams), Box<dyn Any + Send>>>,
 }
 
 impl VKDCache {
@@ -34,14 +37,16 @@ impl VKDCache {
         }
     }
 
-    pub fn get_or_init(
+    pub fn get_or_init<F: PrimeField + Absorb>(
         &self,
         params: VerifiableKeyDirectoryCircuitParams,
-    ) -> VerifiableKeyDirectoryCircuit {
+    ) -> VerifiableKeyDirectoryCircuit<F> {
         let mut circuits = self.circuits.lock().unwrap();
         circuits
-            .entry(params.clone())
-            .or_insert_with(|| VerifiableKeyDirectoryCircuit::random(&params))
+            .entry((TypeId::of::<F>(), params.clone()))
+            .or_insert_with(|| Box::new(VerifiableKeyDirectoryCircuit::<F>::random(&params)))
+            .downcast_ref::<VerifiableKeyDirectoryCircuit<F>>()
+            .unwrap()
             .clone()
     }
 }
@@ -51,9 +56,9 @@ lazy_static! {
 }
 
 // InnerHash <====> UInt8 <== digest_to_fpvar ==> FpVar
-impl CircuitWithPortals<Fr> for VerifiableKeyDirectoryCircuit {
+impl<F: PrimeField + Absorb> CircuitWithPortals<F> for VerifiableKeyDirectoryCircuit<F> {
     type Parameters = VerifiableKeyDirectoryCircuitParams;
-    type ProverPortalManager = RomProverPortalManager<Fr>;
+    type ProverPortalManager = RomProverPortalManager<F>;
     const MEM_TYPE: MemType = MemType::Rom;
 
     /// Makes a random instance of this circuit with the given parameters
@@ -67,7 +72,13 @@ impl CircuitWithPortals<Fr> for VerifiableKeyDirectoryCircuit {
 
     // This produces the same portal trace as generate_constraints(0...num_circuits) would do, but
     // without having to do all the ZK SHA2 computations
-    fn get_portal_subtraces(&self) -> Vec<Vec<TranscriptEntry<Fr>>> {
+    fn get_portal_subtraces(&self) -> Vec<Vec<TranscriptEntry<F>>> {
+        self.portal_subtraces_iter().collect()
+    }
+
+    /// Makes the subtraces one subcircuit at a time. The memories of the non-ZK computation are
+    /// kept between subcircuits, but a subtrace isn't kept once it's yielded.
+    fn portal_subtraces_iter(&self) -> Box<dyn Iterator<Item = Vec<TranscriptEntry<F>>> + '_> {
         // make a portal manager to collect the subtraces
         let cs = ConstraintSystem::new_ref();
         let mut pm = SetupRomPortalManager::new(cs.clone());
@@ -76,7 +87,7 @@ impl CircuitWithPortals<Fr> for VerifiableKeyDirectoryCircuit {
         let mut inner_node_memory: HashMap<String, InnerHash> = HashMap::new();
         // get subcircuit vector
         let subcircuits = vkd_update_to_subcircuit(&self.update);
-        for subcircuit in subcircuits.iter() {
+        Box::new(subcircuits.into_iter().map(move |subcircuit| {
             pm.start_subtrace(ConstraintSystem::new_ref());
             for primitive in &subcircuit.compound_primitive_vec {
                 match primitive {
@@ -118,13 +129,14 @@ impl CircuitWithPortals<Fr> for VerifiableKeyDirectoryCircuit {
                     // TODO: it works only for depth of less than 128
                     PrimitiveSubcircuit::GetIndexPrimitive(ind) => {
                         // set index_memory in non-ZK
-                        let leaf_h = hash(&ind.leaf[0..32]).unwrap();
-                        let index_vector = hash_leaf_to_split_index::<MerkleTreeConcreteParameters>(
-                            leaf_h.as_slice(),
-                            cs.clone(),
-                            AllocationMode::Witness,
-                            SPLIT_FACTOR,
-                        );
+                        let leaf_h = hash::<F>(&ind.leaf[0..32]).unwrap();
+                        let index_vector =
+                            hash_leaf_to_split_index::<MerkleTreeConcreteParameters, F>(
+                                leaf_h.as_slice(),
+                                cs.clone(),
+                                AllocationMode::Witness,
+                                SPLIT_FACTOR,
+                            );
                         for i in 0..SPLIT_FACTOR {
                             let addr = IndexAddress {
                                 indicator: i,
@@ -151,7 +163,7 @@ impl CircuitWithPortals<Fr> for VerifiableKeyDirectoryCircuit {
                         // compute the root
                         let root = p
                             .path
-                            .compute_root(initial_value, index, NodeType::InternalNode)
+                            .compute_root::<F>(initial_value, index, NodeType::InternalNode)
                             .unwrap();
                         let final_value_addr =
                             NodeAddressBytes::bytes_to_node_address(p.final_value_addr.clone());
@@ -164,7 +176,7 @@ impl CircuitWithPortals<Fr> for VerifiableKeyDirectoryCircuit {
 
                     PrimitiveSubcircuit::HashLeafPrimitive(h) => {
                         // set leaf_hash_memory
-                        let leaf_h = hash_leaf(&h.leaf).unwrap();
+                        let leaf_h = hash_leaf::<F>(&h.leaf).unwrap();
                         let addr = LeafHashAddress { leaf: h.leaf }.to_string();
                         inner_node_memory.insert(addr, leaf_h);
                         // set portal manager
@@ -177,19 +189,14 @@ impl CircuitWithPortals<Fr> for VerifiableKeyDirectoryCircuit {
                     PrimitiveSubcircuit::PaddingPrimitive(_) => {},
                 }
             }
-        }
-        // We don't compute any portal wires for the equality circuit
-
-        // Return the subtraces, wrapped appropriately
-        pm.subtraces
-            .into_iter()
-            .map(|subtrace| {
-                subtrace
-                    .into_iter()
-                    .map(|e| TranscriptEntry::Rom(e))
-                    .collect()
-            })
-            .collect()
+            // We don't compute any portal wires for the equality circuit
+
+            // Return the subtrace, wrapped appropriately
+            pm.take_subtrace()
+                .into_iter()
+                .map(|e| TranscriptEntry::Rom(e))
+                .collect()
+        }))
     }
 
     fn num_subcircuits(&self) -> usize {
@@ -234,9 +241,9 @@ impl CircuitWithPortals<Fr> for VerifiableKeyDirectoryCircuit {
         self.subcircuits[subcircuit_idx] = subcircuit;
     }
 
-    fn generate_constraints<P: PortalManager<Fr>>(
+    fn generate_constraints<P: PortalManager<F>>(
         &mut self,
-        cs: ConstraintSystemRef<Fr>,
+        cs: ConstraintSystemRef<F>,
         subcircuit_idx: usize,
         pm: &mut P,
     ) -> Result<(), SynthesisError> {
@@ -283,11 +290,12 @@ impl CircuitWithPortals<Fr> for VerifiableKeyDirectoryCircuit {
                     let index = fpvar_to_boolean_index(index_fpvar).unwrap();
 
                     // add path as private input
-                    let path_var = MerkleTreePathVar::<MerkleTreeConcreteParameters>::new_witness(
-                        cs.clone(),
-                        || Ok(&p.path),
-                    )
-                    .unwrap();
+                    let path_var =
+                        MerkleTreePathVar::<MerkleTreeConcreteParameters, F>::new_witness(
+                            cs.clone(),
+                            || Ok(&p.path),
+                        )
+                        .unwrap();
 
                     // assert validity
                     let root = path_var
@@ -362,14 +370,14 @@ mod tests {
             const DEPTH: usize = DEPTH;
         }
 
-        type TestMerkleTree = SparseMerkleTree<MerkleTreeTestParameters>;
+        type TestMerkleTree = SparseMerkleTree<MerkleTreeTestParameters, Fr>;
         let tree = TestMerkleTree::new().unwrap();
 
         let vkd_params = VerifiableKeyDirectoryCircuitParams {
             log_num_subcircuits: 4,
             null_leaf: tree.sparse_initial_hashes[DEPTH],
         };
-        let mut vkd: VerifiableKeyDirectoryCircuit =
+        let mut vkd: VerifiableKeyDirectoryCircuit<Fr> =
             VerifiableKeyDirectoryCircuit::new(&vkd_params);
 
         // testing the lazy static making sure the vkd is initiated only once for each params
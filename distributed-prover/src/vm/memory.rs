@@ -1,20 +1,39 @@
 use crate::portal_manager::PortalManager;
-use crate::vkd::hash::hash;
-use crate::vkd::{MerkleTreeParameters, MerkleTreePathVar, NodeType, SparseMerkleTree};
-use ark_bls12_381::Fr;
+use crate::vkd::{
+    MerkleIndex, MerkleTreeParameters, MerkleTreePath, MerkleTreePathVar, NodeType,
+    SparseMerkleTree,
+};
+use crate::vm::vm::{field_to_usize, mem_name, MEM_ROOT_NAME};
+use ark_crypto_primitives::sponge::Absorb;
+use ark_ff::{BigInteger, PrimeField};
 use ark_r1cs_std::alloc::AllocVar;
+use ark_r1cs_std::boolean::Boolean;
+use ark_r1cs_std::eq::EqGadget;
 use ark_r1cs_std::fields::fp::FpVar;
-use ark_r1cs_std::prelude::UInt8;
-use ark_r1cs_std::uint64::UInt64;
-use ark_relations::r1cs::ConstraintSystemRef;
+use ark_r1cs_std::fields::FieldVar;
+use ark_r1cs_std::{R1CSVar, ToBytesGadget};
+use ark_relations::ns;
+use ark_relations::r1cs::{ConstraintSystemRef, SynthesisError};
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+use num_bigint::BigUint;
 use std::marker::PhantomData;
+use std::slice;
 
 pub const MERKLE_MEMORY_DEPTH: usize = 32;
 
-pub struct Memory<'a, P: PortalManager<Fr>> {
+/// The VM's data memory. In portal mode, word `k` is the portal wire `mem k`, which lives at RAM
+/// address `base_addr + k`. In Merkle mode, word `k` is leaf `k` of a sparse Merkle tree, and the
+/// only portal wire is the tree root `mem root`, which lives at `base_addr`.
+pub struct Memory<'a, F: PrimeField, P: PortalManager<F>> {
     pub use_merkle_memory: bool,
+    pub base_addr: u64,
+    pub cs: ConstraintSystemRef<F>,
     pub portal_manager: &'a mut P,
-    pub phantom: PhantomData<Fr>,
+    /// The current Merkle root. Only used in Merkle mode
+    pub root: Option<FpVar<F>>,
+    /// The values and paths of the upcoming Merkle memory accesses. Only used in Merkle mode
+    pub hints: slice::Iter<'a, MerkleAccessHint<F>>,
+    pub phantom: PhantomData<F>,
 }
 
 #[derive(Clone)]
@@ -24,82 +43,230 @@ impl MerkleTreeParameters for MerkleTreeTestParameters {
     const DEPTH: usize = MERKLE_MEMORY_DEPTH;
 }
 
-impl<P: PortalManager<Fr>> Memory<'_, P> {
-    pub fn get(&mut self, name: &str, cs: ConstraintSystemRef<Fr>) {
-        match self.use_merkle_memory {
-            true => {
-                merkle_path_verification(cs.clone());
-            },
-            false => {
-                let _ = self.portal_manager.get(name).expect("get error");
-            },
+type MemoryPathVar<F> = MerkleTreePathVar<MerkleTreeTestParameters, F>;
+
+/// The opened value, the leaf index in little-endian bits, and the path of a Merkle memory access
+type MerkleAccessVar<F> = (FpVar<F>, Vec<Boolean<F>>, MemoryPathVar<F>);
+
+/// Everything needed to check one access to Merkle memory: the current value of the accessed word,
+/// and its authentication path
+#[derive(Clone, Default, CanonicalSerialize, CanonicalDeserialize)]
+pub struct MerkleAccessHint<F: PrimeField> {
+    pub value: F,
+    pub path: MerkleTreePath<MerkleTreeTestParameters>,
+}
+
+impl<F: PrimeField + Absorb, P: PortalManager<F>> Memory<'_, F, P> {
+    /// Witnesses and writes the initial contents of memory. This must happen before any `read` or
+    /// `write`, so that the words get their addresses in order. In Merkle mode, only the root of
+    /// the initial memory is witnessed.
+    pub fn init(&mut self, initial_memory: &[F]) -> Result<(), SynthesisError> {
+        let cs = self.cs.clone();
+        if self.use_merkle_memory {
+            let root = FpVar::new_witness(ns!(cs, "mem root"), || {
+                Ok(NativeMerkleMemory::new(initial_memory).root())
+            })?;
+            self.root = Some(root);
+            self.store()
+        } else {
+            for (k, word) in initial_memory.iter().enumerate() {
+                let word = FpVar::new_witness(ns!(cs, "mem word"), || Ok(word))?;
+                self.portal_manager.set(mem_name(k), &word)?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Picks up the memory left by the previous subcircuit. In portal mode this is a no-op.
+    pub fn load(&mut self) -> Result<(), SynthesisError> {
+        if self.use_merkle_memory {
+            self.root = Some(self.portal_manager.get(MEM_ROOT_NAME)?);
+        }
+        Ok(())
+    }
+
+    /// Hands the memory to the next subcircuit. In portal mode this is a no-op.
+    pub fn store(&mut self) -> Result<(), SynthesisError> {
+        if self.use_merkle_memory {
+            let root = self.root.as_ref().expect("Merkle memory was never loaded");
+            self.portal_manager.set(MEM_ROOT_NAME.to_string(), root)?;
         }
+        Ok(())
     }
 
-    pub fn  set(&mut self, name: String, value: &FpVar<Fr>, cs: ConstraintSystemRef<Fr>) {
-        match self.use_merkle_memory {
-            true => {
-                merkle_path_verification(cs.clone());
-                merkle_path_verification(cs.clone());
-            },
-            false => {
-                let _ = self
-                    .portal_manager
-                    .set(name.parse().unwrap(), value)
-                    .expect("set error");
-            },
+    /// Reads the word at `offset`
+    pub fn read(&mut self, offset: &FpVar<F>) -> Result<FpVar<F>, SynthesisError> {
+        if self.use_merkle_memory {
+            let (value, _, _) = self.merkle_access(offset)?;
+            Ok(value)
+        } else {
+            let (name, addr) = self.locate(offset);
+            self.portal_manager.get_at(&name, &addr)
         }
     }
+
+    /// Writes `value` to the word at `offset`
+    pub fn write(&mut self, offset: &FpVar<F>, value: &FpVar<F>) -> Result<(), SynthesisError> {
+        if self.use_merkle_memory {
+            // Check the path to the old value, then use the same path to compute the new root
+            let (_, index, path) = self.merkle_access(offset)?;
+            let new_root = path.compute_root_var_from_leaf(&value.to_bytes()?, &index)?;
+            self.root = Some(new_root);
+            Ok(())
+        } else {
+            let (name, addr) = self.locate(offset);
+            self.portal_manager.set_at(name, &addr, value)
+        }
+    }
+
+    /// Witnesses the next hint and checks that it is an opening of the current root at `offset`
+    fn merkle_access(&mut self, offset: &FpVar<F>) -> Result<MerkleAccessVar<F>, SynthesisError> {
+        let cs = self.cs.clone();
+        let hint = self.hints.next();
+
+        let value = FpVar::new_witness(ns!(cs, "mem word"), || {
+            hint.map(|h| h.value)
+                .ok_or(SynthesisError::AssignmentMissing)
+        })?;
+        // Path allocation always needs a value. During CRS generation there are no hints, so
+        // use a placeholder
+        let path = MemoryPathVar::new_witness(ns!(cs, "mem path"), || {
+            Ok(hint.map(|h| h.path.clone()).unwrap_or_default())
+        })?;
+
+        // Decompose the offset into the leaf index. This also checks that it fits in the tree
+        let index = (0..MERKLE_MEMORY_DEPTH)
+            .map(|i| {
+                Boolean::new_witness(ns!(cs, "mem index bit"), || {
+                    Ok(offset.value()?.into_bigint().get_bit(i))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Boolean::le_bits_to_fp_var(&index)?.enforce_equal(offset)?;
+
+        let root = self.root.as_ref().expect("Merkle memory was never loaded");
+        path.check_path_from_leaf(root, &value.to_bytes()?, &index)?;
+
+        Ok((value, index, path))
+    }
+
+    /// Returns the wire name and RAM address of the word at `offset`. The name is only meaningful
+    /// when `offset` has a value, i.e., outside of CRS generation.
+    fn locate(&self, offset: &FpVar<F>) -> (String, FpVar<F>) {
+        let name = mem_name(
+            offset
+                .value()
+                .ok()
+                .and_then(field_to_usize)
+                .unwrap_or_default(),
+        );
+        let addr = offset + FpVar::constant(F::from(self.base_addr));
+        (name, addr)
+    }
 }
 
-pub fn merkle_path_verification(cs: ConstraintSystemRef<Fr>) {
-    // define the tree, leaf and leaf_h
-    type TestMerkleTree = SparseMerkleTree<MerkleTreeTestParameters>;
-    let mut tree = TestMerkleTree::new().unwrap();
-    let leaf = [1_u8; 32];
-    let leaf_hash = hash(&leaf).unwrap();
-
-    // get index and insert it into the tree
-    let index = SparseMerkleTree::<MerkleTreeTestParameters>::get_index(
-        leaf_hash.as_slice(),
-        MerkleTreeTestParameters::DEPTH,
-    )
-    .unwrap();
-    tree.insert(index.clone(), &leaf, NodeType::Leaf).unwrap();
-
-    // get the path
-    let path = tree.lookup_path(&index).unwrap();
-
-    // Allocate leaf
-    let leaf_var = Vec::<UInt8<Fr>>::new_witness(cs.clone(), || Ok(leaf)).unwrap();
-
-    // allocate index
-    let mut fixed_size_array: [u8; 8] = [0; 8];
-    fixed_size_array.copy_from_slice(&leaf_hash.as_slice()[0..8]);
-    let index_var =
-        UInt64::<Fr>::new_witness(cs.clone(), || Ok(u64::from_le_bytes(fixed_size_array)))
-            .unwrap()
-            .to_bits_le();
-
-    // allocate path
-    let path_var =
-        MerkleTreePathVar::<MerkleTreeTestParameters>::new_witness(cs.clone(), || Ok(path))
+/// The native counterpart of Merkle-mode `Memory`. Leaf `k` of the tree is the little-endian
+/// encoding of word `k`.
+pub(crate) struct NativeMerkleMemory<F: PrimeField> {
+    tree: SparseMerkleTree<MerkleTreeTestParameters, F>,
+}
+
+impl<F: PrimeField + Absorb> NativeMerkleMemory<F> {
+    pub(crate) fn new(words: &[F]) -> Self {
+        assert!(
+            words.len() <= 1 << MERKLE_MEMORY_DEPTH,
+            "memory doesn't fit in the Merkle tree"
+        );
+        let mut mem = NativeMerkleMemory {
+            tree: SparseMerkleTree::new().unwrap(),
+        };
+        for (k, word) in words.iter().enumerate() {
+            mem.write(k, *word);
+        }
+        mem
+    }
+
+    /// The root, as it appears in the circuit
+    pub(crate) fn root(&self) -> F {
+        F::from_le_bytes_mod_order(&self.tree.root)
+    }
+
+    /// The hint for an access to word `k`, whose current value is `value`
+    pub(crate) fn hint(&self, k: usize, value: F) -> MerkleAccessHint<F> {
+        MerkleAccessHint {
+            value,
+            path: self.tree.lookup_path(&leaf_index(k)).unwrap(),
+        }
+    }
+
+    pub(crate) fn write(&mut self, k: usize, value: F) {
+        let leaf = value.into_bigint().to_bytes_le();
+        self.tree
+            .insert(leaf_index(k), &leaf, NodeType::Leaf)
             .unwrap();
+    }
+}
 
-    // do path verification
-    let _ = path_var
-        .compute_root_var_from_leaf(&leaf_var, &index_var)
-        .unwrap();
+fn leaf_index(k: usize) -> MerkleIndex {
+    MerkleIndex {
+        index: BigUint::from(k),
+        depth: MERKLE_MEMORY_DEPTH,
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::vm::memory::merkle_path_verification;
+    use crate::portal_manager::SetupRamPortalManager;
+    use crate::vm::memory::{Memory, NativeMerkleMemory};
     use ark_bls12_381::Fr;
+    use ark_ff::UniformRand;
+    use ark_r1cs_std::alloc::AllocVar;
+    use ark_r1cs_std::fields::fp::FpVar;
+    use ark_r1cs_std::R1CSVar;
     use ark_relations::r1cs::ConstraintSystem;
+    use ark_std::test_rng;
+    use std::marker::PhantomData;
 
+    // Checks that in-circuit Merkle memory reads and writes agree with the native tree, and that
+    // a read with a wrong value is caught
     #[test]
-    fn test_path_verification() {
-        merkle_path_verification(ConstraintSystem::<Fr>::new_ref());
+    fn test_merkle_memory() {
+        let mut rng = test_rng();
+        let words = (0..5).map(|_| Fr::rand(&mut rng)).collect::<Vec<_>>();
+        let new_word = Fr::rand(&mut rng);
+
+        let mut native_mem = NativeMerkleMemory::new(&words);
+        let hints = [native_mem.hint(3, words[3]), native_mem.hint(3, words[3])];
+        native_mem.write(3, new_word);
+        let final_root: Fr = native_mem.root();
+        let bad_hints = [native_mem.hint(4, new_word)];
+
+        let cs = ConstraintSystem::<Fr>::new_ref();
+        let mut pm = SetupRamPortalManager::new(cs.clone());
+        pm.start_subtrace(cs.clone());
+        let mut memory = Memory {
+            use_merkle_memory: true,
+            base_addr: 1,
+            cs: cs.clone(),
+            portal_manager: &mut pm,
+            root: None,
+            hints: hints.iter(),
+            phantom: PhantomData,
+        };
+
+        memory.init(&words).unwrap();
+        let offset = FpVar::new_witness(cs.clone(), || Ok(Fr::from(3u8))).unwrap();
+        let val = memory.read(&offset).unwrap();
+        assert_eq!(val.value().unwrap(), words[3]);
+        let new_val = FpVar::new_witness(cs.clone(), || Ok(new_word)).unwrap();
+        memory.write(&offset, &new_val).unwrap();
+        assert_eq!(memory.root.as_ref().unwrap().value().unwrap(), final_root);
+        assert!(cs.is_satisfied().unwrap());
+
+        // Word 4 is unchanged by the write. Claiming it's something else is unsatisfiable
+        memory.hints = bad_hints.iter();
+        let offset = FpVar::new_witness(cs.clone(), || Ok(Fr::from(4u8))).unwrap();
+        let _ = memory.read(&offset).unwrap();
+        assert!(!cs.is_satisfied().unwrap());
     }
 }
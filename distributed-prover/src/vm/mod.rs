@@ -1,10 +1,8 @@
 mod memory;
 mod vm;
-mod vm_circuits;
 mod vm_constraints;
 
 pub use vm::*;
-pub use vm_circuits::*;
 
 pub use memory::MERKLE_MEMORY_DEPTH;
 pub use vm::REGISTER_NUM;
@@ -1,63 +1,575 @@
-use ark_ff::PrimeField;
+use ark_crypto_primitives::sponge::Absorb;
+use ark_ff::{BigInteger, PrimeField};
 use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+use rand::Rng;
+use std::fmt::{self, Display, Formatter};
+use std::sync::OnceLock;
+
+use crate::vm::memory::{MerkleAccessHint, NativeMerkleMemory};
 
 pub const REGISTER_NUM: usize = 16;
 
-#[derive(Clone)]
-pub struct VirtualMachine<F: PrimeField> {
-    pub data: [F; REGISTER_NUM],
-    pub params: VirtualMachineParameters,
+// An encoded instruction is, from least to most significant bit: opcode, rd, rs1, rs2, imm
+pub(crate) const OPCODE_BITS: usize = 3;
+pub(crate) const REG_IDX_BITS: usize = 4;
+pub(crate) const IMM_BITS: usize = 16;
+pub(crate) const INSTRUCTION_BITS: usize = OPCODE_BITS + 3 * REG_IDX_BITS + IMM_BITS;
+
+// Opcodes. 7 is not a valid opcode
+pub(crate) const OP_ADD: u64 = 0;
+pub(crate) const OP_SUB: u64 = 1;
+pub(crate) const OP_MUL: u64 = 2;
+pub(crate) const OP_LOAD: u64 = 3;
+pub(crate) const OP_STORE: u64 = 4;
+pub(crate) const OP_BZ: u64 = 5;
+pub(crate) const OP_HALT: u64 = 6;
+
+/// An instruction of the VM. Registers are indexed by numbers less than `REGISTER_NUM`, and all
+/// arithmetic is over the scalar field.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    /// `rd ← rs1 + rs2`
+    Add { rd: u8, rs1: u8, rs2: u8 },
+    /// `rd ← rs1 - rs2`
+    Sub { rd: u8, rs1: u8, rs2: u8 },
+    /// `rd ← rs1 * rs2`
+    Mul { rd: u8, rs1: u8, rs2: u8 },
+    /// `rd ← mem[rs1 + imm]`
+    Load { rd: u8, rs1: u8, imm: u16 },
+    /// `mem[rs1 + imm] ← rs2`
+    Store { rs1: u8, rs2: u8, imm: u16 },
+    /// `pc ← imm` if `rs1 = 0`, and `pc ← pc + 1` otherwise
+    Bz { rs1: u8, imm: u16 },
+    /// Stops the VM. The VM stays at this instruction forever.
+    Halt,
+}
+
+impl Instruction {
+    /// Packs this instruction into an `INSTRUCTION_BITS`-bit word. Unused fields are 0.
+    pub fn encode(&self) -> u64 {
+        let (op, rd, rs1, rs2, imm) = match *self {
+            Instruction::Add { rd, rs1, rs2 } => (OP_ADD, rd, rs1, rs2, 0),
+            Instruction::Sub { rd, rs1, rs2 } => (OP_SUB, rd, rs1, rs2, 0),
+            Instruction::Mul { rd, rs1, rs2 } => (OP_MUL, rd, rs1, rs2, 0),
+            Instruction::Load { rd, rs1, imm } => (OP_LOAD, rd, rs1, 0, imm),
+            Instruction::Store { rs1, rs2, imm } => (OP_STORE, 0, rs1, rs2, imm),
+            Instruction::Bz { rs1, imm } => (OP_BZ, 0, rs1, 0, imm),
+            Instruction::Halt => (OP_HALT, 0, 0, 0, 0),
+        };
+        for reg in [rd, rs1, rs2] {
+            assert!((reg as usize) < REGISTER_NUM, "invalid register {reg}");
+        }
+
+        op | (rd as u64) << OPCODE_BITS
+            | (rs1 as u64) << (OPCODE_BITS + REG_IDX_BITS)
+            | (rs2 as u64) << (OPCODE_BITS + 2 * REG_IDX_BITS)
+            | (imm as u64) << (OPCODE_BITS + 3 * REG_IDX_BITS)
+    }
+
+    /// Unpacks an instruction from a word made by `encode`. Returns `None` if the opcode is
+    /// invalid.
+    pub fn decode(word: u64) -> Option<Self> {
+        let field = |offset: usize, len: usize| (word >> offset) & ((1 << len) - 1);
+        let op = field(0, OPCODE_BITS);
+        let rd = field(OPCODE_BITS, REG_IDX_BITS) as u8;
+        let rs1 = field(OPCODE_BITS + REG_IDX_BITS, REG_IDX_BITS) as u8;
+        let rs2 = field(OPCODE_BITS + 2 * REG_IDX_BITS, REG_IDX_BITS) as u8;
+        let imm = field(OPCODE_BITS + 3 * REG_IDX_BITS, IMM_BITS) as u16;
+
+        let instr = match op {
+            OP_ADD => Instruction::Add { rd, rs1, rs2 },
+            OP_SUB => Instruction::Sub { rd, rs1, rs2 },
+            OP_MUL => Instruction::Mul { rd, rs1, rs2 },
+            OP_LOAD => Instruction::Load { rd, rs1, imm },
+            OP_STORE => Instruction::Store { rs1, rs2, imm },
+            OP_BZ => Instruction::Bz { rs1, imm },
+            OP_HALT => Instruction::Halt,
+            _ => return None,
+        };
+        Some(instr)
+    }
 }
 
 #[derive(Clone, Copy, CanonicalSerialize, CanonicalDeserialize)]
 pub struct VirtualMachineParameters {
     pub use_merkle_memory: bool,
 
-    // number of subcircuits are 2 ^ log_num_subcircuit
-    pub log_num_subcircuit: usize,
+    // number of subcircuits. every subcircuit executes a chunk of the instructions
+    pub num_subcircuits: usize,
 
-    pub dummy_constraint_num: usize,
-
-    // it has to be a power of two
+    // number of instructions executed per subcircuit
     pub operations_per_chunk: usize,
+
+    // number of instructions in the program ROM. branch targets are immediates, so this is at most
+    // 2 ^ IMM_BITS
+    pub program_len: usize,
+
+    // number of words of data memory. addresses are immediates, so this is at most 2 ^ IMM_BITS
+    pub memory_size: usize,
+}
+
+impl VirtualMachineParameters {
+    /// Panics if the program ROM or the data memory can't be addressed by an immediate
+    pub(crate) fn check(&self) {
+        assert!(
+            self.program_len <= 1 << IMM_BITS,
+            "program ROM has more than 2^{IMM_BITS} instructions"
+        );
+        assert!(
+            self.memory_size <= 1 << IMM_BITS,
+            "data memory has more than 2^{IMM_BITS} words"
+        );
+    }
+
+    /// The total number of instructions executed
+    pub fn num_steps(&self) -> usize {
+        self.num_subcircuits * self.operations_per_chunk
+    }
+
+    /// The RAM address of the first program word. Addresses are handed out in order of first use,
+    /// and the first subcircuit writes the registers, then the pc, then the program, then the data
+    /// memory. Address 0 is reserved for padding.
+    pub(crate) fn prog_base(&self) -> u64 {
+        REGISTER_NUM as u64 + 2
+    }
+
+    /// The RAM address of the first data memory word. In Merkle mode, this is the address of the
+    /// memory root
+    pub(crate) fn mem_base(&self) -> u64 {
+        self.prog_base() + self.program_len as u64
+    }
+}
+
+// Names of the portal wires that hold the VM state
+pub(crate) const PC_NAME: &str = "pc";
+pub(crate) const MEM_ROOT_NAME: &str = "mem root";
+
+pub(crate) fn register_name(i: usize) -> String {
+    format!("register {i}")
+}
+
+pub(crate) fn prog_name(k: usize) -> String {
+    format!("prog {k}")
+}
+
+pub(crate) fn mem_name(k: usize) -> String {
+    format!("mem {k}")
+}
+
+/// Why a VM can't run the program it's given
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VmError {
+    /// The program has more instructions than the program ROM
+    ProgramTooLong(usize),
+    /// The initial memory has more words than the data memory
+    MemoryTooLarge(usize),
+    /// The instruction at this pc accesses memory out of bounds
+    MemoryOutOfBounds { pc: usize },
+    /// The instruction at this pc jumps, or goes on, past the end of the program
+    PcOutOfRange { pc: usize },
+}
+
+impl std::error::Error for VmError {}
+
+impl Display for VmError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::ProgramTooLong(len) => {
+                write!(
+                    f,
+                    "program of {len} instructions doesn't fit in the program ROM"
+                )
+            },
+            VmError::MemoryTooLarge(len) => {
+                write!(
+                    f,
+                    "initial memory of {len} words doesn't fit in the data memory"
+                )
+            },
+            VmError::MemoryOutOfBounds { pc } => {
+                write!(f, "memory access out of bounds at pc {pc}")
+            },
+            VmError::PcOutOfRange { pc } => {
+                write!(f, "pc ran off the end of the program at pc {pc}")
+            },
+        }
+    }
+}
+
+/// The state of the VM in between instructions
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VmState<F: PrimeField> {
+    pub registers: [F; REGISTER_NUM],
+    pub pc: usize,
+    pub memory: Vec<F>,
+}
+
+/// A VM with a fixed program and initial memory. Execution starts at pc 0 with all registers set
+/// to 0, and runs for exactly `params.num_steps()` steps.
+#[derive(Clone)]
+pub struct VirtualMachine<F: PrimeField> {
+    /// The program ROM. This has length `params.program_len`
+    pub program: Vec<Instruction>,
+    /// The initial data memory. This has length `params.memory_size`
+    pub initial_memory: Vec<F>,
+    pub params: VirtualMachineParameters,
+    /// In Merkle mode, the hints for every memory access, grouped by subcircuit. This is computed
+    /// on first use, or filled in one subcircuit at a time by `set_serialized_witnesses`.
+    pub(crate) merkle_hints: OnceLock<Vec<Vec<MerkleAccessHint<F>>>>,
 }
 
 impl<F: PrimeField> VirtualMachine<F> {
+    /// Makes a VM whose program is all `HALT`s and whose memory is all 0
     pub fn new(params: &VirtualMachineParameters) -> Self {
+        // A program of HALTs stays put, so there's nothing to check
+        params.check();
+        Self::padded(params, Vec::new(), Vec::new())
+    }
+
+    /// Makes a VM with the given program and initial memory. The program is padded with `HALT`
+    /// and the memory is padded with 0. The program is run to check that it doesn't access memory
+    /// out of bounds or run off the end of the program ROM in `params.num_steps()` steps.
+    pub fn with_program(
+        params: &VirtualMachineParameters,
+        program: Vec<Instruction>,
+        initial_memory: Vec<F>,
+    ) -> Result<Self, VmError> {
+        params.check();
+        if program.len() > params.program_len {
+            return Err(VmError::ProgramTooLong(program.len()));
+        }
+        if initial_memory.len() > params.memory_size {
+            return Err(VmError::MemoryTooLarge(initial_memory.len()));
+        }
+
+        let vm = Self::padded(params, program, initial_memory);
+        let mut state = vm.initial_state();
+        for _ in 0..params.num_steps() {
+            vm.try_step(&mut state)?;
+        }
+        Ok(vm)
+    }
+
+    fn padded(
+        params: &VirtualMachineParameters,
+        mut program: Vec<Instruction>,
+        mut initial_memory: Vec<F>,
+    ) -> Self {
+        program.resize(params.program_len, Instruction::Halt);
+        initial_memory.resize(params.memory_size, F::ZERO);
+
         VirtualMachine {
-            data: [F::ZERO; REGISTER_NUM],
-            params: VirtualMachineParameters {
-                use_merkle_memory: params.use_merkle_memory,
-                log_num_subcircuit: params.log_num_subcircuit,
-                dummy_constraint_num: params.dummy_constraint_num,
-                operations_per_chunk: params.operations_per_chunk,
+            program,
+            initial_memory,
+            params: *params,
+            merkle_hints: OnceLock::new(),
+        }
+    }
+
+    /// Makes a random straight-line program that ends in `HALT`, and a random initial memory.
+    /// Register 0 is never written, so it can be used as a base register for memory accesses.
+    pub fn rand(rng: &mut impl Rng, params: &VirtualMachineParameters) -> Self {
+        params.check();
+        assert!(params.memory_size > 0, "VM must have some data memory");
+
+        let mut program = Vec::with_capacity(params.program_len);
+        for pc in 0..params.program_len.saturating_sub(1) {
+            let rd = rng.gen_range(1..REGISTER_NUM as u8);
+            let rs1 = rng.gen_range(0..REGISTER_NUM as u8);
+            let rs2 = rng.gen_range(0..REGISTER_NUM as u8);
+            let instr = match rng.gen_range(0..6) {
+                0 => Instruction::Add { rd, rs1, rs2 },
+                1 => Instruction::Sub { rd, rs1, rs2 },
+                2 => Instruction::Mul { rd, rs1, rs2 },
+                3 => Instruction::Load {
+                    rd,
+                    rs1: 0,
+                    imm: rng.gen_range(0..params.memory_size) as u16,
+                },
+                4 => Instruction::Store {
+                    rs1: 0,
+                    rs2,
+                    imm: rng.gen_range(0..params.memory_size) as u16,
+                },
+                // Either way, this goes to the next instruction
+                _ => Instruction::Bz {
+                    rs1,
+                    imm: (pc + 1) as u16,
+                },
+            };
+            program.push(instr);
+        }
+        let initial_memory = (0..params.memory_size).map(|_| F::rand(rng)).collect();
+
+        Self::with_program(params, program, initial_memory)
+            .expect("random program accessed memory out of bounds or ran off the end")
+    }
+
+    pub fn initial_state(&self) -> VmState<F> {
+        VmState {
+            registers: [F::ZERO; REGISTER_NUM],
+            pc: 0,
+            memory: self.initial_memory.clone(),
+        }
+    }
+
+    /// The data memory address that the next instruction touches. This is 0 if the instruction
+    /// doesn't touch memory. Panics if the access is out of bounds, which `with_program` rules
+    /// out for the steps the VM runs.
+    pub fn mem_addr(&self, state: &VmState<F>) -> usize {
+        self.try_mem_addr(state).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    fn try_mem_addr(&self, state: &VmState<F>) -> Result<usize, VmError> {
+        match self.program[state.pc] {
+            Instruction::Load { rs1, imm, .. } | Instruction::Store { rs1, imm, .. } => {
+                field_to_usize(state.registers[rs1 as usize])
+                    .and_then(|base| base.checked_add(imm as usize))
+                    .filter(|&addr| addr < self.params.memory_size)
+                    .ok_or(VmError::MemoryOutOfBounds { pc: state.pc })
             },
+            _ => Ok(0),
         }
     }
 
-    pub fn execute_dummy_operation(&mut self) {}
+    /// Executes the instruction at `state.pc`. Panics if the instruction accesses memory out of
+    /// bounds or runs off the end of the program, which `with_program` rules out for the steps
+    /// the VM runs.
+    pub fn step(&self, state: &mut VmState<F>) {
+        self.try_step(state).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Executes the instruction at `state.pc`, or returns why it can't be. The state is unchanged
+    /// on error.
+    fn try_step(&self, state: &mut VmState<F>) -> Result<(), VmError> {
+        let addr = self.try_mem_addr(state)?;
+        let r = state.registers;
+
+        let next_pc = match self.program[state.pc] {
+            Instruction::Bz { rs1, imm } if r[rs1 as usize].is_zero() => imm as usize,
+            Instruction::Halt => state.pc,
+            _ => state.pc + 1,
+        };
+        if next_pc >= self.params.program_len {
+            return Err(VmError::PcOutOfRange { pc: state.pc });
+        }
+
+        match self.program[state.pc] {
+            Instruction::Add { rd, rs1, rs2 } => {
+                state.registers[rd as usize] = r[rs1 as usize] + r[rs2 as usize]
+            },
+            Instruction::Sub { rd, rs1, rs2 } => {
+                state.registers[rd as usize] = r[rs1 as usize] - r[rs2 as usize]
+            },
+            Instruction::Mul { rd, rs1, rs2 } => {
+                state.registers[rd as usize] = r[rs1 as usize] * r[rs2 as usize]
+            },
+            Instruction::Load { rd, .. } => state.registers[rd as usize] = state.memory[addr],
+            Instruction::Store { rs2, .. } => state.memory[addr] = r[rs2 as usize],
+            Instruction::Bz { .. } | Instruction::Halt => (),
+        }
+        state.pc = next_pc;
+        Ok(())
+    }
 
-    pub fn run(&mut self) {
-        for _i in 0..(1 << self.params.log_num_subcircuit) * self.params.operations_per_chunk {
-            self.execute_dummy_operation();
+    /// Runs the VM for `params.num_steps()` steps and returns the final state
+    pub fn run(&self) -> VmState<F> {
+        let mut state = self.initial_state();
+        for _ in 0..self.params.num_steps() {
+            self.step(&mut state);
         }
+        state
+    }
+}
+
+impl<F: PrimeField + Absorb> VirtualMachine<F> {
+    /// Returns the Merkle memory hints for every subcircuit. Every instruction accesses memory
+    /// twice, once to read and once to write, and each access gets a hint.
+    pub(crate) fn merkle_hints(&self) -> &[Vec<MerkleAccessHint<F>>] {
+        self.merkle_hints.get_or_init(|| {
+            let mut state = self.initial_state();
+            let mut memory = NativeMerkleMemory::new(&state.memory);
+
+            (0..self.params.num_subcircuits)
+                .map(|_| {
+                    let mut chunk_hints = Vec::with_capacity(2 * self.params.operations_per_chunk);
+                    for _ in 0..self.params.operations_per_chunk {
+                        let addr = self.mem_addr(&state);
+                        let hint = memory.hint(addr, state.memory[addr]);
+                        chunk_hints.push(hint.clone());
+                        chunk_hints.push(hint);
+
+                        self.step(&mut state);
+                        memory.write(addr, state.memory[addr]);
+                    }
+                    chunk_hints
+                })
+                .collect()
+        })
     }
 }
 
+/// Converts a field element to a `usize`, if it fits
+pub(crate) fn field_to_usize<F: PrimeField>(x: F) -> Option<usize> {
+    let bytes = x.into_bigint().to_bytes_le();
+    if bytes[8..].iter().any(|&b| b != 0) {
+        return None;
+    }
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[..8]);
+    usize::try_from(u64::from_le_bytes(buf)).ok()
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::vm::{VirtualMachine, VirtualMachineParameters};
+    use crate::vm::{Instruction, VirtualMachine, VirtualMachineParameters, VmError};
     use ark_bls12_381::Fq;
+    use ark_std::test_rng;
 
     #[test]
     fn test_virtual_machine() {
         let virtual_machine_parameter = VirtualMachineParameters {
             use_merkle_memory: false,
-            log_num_subcircuit: 5,
-            dummy_constraint_num: 30,
+            num_subcircuits: 32,
             operations_per_chunk: 2,
+            program_len: 16,
+            memory_size: 8,
         };
-        let mut vm: VirtualMachine<Fq> = VirtualMachine::new(&virtual_machine_parameter);
+        let vm: VirtualMachine<Fq> =
+            VirtualMachine::rand(&mut test_rng(), &virtual_machine_parameter);
         vm.run();
     }
+
+    // Computes 5! with a loop
+    #[test]
+    fn test_factorial() {
+        let params = VirtualMachineParameters {
+            use_merkle_memory: false,
+            num_subcircuits: 8,
+            operations_per_chunk: 4,
+            program_len: 16,
+            memory_size: 4,
+        };
+        let program = vec![
+            // r1 ← n, r2 ← 1, r3 ← 1
+            Instruction::Load {
+                rd: 1,
+                rs1: 0,
+                imm: 0,
+            },
+            Instruction::Load {
+                rd: 2,
+                rs1: 0,
+                imm: 1,
+            },
+            Instruction::Load {
+                rd: 3,
+                rs1: 0,
+                imm: 1,
+            },
+            // Loop: if r1 = 0, exit
+            Instruction::Bz { rs1: 1, imm: 7 },
+            // r2 ← r2 * r1, r1 ← r1 - 1
+            Instruction::Mul {
+                rd: 2,
+                rs1: 2,
+                rs2: 1,
+            },
+            Instruction::Sub {
+                rd: 1,
+                rs1: 1,
+                rs2: 3,
+            },
+            // Jump back to the top of the loop
+            Instruction::Bz { rs1: 0, imm: 3 },
+            // mem[2] ← r2
+            Instruction::Store {
+                rs1: 0,
+                rs2: 2,
+                imm: 2,
+            },
+            Instruction::Halt,
+        ];
+        let vm = VirtualMachine::with_program(&params, program, vec![Fq::from(5u8), Fq::from(1u8)])
+            .unwrap();
+
+        let final_state = vm.run();
+        assert_eq!(final_state.memory[2], Fq::from(120u8));
+        assert_eq!(final_state.pc, 8);
+    }
+
+    // Programs that fault are rejected when they're loaded, not mid-run
+    #[test]
+    fn test_faulting_program() {
+        let params = VirtualMachineParameters {
+            use_merkle_memory: false,
+            num_subcircuits: 8,
+            operations_per_chunk: 4,
+            program_len: 16,
+            memory_size: 4,
+        };
+
+        // The load is reached on the second step
+        let oob_load = vec![
+            Instruction::Add {
+                rd: 1,
+                rs1: 0,
+                rs2: 0,
+            },
+            Instruction::Load {
+                rd: 1,
+                rs1: 0,
+                imm: 4,
+            },
+        ];
+        let err = VirtualMachine::<Fq>::with_program(&params, oob_load, Vec::new()).unwrap_err();
+        assert_eq!(err, VmError::MemoryOutOfBounds { pc: 1 });
+
+        let oob_jump = vec![Instruction::Bz { rs1: 0, imm: 16 }];
+        let err = VirtualMachine::<Fq>::with_program(&params, oob_jump, Vec::new()).unwrap_err();
+        assert_eq!(err, VmError::PcOutOfRange { pc: 0 });
+
+        let err = VirtualMachine::<Fq>::with_program(&params, Vec::new(), vec![Fq::from(0u8); 5])
+            .unwrap_err();
+        assert_eq!(err, VmError::MemoryTooLarge(5));
+    }
+
+    #[test]
+    fn test_instruction_encoding() {
+        let instrs = [
+            Instruction::Add {
+                rd: 1,
+                rs1: 2,
+                rs2: 3,
+            },
+            Instruction::Sub {
+                rd: 15,
+                rs1: 0,
+                rs2: 7,
+            },
+            Instruction::Mul {
+                rd: 4,
+                rs1: 4,
+                rs2: 4,
+            },
+            Instruction::Load {
+                rd: 9,
+                rs1: 10,
+                imm: 65535,
+            },
+            Instruction::Store {
+                rs1: 11,
+                rs2: 12,
+                imm: 42,
+            },
+            Instruction::Bz { rs1: 13, imm: 1 },
+            Instruction::Halt,
+        ];
+        for instr in instrs {
+            assert_eq!(Instruction::decode(instr.encode()), Some(instr));
+        }
+
+        // Opcode 7 isn't an instruction
+        assert_eq!(Instruction::decode(7), None);
+    }
 }
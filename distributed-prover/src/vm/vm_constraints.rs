@@ -1,75 +1,123 @@
 use crate::portal_manager::{PortalManager, RamProverPortalManager, SetupRamPortalManager};
 use crate::transcript::{MemType, TranscriptEntry};
-use crate::vm::memory::Memory;
-use crate::vm::{VirtualMachine, VirtualMachineParameters, REGISTER_NUM};
+use crate::vm::memory::{Memory, MerkleAccessHint, NativeMerkleMemory};
+use crate::vm::vm::{
+    field_to_usize, mem_name, prog_name, register_name, IMM_BITS, INSTRUCTION_BITS, MEM_ROOT_NAME,
+    OPCODE_BITS, OP_ADD, OP_BZ, OP_HALT, OP_LOAD, OP_MUL, OP_STORE, OP_SUB, PC_NAME, REG_IDX_BITS,
+};
+use crate::vm::{Instruction, VirtualMachine, VirtualMachineParameters, REGISTER_NUM};
 use crate::CircuitWithPortals;
-use ark_bls12_381::Fr;
-use ark_ff::Field;
+use ark_crypto_primitives::sponge::Absorb;
+use ark_ff::{BigInteger, PrimeField};
 use ark_r1cs_std::alloc::AllocVar;
+use ark_r1cs_std::boolean::Boolean;
+use ark_r1cs_std::eq::EqGadget;
 use ark_r1cs_std::fields::fp::FpVar;
+use ark_r1cs_std::fields::FieldVar;
+use ark_r1cs_std::select::CondSelectGadget;
+use ark_r1cs_std::R1CSVar;
+use ark_relations::ns;
 use ark_relations::r1cs::{ConstraintSystem, ConstraintSystemRef, SynthesisError};
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
 use rand::Rng;
+use std::cmp::Ordering;
 use std::marker::PhantomData;
-use std::ops::Mul;
+use std::sync::OnceLock;
 
-impl CircuitWithPortals<Fr> for VirtualMachine<Fr> {
+impl<F: PrimeField + Absorb> CircuitWithPortals<F> for VirtualMachine<F> {
     type Parameters = VirtualMachineParameters;
-    type ProverPortalManager = RamProverPortalManager<Fr>;
+    type ProverPortalManager = RamProverPortalManager<F>;
     const MEM_TYPE: MemType = MemType::Ram;
 
-    /// Makes a random instance of this circuit. This is just new()
-    fn rand(_: &mut impl Rng, params: &VirtualMachineParameters) -> Self {
-        Self::new(params)
+    /// Makes a VM with a random straight-line program and random initial memory
+    fn rand(rng: &mut impl Rng, params: &VirtualMachineParameters) -> Self {
+        VirtualMachine::rand(rng, params)
     }
 
     fn get_params(&self) -> Self::Parameters {
         self.params
     }
 
-    fn get_portal_subtraces(&self) -> Vec<Vec<TranscriptEntry<Fr>>> {
+    /// Runs the VM natively and records every portal wire access that `generate_constraints`
+    /// would make
+    fn get_portal_subtraces(&self) -> Vec<Vec<TranscriptEntry<F>>> {
         let cs = ConstraintSystem::new_ref();
         let mut pm = SetupRamPortalManager::new(cs.clone());
+        let prog_base = self.params.prog_base();
+        let mem_base = self.params.mem_base();
+        let constant = |x: F| FpVar::Constant(x);
+        let addr = |x: u64| FpVar::Constant(F::from(x));
+
+        let use_merkle_memory = self.params.use_merkle_memory;
+        let mut state = self.initial_state();
+        let mut merkle_memory = use_merkle_memory.then(|| NativeMerkleMemory::new(&state.memory));
         for subcircuit_idx in 0..self.num_subcircuits() {
             pm.start_subtrace(cs.clone());
-            if subcircuit_idx != 0 {
-                // get the registers
-                for i in 0..REGISTER_NUM {
-                    let _ = pm.get(&format!("register {i}"));
+
+            if subcircuit_idx == 0 {
+                // Write the initial registers, pc, program, and memory, in that order
+                for (i, reg) in state.registers.iter().enumerate() {
+                    pm.set(register_name(i), &constant(*reg)).unwrap();
                 }
-                // do the dummy operation
-                for _ in 0..self.params.operations_per_chunk {
-                    let _ = pm.set(
-                        format!("register 1"),
-                        &FpVar::new_witness(cs.clone(), || Ok(Fr::ONE)).unwrap(),
-                    );
-                    let _ = pm.get(&format!("register 1"));
-                    let _ = pm.get(&format!("register 1"));
+                pm.set(PC_NAME.to_string(), &addr(state.pc as u64)).unwrap();
+                for (k, instr) in self.program.iter().enumerate() {
+                    pm.set(prog_name(k), &addr(instr.encode())).unwrap();
                 }
-                // write the resulting registers to pm
-                for i in 0..REGISTER_NUM {
-                    let _ = pm.set(
-                        format!("register {i}"),
-                        &FpVar::new_witness(cs.clone(), || Ok(Fr::ONE)).unwrap(),
-                    );
+                match &merkle_memory {
+                    Some(mem) => pm
+                        .set(MEM_ROOT_NAME.to_string(), &constant(mem.root()))
+                        .unwrap(),
+                    None => {
+                        for (k, word) in state.memory.iter().enumerate() {
+                            pm.set(mem_name(k), &constant(*word)).unwrap();
+                        }
+                    },
                 }
             } else {
-                // write the initial registers
+                // Read the registers, pc, and memory root left by the previous subcircuit
                 for i in 0..REGISTER_NUM {
-                    let _ = pm.set(
-                        format!("register {i}"),
-                        &FpVar::new_witness(cs.clone(), || Ok(Fr::ONE)).unwrap(),
-                    );
+                    let _ = pm.get(&register_name(i)).unwrap();
+                }
+                let _ = pm.get(PC_NAME).unwrap();
+                if use_merkle_memory {
+                    let _ = pm.get(MEM_ROOT_NAME).unwrap();
+                }
+            }
+
+            // Every step fetches an instruction, then reads and writes one memory word. Merkle
+            // memory accesses don't touch portal wires.
+            for _ in 0..self.params.operations_per_chunk {
+                let _ = pm
+                    .get_at(&prog_name(state.pc), &addr(prog_base + state.pc as u64))
+                    .unwrap();
+
+                let mem_addr = self.mem_addr(&state);
+                let mem_addr_var = addr(mem_base + mem_addr as u64);
+                if !use_merkle_memory {
+                    let _ = pm.get_at(&mem_name(mem_addr), &mem_addr_var).unwrap();
                 }
-                // do the dummy operation
-                for _ in 0..self.params.operations_per_chunk {
-                    let _ = pm.set(
-                        format!("register 1"),
-                        &FpVar::new_witness(cs.clone(), || Ok(Fr::ONE)).unwrap(),
-                    );
-                    let _ = pm.get(&format!("register 1"));
-                    let _ = pm.get(&format!("register 1"));
+                self.step(&mut state);
+                match &mut merkle_memory {
+                    Some(mem) => mem.write(mem_addr, state.memory[mem_addr]),
+                    None => pm
+                        .set_at(
+                            mem_name(mem_addr),
+                            &mem_addr_var,
+                            &constant(state.memory[mem_addr]),
+                        )
+                        .unwrap(),
                 }
             }
+
+            // Hand the registers, pc, and memory root to the next subcircuit
+            for (i, reg) in state.registers.iter().enumerate() {
+                pm.set(register_name(i), &constant(*reg)).unwrap();
+            }
+            pm.set(PC_NAME.to_string(), &addr(state.pc as u64)).unwrap();
+            if let Some(mem) = &merkle_memory {
+                pm.set(MEM_ROOT_NAME.to_string(), &constant(mem.root()))
+                    .unwrap();
+            }
         }
 
         // Return the subtraces, wrapped appropriately
@@ -85,11 +133,12 @@ impl CircuitWithPortals<Fr> for VirtualMachine<Fr> {
     }
 
     fn num_subcircuits(&self) -> usize {
-        1 << self.params.log_num_subcircuit
-    } 
+        self.params.num_subcircuits
+    }
 
+    /// The first subcircuit, and any other one. A VM with one subcircuit only has the first
     fn get_unique_subcircuits(&self) -> Vec<usize> {
-        vec![0, 1]
+        (0..self.num_subcircuits().min(2)).collect()
     }
 
     fn representative_subcircuit(&self, subcircuit_idx: usize) -> usize {
@@ -100,94 +149,264 @@ impl CircuitWithPortals<Fr> for VirtualMachine<Fr> {
         VirtualMachine::new(params)
     }
 
-    fn get_serialized_witnesses(&self, _subcircuit_idx: usize) -> Vec<u8> {
-        Vec::new()
+    /// The program is a public input, so it's part of every VM's parameters. Nonetheless, the
+    /// first subcircuit gets a copy of it, along with the initial memory.
+    fn public_inputs(&self) -> Vec<F> {
+        self.program
+            .iter()
+            .map(|instr| F::from(instr.encode()))
+            .collect()
     }
 
-    fn set_serialized_witnesses(&mut self, _subcircuit_idx: usize, _bytes: &[u8]) {}
+    /// The first subcircuit's witnesses are the program and the initial memory. In Merkle mode,
+    /// every subcircuit also gets the hints for its memory accesses. The rest of the state is
+    /// passed through portal wires.
+    fn get_serialized_witnesses(&self, subcircuit_idx: usize) -> Vec<u8> {
+        let mut out_buf = Vec::new();
+        if subcircuit_idx == 0 {
+            let encoded_program: Vec<u64> = self.program.iter().map(Instruction::encode).collect();
+            encoded_program
+                .serialize_uncompressed(&mut out_buf)
+                .unwrap();
+            self.initial_memory
+                .serialize_uncompressed(&mut out_buf)
+                .unwrap();
+        }
+        if self.params.use_merkle_memory {
+            self.merkle_hints()[subcircuit_idx]
+                .serialize_uncompressed(&mut out_buf)
+                .unwrap();
+        }
+        out_buf
+    }
 
-    fn generate_constraints<P: PortalManager<Fr>>(
+    fn set_serialized_witnesses(&mut self, subcircuit_idx: usize, mut bytes: &[u8]) {
+        if subcircuit_idx == 0 {
+            let encoded_program =
+                Vec::<u64>::deserialize_uncompressed_unchecked(&mut bytes).unwrap();
+            self.program = encoded_program
+                .into_iter()
+                .enumerate()
+                .map(|(pc, word)| {
+                    Instruction::decode(word).unwrap_or_else(|| {
+                        panic!("witness program has an invalid instruction {word:#x} at pc {pc}")
+                    })
+                })
+                .collect();
+            self.initial_memory = Vec::<F>::deserialize_uncompressed_unchecked(&mut bytes).unwrap();
+        }
+        if self.params.use_merkle_memory {
+            // Only this subcircuit's hints are known
+            let mut hints = vec![Vec::new(); self.num_subcircuits()];
+            hints[subcircuit_idx] =
+                Vec::<MerkleAccessHint<F>>::deserialize_uncompressed_unchecked(&mut bytes).unwrap();
+            self.merkle_hints = OnceLock::from(hints);
+        }
+    }
+
+    fn generate_constraints<P: PortalManager<F>>(
         &mut self,
-        cs: ConstraintSystemRef<Fr>,
+        cs: ConstraintSystemRef<F>,
         subcircuit_idx: usize,
         pm: &mut P,
     ) -> Result<(), SynthesisError> {
-        let starting_num_constraints = cs.num_constraints();
-        // set memory struct
-        let mut memory = Memory {
-            use_merkle_memory: self.params.use_merkle_memory,
-            portal_manager: pm,
-            phantom: PhantomData,
+        // Without public inputs, the first subcircuit witnesses the program itself
+        let program = if subcircuit_idx == 0 {
+            self.public_inputs()
+                .into_iter()
+                .map(|word| FpVar::new_witness(ns!(cs, "prog"), || Ok(word)))
+                .collect::<Result<Vec<_>, _>>()?
+        } else {
+            Vec::new()
+        };
+        self.generate_constraints_with_public_inputs(cs, subcircuit_idx, pm, &program)
+    }
+
+    /// The public inputs are the program words. The first subcircuit writes them to the program
+    /// ROM.
+    fn generate_constraints_with_public_inputs<P: PortalManager<F>>(
+        &mut self,
+        cs: ConstraintSystemRef<F>,
+        subcircuit_idx: usize,
+        pm: &mut P,
+        public_inputs: &[FpVar<F>],
+    ) -> Result<(), SynthesisError> {
+        let (mut registers, mut pc) = if subcircuit_idx == 0 {
+            assert_eq!(public_inputs.len(), self.params.program_len);
+
+            // Execution starts at pc 0 with zeroed registers
+            let registers = vec![FpVar::zero(); REGISTER_NUM];
+            let pc = FpVar::zero();
+            for (i, reg) in registers.iter().enumerate() {
+                pm.set(register_name(i), reg)?;
+            }
+            pm.set(PC_NAME.to_string(), &pc)?;
+            for (k, word) in public_inputs.iter().enumerate() {
+                pm.set(prog_name(k), word)?;
+            }
+            (registers, pc)
+        } else {
+            let registers = (0..REGISTER_NUM)
+                .map(|i| pm.get(&register_name(i)))
+                .collect::<Result<Vec<_>, _>>()?;
+            let pc = pm.get(PC_NAME)?;
+            (registers, pc)
         };
-        // subcircuit_idx == 0 ==> write registers to memory otherwise dummy variable
-        if subcircuit_idx != 0 {
-            // get the registers
-            get_registers(&mut memory, cs.clone());
-            // do the dummy operation
-            do_dummy_operation(
-                self,
-                cs.clone(),
-                self.params.dummy_constraint_num,
-                &mut memory,
-            );
-            // write the resulting registers to pm
-            set_registers(&mut memory, cs.clone());
+
+        let mut memory = self.memory(cs.clone(), pm, subcircuit_idx);
+        if subcircuit_idx == 0 {
+            memory.init(&self.initial_memory)?;
         } else {
-            // write the initial registers
-            set_registers(&mut memory, cs.clone());
-            do_dummy_operation(
-                self,
-                cs.clone(),
-                self.params.dummy_constraint_num,
-                &mut memory,
-            );
+            memory.load()?;
+        }
+
+        for _ in 0..self.params.operations_per_chunk {
+            self.step_constraints(cs.clone(), &mut memory, &mut registers, &mut pc)?;
         }
-        let ending_num_constraints = cs.num_constraints();
-        println!(
-            "Test subcircuit {subcircuit_idx} costs {} constraints",
-            ending_num_constraints - starting_num_constraints
-        );
+
+        // Hand the registers, pc, and memory to the next subcircuit
+        for (i, reg) in registers.iter().enumerate() {
+            memory.portal_manager.set(register_name(i), reg)?;
+        }
+        memory.portal_manager.set(PC_NAME.to_string(), &pc)?;
+        memory.store()?;
         Ok(())
     }
 }
 
-pub fn get_registers<P: PortalManager<Fr>>(memory: &mut Memory<P>, cs: ConstraintSystemRef<Fr>) {
-    for i in 0..REGISTER_NUM {
-        let _ = memory.get(&format!("register {i}"), cs.clone());
-    }
-}
+impl<F: PrimeField + Absorb> VirtualMachine<F> {
+    /// Makes the data memory for the given subcircuit. In Merkle mode, the hints are the ones set
+    /// by `set_serialized_witnesses`. If there are none, e.g., during CRS generation, every
+    /// access's value is missing.
+    fn memory<'a, P: PortalManager<F>>(
+        &'a self,
+        cs: ConstraintSystemRef<F>,
+        pm: &'a mut P,
+        subcircuit_idx: usize,
+    ) -> Memory<'a, F, P> {
+        let hints = self
+            .merkle_hints
+            .get()
+            .and_then(|hints| hints.get(subcircuit_idx))
+            .map(Vec::as_slice)
+            .unwrap_or_default();
 
-pub fn set_registers<P: PortalManager<Fr>>(memory: &mut Memory<P>, cs: ConstraintSystemRef<Fr>) {
-    for i in 0..REGISTER_NUM {
-        let _ = memory.set(
-            format!("register {i}"),
-            &FpVar::new_witness(cs.clone(), || Ok(Fr::ONE)).unwrap(),
-            cs.clone(),
-        );
+        Memory {
+            use_merkle_memory: self.params.use_merkle_memory,
+            base_addr: self.params.mem_base(),
+            cs,
+            portal_manager: pm,
+            root: None,
+            hints: hints.iter(),
+            phantom: PhantomData,
+        }
     }
-}
 
-pub fn do_dummy_operation<P: PortalManager<Fr>>(
-    vm: &mut VirtualMachine<Fr>,
-    cs: ConstraintSystemRef<Fr>,
-    dummy_constraint_num: usize,
-    memory: &mut Memory<P>,
-) {
-    for _ in 0usize..vm.params.operations_per_chunk {
-        // memory operations
-        let _ = memory.set(
-            "register 1".to_string(),
-            &FpVar::new_witness(cs.clone(), || Ok(Fr::ONE)).unwrap(),
-            cs.clone(),
-        );
-        let _ = memory.get(&"register 1".to_string(), cs.clone());
-        let _ = memory.get(&"register 1".to_string(), cs.clone());
-        // do some dummy operation
-        for _ in 0..dummy_constraint_num / 2 {
-            let fp1 = FpVar::new_witness(cs.clone(), || Ok(Fr::from(12u128))).unwrap();
-            let fp2 = FpVar::new_witness(cs.clone(), || Ok(Fr::from(12u128))).unwrap();
-            let _ = fp1.mul(fp2);
+    /// Constrains the execution of a single instruction. Every instruction does the same portal
+    /// wire accesses: one program fetch, one memory read, and one memory write. Instructions that
+    /// don't touch memory rewrite word 0 with its current value.
+    fn step_constraints<P: PortalManager<F>>(
+        &self,
+        cs: ConstraintSystemRef<F>,
+        memory: &mut Memory<F, P>,
+        registers: &mut [FpVar<F>],
+        pc: &mut FpVar<F>,
+    ) -> Result<(), SynthesisError> {
+        let constant = |x: u64| FpVar::constant(F::from(x));
+
+        // Fetch the instruction. The pc must be in the program ROM. The only constant pc is the
+        // initial one, which is 0
+        if !pc.is_constant() {
+            pc.enforce_cmp(
+                &constant(self.params.program_len as u64),
+                Ordering::Less,
+                false,
+            )?;
         }
+        let pc_val = pc.value().ok().and_then(field_to_usize).unwrap_or_default();
+        let word = memory.portal_manager.get_at(
+            &prog_name(pc_val),
+            &(&*pc + constant(self.params.prog_base())),
+        )?;
+
+        // Decode the instruction
+        let bits = (0..INSTRUCTION_BITS)
+            .map(|i| {
+                Boolean::new_witness(ns!(cs, "instr bit"), || {
+                    Ok(word.value()?.into_bigint().get_bit(i))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Boolean::le_bits_to_fp_var(&bits)?.enforce_equal(&word)?;
+        let (opcode_bits, rest) = bits.split_at(OPCODE_BITS);
+        let (rd_bits, rest) = rest.split_at(REG_IDX_BITS);
+        let (rs1_bits, rest) = rest.split_at(REG_IDX_BITS);
+        let (rs2_bits, imm_bits) = rest.split_at(REG_IDX_BITS);
+        debug_assert_eq!(imm_bits.len(), IMM_BITS);
+
+        let opcode = Boolean::le_bits_to_fp_var(opcode_bits)?;
+        let rd = Boolean::le_bits_to_fp_var(rd_bits)?;
+        let imm = Boolean::le_bits_to_fp_var(imm_bits)?;
+        let is_op = |op: u64| opcode.is_eq(&constant(op));
+        let is_add = is_op(OP_ADD)?;
+        let is_sub = is_op(OP_SUB)?;
+        let is_mul = is_op(OP_MUL)?;
+        let is_load = is_op(OP_LOAD)?;
+        let is_store = is_op(OP_STORE)?;
+        let is_bz = is_op(OP_BZ)?;
+        let is_halt = is_op(OP_HALT)?;
+        // The only opcode left over is invalid
+        Boolean::kary_or(&[
+            is_add.clone(),
+            is_sub.clone(),
+            is_mul.clone(),
+            is_load.clone(),
+            is_store.clone(),
+            is_bz.clone(),
+            is_halt.clone(),
+        ])?
+        .enforce_equal(&Boolean::TRUE)?;
+
+        // Read the source registers. Selection takes big-endian indices
+        let select_reg = |idx_bits: &[Boolean<F>]| {
+            let be_bits: Vec<_> = idx_bits.iter().rev().cloned().collect();
+            FpVar::conditionally_select_power_of_two_vector(&be_bits, registers)
+        };
+        let rs1_val = select_reg(rs1_bits)?;
+        let rs2_val = select_reg(rs2_bits)?;
+
+        // Do the memory access. This must be in bounds
+        let is_mem = is_load.or(&is_store)?;
+        let mem_offset = is_mem.select(&(&rs1_val + &imm), &FpVar::zero())?;
+        mem_offset.enforce_cmp(
+            &constant(self.params.memory_size as u64),
+            Ordering::Less,
+            false,
+        )?;
+        let old_word = memory.read(&mem_offset)?;
+        let new_word = is_store.select(&rs2_val, &old_word)?;
+        memory.write(&mem_offset, &new_word)?;
+
+        // Write the result to rd, if the instruction has a result
+        let result = is_add.select(
+            &(&rs1_val + &rs2_val),
+            &is_sub.select(
+                &(&rs1_val - &rs2_val),
+                &is_mul.select(&(&rs1_val * &rs2_val), &old_word)?,
+            )?,
+        )?;
+        let writes_rd = Boolean::kary_or(&[is_add, is_sub, is_mul, is_load])?;
+        for (j, reg) in registers.iter_mut().enumerate() {
+            let should_write = writes_rd.and(&rd.is_eq(&constant(j as u64))?)?;
+            *reg = should_write.select(&result, reg)?;
+        }
+
+        // Compute the next pc
+        let is_jump = is_bz.and(&rs1_val.is_zero()?)?;
+        let next_pc = is_halt.select(pc, &is_jump.select(&imm, &(&*pc + FpVar::one()))?)?;
+        *pc = next_pc;
+
+        Ok(())
     }
 }
 
@@ -195,20 +414,15 @@ pub fn do_dummy_operation<P: PortalManager<Fr>>(
 mod tests {
     use crate::portal_manager::SetupRamPortalManager;
     use crate::transcript::TranscriptEntry;
-    use crate::vm::{VirtualMachine, VirtualMachineParameters};
+    use crate::vm::{Instruction, VirtualMachine, VirtualMachineParameters};
     use crate::CircuitWithPortals;
     use ark_bls12_381::Fr;
     use ark_relations::r1cs::ConstraintSystem;
+    use ark_std::test_rng;
 
-    #[test]
-    fn test_vm() {
-        let virtual_machine_parameter = VirtualMachineParameters {
-            use_merkle_memory: false,
-            log_num_subcircuit: 3,
-            dummy_constraint_num: 30,
-            operations_per_chunk: 2,
-        };
-        let mut vm: VirtualMachine<Fr> = VirtualMachine::new(&virtual_machine_parameter);
+    // Runs every subcircuit of the VM on a pared-down copy of the circuit, and checks that the
+    // constraints are satisfied and the subtraces match the natively computed ones
+    fn check_vm(vm: &VirtualMachine<Fr>) {
         let expected_subtraces = vm.get_portal_subtraces();
 
         // initializing the portal manager
@@ -217,24 +431,98 @@ mod tests {
         let cs = pm.cs.clone();
         for subcircuit_idx in 0..vm.num_subcircuits() {
             pm.start_subtrace(cs.clone());
-            vm.generate_constraints(cs.clone(), subcircuit_idx, &mut pm)
+
+            let mut circ_copy = VirtualMachine::<Fr>::new(&vm.params);
+            let wits = vm.get_serialized_witnesses(subcircuit_idx);
+            circ_copy.set_serialized_witnesses(subcircuit_idx, &wits);
+            circ_copy
+                .generate_constraints(cs.clone(), subcircuit_idx, &mut pm)
                 .unwrap();
         }
         assert!(pm.cs.is_satisfied().unwrap());
         println!("num_constraints: {}", cs.num_constraints());
 
-        if !virtual_machine_parameter.use_merkle_memory {
-            // Compare the subtraces
-            let wrapped_subtraces = pm
-                .subtraces
-                .into_iter()
-                .map(|st| {
-                    st.into_iter()
-                        .map(|e| TranscriptEntry::Ram(e))
-                        .collect::<Vec<_>>()
-                })
-                .collect::<Vec<_>>();
-            assert_eq!(wrapped_subtraces, expected_subtraces);
+        // Compare the subtraces
+        let wrapped_subtraces = pm
+            .subtraces
+            .into_iter()
+            .map(|st| {
+                st.into_iter()
+                    .map(|e| TranscriptEntry::Ram(e))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(wrapped_subtraces, expected_subtraces);
+    }
+
+    #[test]
+    fn test_vm() {
+        let mut rng = test_rng();
+        // Include counts that aren't powers of two, and a single subcircuit
+        for (num_subcircuits, operations_per_chunk) in [(8, 2), (5, 3), (1, 3)] {
+            for use_merkle_memory in [false, true] {
+                let virtual_machine_parameter = VirtualMachineParameters {
+                    use_merkle_memory,
+                    num_subcircuits,
+                    operations_per_chunk,
+                    program_len: 8,
+                    memory_size: 4,
+                };
+                let vm = VirtualMachine::<Fr>::rand(&mut rng, &virtual_machine_parameter);
+                check_vm(&vm);
+            }
         }
     }
+
+    // Computes 4! with a loop that crosses subcircuit boundaries
+    #[test]
+    fn test_vm_factorial() {
+        let params = VirtualMachineParameters {
+            use_merkle_memory: false,
+            num_subcircuits: 8,
+            operations_per_chunk: 4,
+            program_len: 16,
+            memory_size: 4,
+        };
+        let program = vec![
+            Instruction::Load {
+                rd: 1,
+                rs1: 0,
+                imm: 0,
+            },
+            Instruction::Load {
+                rd: 2,
+                rs1: 0,
+                imm: 1,
+            },
+            Instruction::Load {
+                rd: 3,
+                rs1: 0,
+                imm: 1,
+            },
+            Instruction::Bz { rs1: 1, imm: 7 },
+            Instruction::Mul {
+                rd: 2,
+                rs1: 2,
+                rs2: 1,
+            },
+            Instruction::Sub {
+                rd: 1,
+                rs1: 1,
+                rs2: 3,
+            },
+            Instruction::Bz { rs1: 0, imm: 3 },
+            Instruction::Store {
+                rs1: 0,
+                rs2: 2,
+                imm: 2,
+            },
+            Instruction::Halt,
+        ];
+        let vm = VirtualMachine::with_program(&params, program, vec![Fr::from(4u8), Fr::from(1u8)])
+            .unwrap();
+        assert_eq!(vm.run().memory[2], Fr::from(24u8));
+
+        check_vm(&vm);
+    }
 }
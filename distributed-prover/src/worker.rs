@@ -1,5 +1,5 @@
 use crate::{
-    coordinator::{Stage0Request, Stage1Request},
+    coordinator::{RoundRequest, Stage0Request, Stage1Request},
     eval_tree::{ExecTreeParams, SerializedLeaf, SerializedLeafVar, TreeConfig, TreeConfigGadget},
     subcircuit_circuit::SubcircuitWithPortalsProver,
     util::{G16Com, G16ComRandomness, G16ComSeed, G16ProvingKey},
@@ -44,6 +44,9 @@ impl<E: Pairing> Stage0Response<E> {
     }
 }
 
+/// The response to a round request is the Groth16 commitment to the round's witnesses, and its seed
+pub type RoundResponse<E> = Stage0Response<E>;
+
 /// The repsonse is the Groth16 proof for the requested subcircuit
 #[derive(Clone, CanonicalSerialize, CanonicalDeserialize)]
 pub struct Stage1Response<E: Pairing> {
@@ -145,13 +148,67 @@ where
     (resp, cb)
 }
 
+/// Performs the Groth16 commitment of the given extra round, using the builder that committed to
+/// the subcircuit's earlier stages. Returns the response and the commitment randomness
+pub fn process_round_request_with_cb<C, CG, E, P, R>(
+    mut rng: R,
+    cb: &mut G16CommitmentBuilder<SubcircuitWithPortalsProver<E::ScalarField, P, C, CG>, E, QAP>,
+    req: RoundRequest<E::ScalarField, P>,
+) -> (RoundResponse<E>, G16ComRandomness<E>)
+where
+    C: TreeConfig<Leaf = SerializedLeaf<E::ScalarField>>,
+    CG: TreeConfigGadget<C, E::ScalarField, Leaf = SerializedLeafVar<E::ScalarField>>,
+    E: Pairing,
+    P: CircuitWithPortals<E::ScalarField>,
+    R: RngCore,
+{
+    let RoundRequest {
+        subcircuit_idx,
+        round,
+        challenges,
+        serialized_witnesses,
+        circ_params,
+    } = req;
+
+    assert_eq!(cb.circuit.subcircuit_idx, subcircuit_idx);
+    assert_eq!(
+        challenges.len(),
+        P::MEM_TYPE.num_challenges() + round * P::NUM_ROUND_CHALLENGES,
+        "wrong number of challenges for round {round}"
+    );
+
+    // The circuit keeps the variables it allocates in every round, so only make it the first time
+    if cb.circuit.circ.is_none() {
+        let mut underlying_circuit = P::new(&circ_params);
+        underlying_circuit.set_serialized_witnesses(subcircuit_idx, &serialized_witnesses);
+        cb.circuit.circ = Some(underlying_circuit);
+    }
+    cb.circuit.challenges[..challenges.len()].copy_from_slice(&challenges);
+
+    // Commit to the round's witnesses, and save the seed like in stage 0
+    let com_seed = rng.gen::<G16ComSeed>();
+    let mut subcircuit_rng = ChaCha12Rng::from_seed(com_seed);
+    let (com, rand) = cb
+        .commit(&mut subcircuit_rng)
+        .expect("failed to commit to round witnesses");
+
+    let resp = RoundResponse {
+        subcircuit_idx,
+        com,
+        com_seed,
+    };
+
+    (resp, rand)
+}
+
 /// Process the given stage1 request, along with all the previous messages in this execution, and
-/// produces a Groth16 proof
+/// produces a Groth16 proof. `coms` and `rands` are the commitments and randomness of stage 0 and
+/// every extra round, in order.
 pub fn process_stage1_request_with_cb<C, CG, E, P, R>(
     mut rng: R,
     mut cb: G16CommitmentBuilder<SubcircuitWithPortalsProver<E::ScalarField, P, C, CG>, E, QAP>,
-    com: G16Com<E>,
-    rand: G16ComRandomness<E>,
+    coms: &[G16Com<E>],
+    rands: &[G16ComRandomness<E>],
     stage1_req: Stage1Request<C, E::ScalarField, P>,
 ) -> Stage1Response<E>
 where
@@ -169,24 +226,34 @@ where
         root,
         serialized_witnesses,
         circ_params,
+        public_inputs,
+        public_input_membership,
+        round_challenges,
     } = stage1_req;
-    let challenges = cur_leaf.evals.challenges();
+    let challenges = [cur_leaf.evals.challenges(), round_challenges].concat();
 
     assert_eq!(cb.circuit.subcircuit_idx, subcircuit_idx);
 
     // Make an empty version of the large circuit and fill in just the witnesses for the
-    // subcircuit we're proving now
-    let mut underlying_circuit = P::new(&circ_params);
-    underlying_circuit.set_serialized_witnesses(subcircuit_idx, &serialized_witnesses);
-    cb.circuit.circ = Some(underlying_circuit);
+    // subcircuit we're proving now. If there were extra rounds, the circuit was already made then,
+    // and it holds the variables allocated in them
+    if cb.circuit.circ.is_none() {
+        let mut underlying_circuit = P::new(&circ_params);
+        underlying_circuit.set_serialized_witnesses(subcircuit_idx, &serialized_witnesses);
+        cb.circuit.circ = Some(underlying_circuit);
+    }
 
     // Put the request values into our circuit
     cb.circuit.cur_leaf = cur_leaf;
     cb.circuit.next_leaf_membership = next_leaf_membership;
     cb.circuit.root = root;
     cb.circuit.challenges = challenges;
+    cb.circuit.public_inputs = public_inputs;
+    if let Some(path) = public_input_membership {
+        cb.circuit.public_input_membership = path;
+    }
 
-    let proof = cb.prove(&[com], &[rand], &mut rng).unwrap();
+    let proof = cb.prove(coms, rands, &mut rng).unwrap();
 
     Stage1Response {
         subcircuit_idx,
@@ -195,7 +262,9 @@ where
 }
 
 /// Process the given stage1 request, along with all the previous messages in this execution, and
-/// produces a Groth16 proof
+/// produces a Groth16 proof. The stage0 commitment is recomputed from the seed in `stage0_resp`,
+/// so this can run on a different worker than the one that did stage0. The circuit MUST have no
+/// extra rounds. Otherwise, use [`process_stage1_request_with_rounds`].
 pub fn process_stage1_request<C, CG, E, P, R>(
     rng: R,
     tree_params: ExecTreeParams<C>,
@@ -210,6 +279,121 @@ where
     E: Pairing,
     P: CircuitWithPortals<E::ScalarField>,
     R: RngCore,
+{
+    process_stage1_request_with_rounds::<_, CG, _, _, _>(
+        rng,
+        tree_params,
+        pk,
+        stage0_req,
+        stage0_resp,
+        &[],
+        stage1_req,
+    )
+}
+
+/// Like [`process_stage1_request`], but for circuits with extra rounds. The commitment of every
+/// extra round is recomputed from the seed in its response in `round_resps`. The witnesses and
+/// challenges of the rounds are taken from `stage1_req`.
+pub fn process_stage1_request_with_rounds<C, CG, E, P, R>(
+    rng: R,
+    tree_params: ExecTreeParams<C>,
+    pk: &G16ProvingKey<E>,
+    stage0_req: Stage0Request<E::ScalarField>,
+    stage0_resp: &Stage0Response<E>,
+    round_resps: &[RoundResponse<E>],
+    stage1_req: Stage1Request<C, E::ScalarField, P>,
+) -> Stage1Response<E>
+where
+    C: TreeConfig<Leaf = SerializedLeaf<E::ScalarField>>,
+    CG: TreeConfigGadget<C, E::ScalarField, Leaf = SerializedLeafVar<E::ScalarField>>,
+    E: Pairing,
+    P: CircuitWithPortals<E::ScalarField>,
+    R: RngCore,
+{
+    assert_eq!(
+        round_resps.len(),
+        P::NUM_EXTRA_ROUNDS,
+        "wrong number of round responses"
+    );
+
+    let challenges = [
+        stage1_req.cur_leaf.evals.challenges(),
+        stage1_req.round_challenges.clone(),
+    ]
+    .concat();
+    let (cb, coms, rands) = replay_commitments::<_, CG, _, _>(
+        tree_params,
+        pk,
+        stage0_req,
+        stage0_resp,
+        round_resps,
+        &stage1_req.circ_params,
+        &stage1_req.serialized_witnesses,
+        &challenges,
+    );
+    process_stage1_request_with_cb(rng, cb, &coms, &rands, stage1_req)
+}
+
+/// Performs the Groth16 commitment of the given extra round. Like [`process_stage1_request`], the
+/// commitments of stage 0 and the earlier rounds are recomputed from the seeds in `stage0_resp` and
+/// `earlier_round_resps`, so this needn't run on the worker that made them.
+pub fn process_round_request<C, CG, E, P, R>(
+    rng: R,
+    tree_params: ExecTreeParams<C>,
+    pk: &G16ProvingKey<E>,
+    stage0_req: Stage0Request<E::ScalarField>,
+    stage0_resp: &Stage0Response<E>,
+    earlier_round_resps: &[RoundResponse<E>],
+    round_req: RoundRequest<E::ScalarField, P>,
+) -> RoundResponse<E>
+where
+    C: TreeConfig<Leaf = SerializedLeaf<E::ScalarField>>,
+    CG: TreeConfigGadget<C, E::ScalarField, Leaf = SerializedLeafVar<E::ScalarField>>,
+    E: Pairing,
+    P: CircuitWithPortals<E::ScalarField>,
+    R: RngCore,
+{
+    assert_eq!(
+        earlier_round_resps.len(),
+        round_req.round,
+        "wrong number of round responses"
+    );
+
+    let (mut cb, _, _) = replay_commitments::<_, CG, _, _>(
+        tree_params,
+        pk,
+        stage0_req,
+        stage0_resp,
+        earlier_round_resps,
+        &round_req.circ_params,
+        &round_req.serialized_witnesses,
+        &round_req.challenges,
+    );
+    process_round_request_with_cb(rng, &mut cb, round_req).0
+}
+
+/// Recomputes the commitments of stage 0 and of the given extra rounds of a subcircuit from the
+/// seeds in their responses. The circuit parameters, witnesses, and challenges are only used if
+/// there are extra rounds. Returns the commitment builder, the commitments, and their randomness
+fn replay_commitments<'a, C, CG, E, P>(
+    tree_params: ExecTreeParams<C>,
+    pk: &'a G16ProvingKey<E>,
+    stage0_req: Stage0Request<E::ScalarField>,
+    stage0_resp: &Stage0Response<E>,
+    round_resps: &[RoundResponse<E>],
+    circ_params: &P::Parameters,
+    serialized_witnesses: &[u8],
+    challenges: &[E::ScalarField],
+) -> (
+    G16CommitmentBuilder<'a, SubcircuitWithPortalsProver<E::ScalarField, P, C, CG>, E, QAP>,
+    Vec<G16Com<E>>,
+    Vec<G16ComRandomness<E>>,
+)
+where
+    C: TreeConfig<Leaf = SerializedLeaf<E::ScalarField>>,
+    CG: TreeConfigGadget<C, E::ScalarField, Leaf = SerializedLeafVar<E::ScalarField>>,
+    E: Pairing,
+    P: CircuitWithPortals<E::ScalarField>,
 {
     let Stage0Request {
         subcircuit_idx,
@@ -232,12 +416,38 @@ where
     circ.addr_ordered_subtrace = addr_ordered_subtrace;
 
     // The commitment RNG is determined by com_seed
-    let mut cb = G16CommitmentBuilder::<_, E, QAP>::new(circ, &pk);
+    let mut cb = G16CommitmentBuilder::<_, E, QAP>::new(circ, pk);
     let mut subcircuit_rng = {
         let com_seed = stage0_resp.com_seed.clone();
         ChaCha12Rng::from_seed(com_seed)
     };
 
     let (com, rand) = cb.commit(&mut subcircuit_rng).unwrap();
-    process_stage1_request_with_cb(rng, cb, com, rand, stage1_req)
+    assert!(
+        com == stage0_resp.com,
+        "the seed doesn't reproduce the stage0 commitment of subcircuit {subcircuit_idx}"
+    );
+    let mut coms = vec![com];
+    let mut rands = vec![rand];
+
+    // Redo the extra rounds the same way. Their witnesses need the underlying circuit and the
+    // challenges of the earlier rounds
+    if !round_resps.is_empty() {
+        let mut underlying_circuit = P::new(circ_params);
+        underlying_circuit.set_serialized_witnesses(subcircuit_idx, serialized_witnesses);
+        cb.circuit.circ = Some(underlying_circuit);
+        cb.circuit.challenges[..challenges.len()].copy_from_slice(challenges);
+    }
+    for (round, round_resp) in round_resps.iter().enumerate() {
+        let mut subcircuit_rng = ChaCha12Rng::from_seed(round_resp.com_seed);
+        let (com, rand) = cb.commit(&mut subcircuit_rng).unwrap();
+        assert!(
+            com == round_resp.com,
+            "the seed doesn't reproduce the round {round} commitment of subcircuit {subcircuit_idx}"
+        );
+        coms.push(com);
+        rands.push(rand);
+    }
+
+    (cb, coms, rands)
 }
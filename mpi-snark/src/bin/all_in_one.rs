@@ -1,28 +1,19 @@
 #![allow(warnings)]
 use ark_std::{cfg_chunks, cfg_chunks_mut, cfg_into_iter, cfg_iter};
-use distributed_prover::{
-    tree_hash_circuit::{MerkleTreeCircuit, MerkleTreeCircuitParams},
-    vkd::{
-        MerkleTreeParameters, VerifiableKeyDirectoryCircuit, VerifiableKeyDirectoryCircuitParams,
-    },
-    vm::VirtualMachine,
-    CircuitWithPortals,
-};
+use distributed_prover::{util::CircuitInstance, CircuitWithPortals};
 use rand::{Rng, SeedableRng};
 use rand_chacha::ChaCha12Rng;
 
-use ark_bls12_381::Fr;
 use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
 use clap::{Parser, Subcommand};
 use mimalloc::MiMalloc;
 use mpi_snark::{
+    checkpoint::Checkpoint,
+    circuits::{with_circuit_of, RegisteredCircuit, WithCircuit},
     coordinator::CoordinatorState,
-    data_structures::{
-        ProvingKeys, Stage0Request, Stage0Response, Stage1Response, MERKLE_CIRCUIT_ID,
-        VKD_CIRCUIT_ID, VM_CIRCUIT_ID,
-    },
+    data_structures::{Curve, ProvingKeys, Stage0Response, Stage1Response, SupportedCurve},
     deserialize_flattened_bytes, deserialize_from_packed_bytes, serialize_to_packed_vec,
-    serialize_to_vec,
+    serialize_to_vec, with_curve,
     worker::WorkerState,
     Packed, VkdMerkleParams,
 };
@@ -77,6 +68,24 @@ struct Args {
     /// usage.
     #[clap(long, value_name = "NUM")]
     num_concurrent_proofs: usize,
+
+    /// Path to the circuit instance to prove. If omitted, a random instance is proven.
+    #[clap(long, value_name = "FILE")]
+    instance: Option<PathBuf>,
+
+    /// Directory to save the progress of the proof to, so that a crashed run can be resumed
+    #[clap(long, value_name = "DIR", conflicts_with = "resume")]
+    checkpoint: Option<PathBuf>,
+
+    /// Resumes the crashed run that saved its progress to the given directory. Subcircuits whose
+    /// stage0 or stage1 responses were saved are not proven again.
+    #[clap(long, value_name = "DIR")]
+    resume: Option<PathBuf>,
+
+    /// Path to write the proof to. The key that verifies it is written to the same path with
+    /// `.vk` appended.
+    #[clap(long, value_name = "FILE")]
+    proof_out: Option<PathBuf>,
 }
 
 fn main() {
@@ -86,27 +95,91 @@ fn main() {
     let Args {
         key_file,
         num_concurrent_proofs,
+        instance,
+        checkpoint,
+        resume,
+        proof_out,
     } = Args::parse();
 
+    // The keys say which curve they're over
+    let curve = {
+        let f = File::open(&key_file).unwrap_or_else(|_| panic!("couldn't open file {key_file:?}"));
+        Curve::read_from_key_file(f).unwrap()
+    };
+    with_curve!(
+        curve,
+        run(
+            key_file,
+            num_concurrent_proofs,
+            instance,
+            checkpoint,
+            resume,
+            proof_out
+        )
+    );
+}
+
+fn run<E: SupportedCurve>(
+    key_file: PathBuf,
+    num_concurrent_proofs: usize,
+    instance: Option<PathBuf>,
+    checkpoint: Option<PathBuf>,
+    resume: Option<PathBuf>,
+    proof_out: Option<PathBuf>,
+) {
     // Deserialize the proving keys
     let proving_keys = {
-        let mut f = File::open(&key_file).expect(&format!("couldn't open file {:?}", key_file));
-        ProvingKeys::deserialize_uncompressed_unchecked(&mut f).unwrap()
+        let mut f =
+            File::open(&key_file).unwrap_or_else(|_| panic!("couldn't open file {key_file:?}"));
+        ProvingKeys::<E>::deserialize_uncompressed_unchecked(&mut f).unwrap()
     };
 
-    let circ_id = proving_keys.get_id_str();
-    if circ_id == MERKLE_CIRCUIT_ID {
-        work::<MerkleTreeCircuit>(proving_keys, num_concurrent_proofs);
-    } else if circ_id == VKD_CIRCUIT_ID {
-        work::<VerifiableKeyDirectoryCircuit>(proving_keys, num_concurrent_proofs);
-    } else if circ_id == VM_CIRCUIT_ID {
-        work::<VirtualMachine<Fr>>(proving_keys, num_concurrent_proofs);
-    } else {
-        panic!("unknown circuit ID {circ_id}")
+    let checkpoint = match (checkpoint, resume) {
+        (Some(dir), _) => Some(Checkpoint::create(&dir, &proving_keys).unwrap()),
+        (_, Some(dir)) => Some(Checkpoint::open(&dir, &proving_keys).unwrap()),
+        (None, None) => None,
+    };
+
+    with_circuit_of(
+        proving_keys,
+        Work {
+            num_concurrent_proofs,
+            instance,
+            checkpoint,
+            proof_out,
+        },
+    );
+}
+
+/// Proves the circuit the proving keys are for
+struct Work {
+    num_concurrent_proofs: usize,
+    instance: Option<PathBuf>,
+    checkpoint: Option<Checkpoint>,
+    proof_out: Option<PathBuf>,
+}
+
+impl<E: SupportedCurve> WithCircuit<E> for Work {
+    type Output = ();
+
+    fn call<C: RegisteredCircuit>(self, proving_keys: ProvingKeys<E>) {
+        work::<E, C::Circuit<E::ScalarField>>(
+            proving_keys,
+            self.num_concurrent_proofs,
+            self.instance,
+            self.checkpoint,
+            self.proof_out,
+        );
     }
 }
 
-fn work<P: CircuitWithPortals<Fr>>(proving_keys: ProvingKeys, num_concurrent_proofs: usize) {
+fn work<E: SupportedCurve, P: CircuitWithPortals<E::ScalarField>>(
+    proving_keys: ProvingKeys<E>,
+    num_concurrent_proofs: usize,
+    instance_path: Option<PathBuf>,
+    checkpoint: Option<Checkpoint>,
+    proof_out: Option<PathBuf>,
+) {
     let tmp_dir = mktemp::Temp::new_dir().unwrap().to_path_buf();
     std::fs::create_dir(&tmp_dir);
     let num_subcircuits = proving_keys.num_subcircuits();
@@ -114,82 +187,213 @@ fn work<P: CircuitWithPortals<Fr>>(proving_keys: ProvingKeys, num_concurrent_pro
     let very_start = start_timer!(|| format!("Beginning work"));
 
     let start = start_timer!(|| format!("Construct coordinator state"));
-    let mut coordinator_state = CoordinatorState::<P>::new(&proving_keys);
+    // The subtraces and witnesses are kept on disk, in the checkpoint if there is one
+    let mut coordinator_state = match &checkpoint {
+        Some(checkpoint) => {
+            CoordinatorState::<E, P>::with_checkpoint(&proving_keys, checkpoint.clone())
+        },
+        None => CoordinatorState::<E, P>::with_store_dir(&proving_keys, tmp_dir.clone()),
+    };
     end_timer!(start);
 
+    // What an earlier run already did, if we're resuming one
+    let has_stage0_state = checkpoint
+        .as_ref()
+        .map_or(false, Checkpoint::has_stage0_state);
+    let has_stage1_state = checkpoint
+        .as_ref()
+        .map_or(false, Checkpoint::has_stage1_state);
+    let (saved_stage0_resps, saved_stage1_resps) = match &checkpoint {
+        Some(checkpoint) => (
+            checkpoint.load_stage0_resps::<E>(num_subcircuits).unwrap(),
+            checkpoint.load_stage1_resps::<E>(num_subcircuits).unwrap(),
+        ),
+        None => (
+            (0..num_subcircuits).map(|_| None).collect(),
+            (0..num_subcircuits).map(|_| None).collect(),
+        ),
+    };
+
     // Stage0 responses
     // Each commitment comes with a seed so we can reconstruct the commitment in stage1
-    let (stage0_resps, stage0_seeds): (Vec<Stage0Response>, Vec<[u8; 32]>) = {
-        // Stage0 requests
-        let start = start_timer!(|| format!("Generating stage0 requests"));
-        // TODO: Don't put all stage0 requests in memory at once
-        let stage0_reqs = coordinator_state.stage_0();
+    let stage0_resps: Vec<Stage0Response<E>> = {
+        let start = start_timer!(|| format!("Generating stage0 state"));
+        if has_stage0_state {
+            coordinator_state.resume_stage_0()
+        } else {
+            let circ = match &instance_path {
+                Some(path) => {
+                    let mut f =
+                        File::open(path).unwrap_or_else(|_| panic!("couldn't open file {path:?}"));
+                    let instance =
+                        CircuitInstance::deserialize_uncompressed_unchecked(&mut f).unwrap();
+                    coordinator_state.circuit_from_instance(&instance)
+                },
+                None => coordinator_state.rand_circuit(),
+            };
+            coordinator_state.stage_0(circ)
+        };
         end_timer!(start);
 
-        // Save stage0 requests to file. This is for two reasons:
-        // 1) they're big
-        // 2) they are a reference to coordinator_state.stage0_state, which is consumed by stage_1, but
-        //    then later needed by WorkerState::stage_0 when reconstructing the committing state
-
-        cfg_iter!(stage0_reqs).enumerate().for_each(|(i, req)| {
-            let filename = format!("stage0_req_{i}.bin");
-            let path = tmp_dir.join(filename);
-            let mut file = File::create(path).unwrap();
-            req.serialize_uncompressed(&mut file).unwrap();
-        });
-
-        cfg_into_iter!(stage0_reqs)
+        // Only make the requests of the proofs we're about to do, so that at most
+        // num_concurrent_proofs of them are in memory at once
+        let mut stage0_resps = Vec::with_capacity(num_subcircuits);
+        let batches = coordinator_state
+            .stage0_requests()
+            .zip(saved_stage0_resps)
             .enumerate()
-            .map(|(i, req)| {
-                // Per-worker seed
-                let mut seed: [u8; 32] = rand::thread_rng().gen();
-                let mut rng = ChaCha12Rng::from_seed(seed);
-
-                let start = start_timer!(|| format!("Processing stage0 request #{i}"));
-
-                // Make a new state for each worker and compute the commimtent
-                let resp =
-                    WorkerState::<P>::new(num_subcircuits, &proving_keys).stage_0(&mut rng, &req);
-
-                end_timer!(start);
-                (resp, seed)
-            })
-            .unzip()
+            .chunks(num_concurrent_proofs);
+        for batch in &batches {
+            let batch = batch.collect::<Vec<_>>();
+            let resps = cfg_into_iter!(batch)
+                .map(|(i, (req, saved_resp))| {
+                    // Skip the subcircuits an earlier run already did
+                    if let Some(resp) = saved_resp {
+                        return resp;
+                    }
+
+                    // Per-worker seed
+                    let mut seed: [u8; 32] = rand::thread_rng().gen();
+                    let mut rng = ChaCha12Rng::from_seed(seed);
+
+                    let start = start_timer!(|| format!("Processing stage0 request #{i}"));
+
+                    // Make a new state for each worker and compute the commimtent
+                    let resp = WorkerState::<E, P>::new(num_subcircuits, &proving_keys)
+                        .stage_0(&mut rng, &req);
+
+                    end_timer!(start);
+                    if let Some(checkpoint) = &checkpoint {
+                        checkpoint.save_stage0_resp(&resp).unwrap();
+                    }
+                    resp
+                })
+                .collect::<Vec<_>>();
+            stage0_resps.extend(resps);
+        }
+        stage0_resps
     };
 
     // Stage1 requests
     let start = start_timer!(|| format!("Processing stage0 responses"));
-    let stage1_reqs = coordinator_state.stage_1(&stage0_resps);
+    if has_stage1_state {
+        coordinator_state.resume_stage_1()
+    } else {
+        coordinator_state.stage_1(&stage0_resps)
+    };
     end_timer!(start);
 
+    // Extra rounds. The stage1 state says how many of them an earlier run already finished.
+    while coordinator_state.num_rounds_left() > 0 {
+        let round = P::NUM_EXTRA_ROUNDS - coordinator_state.num_rounds_left();
+        let saved_round_resps = match &checkpoint {
+            Some(checkpoint) => checkpoint
+                .load_round_resps::<E>(round, num_subcircuits)
+                .unwrap(),
+            None => (0..num_subcircuits).map(|_| None).collect(),
+        };
+
+        let start = start_timer!(|| format!("Processing round {round} requests"));
+        let mut round_resps = Vec::with_capacity(num_subcircuits);
+        let batches = stage0_resps
+            .iter()
+            .zip(saved_round_resps)
+            .enumerate()
+            .map(|(i, (resp0, saved_resp))| {
+                // Only make the requests of the subcircuits that aren't skipped
+                let reqs = saved_resp.is_none().then(|| {
+                    (
+                        coordinator_state.stage0_request(i),
+                        coordinator_state.subcircuit_round_resps(i),
+                        coordinator_state.round_request(i),
+                    )
+                });
+                (resp0, reqs, saved_resp)
+            })
+            .chunks(num_concurrent_proofs);
+        for batch in &batches {
+            let batch = batch.collect::<Vec<_>>();
+            let resps = cfg_into_iter!(batch)
+                .map(|(resp0, reqs, saved_resp)| {
+                    // Skip the subcircuits an earlier run already did
+                    if let Some(resp) = saved_resp {
+                        return resp;
+                    }
+                    let (req0, earlier_resps, req) = reqs.unwrap();
+
+                    // Per-worker seed
+                    let mut seed: [u8; 32] = rand::thread_rng().gen();
+                    let mut rng = ChaCha12Rng::from_seed(seed);
+
+                    // The earlier commitments are recomputed from the stage0 seed
+                    let state = WorkerState::<E, P>::new(num_subcircuits, &proving_keys);
+                    let resp = state.round_from_seed(&mut rng, &req0, resp0, &earlier_resps, &req);
+                    if let Some(checkpoint) = &checkpoint {
+                        checkpoint.save_round_resp(round, &resp).unwrap();
+                    }
+                    resp
+                })
+                .collect::<Vec<_>>();
+            round_resps.extend(resps);
+        }
+        coordinator_state.process_round(&round_resps);
+        end_timer!(start);
+    }
+
     // Stage1 responses
-    let chunk_size = num_subcircuits / num_concurrent_proofs;
-    let stage1_resps = cfg_into_iter!(stage1_reqs, chunk_size)
+    let mut stage1_resps = Vec::with_capacity(num_subcircuits);
+    let batches = coordinator_state
+        .stage1_requests()
+        .zip(&stage0_resps)
+        .zip(saved_stage1_resps)
         .enumerate()
-        .zip(cfg_into_iter!(stage0_seeds, chunk_size))
-        .map(|((i, req1), seed)| {
-            // Per-worker seed
-            let mut seed: [u8; 32] = rand::thread_rng().gen();
-            let mut rng = ChaCha12Rng::from_seed(seed);
-
-            // Load up the stage0 req
-            let filename = format!("stage0_req_{i}.bin");
-            let mut file = File::open(tmp_dir.join(filename)).unwrap();
-            let req0 = Stage0Request::deserialize_uncompressed_unchecked(&mut file).unwrap();
-
-            let mut state = WorkerState::new(num_subcircuits, &proving_keys);
-            state.stage_0(&mut rng, &req0.to_ref());
-            let start = start_timer!(|| format!("Processing stage1 request #{i}"));
-            let resp = state.stage_1(&mut rng, &req1);
-            end_timer!(start);
-            resp
+        .map(|(i, ((req1, resp0), saved_resp))| {
+            // The stage1 worker needs the stage0 request again, unless the subcircuit is skipped
+            let req0 = saved_resp
+                .is_none()
+                .then(|| coordinator_state.stage0_request(i));
+            let round_resps = coordinator_state.subcircuit_round_resps(i);
+            (i, req0, req1, resp0, round_resps, saved_resp)
         })
-        .collect::<Vec<_>>();
+        .chunks(num_concurrent_proofs);
+    for batch in &batches {
+        let batch = batch.collect::<Vec<_>>();
+        let resps = cfg_into_iter!(batch)
+            .map(|(i, req0, req1, resp0, round_resps, saved_resp)| {
+                // Skip the subcircuits an earlier run already did
+                if let Some(resp) = saved_resp {
+                    return resp;
+                }
+
+                // Per-worker seed
+                let mut seed: [u8; 32] = rand::thread_rng().gen();
+                let mut rng = ChaCha12Rng::from_seed(seed);
+
+                // The commitment is recomputed from the stage0 seed
+                let state = WorkerState::<E, P>::new(num_subcircuits, &proving_keys);
+                let start = start_timer!(|| format!("Processing stage1 request #{i}"));
+                let resp =
+                    state.stage_1_from_seed(&mut rng, &req0.unwrap(), resp0, &round_resps, &req1);
+                end_timer!(start);
+                if let Some(checkpoint) = &checkpoint {
+                    checkpoint.save_stage1_resp(&resp).unwrap();
+                }
+                resp
+            })
+            .collect::<Vec<_>>();
+        stage1_resps.extend(resps);
+    }
 
     let start = start_timer!(|| format!("Aggregating"));
-    let _proof = coordinator_state.aggregate(&stage1_resps);
+    let proof = coordinator_state.aggregate(&stage1_resps);
     end_timer!(start);
 
+    if let Some(path) = proof_out {
+        coordinator_state
+            .write_proof(&path, &proof)
+            .unwrap_or_else(|e| panic!("couldn't write the proof to {path:?}: {e}"));
+    }
+
     end_timer!(very_start);
 
     std::fs::remove_dir_all(&tmp_dir);
@@ -2,43 +2,39 @@
 
 use ark_std::{cfg_chunks, cfg_chunks_mut, cfg_into_iter, cfg_iter};
 use distributed_prover::{
-    tree_hash_circuit::{MerkleTreeCircuit, MerkleTreeCircuitParams},
-    test_circuit::{ZkDbSqlCircuit, ZkDbSqlCircuitParams},
-    vkd::{
-        MerkleTreeParameters, VerifiableKeyDirectoryCircuit, VerifiableKeyDirectoryCircuitParams,
-    },
-    vm::{VirtualMachine, VirtualMachineParameters, MERKLE_MEMORY_DEPTH, REGISTER_NUM},
+    tpch::{load_lineitem_table, TpchQ1Circuit, TpchQ1Params},
+    util::CircuitInstance,
     CircuitWithPortals,
 };
 
-use ark_bls12_381::{Bls12_381 as E, Fr};
 use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
-use clap::{Parser, Subcommand};
+use clap::{error::ErrorKind, CommandFactory, Parser, Subcommand};
 use mimalloc::MiMalloc;
-use mpi::{
-    datatype::{Partition, PartitionMut},
-    topology::Process,
-    Count,
-};
+use mpi::{point_to_point::Status, topology::Process, Count, Tag};
 use mpi::{request, traits::*};
 use mpi_snark::{
-    construct_partitioned_buffer_for_scatter, construct_partitioned_mut_buffer_for_gather,
-    coordinator::CoordinatorState,
+    checkpoint::Checkpoint,
+    circuits::{run_setup, with_circuit_of, RegisteredCircuit, SetupCommand, WithCircuit},
+    coordinator::{append_to_path, write_to_path, CoordinatorState, MAX_INVALID_PROOFS},
     data_structures::{
-        ProvingKeys, Stage0Request, Stage0Response, Stage1Request, Stage1Response,
-        MERKLE_CIRCUIT_ID, VKD_CIRCUIT_ID, VM_CIRCUIT_ID,TEST_CIRCUIT_ID
+        Curve, GroupAggRequest, GroupAggResponse, ProofId, ProofMessage, ProvingKeys, RoundRequest,
+        RoundResponse, Stage0Request, Stage0Response, Stage1Request, Stage1Response,
+        SupportedCurve,
     },
-    deserialize_flattened_bytes, deserialize_from_packed_bytes, serialize_to_packed_vec,
-    serialize_to_vec,
+    deserialize_from_packed_bytes,
+    schedule::{Assignments, WorkPlan},
+    serialize_to_packed_vec, serialize_to_vec, with_curve,
     worker::WorkerState,
-    Packed, VkdMerkleParams, VM_CONSTRAINTS_PER_CYCLE,
+    Packed,
 };
 
 use std::{
+    collections::BTreeMap,
     fs::File,
     io::{Read, Write},
     num::NonZeroUsize,
     path::PathBuf,
+    time::{Duration, Instant},
 };
 
 #[cfg(feature = "parallel")]
@@ -81,6 +77,15 @@ macro_rules! end_timer_buf {
     }};
 }
 
+/// Prints a message, and keeps it in the log buffer
+macro_rules! log_buf {
+    ($buf:ident, $($arg:tt)*) => {{
+        let msg = format!($($arg)*);
+        println!("{}", msg);
+        $buf.push(msg);
+    }};
+}
+
 #[derive(Parser)]
 struct Args {
     #[clap(subcommand)]
@@ -89,62 +94,39 @@ struct Args {
 
 #[derive(Subcommand)]
 enum Command {
-    SetupTest {
-        /// Test circuit param: Number of subcircuits. MUST be a power of two and greater than 1.
+    #[command(flatten)]
+    Setup(SetupCommand),
+
+    MakeTpchQ1Instance {
+        /// Number of rows of the lineitem table. MUST match the proving keys.
         #[clap(long, value_name = "NUM")]
         num_rows: usize,
 
-        /// Path for the output coordinator key package
-        #[clap(long, value_name = "DIR")]
-        key_out: PathBuf,
-    },
-
-    SetupBigMerkle {
-        /// Test circuit param: Number of subcircuits. MUST be a power of two and greater than 1.
+        /// Number of rows per subcircuit. MUST match the proving keys.
         #[clap(long, value_name = "NUM")]
-        num_subcircuits: usize,
+        rows_per_subcircuit: usize,
 
-        /// Test circuit param: Number of SHA256 iterations per subcircuit. MUST be at least 1.
-        #[clap(long, value_name = "NUM")]
-        num_sha2_iters: usize,
+        /// The DELTA parameter of the query, in days. MUST match the proving keys.
+        #[clap(long, value_name = "NUM", default_value_t = 90)]
+        delta: u64,
 
-        /// Test circuit param: Number of portal wire ops per subcircuit. MUST be at least 1.
-        #[clap(long, value_name = "NUM")]
-        num_portals: usize,
+        /// The curve of the proving keys
+        #[clap(long, value_enum, default_value_t = Curve::Bls12_381)]
+        curve: Curve,
 
-        /// Path for the output coordinator key package
-        #[clap(long, value_name = "DIR")]
-        key_out: PathBuf,
-    },
+        /// Path to a lineitem .tbl file made by the TPC-H dbgen tool
+        #[clap(long, value_name = "FILE")]
+        lineitem: PathBuf,
 
-    SetupVkd {
-        /// Test circuit param: Number of subcircuits. MUST be a power of two and greater than 1.
-        #[clap(long, value_name = "NUM")]
-        num_subcircuits: usize,
-
-        /// Path for the output coordinator key package
-        #[clap(long, value_name = "DIR")]
-        key_out: PathBuf,
-    },
-
-    SetupVm {
-        /// Test circuit param: Number of subcircuits. MUST be a power of two and greater than 1.
-        #[clap(long, value_name = "NUM")]
-        num_subcircuits: usize,
-
-        /// If on, uses a Merkle tree for memory
-        #[clap(long, value_name = "")]
-        use_merkle_memory: bool,
-
-        /// Number of cycles per subcircuit
-        #[clap(long, value_name = "NUM")]
-        num_cycles_per_subcircuit: usize,
-
-        /// Path for the output coordinator key package
-        #[clap(long, value_name = "DIR")]
-        key_out: PathBuf,
+        /// Path for the output circuit instance
+        #[clap(long, value_name = "FILE")]
+        instance_out: PathBuf,
     },
 
+    /// Proves a circuit over MPI. Workers that stall past `--task-timeout` have their subcircuits
+    /// reassigned, but MPI aborts the whole job if any rank dies. For long proofs on unreliable
+    /// machines, use the `tcp_node` binary instead, which also reassigns the subcircuits of
+    /// workers that die.
     Work {
         /// Path to the coordinator key package
         #[clap(long, value_name = "DIR")]
@@ -153,464 +135,1844 @@ enum Command {
         /// The number of workers who will do the committing and proving. Each worker has 1 core.
         #[clap(long, value_name = "NUM")]
         num_workers: usize,
+
+        /// Path to the circuit instance to prove. Give this more than once to make a proof of
+        /// each instance, which pipelines the proofs like --num-proofs does, or a single proof of
+        /// them all with --batch. If omitted, a random instance is proven.
+        #[clap(long, value_name = "FILE")]
+        instance: Vec<PathBuf>,
+
+        /// The number of random instances to prove. Several proofs are pipelined: the workers start
+        /// on the next proof while the coordinator builds the execution tree of the current one,
+        /// or aggregates the last one. Pipelined runs can't be checkpointed, verify proofs, or
+        /// time out workers.
+        #[clap(
+            long,
+            value_name = "NUM",
+            conflicts_with_all = ["instance", "checkpoint", "resume", "verify_proofs", "task_timeout"]
+        )]
+        num_proofs: Option<usize>,
+
+        /// If a worker takes longer than this to answer a batch of subcircuits, drop it and give
+        /// its subcircuits to the other workers. If omitted, workers are never dropped for being
+        /// slow. A dropped worker that is still running once the proof is done is aborted.
+        #[clap(long, value_name = "SECS")]
+        task_timeout: Option<u64>,
+
+        /// Directory for the coordinator to save the progress of the proof to, so that a crashed
+        /// run can be resumed
+        #[clap(long, value_name = "DIR", conflicts_with = "resume")]
+        checkpoint: Option<PathBuf>,
+
+        /// Resumes the crashed run that saved its progress to the given directory. Stages whose
+        /// responses were all saved are not run again.
+        #[clap(long, value_name = "DIR")]
+        resume: Option<PathBuf>,
+
+        /// Directory for the coordinator to keep the subtraces and witnesses of the circuit in. If
+        /// omitted, they're kept in memory, or in the checkpoint directory if there is one. A run
+        /// that makes several proofs keeps each proof in a subdirectory.
+        #[clap(long, value_name = "DIR")]
+        store_dir: Option<PathBuf>,
+
+        /// Checks every stage1 proof before aggregating. A worker that sends an invalid proof is
+        /// reported and gets no more work, and its subcircuit is proven again by another worker.
+        /// The run fails if no other worker is left, or if a subcircuit gets 3 invalid proofs.
+        #[clap(long)]
+        verify_proofs: bool,
+
+        /// Path to write the proof to. The key that verifies it is written to the same path with
+        /// `.vk` appended. A pipelined run writes proof k to the path with `.k` appended, and
+        /// writes one verifying key, since every proof shares it.
+        #[clap(long, value_name = "FILE")]
+        proof_out: Option<PathBuf>,
+
+        /// Proves the instances given by --instance or --num-proofs together, into a single batch
+        /// proof. Every instance does stage 0 before any starts stage 1, since they share their
+        /// challenges. Batched runs can't be checkpointed, verify proofs, or time out workers.
+        #[clap(
+            long,
+            conflicts_with_all = ["checkpoint", "resume", "verify_proofs", "task_timeout"]
+        )]
+        batch: bool,
+
+        /// Aggregates the subcircuit proofs in groups of NUM subcircuits, each under its own TIPP
+        /// key. The workers aggregate the groups once stage 1 is done, and the coordinator only
+        /// combines the group aggregates. The proof is verified with
+        /// `HekatonProof::verify_grouped`. Only a run that makes a single proof can be grouped.
+        #[clap(
+            long,
+            value_name = "NUM",
+            conflicts_with_all = ["num_proofs", "batch", "checkpoint", "resume"]
+        )]
+        group_size: Option<NonZeroUsize>,
     },
 }
 
+/// Where a run starts, going by which stages an earlier run already finished
+const START_AT_STAGE0: u8 = 0;
+const START_AT_STAGE1: u8 = 1;
+const START_AT_AGGREGATION: u8 = 2;
+
 fn main() {
     println!("Rayon num threads: {}", current_num_threads());
 
     let args = Args::parse();
 
     match args.command {
-        Command::SetupTest {
+        Command::Setup(setup) => run_setup(setup),
+        Command::MakeTpchQ1Instance {
             num_rows,
-            key_out,
-        } => setup_test_circuit(key_out, num_rows),
-        Command::SetupBigMerkle {
-            num_subcircuits,
-            num_sha2_iters,
-            num_portals,
-            key_out,
-        } => setup_big_merkle(key_out, num_subcircuits, num_sha2_iters, num_portals),
-        Command::SetupVkd {
-            num_subcircuits,
-            key_out,
-        } => setup_vkd(key_out, num_subcircuits),
-        Command::SetupVm {
-            num_subcircuits,
-            use_merkle_memory,
-            num_cycles_per_subcircuit,
-            key_out,
-        } => setup_vm(
-            key_out,
-            num_subcircuits,
-            use_merkle_memory,
-            VM_CONSTRAINTS_PER_CYCLE,
-            num_cycles_per_subcircuit,
+            rows_per_subcircuit,
+            delta,
+            curve,
+            lineitem,
+            instance_out,
+        } => with_curve!(
+            curve,
+            make_tpch_q1_instance(instance_out, num_rows, rows_per_subcircuit, delta, lineitem)
         ),
         Command::Work {
             key_file,
             num_workers,
+            instance,
+            num_proofs,
+            task_timeout,
+            checkpoint,
+            resume,
+            store_dir,
+            verify_proofs,
+            proof_out,
+            batch,
+            group_size,
         } => {
-            // Deserialize the proving keys
-            let proving_keys = {
-                let mut buf = Vec::new();
-                let mut f =
-                    File::open(&key_file).expect(&format!("couldn't open file {:?}", key_file));
-                let _ = f.read_to_end(&mut buf);
-                ProvingKeys::deserialize_uncompressed_unchecked(&mut buf.as_slice()).unwrap()
-            };
+            // clap can't tell one --instance from several, so the options that conflict with
+            // several proofs are only checked by clap for --num-proofs
+            if instance.len() > 1 {
+                let conflicts = [
+                    ("--checkpoint", checkpoint.is_some()),
+                    ("--resume", resume.is_some()),
+                    ("--verify-proofs", verify_proofs),
+                    ("--task-timeout", task_timeout.is_some()),
+                    ("--group-size", group_size.is_some()),
+                ];
+                if let Some((name, _)) = conflicts.iter().find(|(_, given)| *given) {
+                    Args::command()
+                        .error(
+                            ErrorKind::ArgumentConflict,
+                            format!("giving --instance more than once can't be used with {name}"),
+                        )
+                        .exit();
+                }
+            }
 
-            let circ_id = proving_keys.get_id_str();
-            if circ_id == MERKLE_CIRCUIT_ID {
-                work::<MerkleTreeCircuit>(num_workers, proving_keys);
-            } else if circ_id == VKD_CIRCUIT_ID {
-                // Taken from VerifiableKeyDirectoryCircuit::random(). This is how many
-                // update operations we can fit in a VKD circuit that was generated with random(),
-                // with a given power-of-two num_subcircuits value
-                let num_subcircuits = proving_keys.num_subcircuits();
-                let num_updates = ((num_subcircuits - 8) / 8) - 1;
-                println!("Number of VKD updates: {num_updates}");
-                println!("VKD depth: {}", VkdMerkleParams::DEPTH);
-                println!(
-                    "VKD hash function: {:?}",
-                    distributed_prover::vkd::HASH_TYPE
-                );
-                work::<VerifiableKeyDirectoryCircuit>(num_workers, proving_keys);
-            } else if circ_id == VM_CIRCUIT_ID {
-                let params = VirtualMachineParameters::deserialize_uncompressed_unchecked(
-                    proving_keys.serialized_circ_params.as_slice(),
+            let mut buf = Vec::new();
+            let mut f =
+                File::open(&key_file).unwrap_or_else(|_| panic!("couldn't open file {key_file:?}"));
+            let _ = f.read_to_end(&mut buf);
+
+            // The keys say which curve they're over
+            let curve = Curve::read_from_key_file(buf.as_slice()).unwrap();
+            with_curve!(
+                curve,
+                work_over_curve(
+                    buf,
+                    num_workers,
+                    instance,
+                    num_proofs,
+                    task_timeout.map(Duration::from_secs),
+                    checkpoint,
+                    resume,
+                    store_dir,
+                    verify_proofs,
+                    proof_out,
+                    batch,
+                    group_size
                 )
-                .unwrap();
-                println!(
-                    "Number of VM cycle chunks: {}",
-                    proving_keys.num_subcircuits()
-                );
-                println!(
-                    "Number of cycles per chunk: {}",
-                    params.operations_per_chunk,
-                );
-                println!(
-                    "Number of dummy constraints per cycle: {}",
-                    params.dummy_constraint_num,
-                );
-                println!("VM using Merkle memory: {}", params.use_merkle_memory);
-                println!("Depth of Merkle memory: {}", MERKLE_MEMORY_DEPTH);
-                println!("Number of VM CPU registers: {}", REGISTER_NUM);
-
-                work::<VirtualMachine<Fr>>(num_workers, proving_keys);
-            }else if circ_id == TEST_CIRCUIT_ID{
-                let params = ZkDbSqlCircuitParams::deserialize_uncompressed_unchecked(
-                    proving_keys.serialized_circ_params.as_slice(),
-                ).unwrap();
-                work::<ZkDbSqlCircuit<Fr>>(num_workers, proving_keys);
-            } else {
-                panic!("unknown circuit ID {circ_id}")
-            }
+            );
+        },
+    }
+}
+
+/// Deserializes the proving keys over the curve `E`, and proves the circuit they're for
+fn work_over_curve<E: SupportedCurve>(
+    key_bytes: Vec<u8>,
+    num_workers: usize,
+    instance: Vec<PathBuf>,
+    num_proofs: Option<usize>,
+    task_timeout: Option<Duration>,
+    checkpoint: Option<PathBuf>,
+    resume: Option<PathBuf>,
+    store_dir: Option<PathBuf>,
+    verify_proofs: bool,
+    proof_out: Option<PathBuf>,
+    batch: bool,
+    group_size: Option<NonZeroUsize>,
+) {
+    // Deserialize the proving keys
+    let proving_keys =
+        ProvingKeys::<E>::deserialize_uncompressed_unchecked(key_bytes.as_slice()).unwrap();
+
+    with_circuit_of(
+        proving_keys,
+        Work {
+            num_workers,
+            instance,
+            num_proofs,
+            task_timeout,
+            checkpoint,
+            resume,
+            store_dir,
+            verify_proofs,
+            proof_out,
+            batch,
+            group_size,
         },
+    );
+}
+
+/// Proves the circuit the proving keys are for
+struct Work {
+    num_workers: usize,
+    instance: Vec<PathBuf>,
+    num_proofs: Option<usize>,
+    task_timeout: Option<Duration>,
+    checkpoint: Option<PathBuf>,
+    resume: Option<PathBuf>,
+    store_dir: Option<PathBuf>,
+    verify_proofs: bool,
+    proof_out: Option<PathBuf>,
+    batch: bool,
+    group_size: Option<NonZeroUsize>,
+}
+
+impl<E: SupportedCurve> WithCircuit<E> for Work {
+    type Output = ();
+
+    fn call<C: RegisteredCircuit>(self, proving_keys: ProvingKeys<E>) {
+        work::<E, C::Circuit<E::ScalarField>>(
+            self.num_workers,
+            proving_keys,
+            self.instance,
+            self.num_proofs,
+            self.task_timeout,
+            self.checkpoint,
+            self.resume,
+            self.store_dir,
+            self.verify_proofs,
+            self.proof_out,
+            self.batch,
+            self.group_size,
+        );
     }
 }
 
-fn setup_test_circuit(  
-    key_out_path: PathBuf, 
+fn make_tpch_q1_instance<E: SupportedCurve>(
+    instance_out_path: PathBuf,
     num_rows: usize,
-){
-    let circ_params: ZkDbSqlCircuitParams = ZkDbSqlCircuitParams {
-        num_rows: num_rows,
+    rows_per_subcircuit: usize,
+    delta: u64,
+    lineitem_path: PathBuf,
+) {
+    let circ_params = TpchQ1Params {
+        num_rows,
+        rows_per_subcircuit,
+        delta,
     };
-    let pks = ProvingKeys::new::<ZkDbSqlCircuit<Fr>>(circ_params, TEST_CIRCUIT_ID.to_string());
+
+    let rows = load_lineitem_table::<E::ScalarField>(&lineitem_path)
+        .unwrap_or_else(|e| panic!("couldn't read lineitem table {lineitem_path:?}: {e}"));
+    assert!(
+        rows.len() <= num_rows,
+        "lineitem table has {} rows, but the circuit only fits {num_rows}",
+        rows.len()
+    );
+    let circ = TpchQ1Circuit::<E::ScalarField>::with_table(&circ_params, rows);
+    println!("Number of Q1 result rows: {}", circ.output_rows().len());
+
     let mut buf = Vec::new();
-    pks.serialize_uncompressed(&mut buf).unwrap();
-    let mut f =
-    File::create(&key_out_path).expect(&format!("could not create file {:?}", key_out_path));
+    CircuitInstance::new(&circ)
+        .serialize_uncompressed(&mut buf)
+        .unwrap();
+
+    let mut f = File::create(&instance_out_path)
+        .unwrap_or_else(|_| panic!("could not create file {instance_out_path:?}"));
     f.write_all(&buf).unwrap();
 }
 
-
-fn setup_big_merkle(
-    key_out_path: PathBuf,
-    num_subcircuits: usize,
-    num_sha_iterations: usize,
-    num_portals_per_subcircuit: usize,
+fn work<E: SupportedCurve, P: CircuitWithPortals<E::ScalarField>>(
+    num_workers: usize,
+    proving_keys: ProvingKeys<E>,
+    instance_paths: Vec<PathBuf>,
+    num_proofs: Option<usize>,
+    task_timeout: Option<Duration>,
+    checkpoint_dir: Option<PathBuf>,
+    resume_dir: Option<PathBuf>,
+    store_dir: Option<PathBuf>,
+    verify_proofs: bool,
+    proof_out: Option<PathBuf>,
+    batch: bool,
+    group_size: Option<NonZeroUsize>,
 ) {
-    assert!(
-        num_subcircuits.is_power_of_two(),
-        "#subcircuits MUST be a power of 2"
-    );
-    assert!(num_subcircuits > 1, "num. of subcircuits MUST be > 1");
-    assert!(
-        num_sha_iterations > 0,
-        "num. of SHA256 iterations per subcircuit MUST be > 0"
+    let (universe, _) = mpi::initialize_with_threading(mpi::Threading::Funneled).unwrap();
+    let world = universe.world();
+    let root_rank = 0;
+    let root_process = world.process_at_rank(root_rank);
+    let rank = world.rank();
+    let size = world.size();
+
+    let num_subcircuits = proving_keys.num_subcircuits();
+
+    assert_eq!(
+        size as usize,
+        num_workers + 1,
+        "there must be one rank per worker, plus the root"
     );
     assert!(
-        num_portals_per_subcircuit > 0,
-        "num. of portal ops per subcircuit MUST be > 0"
+        num_subcircuits >= num_workers,
+        "{num_workers} workers is more than the {num_subcircuits} subcircuits"
     );
 
-    let circ_params = MerkleTreeCircuitParams {
-        num_leaves: num_subcircuits / 2,
-        num_sha_iters_per_subcircuit: num_sha_iterations,
-        num_portals_per_subcircuit,
-    };
+    let mut log = Vec::new();
+    let very_start = start_timer_buf!(log, || format!("Node {rank}: Beginning work"));
+
+    // Several proofs are pipelined, and a batch of instances is proven together. Both take their
+    // own kind of message passing
+    let num_proofs = num_proofs.unwrap_or(instance_paths.len().max(1));
+    if num_proofs > 1 || batch {
+        assert!(
+            checkpoint_dir.is_none() && resume_dir.is_none() && !verify_proofs,
+            "a pipelined or batched run can't be checkpointed or verify proofs"
+        );
+        if rank == root_rank && batch {
+            coordinate_batch::<_, E, P>(
+                &mut log,
+                &world,
+                &proving_keys,
+                &instance_paths,
+                num_proofs,
+                store_dir,
+                proof_out,
+            );
+        } else if rank == root_rank {
+            coordinate_pipelined::<_, E, P>(
+                &mut log,
+                &world,
+                &proving_keys,
+                &instance_paths,
+                num_proofs,
+                store_dir,
+                proof_out,
+            );
+        } else {
+            serve_requests::<_, E, P>(
+                &mut log,
+                rank,
+                &root_process,
+                num_subcircuits,
+                &proving_keys,
+            );
+        }
 
-    let pks = ProvingKeys::new::<MerkleTreeCircuit>(circ_params, MERKLE_CIRCUIT_ID.to_string());
+        end_timer_buf!(log, very_start);
+        println!("Rank {rank} log: {}", log.join(";"));
+        return;
+    }
+    let instance_path = instance_paths.into_iter().next();
 
-    let mut buf = Vec::new();
-    pks.serialize_uncompressed(&mut buf).unwrap();
+    if rank == root_rank {
+        let mut assignments = Assignments::new(num_workers, task_timeout);
+        let result = coordinate::<_, E, P>(
+            &mut log,
+            &world,
+            &proving_keys,
+            &mut assignments,
+            instance_path,
+            checkpoint_dir,
+            resume_dir,
+            store_dir,
+            verify_proofs,
+            proof_out,
+            group_size,
+        );
 
-    let mut f =
-        File::create(&key_out_path).expect(&format!("could not create file {:?}", key_out_path));
-    f.write_all(&buf).unwrap();
+        end_timer_buf!(log, very_start);
+        println!("Rank {rank} log: {}", log.join(";"));
+
+        if let Err(e) = result {
+            println!("Coord: {e}");
+            world.abort(1);
+        }
+        // A worker that never finished its batch would keep the job from ending
+        let stalled = assignments.stalled_workers();
+        if !stalled.is_empty() {
+            let ranks = stalled.iter().map(|w| w + 1).collect::<Vec<_>>();
+            println!("Coord: The proof is done, but workers {ranks:?} are stuck. Aborting them");
+            world.abort(0);
+        }
+    } else {
+        serve_requests::<_, E, P>(
+            &mut log,
+            rank,
+            &root_process,
+            num_subcircuits,
+            &proving_keys,
+        );
+
+        end_timer_buf!(log, very_start);
+        println!("Rank {rank} log: {}", log.join(";"));
+    }
 }
 
-fn setup_vkd(key_out_path: PathBuf, num_subcircuits: usize) {
-    assert!(
-        num_subcircuits.is_power_of_two(),
-        "#subcircuits MUST be a power of 2"
-    );
-    assert!(num_subcircuits > 1, "num. of subcircuits MUST be > 1");
+/// The coordinator's side of a run that makes a single proof. The workers take part by calling
+/// `serve_requests`. Stages are handed out by `assignments`, so a worker that stalls has its
+/// subcircuits reassigned. With a group size, the workers also aggregate the groups, which are
+/// handed out the same way.
+fn coordinate<C, E, P>(
+    log: &mut Vec<String>,
+    world: &C,
+    proving_keys: &ProvingKeys<E>,
+    assignments: &mut Assignments,
+    instance_path: Option<PathBuf>,
+    checkpoint_dir: Option<PathBuf>,
+    resume_dir: Option<PathBuf>,
+    store_dir: Option<PathBuf>,
+    verify_proofs: bool,
+    proof_out: Option<PathBuf>,
+    group_size: Option<NonZeroUsize>,
+) -> Result<(), String>
+where
+    C: Communicator,
+    E: SupportedCurve,
+    P: CircuitWithPortals<E::ScalarField>,
+{
+    let num_subcircuits = proving_keys.num_subcircuits();
+    let num_workers = world.size() as usize - 1;
 
-    let log_num_subcircuits = ark_std::log2(num_subcircuits) as usize;
-    type TestMerkleTree = SparseMerkleTree<MerkleTreeConcreteParameters>;
-    let tree = TestMerkleTree::new().unwrap();
+    let start = start_timer_buf!(log, || format!("Coord: construct coordinator state"));
+    let checkpoint = match (checkpoint_dir, resume_dir) {
+        (Some(dir), _) => Some(Checkpoint::create(&dir, proving_keys).unwrap()),
+        (_, Some(dir)) => Some(Checkpoint::open(&dir, proving_keys).unwrap()),
+        (None, None) => None,
+    };
+    let mut coordinator_state = match &checkpoint {
+        Some(checkpoint) => {
+            CoordinatorState::<E, P>::with_checkpoint(proving_keys, checkpoint.clone())
+        },
+        None => {
+            let state = match group_size {
+                Some(group_size) => {
+                    CoordinatorState::<E, P>::with_group_size(proving_keys, group_size.get())
+                },
+                None => CoordinatorState::<E, P>::new(proving_keys),
+            };
+            match store_dir {
+                Some(dir) => state.fork_with_store_dir(dir),
+                None => state,
+            }
+        },
+    };
+    end_timer_buf!(log, start);
 
-    let circ_params = VerifiableKeyDirectoryCircuitParams {
-        log_num_subcircuits,
-        null_leaf: tree.sparse_initial_hashes[VkdMerkleParams::DEPTH],
+    let plan = plan_work(log, proving_keys, num_workers);
+    let cost = |i| proving_keys.num_constraints(i);
+
+    // See what an earlier run already did. The responses of a stage are saved once the stage is
+    // done, so a stage is only skipped if all its responses were saved
+    let has_stage0_state = checkpoint
+        .as_ref()
+        .map_or(false, Checkpoint::has_stage0_state);
+    let has_stage1_state = checkpoint
+        .as_ref()
+        .map_or(false, Checkpoint::has_stage1_state);
+    let saved_stage0_resps = checkpoint.as_ref().and_then(|c| {
+        c.load_stage0_resps::<E>(num_subcircuits)
+            .unwrap()
+            .into_iter()
+            .collect::<Option<Vec<_>>>()
+    });
+    let saved_stage1_resps = checkpoint.as_ref().and_then(|c| {
+        c.load_stage1_resps::<E>(num_subcircuits)
+            .unwrap()
+            .into_iter()
+            .collect::<Option<Vec<_>>>()
+    });
+    let start_at = if saved_stage1_resps.is_some() {
+        START_AT_AGGREGATION
+    } else if saved_stage0_resps.is_some() {
+        START_AT_STAGE1
+    } else {
+        START_AT_STAGE0
     };
 
-    let mut pks =
-        ProvingKeys::new::<VerifiableKeyDirectoryCircuit>(circ_params, VKD_CIRCUIT_ID.to_string());
+    /***************************************************************************/
+    /***************************************************************************/
+    // Stage 0
+    let responses = if start_at == START_AT_AGGREGATION {
+        None
+    } else {
+        let start = start_timer_buf!(log, || format!("Coord: Generating stage0 state"));
+        if has_stage0_state {
+            coordinator_state.resume_stage_0()
+        } else {
+            let circ = match &instance_path {
+                Some(path) => {
+                    let mut f =
+                        File::open(path).unwrap_or_else(|_| panic!("couldn't open file {path:?}"));
+                    let instance =
+                        CircuitInstance::deserialize_uncompressed_unchecked(&mut f).unwrap();
+                    coordinator_state.circuit_from_instance(&instance)
+                },
+                None => coordinator_state.rand_circuit(),
+            };
+            coordinator_state.stage_0(circ)
+        };
+        end_timer_buf!(log, start);
 
-    let mut buf = Vec::new();
-    pks.serialize_uncompressed(&mut buf).unwrap();
+        if let Some(responses) = saved_stage0_resps {
+            // The workers don't have the states of an earlier run, so the commitments are
+            // recomputed from their seeds, by whichever worker is free
+            Some((responses, None))
+        } else {
+            let responses = run_stage(
+                log,
+                "stage0",
+                world,
+                assignments,
+                &plan,
+                cost,
+                STAGE0_TAG,
+                |i| coordinator_state.stage0_request(i),
+                STAGE0_REDO_TAG,
+                |i| coordinator_state.stage0_request(i),
+                |resp: &Stage0Response<E>| resp.subcircuit_idx,
+            )?;
+
+            if let Some(checkpoint) = &checkpoint {
+                cfg_iter!(responses).for_each(|resp| checkpoint.save_stage0_resp(resp).unwrap());
+            }
+            Some((responses, Some(&plan)))
+        }
+    };
+    /***************************************************************************/
+    /***************************************************************************/
+
+    /***************************************************************************/
+    /***************************************************************************/
+    // Stage 1
+    let responses = match responses {
+        None => saved_stage1_resps.unwrap(),
+        Some((mut responses, plan)) => {
+            let start = start_timer_buf!(log, || format!("Coord: Processing stage0 responses"));
+            if has_stage1_state {
+                coordinator_state.resume_stage_1()
+            } else {
+                coordinator_state.stage_1(&responses)
+            };
+            end_timer_buf!(log, start);
+
+            let mut stage1_responses = prove_after_stage0(
+                log,
+                world,
+                &mut coordinator_state,
+                checkpoint.as_ref(),
+                assignments,
+                plan,
+                cost,
+                &responses,
+            )?;
+
+            if verify_proofs {
+                let start = start_timer_buf!(log, || format!("Coord: Verifying stage1 proofs"));
+                redo_invalid_stage1_proofs(
+                    log,
+                    world,
+                    &mut coordinator_state,
+                    checkpoint.as_ref(),
+                    &mut responses,
+                    &mut stage1_responses,
+                    assignments,
+                    cost,
+                )?;
+                end_timer_buf!(log, start);
+            }
+            let responses = stage1_responses;
 
-    let mut f =
-        File::create(&key_out_path).expect(&format!("could not create file {:?}", key_out_path));
-    f.write_all(&buf).unwrap();
+            if let Some(checkpoint) = &checkpoint {
+                cfg_iter!(responses).for_each(|resp| checkpoint.save_stage1_resp(resp).unwrap());
+            }
+            responses
+        },
+    };
+    /***************************************************************************/
+    /***************************************************************************/
+
+    // In a grouped run, the workers aggregate the groups, and are handed them like subcircuits
+    let group_resps = match coordinator_state.num_agg_groups() {
+        Some(num_groups) => {
+            coordinator_state.start_group_aggregation();
+            let state = &coordinator_state;
+            Some(run_stage_queued(
+                log,
+                "group aggregation",
+                world,
+                assignments,
+                num_groups,
+                |_| 1,
+                GROUP_AGG_TAG,
+                |g| state.group_agg_request(g, &responses),
+                |resp: &GroupAggResponse<E>| resp.group_idx,
+            )?)
+        },
+        None => None,
+    };
+
+    // The workers are done once stage 1, and the group aggregation if any, is. A stalled worker
+    // isn't listening, and is aborted once the proof is done
+    let stalled = assignments.stalled_workers();
+    send_done(world, (0..num_workers).filter(|w| !stalled.contains(w)));
+
+    let start = start_timer_buf!(log, || format!("Coord: Aggregating"));
+    let proof = match group_resps {
+        Some(group_resps) => coordinator_state.combine_group_aggs(group_resps),
+        None => coordinator_state.aggregate(&responses),
+    };
+    end_timer_buf!(log, start);
+
+    if let Some(path) = proof_out {
+        coordinator_state
+            .write_proof(&path, &proof)
+            .map_err(|e| format!("couldn't write the proof to {path:?}: {e}"))?;
+    }
+
+    Ok(())
 }
 
-fn setup_vm(
-    key_out_path: PathBuf,
-    num_subcircuits: usize,
-    use_merkle_memory: bool,
-    dummy_constraint_num: usize,
-    operations_per_chunk: usize,
-) {
-    assert!(
-        num_subcircuits.is_power_of_two(),
-        "#subcircuits MUST be a power of 2"
-    );
-    assert!(num_subcircuits > 1, "num. of subcircuits MUST be > 1");
-    assert!(
-        operations_per_chunk.is_power_of_two(),
-        "num. ops per chunk MUST be a power of 2"
-    );
-    assert!(operations_per_chunk > 1, "num. ops per chunk MUST be > 1");
-    assert!(
-        dummy_constraint_num > 1,
-        "num. dummy constraints MUST be > 1"
-    );
+/// Runs the extra rounds the circuit has left, then stage 1, and returns the stage1 responses in
+/// order of subcircuit. With a plan, each worker goes on from the states it kept in stage 0, and a
+/// subcircuit that's handed over has its commitments recomputed from the seed in `stage0_resps`.
+/// Without one, every subcircuit is handed over like that. The responses of a round are saved to
+/// the checkpoint once the round is done.
+fn prove_after_stage0<C, E, P>(
+    log: &mut Vec<String>,
+    world: &C,
+    coordinator_state: &mut CoordinatorState<E, P>,
+    checkpoint: Option<&Checkpoint>,
+    assignments: &mut Assignments,
+    plan: Option<&WorkPlan>,
+    cost: impl Fn(usize) -> usize,
+    stage0_resps: &[Stage0Response<E>],
+) -> Result<Vec<Stage1Response<E>>, String>
+where
+    C: Communicator,
+    E: SupportedCurve,
+    P: CircuitWithPortals<E::ScalarField>,
+{
+    let num_subcircuits = stage0_resps.len();
+
+    while coordinator_state.num_rounds_left() > 0 {
+        let round = P::NUM_EXTRA_ROUNDS - coordinator_state.num_rounds_left();
+        let stage = format!("round {round}");
+        let state = &*coordinator_state;
+        let redo_request = |i| {
+            (
+                state.stage0_request(i),
+                stage0_resps[i].clone(),
+                state.subcircuit_round_resps(i),
+                state.round_request(i),
+            )
+        };
+        let subcircuit_idx = |resp: &RoundResponse<E>| resp.subcircuit_idx;
+        let responses = match plan {
+            Some(plan) => run_stage(
+                log,
+                &stage,
+                world,
+                assignments,
+                plan,
+                &cost,
+                ROUND_TAG,
+                |i| state.round_request(i),
+                ROUND_REDO_TAG,
+                redo_request,
+                subcircuit_idx,
+            )?,
+            None => run_stage_queued(
+                log,
+                &stage,
+                world,
+                assignments,
+                num_subcircuits,
+                &cost,
+                ROUND_REDO_TAG,
+                redo_request,
+                subcircuit_idx,
+            )?,
+        };
+        if let Some(checkpoint) = checkpoint {
+            cfg_iter!(responses).for_each(|resp| checkpoint.save_round_resp(round, resp).unwrap());
+        }
 
-    let log_num_subcircuit = ark_std::log2(num_subcircuits) as usize;
+        let start = start_timer_buf!(log, || format!("Coord: Processing {stage} responses"));
+        coordinator_state.process_round(&responses);
+        end_timer_buf!(log, start);
+    }
 
-    let circ_params = VirtualMachineParameters {
-        use_merkle_memory,
-        log_num_subcircuit,
-        dummy_constraint_num,
-        operations_per_chunk,
+    let state = &*coordinator_state;
+    let redo_request = |i| {
+        (
+            state.stage0_request(i),
+            stage0_resps[i].clone(),
+            state.subcircuit_round_resps(i),
+            state.stage1_request(i),
+        )
     };
+    let subcircuit_idx = |resp: &Stage1Response<E>| resp.subcircuit_idx;
+    match plan {
+        Some(plan) => run_stage(
+            log,
+            "stage1",
+            world,
+            assignments,
+            plan,
+            &cost,
+            STAGE1_TAG,
+            |i| state.stage1_request(i),
+            STAGE1_REDO_TAG,
+            redo_request,
+            subcircuit_idx,
+        ),
+        None => run_stage_queued(
+            log,
+            "stage1",
+            world,
+            assignments,
+            num_subcircuits,
+            &cost,
+            STAGE1_REDO_TAG,
+            redo_request,
+            subcircuit_idx,
+        ),
+    }
+}
 
-    let pks = ProvingKeys::new::<VirtualMachine<Fr>>(circ_params, VM_CIRCUIT_ID.to_string());
+/// Verifies the stage1 proofs, and has another worker prove each subcircuit whose proof is
+/// invalid, until every proof is valid. A worker that sends an invalid proof is reported and
+/// dropped. Fails once a subcircuit has had `MAX_INVALID_PROOFS` invalid proofs, or if no worker
+/// is left to prove it again. `stage0_resps` and `stage1_resps` are indexed by subcircuit.
+///
+/// A proof that isn't made wrt the stage0 commitment of its subcircuit may come from a bad stage0
+/// response, which no other worker could recompute the commitment from. So stage 0 of such a
+/// subcircuit is redone too, and `stage0_resps` is updated. The new commitment changes the
+/// challenges, so every subcircuit's extra rounds and stage 1 are then redone.
+fn redo_invalid_stage1_proofs<C, E, P>(
+    log: &mut Vec<String>,
+    world: &C,
+    coordinator_state: &mut CoordinatorState<E, P>,
+    checkpoint: Option<&Checkpoint>,
+    stage0_resps: &mut Vec<Stage0Response<E>>,
+    stage1_resps: &mut Vec<Stage1Response<E>>,
+    assignments: &mut Assignments,
+    cost: impl Fn(usize) -> usize,
+) -> Result<(), String>
+where
+    C: Communicator,
+    E: SupportedCurve,
+    P: CircuitWithPortals<E::ScalarField>,
+{
+    let num_subcircuits = stage1_resps.len();
+    let mut resps = stage1_resps.drain(..).map(Some).collect::<Vec<_>>();
+    let mut num_invalid = vec![0; num_subcircuits];
+
+    // Only the new proofs need checking each time around
+    let mut unchecked = (0..num_subcircuits).collect::<Vec<_>>();
+    loop {
+        let invalid = unchecked
+            .into_iter()
+            .filter(|&i| !coordinator_state.verify_stage1_response(resps[i].as_ref().unwrap()))
+            .collect::<Vec<_>>();
+        if invalid.is_empty() {
+            break;
+        }
 
-    let mut buf = Vec::new();
-    pks.serialize_uncompressed(&mut buf).unwrap();
+        let mut stage0_redos = Vec::new();
+        for &i in &invalid {
+            let w = assignments.requeue(i).unwrap();
+            let rank = w + 1;
+            num_invalid[i] += 1;
+            if num_invalid[i] == MAX_INVALID_PROOFS {
+                return Err(format!(
+                    "subcircuit {i} has had {MAX_INVALID_PROOFS} invalid proofs, the last from \
+                     rank {rank}. Giving up"
+                ));
+            }
+            assignments.drop_worker(w);
+            if assignments.num_live() == 0 {
+                return Err(format!(
+                    "rank {rank} sent an invalid proof for subcircuit {i}, and no other worker is \
+                     left to prove it"
+                ));
+            }
 
-    let mut f =
-        File::create(&key_out_path).expect(&format!("could not create file {:?}", key_out_path));
-    f.write_all(&buf).unwrap();
+            if coordinator_state.stage1_commitments_match(resps[i].as_ref().unwrap()) {
+                log_buf!(
+                    log,
+                    "Coord: Worker {rank} sent an invalid proof for subcircuit {i}. Reassigning it"
+                );
+            } else {
+                log_buf!(
+                    log,
+                    "Coord: Worker {rank} sent a proof for subcircuit {i} with the wrong \
+                     commitments. Redoing its stage 0"
+                );
+                stage0_redos.push(i);
+            }
+            resps[i] = None;
+        }
+
+        if stage0_redos.is_empty() {
+            // The commitments are recomputed from the seeds in the stage0 responses
+            collect_responses(
+                log,
+                "stage1 redo",
+                world,
+                assignments,
+                &cost,
+                &[STAGE1_REDO_TAG],
+                &mut resps,
+                |resp: &Stage1Response<E>| resp.subcircuit_idx,
+                |w, subcircuits| {
+                    send_batch(world, w, STAGE1_REDO_TAG, subcircuits, |i| {
+                        (
+                            coordinator_state.stage0_request(i),
+                            stage0_resps[i].clone(),
+                            coordinator_state.subcircuit_round_resps(i),
+                            coordinator_state.stage1_request(i),
+                        )
+                    })
+                },
+            )?;
+            unchecked = invalid;
+        } else {
+            redo_stage0_requests(
+                log,
+                world,
+                coordinator_state,
+                checkpoint,
+                stage0_resps,
+                stage0_redos,
+                assignments,
+                &cost,
+            )?;
+
+            // The challenges changed, so every proof has to be made again
+            let start = start_timer_buf!(log, || format!("Coord: Processing stage0 responses"));
+            coordinator_state.stage_1(stage0_resps);
+            end_timer_buf!(log, start);
+            resps = prove_after_stage0(
+                log,
+                world,
+                coordinator_state,
+                checkpoint,
+                assignments,
+                None,
+                &cost,
+                stage0_resps,
+            )?
+            .into_iter()
+            .map(Some)
+            .collect();
+            unchecked = (0..num_subcircuits).collect();
+        }
+    }
+
+    *stage1_resps = resps.into_iter().map(Option::unwrap).collect();
+    Ok(())
 }
 
-fn work<P: CircuitWithPortals<Fr>>(num_workers: usize, proving_keys: ProvingKeys) {
-    let (universe, _) = mpi::initialize_with_threading(mpi::Threading::Funneled).unwrap();
-    let world = universe.world();
-    let root_rank = 0;
-    let root_process = world.process_at_rank(root_rank);
-    let rank = world.rank();
-    let size = world.size();
+/// Goes back to stage 0, and has the live workers do stage 0 of the given subcircuits again. Their
+/// new responses replace the old ones in `stage0_resps`, and in the checkpoint if there is one.
+/// Call `stage_1` afterwards.
+fn redo_stage0_requests<C, E, P>(
+    log: &mut Vec<String>,
+    world: &C,
+    coordinator_state: &mut CoordinatorState<E, P>,
+    checkpoint: Option<&Checkpoint>,
+    stage0_resps: &mut Vec<Stage0Response<E>>,
+    subcircuits: Vec<usize>,
+    assignments: &mut Assignments,
+    cost: impl Fn(usize) -> usize,
+) -> Result<(), String>
+where
+    C: Communicator,
+    E: SupportedCurve,
+    P: CircuitWithPortals<E::ScalarField>,
+{
+    coordinator_state.redo_stage_0();
 
-    let num_subcircuits = proving_keys.num_subcircuits();
+    let num_subcircuits = stage0_resps.len();
+    let mut resps = stage0_resps.drain(..).map(Some).collect::<Vec<_>>();
+    for &i in &subcircuits {
+        resps[i] = None;
+    }
+    assignments.start_stage_queued(num_subcircuits, subcircuits.clone());
+    collect_responses(
+        log,
+        "stage0 redo",
+        world,
+        assignments,
+        &cost,
+        &[STAGE0_REDO_TAG],
+        &mut resps,
+        |resp: &Stage0Response<E>| resp.subcircuit_idx,
+        |w, batch| {
+            send_batch(world, w, STAGE0_REDO_TAG, batch, |i| {
+                coordinator_state.stage0_request(i)
+            })
+        },
+    )?;
+    *stage0_resps = resps.into_iter().map(Option::unwrap).collect();
 
-    let num_subcircuits_per_worker = num_subcircuits / num_workers;
-    assert_eq!(num_subcircuits_per_worker * num_workers, num_subcircuits);
+    if let Some(checkpoint) = checkpoint {
+        for &i in &subcircuits {
+            checkpoint.save_stage0_resp(&stage0_resps[i]).unwrap();
+        }
+    }
+    Ok(())
+}
 
-    let mut log = Vec::new();
-    let very_start = start_timer_buf!(log, || format!("Node {rank}: Beginning work"));
+/// The MPI tags of the messages between the coordinator and the workers. The tag says what a
+/// message is, and its `ProofMessage` says which proof it's for. A response has the tag of its
+/// request.
+const STAGE0_TAG: Tag = 0;
+const STAGE1_TAG: Tag = 1;
+const DONE_TAG: Tag = 2;
+/// An extra round of multi-round circuits, done between stage 0 and stage 1
+const ROUND_TAG: Tag = 3;
+/// Stage 0 of subcircuits handed over from another worker. The worker doesn't keep their states,
+/// since their stage 1 may go to anyone.
+const STAGE0_REDO_TAG: Tag = 4;
+/// Stage 1 of subcircuits whose stage 0 was done elsewhere. Each request comes with the stage0
+/// request and response of its subcircuit, and its responses in the extra rounds, so the
+/// commitments can be recomputed from the seed.
+const STAGE1_REDO_TAG: Tag = 5;
+/// An extra round of subcircuits whose stage 0 was done elsewhere. Like a stage1 redo, each
+/// request comes with what's needed to recompute the earlier commitments.
+const ROUND_REDO_TAG: Tag = 6;
+/// The aggregation of a group of subcircuit proofs, in a run that aggregates in groups. Each
+/// request has the group's proofs and key, so any worker can take it.
+const GROUP_AGG_TAG: Tag = 7;
+
+/// The proof ID of a run that makes a single proof
+const SINGLE_PROOF_ID: ProofId = 0;
+
+/// How often the coordinator checks for responses while a deadline is pending
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Makes `num_proofs` proofs, overlapping the stages of consecutive proofs. While the coordinator
+/// builds the execution tree of proof k, the workers do stage 0 of proof k+1, and while it
+/// aggregates proof k-1, they do stage 1 of proof k. The workers take part by calling
+/// `serve_requests`. Each worker gets its requests in the order they're sent, and answers them in
+/// that order, so the responses are received in that order too. With a store directory, each proof
+/// keeps its subcircuits in a subdirectory of it. With a proof path, proof k is written to it with
+/// `.k` appended.
+fn coordinate_pipelined<C, E, P>(
+    log: &mut Vec<String>,
+    world: &C,
+    proving_keys: &ProvingKeys<E>,
+    instance_paths: &[PathBuf],
+    num_proofs: usize,
+    store_dir: Option<PathBuf>,
+    proof_out: Option<PathBuf>,
+) where
+    C: Communicator,
+    E: SupportedCurve,
+    P: CircuitWithPortals<E::ScalarField>,
+{
+    let last_proof_id = num_proofs as ProofId - 1;
 
-    if rank == root_rank {
-        // Initial broadcast
+    let start = start_timer_buf!(log, || format!("Coord: construct coordinator state"));
+    let base_state = CoordinatorState::<E, P>::new(proving_keys);
+    end_timer_buf!(log, start);
 
-        let start = start_timer_buf!(log, || format!("Coord: construct coordinator state"));
-        let mut coordinator_state = CoordinatorState::<P>::new(&proving_keys);
-        end_timer_buf!(log, start);
+    // Every proof is of the same circuit, so they can all use the same plan
+    let plan = plan_work(log, proving_keys, world.size() as usize - 1);
 
-        /***************************************************************************/
-        /***************************************************************************/
-        // Stage 0
-        let start = start_timer_buf!(log, || format!("Coord: Generating stage0 requests"));
-        let requests = coordinator_state.stage_0();
-        let requests_chunked = requests
-            .chunks(num_subcircuits_per_worker)
-            .map(|chunk| chunk.to_vec())
-            .collect::<Vec<_>>();
+    // Every proof shares the aggregation key, so a single verifying key verifies them all
+    if let Some(path) = &proof_out {
+        let path = append_to_path(path, ".vk");
+        write_to_path(&path, &base_state.agg_vk())
+            .unwrap_or_else(|e| panic!("couldn't write the verifying key to {path:?}: {e}"));
+    }
+
+    // Aggregates the given proof, and writes it out if asked to
+    let aggregate = |log: &mut Vec<String>,
+                     id: ProofId,
+                     state: &mut CoordinatorState<E, P>,
+                     resps: &[Stage1Response<E>]| {
+        let start = start_timer_buf!(log, || format!("Coord: Aggregating proof {id}"));
+        let proof = state.aggregate(resps);
         end_timer_buf!(log, start);
 
-        // Stage 0 scatter
-        scatter_requests(&mut log, "stage0", &root_process, &requests_chunked);
-        println!("Finished coordinator scatter 0");
+        if let Some(path) = &proof_out {
+            let path = append_to_path(path, &format!(".{id}"));
+            write_to_path(&path, &proof)
+                .unwrap_or_else(|e| panic!("couldn't write the proof to {path:?}: {e}"));
+        }
+    };
 
-        // Stage 0 gather
-        let default_response = vec![Stage0Response::default(); num_subcircuits_per_worker];
-        let responses_chunked: Vec<Vec<_>> =
-            gather_responses(&mut log, "stage0", size, &root_process, default_response);
-        let responses = cfg_into_iter!(responses_chunked)
-            .flatten()
-            .collect::<Vec<_>>();
-        println!("Finished coordinator gather 0");
-        /***************************************************************************/
-        /***************************************************************************/
-
-        /***************************************************************************/
-        /***************************************************************************/
-        // Stage 1
-        let start = start_timer_buf!(log, || format!("Coord: Processing stage0 responses"));
-        let requests = coordinator_state.stage_1(&responses);
-        let requests_chunked = requests
-            .chunks(num_subcircuits_per_worker)
-            .map(|chunk| chunk.to_vec())
-            .collect::<Vec<_>>();
+    // Makes the state of the given proof, and does its stage 0. Every proof gets its own state,
+    // but they all share the aggregation key
+    let start_proof = |log: &mut Vec<String>, proof_id: ProofId| {
+        let start = start_timer_buf!(log, || format!(
+            "Coord: Generating stage0 state of proof {proof_id}"
+        ));
+        let mut state = match &store_dir {
+            // At most 3 proofs are in flight at once: the one being aggregated, the one in stage 1,
+            // and the one in stage 0. So a proof can reuse the directory of the proof 3 before it
+            Some(dir) => base_state.fork_with_store_dir(dir.join(format!("proof{}", proof_id % 3))),
+            None => base_state.fork(),
+        };
+        let circ = match instance_paths.get(proof_id as usize) {
+            Some(path) => {
+                let mut f =
+                    File::open(path).unwrap_or_else(|_| panic!("couldn't open file {path:?}"));
+                let instance = CircuitInstance::deserialize_uncompressed_unchecked(&mut f).unwrap();
+                state.circuit_from_instance(&instance)
+            },
+            None => state.rand_circuit(),
+        };
+        state.stage_0(circ);
         end_timer_buf!(log, start);
+        state
+    };
 
-        // Stage 1 scatter
-        scatter_requests(&mut log, "stage1", &root_process, &requests_chunked);
-        println!("Finished coordinator scatter 1");
+    // The first proof has nothing to overlap with
+    let mut proof_id = 0;
+    let mut state = start_proof(log, proof_id);
+    send_proof_requests_during(
+        log,
+        "stage0",
+        world,
+        &plan,
+        STAGE0_TAG,
+        proof_id,
+        |i| state.stage0_request(i),
+        |_| (),
+    );
+    let mut stage0_resps: Vec<Stage0Response<E>> =
+        receive_proof_responses(log, "stage0", world, &plan, STAGE0_TAG, proof_id);
+
+    // The last proof whose stage 1 is done, and which is waiting to be aggregated
+    let mut unaggregated: Option<(ProofId, CoordinatorState<E, P>, Vec<Stage1Response<E>>)> = None;
+
+    loop {
+        // Start the next proof, and have the workers do its stage 0 while this proof's execution
+        // tree is built
+        let next_proof_id = proof_id + 1;
+        let next_state = (proof_id < last_proof_id).then(|| start_proof(log, next_proof_id));
+        let process_stage0_resps = |log: &mut Vec<String>| {
+            let start = start_timer_buf!(log, || format!(
+                "Coord: Processing stage0 responses of proof {proof_id}"
+            ));
+            state.stage_1(&stage0_resps);
+            end_timer_buf!(log, start);
+        };
+        match &next_state {
+            Some(next_state) => send_proof_requests_during(
+                log,
+                "stage0",
+                world,
+                &plan,
+                STAGE0_TAG,
+                next_proof_id,
+                |i| next_state.stage0_request(i),
+                process_stage0_resps,
+            ),
+            None => process_stage0_resps(log),
+        }
 
-        // Stage 1 gather
-        let default_response = vec![Stage1Response::default(); num_subcircuits_per_worker];
-        let responses_chunked: Vec<Vec<_>> =
-            gather_responses(&mut log, "stage1", size, &root_process, default_response);
-        let responses = cfg_into_iter!(responses_chunked)
-            .flatten()
-            .collect::<Vec<_>>();
-        println!("Finished coordinator gather 1");
-        /***************************************************************************/
-        /***************************************************************************/
+        // A worker answers in order, and can't send its round responses until its next stage0
+        // responses are received. So with extra rounds, those are received first
+        let mut next_stage0_resps = None;
+        if P::NUM_EXTRA_ROUNDS > 0 && next_state.is_some() {
+            next_stage0_resps = Some(receive_proof_responses(
+                log,
+                "stage0",
+                world,
+                &plan,
+                STAGE0_TAG,
+                next_proof_id,
+            ));
+        }
+        while state.num_rounds_left() > 0 {
+            let stage = format!("round {}", P::NUM_EXTRA_ROUNDS - state.num_rounds_left());
+            send_proof_requests_during(
+                log,
+                &stage,
+                world,
+                &plan,
+                ROUND_TAG,
+                proof_id,
+                |i| state.round_request(i),
+                |_| (),
+            );
+            let round_resps: Vec<RoundResponse<E>> =
+                receive_proof_responses(log, &stage, world, &plan, ROUND_TAG, proof_id);
+            state.process_round(&round_resps);
+        }
 
-        let start = start_timer_buf!(log, || format!("Coord: Aggregating"));
-        let _proof = coordinator_state.aggregate(&responses);
-        end_timer_buf!(log, start);
-    } else {
-        let current_num_threads = current_num_threads() - 1;
-        println!(
-            "Rayon num threads in worker {rank}: {}",
-            current_num_threads
+        // Have the workers do this proof's stage 1 while the last proof is aggregated
+        send_proof_requests_during(
+            log,
+            "stage1",
+            world,
+            &plan,
+            STAGE1_TAG,
+            proof_id,
+            |i| state.stage1_request(i),
+            |log| {
+                if let Some((id, mut agg_state, resps)) = unaggregated.take() {
+                    aggregate(log, id, &mut agg_state, &resps);
+                }
+            },
         );
-        // Worker code
-        let start = start_timer_buf!(log, || format!("Worker {rank}: Initializing worker state"));
-        let mut worker_states =
-            std::iter::from_fn(|| Some(WorkerState::<P>::new(num_subcircuits, &proving_keys)))
-                .take(num_subcircuits_per_worker)
-                .collect::<Vec<_>>();
-        end_timer_buf!(log, start);
 
-        /***************************************************************************/
-        /***************************************************************************/
-        // Stage 0
-
-        // Receive Stage 0 request
-        let requests: Vec<Stage0Request> =
-            receive_requests(&mut log, rank, "stage0", &root_process);
-
-        // Compute Stage 0 response
-        let start = start_timer_buf!(log, || format!("Worker {rank}: Processing stage0 requests"));
-        let responses = compute_responses(
-            current_num_threads,
-            &requests,
-            &mut worker_states,
-            |req, state| state.stage_0(rand::thread_rng(), &req.to_ref()),
-        );
-        end_timer_buf!(log, start);
-        println!("Finished worker scatter 0 for rank {rank}");
+        // The next proof's stage0 requests were sent first, so their responses come first
+        let next_stage0_resps = match next_stage0_resps {
+            Some(resps) => resps,
+            None if next_state.is_some() => {
+                receive_proof_responses(log, "stage0", world, &plan, STAGE0_TAG, next_proof_id)
+            },
+            None => Vec::new(),
+        };
+        let stage1_resps =
+            receive_proof_responses(log, "stage1", world, &plan, STAGE1_TAG, proof_id);
+        unaggregated = Some((proof_id, state, stage1_resps));
+
+        match next_state {
+            Some(next_state) => {
+                proof_id = next_proof_id;
+                state = next_state;
+                stage0_resps = next_stage0_resps;
+            },
+            None => break,
+        }
+    }
 
-        // Send Stage 0 response
-        send_responses(&mut log, rank, "stage0", &root_process, &responses);
-        println!("Finished worker gather 0 for rank {rank}");
+    // The workers are done once the last proof's stage 1 is
+    send_done(world, 0..plan.num_workers());
 
-        /***************************************************************************/
-        /***************************************************************************/
+    let (id, mut state, resps) = unaggregated.unwrap();
+    aggregate(log, id, &mut state, &resps);
+}
 
-        /***************************************************************************/
-        /***************************************************************************/
-        // Stage 1
+/// Proves `num_instances` instances together, into a single batch proof. Instance k is the k-th of
+/// `instance_paths`, or a random instance if there are fewer. The instances share their
+/// challenges, so every stage is done for all of them before the next starts. The workers take
+/// part by calling `serve_requests`, with each instance's messages carrying its index as the proof
+/// ID. With a store directory, each instance keeps its subcircuits in a subdirectory of it.
+fn coordinate_batch<C, E, P>(
+    log: &mut Vec<String>,
+    world: &C,
+    proving_keys: &ProvingKeys<E>,
+    instance_paths: &[PathBuf],
+    num_instances: usize,
+    store_dir: Option<PathBuf>,
+    proof_out: Option<PathBuf>,
+) where
+    C: Communicator,
+    E: SupportedCurve,
+    P: CircuitWithPortals<E::ScalarField>,
+{
+    let start = start_timer_buf!(log, || format!("Coord: construct coordinator states"));
+    let mut states = CoordinatorState::<E, P>::new_batch(proving_keys, num_instances, store_dir);
+    end_timer_buf!(log, start);
 
-        // Receive Stage 1 request
-        let requests: Vec<Stage1Request<_>> =receive_requests(&mut log, rank, "stage1", &root_process);
+    let plan = plan_work(log, proving_keys, world.size() as usize - 1);
 
-        // Compute Stage 1 response
-        let start = start_timer_buf!(log, || format!("Worker {rank}: Processing stage1 request"));
-        let responses = compute_responses(
-            current_num_threads,
-            &requests,
-            worker_states,
-            |req, state| state.stage_1(rand::thread_rng(), &req.to_ref()),
-        );
-        end_timer_buf!(log, start);
-        println!("Finished worker scatter 1 for rank {rank}");
+    let start = start_timer_buf!(log, || format!("Coord: Generating stage0 states"));
+    for (k, state) in states.iter_mut().enumerate() {
+        let circ = match instance_paths.get(k) {
+            Some(path) => {
+                let mut f =
+                    File::open(path).unwrap_or_else(|_| panic!("couldn't open file {path:?}"));
+                let instance = CircuitInstance::deserialize_uncompressed_unchecked(&mut f).unwrap();
+                state.circuit_from_instance(&instance)
+            },
+            None => state.rand_circuit(),
+        };
+        state.stage_0(circ);
+    }
+    end_timer_buf!(log, start);
 
-        send_responses(&mut log, rank, "stage1", &root_process, &responses);
+    let stage0_resps = run_batch_stage(
+        log,
+        "stage0",
+        world,
+        &plan,
+        STAGE0_TAG,
+        states.len(),
+        |k, i| states[k].stage0_request(i),
+    );
+    let start = start_timer_buf!(log, || format!("Coord: Processing stage0 responses"));
+    CoordinatorState::stage_1_batch(&mut states, &stage0_resps);
+    end_timer_buf!(log, start);
 
-        println!("Finished worker gather 1 for rank {rank}");
-        /***************************************************************************/
-        /***************************************************************************/
+    while states[0].num_rounds_left() > 0 {
+        let stage = format!(
+            "round {}",
+            P::NUM_EXTRA_ROUNDS - states[0].num_rounds_left()
+        );
+        let round_resps = run_batch_stage(
+            log,
+            &stage,
+            world,
+            &plan,
+            ROUND_TAG,
+            states.len(),
+            |k, i| states[k].round_request(i),
+        );
+        CoordinatorState::process_round_batch(&mut states, &round_resps);
     }
 
-    end_timer_buf!(log, very_start);
+    let stage1_resps = run_batch_stage(
+        log,
+        "stage1",
+        world,
+        &plan,
+        STAGE1_TAG,
+        states.len(),
+        |k, i| states[k].stage1_request(i),
+    );
+    send_done(world, 0..plan.num_workers());
+
+    let start = start_timer_buf!(log, || format!("Coord: Aggregating the batch"));
+    let proof = CoordinatorState::aggregate_batch(&mut states, &stage1_resps);
+    end_timer_buf!(log, start);
 
-    println!("Rank {rank} log: {}", log.join(";"));
+    if let Some(path) = proof_out {
+        states[0]
+            .write_proof(&path, &proof)
+            .unwrap_or_else(|e| panic!("couldn't write the proof to {path:?}: {e}"));
+    }
 }
 
-fn scatter_requests<'a, C: 'a + Communicator>(
+/// Has the workers do a stage of every instance of a batch, and returns the responses of each
+/// instance in order of subcircuit. `request` makes the request of the given instance and
+/// subcircuit. The requests of an instance are sent while the responses of the one before are
+/// received, like in `coordinate_pipelined`, so no worker is kept waiting to send.
+fn run_batch_stage<C, T, R>(
     log: &mut Vec<String>,
     stage: &str,
+    world: &C,
+    plan: &WorkPlan,
+    tag: Tag,
+    num_instances: usize,
+    request: impl Fn(usize, usize) -> T,
+) -> Vec<Vec<R>>
+where
+    C: Communicator,
+    T: CanonicalSerialize,
+    R: CanonicalDeserialize,
+{
+    let mut resps = Vec::with_capacity(num_instances);
+    for k in 0..num_instances {
+        let id = k as ProofId;
+        send_proof_requests_during(
+            log,
+            stage,
+            world,
+            plan,
+            tag,
+            id,
+            |i| request(k, i),
+            |log| {
+                if k > 0 {
+                    resps.push(receive_proof_responses(
+                        log,
+                        stage,
+                        world,
+                        plan,
+                        tag,
+                        id - 1,
+                    ));
+                }
+            },
+        );
+    }
+    let last_id = num_instances as ProofId - 1;
+    resps.push(receive_proof_responses(
+        log, stage, world, plan, tag, last_id,
+    ));
+    resps
+}
+
+/// The worker's side of `coordinate`, `coordinate_pipelined`, and `coordinate_batch`. Handles
+/// requests in the order they come, keeping the states of every proof whose stage 0 is done until
+/// its stage1 requests come. Each response is sent without blocking, while the next request is
+/// received and worked on. Returns once the coordinator says it's done.
+fn serve_requests<'a, C, E, P>(
+    log: &mut Vec<String>,
+    rank: Count,
     root_process: &Process<'a, C>,
-    requests: &[impl CanonicalSerialize + Send],
-) {
-    let start = start_timer_buf!(log, || format!("Coord: Serializing {stage} requests"));
-    let mut request_bytes = vec![];
-    let request_bytes_buf = construct_partitioned_buffer_for_scatter!(requests, &mut request_bytes);
-    end_timer_buf!(log, start);
+    num_subcircuits: usize,
+    proving_keys: &ProvingKeys<E>,
+) where
+    C: 'a + Communicator,
+    E: SupportedCurve,
+    P: CircuitWithPortals<E::ScalarField>,
+{
+    let current_num_threads = current_num_threads() - 1;
+    println!(
+        "Rayon num threads in worker {rank}: {}",
+        current_num_threads
+    );
+    let new_worker_states = |num: usize| {
+        std::iter::from_fn(|| Some(WorkerState::<E, P>::new(num_subcircuits, proving_keys)))
+            .take(num)
+            .collect::<Vec<_>>()
+    };
 
-    let counts = request_bytes_buf.counts().to_vec();
-    root_process.scatter_into_root(&counts, &mut 0i32);
-    let mut _recv_buf: Vec<u8> = vec![];
+    // The states of the subcircuits whose stage 0 is done, by proof
+    let mut worker_states: BTreeMap<ProofId, Vec<WorkerState<E, P>>> = BTreeMap::new();
+    // The last response and its tag. It's sent while the next request is handled
+    let mut last_response: Option<(Tag, Vec<u8>)> = None;
+
+    loop {
+        let (done, response) = mpi::request::scope(|scope| {
+            let send = last_response
+                .as_ref()
+                .map(|(tag, bytes)| root_process.immediate_send_with_tag(scope, &bytes[..], *tag));
+
+            let start = start_timer_buf!(log, || format!("Worker {rank}: Receiving request"));
+            let (request_ser, status) = root_process.receive_vec::<Packed>();
+            end_timer_buf!(log, start);
+
+            let mut done = false;
+            let response = match status.tag() {
+                STAGE0_TAG | STAGE0_REDO_TAG => {
+                    let msg: ProofMessage<Stage0Request<E>> =
+                        deserialize_from_packed_bytes(&request_ser[..]).unwrap();
+                    let ProofMessage { proof_id, items } = msg;
+                    let start = start_timer_buf!(log, || format!(
+                        "Worker {rank}: Processing stage0 requests of proof {proof_id}"
+                    ));
+                    let mut states = new_worker_states(items.len());
+                    let responses = compute_responses(
+                        current_num_threads,
+                        &items,
+                        &mut states,
+                        |req, state| state.stage_0(rand::thread_rng(), &req.to_ref()),
+                    );
+                    end_timer_buf!(log, start);
+
+                    // Handed-over subcircuits get their stage 1 from seed, maybe elsewhere
+                    if status.tag() == STAGE0_TAG {
+                        worker_states.insert(proof_id, states);
+                    }
+                    let msg = ProofMessage {
+                        proof_id,
+                        items: responses,
+                    };
+                    Some((status.tag(), serialize_to_vec(&msg)))
+                },
+                ROUND_TAG => {
+                    let msg: ProofMessage<RoundRequest<E, P>> =
+                        deserialize_from_packed_bytes(&request_ser[..]).unwrap();
+                    let ProofMessage { proof_id, items } = msg;
+                    let start = start_timer_buf!(log, || format!(
+                        "Worker {rank}: Processing round requests of proof {proof_id}"
+                    ));
+                    let states = worker_states.get_mut(&proof_id).unwrap_or_else(|| {
+                        panic!("got round requests of proof {proof_id} before stage 0")
+                    });
+                    let responses = compute_responses(
+                        current_num_threads,
+                        &items,
+                        states.iter_mut(),
+                        |req, state| state.round(rand::thread_rng(), req),
+                    );
+                    end_timer_buf!(log, start);
+
+                    let msg = ProofMessage {
+                        proof_id,
+                        items: responses,
+                    };
+                    Some((ROUND_TAG, serialize_to_vec(&msg)))
+                },
+                ROUND_REDO_TAG => {
+                    let msg: ProofMessage<(
+                        Stage0Request<E>,
+                        Stage0Response<E>,
+                        Vec<RoundResponse<E>>,
+                        RoundRequest<E, P>,
+                    )> = deserialize_from_packed_bytes(&request_ser[..]).unwrap();
+                    let ProofMessage { proof_id, items } = msg;
+                    let start = start_timer_buf!(log, || format!(
+                        "Worker {rank}: Processing handed-over round requests of proof {proof_id}"
+                    ));
+                    // The earlier commitments are recomputed from their seeds
+                    let responses = compute_responses(
+                        current_num_threads,
+                        &items,
+                        new_worker_states(items.len()),
+                        |(req0, resp0, round_resps, req), state| {
+                            state.round_from_seed(
+                                rand::thread_rng(),
+                                &req0.to_ref(),
+                                resp0,
+                                round_resps,
+                                req,
+                            )
+                        },
+                    );
+                    end_timer_buf!(log, start);
+
+                    let msg = ProofMessage {
+                        proof_id,
+                        items: responses,
+                    };
+                    Some((ROUND_REDO_TAG, serialize_to_vec(&msg)))
+                },
+                STAGE1_TAG => {
+                    let msg: ProofMessage<Stage1Request<E, P>> =
+                        deserialize_from_packed_bytes(&request_ser[..]).unwrap();
+                    let ProofMessage { proof_id, items } = msg;
+                    let start = start_timer_buf!(log, || format!(
+                        "Worker {rank}: Processing stage1 requests of proof {proof_id}"
+                    ));
+                    let states = worker_states.remove(&proof_id).unwrap_or_else(|| {
+                        panic!("got stage1 requests of proof {proof_id} before stage 0")
+                    });
+                    let responses =
+                        compute_responses(current_num_threads, &items, states, |req, state| {
+                            state.stage_1(rand::thread_rng(), &req.to_ref())
+                        });
+                    end_timer_buf!(log, start);
+
+                    let msg = ProofMessage {
+                        proof_id,
+                        items: responses,
+                    };
+                    Some((STAGE1_TAG, serialize_to_vec(&msg)))
+                },
+                STAGE1_REDO_TAG => {
+                    let msg: ProofMessage<(
+                        Stage0Request<E>,
+                        Stage0Response<E>,
+                        Vec<RoundResponse<E>>,
+                        Stage1Request<E, P>,
+                    )> = deserialize_from_packed_bytes(&request_ser[..]).unwrap();
+                    let ProofMessage { proof_id, items } = msg;
+                    let start = start_timer_buf!(log, || format!(
+                        "Worker {rank}: Processing handed-over stage1 requests of proof {proof_id}"
+                    ));
+                    // The commitments are recomputed from their seeds
+                    let responses = compute_responses(
+                        current_num_threads,
+                        &items,
+                        new_worker_states(items.len()),
+                        |(req0, resp0, round_resps, req1), state| {
+                            state.stage_1_from_seed(
+                                rand::thread_rng(),
+                                &req0.to_ref(),
+                                resp0,
+                                round_resps,
+                                &req1.to_ref(),
+                            )
+                        },
+                    );
+                    end_timer_buf!(log, start);
+
+                    let msg = ProofMessage {
+                        proof_id,
+                        items: responses,
+                    };
+                    Some((STAGE1_REDO_TAG, serialize_to_vec(&msg)))
+                },
+                GROUP_AGG_TAG => {
+                    let msg: ProofMessage<GroupAggRequest<E>> =
+                        deserialize_from_packed_bytes(&request_ser[..]).unwrap();
+                    let ProofMessage { proof_id, items } = msg;
+                    let start = start_timer_buf!(log, || format!(
+                        "Worker {rank}: Aggregating {} groups of proof {proof_id}",
+                        items.len()
+                    ));
+                    // A group's aggregation is parallel by itself, so the groups go one at a time
+                    let responses = execute_in_pool(
+                        || items.iter().map(|req| req.aggregate()).collect::<Vec<_>>(),
+                        current_num_threads,
+                    );
+                    end_timer_buf!(log, start);
+
+                    let msg = ProofMessage {
+                        proof_id,
+                        items: responses,
+                    };
+                    Some((GROUP_AGG_TAG, serialize_to_vec(&msg)))
+                },
+                DONE_TAG => {
+                    done = true;
+                    None
+                },
+                // The coordinator never sends anything else. Whatever this is, there's nothing to
+                // answer
+                tag => {
+                    log_buf!(
+                        log,
+                        "Worker {rank}: Ignoring a message with unknown tag {tag}"
+                    );
+                    None
+                },
+            };
 
-    let start = start_timer_buf!(log, || format!("Coord: Scattering {stage} requests"));
-    root_process.scatter_varcount_into_root(&request_bytes_buf, &mut _recv_buf);
-    end_timer_buf!(log, start);
+            // The coordinator receives a worker's responses in order, so the next one can't go
+            // out until this one is through
+            if let Some(send) = send {
+                send.wait();
+            }
+            (done, response)
+        });
+
+        if done {
+            break;
+        }
+        last_response = response;
+    }
 }
 
-fn receive_requests<'a, C: 'a + Communicator, T: CanonicalDeserialize>(
+/// Sends every worker the given proof's requests for the subcircuits the plan gives it, without
+/// blocking, and runs `overlap` while they're in flight. `request` makes the request of a
+/// subcircuit. Returns what `overlap` does, once every chunk is sent.
+fn send_proof_requests_during<C, T, R>(
     log: &mut Vec<String>,
-    rank: i32,
     stage: &str,
-    root_process: &Process<'a, C>,
-) -> T {
-    let mut size = 0 as Count;
-    root_process.scatter_into(&mut size);
-
+    world: &C,
+    plan: &WorkPlan,
+    tag: Tag,
+    proof_id: ProofId,
+    request: impl Fn(usize) -> T,
+    overlap: impl FnOnce(&mut Vec<String>) -> R,
+) -> R
+where
+    C: Communicator,
+    T: CanonicalSerialize,
+{
+    // The chunks have to outlive their sends, so they're all serialized up front
     let start = start_timer_buf!(log, || format!(
-        "Worker {rank}: Receiving scattered {stage} request of size {size}"
+        "Coord: Serializing {stage} requests of proof {proof_id}"
     ));
-    let mut request_ser = vec![Packed::zero(); size as usize];
-    root_process.scatter_varcount_into(&mut request_ser);
+    let chunk_bytes = (0..plan.num_workers())
+        .map(|w| {
+            let items = plan.subcircuits(w).iter().map(|&i| request(i)).collect();
+            serialize_to_packed_vec(&ProofMessage { proof_id, items })
+        })
+        .collect::<Vec<_>>();
     end_timer_buf!(log, start);
 
-    let start = start_timer_buf!(log, || format!(
-        "Worker {rank}: Deserializing {stage} request"
-    ));
-    let ret = deserialize_from_packed_bytes(&request_ser[..]).unwrap();
-    end_timer_buf!(log, start);
+    mpi::request::scope(|scope| {
+        // The coordinator is rank 0, so worker w is rank w + 1
+        let sends = chunk_bytes
+            .iter()
+            .enumerate()
+            .map(|(w, bytes)| {
+                world
+                    .process_at_rank(w as Count + 1)
+                    .immediate_send_with_tag(scope, &bytes[..], tag)
+            })
+            .collect::<Vec<_>>();
+
+        let ret = overlap(log);
 
-    ret
+        let start = start_timer_buf!(log, || format!(
+            "Coord: Finishing sending {stage} requests of proof {proof_id}"
+        ));
+        for send in sends {
+            send.wait();
+        }
+        end_timer_buf!(log, start);
+        ret
+    })
 }
 
-fn send_responses<'a, C: 'a + Communicator, T: CanonicalSerialize>(
+/// Receives every worker's responses for the given proof, and returns them in order of subcircuit
+fn receive_proof_responses<C: Communicator, T: CanonicalDeserialize>(
     log: &mut Vec<String>,
-    rank: i32,
     stage: &str,
-    root_process: &Process<'a, C>,
-    responses: &[T],
-) {
-    // Send Stage 1 response
+    world: &C,
+    plan: &WorkPlan,
+    tag: Tag,
+    proof_id: ProofId,
+) -> Vec<T> {
     let start = start_timer_buf!(log, || format!(
-        "Worker {rank}: Serializing {stage} {}, responses",
-        responses.len(),
+        "Coord: Receiving {stage} responses of proof {proof_id}"
     ));
-    let responses_bytes = serialize_to_vec(&responses);
+    let responses_chunked = (0..plan.num_workers())
+        .map(|w| {
+            let rank = w as Count + 1;
+            let (bytes, _) = world.process_at_rank(rank).receive_vec_with_tag::<u8>(tag);
+            let msg = ProofMessage::<T>::deserialize_uncompressed_unchecked(&bytes[..]).unwrap();
+            assert_eq!(
+                msg.proof_id, proof_id,
+                "worker {rank} sent {stage} responses for the wrong proof"
+            );
+            msg.items
+        })
+        .collect::<Vec<_>>();
     end_timer_buf!(log, start);
 
-    let start = start_timer_buf!(log, || format!(
-        "Worker {rank}: Gathering {stage} response, each of size {}",
-        responses_bytes.len() / responses.len()
-    ));
-    root_process.gather_varcount_into(&responses_bytes);
+    plan.reorder(responses_chunked)
+}
+
+/// Splits the subcircuits among the workers so that each has about the same number of
+/// constraints to prove, going by the constraint counts in the proving keys
+fn plan_work<E: SupportedCurve>(
+    log: &mut Vec<String>,
+    proving_keys: &ProvingKeys<E>,
+    num_workers: usize,
+) -> WorkPlan {
+    let start = start_timer_buf!(log, || format!("Coord: Planning work"));
+    let cost = |i| proving_keys.num_constraints(i);
+    let plan = WorkPlan::lpt(proving_keys.num_subcircuits(), num_workers, cost);
     end_timer_buf!(log, start);
+
+    log_buf!(log, "Coord: Constraints per worker: {:?}", plan.loads(cost));
+    plan
 }
 
-fn gather_responses<'a, C, T>(
+/// Hands out the subcircuits of a stage by the plan, and collects their responses, in order of
+/// subcircuit. `request` makes the request of a subcircuit in its planned worker's batch, which
+/// has what it needs from the earlier stages. `redo_request` makes the request of a subcircuit
+/// that's handed over to another worker, and is sent with `redo_tag`.
+fn run_stage<C, T, R, U>(
     log: &mut Vec<String>,
     stage: &str,
-    size: Count,
-    root_process: &Process<'a, C>,
-    default_response: T,
-) -> Vec<T>
+    world: &C,
+    assignments: &mut Assignments,
+    plan: &WorkPlan,
+    cost: impl Fn(usize) -> usize,
+    tag: Tag,
+    request: impl Fn(usize) -> T,
+    redo_tag: Tag,
+    redo_request: impl Fn(usize) -> R,
+    subcircuit_idx: impl Fn(&U) -> usize,
+) -> Result<Vec<U>, String>
 where
-    C: 'a + Communicator,
-    T: CanonicalSerialize + CanonicalDeserialize + Default,
+    C: Communicator,
+    T: CanonicalSerialize,
+    R: CanonicalSerialize,
+    U: CanonicalDeserialize,
 {
-    let mut response_bytes = vec![];
-    let mut response_bytes_buf =
-        construct_partitioned_mut_buffer_for_gather!(size, default_response, &mut response_bytes);
-    // Root does not send anything, it only receives.
-    let start = start_timer_buf!(log, || format!("Coord: Gathering {stage} responses"));
-    root_process.gather_varcount_into_root(&[0u8; 0], &mut response_bytes_buf);
+    let start = start_timer_buf!(log, || format!("Coord: Sending {stage} requests"));
+    for (w, subcircuits) in assignments.start_stage(plan, Instant::now()) {
+        send_batch(world, w, tag, &subcircuits, &request);
+    }
     end_timer_buf!(log, start);
 
-    let start = start_timer_buf!(log, || format!("Coord: Deserializing {stage} responses"));
-    let ret = deserialize_flattened_bytes!(response_bytes, default_response, T).unwrap();
+    let start = start_timer_buf!(log, || format!("Coord: Receiving {stage} responses"));
+    let mut resps = std::iter::repeat_with(|| None)
+        .take(plan.num_subcircuits())
+        .collect::<Vec<_>>();
+    collect_responses(
+        log,
+        stage,
+        world,
+        assignments,
+        cost,
+        &[tag, redo_tag],
+        &mut resps,
+        subcircuit_idx,
+        |w, subcircuits| send_batch(world, w, redo_tag, subcircuits, &redo_request),
+    )?;
     end_timer_buf!(log, start);
 
-    ret
+    Ok(resps.into_iter().map(Option::unwrap).collect())
+}
+
+/// Like `run_stage`, but without a plan. Every subcircuit is handed out from the queue with
+/// `redo_tag`, e.g., because no worker kept the states from the earlier stages.
+fn run_stage_queued<C, R, U>(
+    log: &mut Vec<String>,
+    stage: &str,
+    world: &C,
+    assignments: &mut Assignments,
+    num_subcircuits: usize,
+    cost: impl Fn(usize) -> usize,
+    redo_tag: Tag,
+    redo_request: impl Fn(usize) -> R,
+    subcircuit_idx: impl Fn(&U) -> usize,
+) -> Result<Vec<U>, String>
+where
+    C: Communicator,
+    R: CanonicalSerialize,
+    U: CanonicalDeserialize,
+{
+    let start = start_timer_buf!(log, || format!("Coord: Receiving {stage} responses"));
+    assignments.start_stage_queued(num_subcircuits, (0..num_subcircuits).collect());
+    let mut resps = std::iter::repeat_with(|| None)
+        .take(num_subcircuits)
+        .collect::<Vec<_>>();
+    collect_responses(
+        log,
+        stage,
+        world,
+        assignments,
+        cost,
+        &[redo_tag],
+        &mut resps,
+        subcircuit_idx,
+        |w, subcircuits| send_batch(world, w, redo_tag, subcircuits, &redo_request),
+    )?;
+    end_timer_buf!(log, start);
+
+    Ok(resps.into_iter().map(Option::unwrap).collect())
+}
+
+/// Receives the responses to the batches in flight, and hands the queued subcircuits to idle
+/// workers with `send_redo`, until every subcircuit of the stage has a response in `resps`. A
+/// response must have one of `tags`. A worker that misses its deadline, or sends a malformed
+/// response, is dropped. Fails if every worker is dropped.
+fn collect_responses<C, U>(
+    log: &mut Vec<String>,
+    stage: &str,
+    world: &C,
+    assignments: &mut Assignments,
+    cost: impl Fn(usize) -> usize,
+    tags: &[Tag],
+    resps: &mut [Option<U>],
+    subcircuit_idx: impl Fn(&U) -> usize,
+    send_redo: impl Fn(usize, &[usize]),
+) -> Result<(), String>
+where
+    C: Communicator,
+    U: CanonicalDeserialize,
+{
+    while !assignments.is_stage_done() {
+        if assignments.num_live() == 0 {
+            return Err(format!("every worker was dropped before {stage} was done"));
+        }
+        for (w, subcircuits) in assignments.reassign(Instant::now(), &cost) {
+            log_buf!(
+                log,
+                "Coord: Handing {stage} subcircuits {subcircuits:?} to worker {}",
+                w + 1
+            );
+            send_redo(w, &subcircuits);
+        }
+
+        let Some((bytes, status)) = probe_until(world, assignments.next_deadline()) else {
+            for w in assignments.expire(Instant::now()) {
+                log_buf!(
+                    log,
+                    "Coord: Worker {} missed its {stage} deadline. Dropping it",
+                    w + 1
+                );
+            }
+            continue;
+        };
+
+        // The coordinator is rank 0, so rank r is worker r - 1
+        let w = status.source_rank() as usize - 1;
+        let Some(subcircuits) = assignments.complete(w) else {
+            log_buf!(log, "Coord: Ignoring a late message from worker {}", w + 1);
+            continue;
+        };
+        match parse_batch_response(&bytes, status.tag(), tags, &subcircuits, &subcircuit_idx) {
+            Ok(items) => {
+                for (i, resp) in subcircuits.into_iter().zip(items) {
+                    resps[i] = Some(resp);
+                }
+            },
+            Err(e) => {
+                log_buf!(
+                    log,
+                    "Coord: Worker {} sent a malformed {stage} response: {e}. Dropping it",
+                    w + 1
+                );
+                for &i in &subcircuits {
+                    assignments.requeue(i);
+                }
+                assignments.drop_worker(w);
+            },
+        }
+    }
+
+    Ok(())
+}
+
+/// Deserializes a worker's response to a batch, and checks that it answers the subcircuits of the
+/// batch, in order
+fn parse_batch_response<U: CanonicalDeserialize>(
+    bytes: &[u8],
+    tag: Tag,
+    tags: &[Tag],
+    subcircuits: &[usize],
+    subcircuit_idx: impl Fn(&U) -> usize,
+) -> Result<Vec<U>, String> {
+    if !tags.contains(&tag) {
+        return Err(format!("unexpected tag {tag}"));
+    }
+    let msg =
+        ProofMessage::<U>::deserialize_uncompressed_unchecked(bytes).map_err(|e| e.to_string())?;
+    if msg.proof_id != SINGLE_PROOF_ID {
+        return Err(format!("it's for proof {}", msg.proof_id));
+    }
+    let idxs = msg.items.iter().map(subcircuit_idx).collect::<Vec<_>>();
+    if idxs != subcircuits {
+        return Err(format!(
+            "it answers subcircuits {idxs:?}, not {subcircuits:?}"
+        ));
+    }
+    Ok(msg.items)
+}
+
+/// Receives the next message from any worker. If there's a deadline, gives up once it passes.
+fn probe_until<C: Communicator>(world: &C, deadline: Option<Instant>) -> Option<(Vec<u8>, Status)> {
+    let source = world.any_process();
+    let (msg, _) = match deadline {
+        None => source.matched_probe(),
+        Some(deadline) => loop {
+            if let Some(found) = source.immediate_matched_probe() {
+                break found;
+            }
+            if Instant::now() >= deadline {
+                return None;
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        },
+    };
+    Some(msg.matched_receive_vec::<u8>())
+}
+
+/// Sends the given worker the requests of a batch of subcircuits. `request` makes the request of a
+/// subcircuit.
+fn send_batch<C: Communicator, T: CanonicalSerialize>(
+    world: &C,
+    worker: usize,
+    tag: Tag,
+    subcircuits: &[usize],
+    request: impl Fn(usize) -> T,
+) {
+    let items = subcircuits.iter().map(|&i| request(i)).collect();
+    let bytes = serialize_to_packed_vec(&ProofMessage {
+        proof_id: SINGLE_PROOF_ID,
+        items,
+    });
+    // The coordinator is rank 0, so worker w is rank w + 1
+    world
+        .process_at_rank(worker as Count + 1)
+        .send_with_tag(&bytes[..], tag);
+}
+
+/// Tells the given workers that there's no more work
+fn send_done<C: Communicator>(world: &C, workers: impl IntoIterator<Item = usize>) {
+    let done: [Packed; 0] = [];
+    for w in workers {
+        world
+            .process_at_rank(w as Count + 1)
+            .send_with_tag(&done[..], DONE_TAG);
+    }
 }
 
 #[cfg(feature = "parallel")]
@@ -632,7 +1994,6 @@ fn execute_in_pool_with_all_threads<T: Send>(f: impl FnOnce() -> T + Send) -> T
     f()
 }
 
-use distributed_prover::vkd::{MerkleTreeConcreteParameters, SparseMerkleTree};
 #[cfg(feature = "parallel")]
 use rayon::current_num_threads;
 
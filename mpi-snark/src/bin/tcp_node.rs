@@ -0,0 +1,474 @@
+use distributed_prover::{util::CircuitInstance, CircuitWithPortals};
+
+use ark_serialize::CanonicalDeserialize;
+use clap::{Parser, Subcommand};
+use mimalloc::MiMalloc;
+use mpi_snark::{
+    circuits::{with_circuit_of, RegisteredCircuit, WithCircuit},
+    coordinator::{CoordinatorState, MAX_INVALID_PROOFS},
+    data_structures::{Curve, ProvingKeys, Stage0Response, Stage1Response, SupportedCurve},
+    net::{
+        encode_round_task, encode_stage0_task, encode_stage1_task, read_frame, write_frame,
+        Dispatcher, Task, WorkerMsg, MAX_TASK_LEN,
+    },
+    with_curve,
+    worker::WorkerState,
+};
+
+use std::{
+    fs::File,
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::PathBuf,
+    thread::sleep,
+    time::Duration,
+};
+
+#[global_allocator]
+static GLOBAL: MiMalloc = MiMalloc;
+
+macro_rules! start_timer {
+    ($msg:expr) => {{
+        use std::time::Instant;
+
+        let msg = $msg();
+        let start_info = "Start:";
+
+        println!("{:8} {}", start_info, msg);
+        (msg.to_string(), Instant::now())
+    }};
+}
+
+macro_rules! end_timer {
+    ($time:expr) => {{
+        let time = $time.1;
+        let final_time = time.elapsed();
+
+        let end_info = "End:";
+        let message = format!("{}", $time.0);
+
+        println!("{:8} {} {}μs", end_info, message, final_time.as_micros());
+    }};
+}
+
+/// How many times a worker tries to connect before giving up, and how long it waits in between
+const NUM_CONNECT_ATTEMPTS: usize = 30;
+const CONNECT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Parser)]
+struct Args {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Runs the coordinator. It waits for workers to connect, hands them the subcircuits to
+    /// prove, and aggregates the results.
+    Coordinator {
+        /// Path to the coordinator key package
+        #[clap(long, value_name = "DIR")]
+        key_file: PathBuf,
+
+        /// The address to listen for workers on
+        #[clap(long, value_name = "ADDR")]
+        listen: SocketAddr,
+
+        /// Path to the circuit instance to prove. If omitted, a random instance is proven.
+        #[clap(long, value_name = "FILE")]
+        instance: Option<PathBuf>,
+
+        /// If a worker takes longer than this to do a subcircuit, give it to another worker too.
+        /// If omitted, subcircuits are only reassigned when their worker disconnects.
+        #[clap(long, value_name = "SECS")]
+        task_timeout: Option<u64>,
+
+        /// Directory to keep the subtraces and witnesses of the circuit in. If omitted, they're
+        /// kept in memory.
+        #[clap(long, value_name = "DIR")]
+        store_dir: Option<PathBuf>,
+
+        /// Checks every stage1 proof before aggregating. A worker that sends an invalid proof is
+        /// reported and hung up on, and its subcircuit is given to another worker. The run fails
+        /// if no other worker is left, or if a subcircuit gets 3 invalid proofs.
+        #[clap(long)]
+        verify_proofs: bool,
+
+        /// Path to write the proof to. The key that verifies it is written to the same path with
+        /// `.vk` appended.
+        #[clap(long, value_name = "FILE")]
+        proof_out: Option<PathBuf>,
+    },
+
+    /// Runs a worker. It proves subcircuits for the coordinator until there are none left.
+    Worker {
+        /// Path to the coordinator key package
+        #[clap(long, value_name = "DIR")]
+        key_file: PathBuf,
+
+        /// The address of the coordinator
+        #[clap(long, value_name = "ADDR")]
+        connect: SocketAddr,
+    },
+}
+
+fn main() {
+    let command = Args::parse().command;
+    let key_file = match &command {
+        Command::Coordinator { key_file, .. } | Command::Worker { key_file, .. } => key_file,
+    };
+    let open_key_file =
+        || File::open(key_file).unwrap_or_else(|_| panic!("couldn't open file {key_file:?}"));
+
+    // The keys say which curve they're over. Deserialize them over that curve
+    let curve = Curve::read_from_key_file(open_key_file()).unwrap();
+    let proving_keys_file = open_key_file();
+    with_curve!(curve, run_over_curve(command, proving_keys_file));
+}
+
+fn run_over_curve<E: SupportedCurve>(command: Command, mut proving_keys_file: File) {
+    // Deserialize the proving keys
+    let proving_keys =
+        ProvingKeys::<E>::deserialize_uncompressed_unchecked(&mut proving_keys_file).unwrap();
+
+    with_circuit_of(proving_keys, Run(command));
+}
+
+/// Runs the command with the circuit the proving keys are for
+struct Run(Command);
+
+impl<E: SupportedCurve> WithCircuit<E> for Run {
+    type Output = ();
+
+    fn call<C: RegisteredCircuit>(self, proving_keys: ProvingKeys<E>) {
+        run::<E, C::Circuit<E::ScalarField>>(self.0, &proving_keys);
+    }
+}
+
+fn run<E: SupportedCurve, P: CircuitWithPortals<E::ScalarField>>(
+    command: Command,
+    proving_keys: &ProvingKeys<E>,
+) {
+    match command {
+        Command::Coordinator {
+            listen,
+            instance,
+            task_timeout,
+            store_dir,
+            verify_proofs,
+            proof_out,
+            ..
+        } => coordinate::<E, P>(
+            proving_keys,
+            listen,
+            instance,
+            task_timeout.map(Duration::from_secs),
+            store_dir,
+            verify_proofs,
+            proof_out,
+        ),
+        Command::Worker { connect, .. } => work::<E, P>(proving_keys, connect),
+    }
+}
+
+fn coordinate<E: SupportedCurve, P: CircuitWithPortals<E::ScalarField>>(
+    proving_keys: &ProvingKeys<E>,
+    listen: SocketAddr,
+    instance_path: Option<PathBuf>,
+    task_timeout: Option<Duration>,
+    store_dir: Option<PathBuf>,
+    verify_proofs: bool,
+    proof_out: Option<PathBuf>,
+) {
+    let listener = TcpListener::bind(listen).expect("couldn't bind listener");
+    println!(
+        "Listening for workers on {}",
+        listener.local_addr().unwrap()
+    );
+    let dispatcher = Dispatcher::<E>::new(task_timeout);
+    dispatcher.listen(listener);
+
+    let very_start = start_timer!(|| "Beginning work");
+
+    let start = start_timer!(|| "Construct coordinator state");
+    let mut coordinator_state = match store_dir {
+        Some(dir) => CoordinatorState::<E, P>::with_store_dir(proving_keys, dir),
+        None => CoordinatorState::<E, P>::new(proving_keys),
+    };
+    end_timer!(start);
+
+    let start = start_timer!(|| "Generating stage0 requests");
+    let circ = match &instance_path {
+        Some(path) => {
+            let mut f = File::open(path).unwrap_or_else(|_| panic!("couldn't open file {path:?}"));
+            let instance = CircuitInstance::deserialize_uncompressed_unchecked(&mut f).unwrap();
+            coordinator_state.circuit_from_instance(&instance)
+        },
+        None => coordinator_state.rand_circuit(),
+    };
+    coordinator_state.stage_0(circ);
+    let stage0_tasks = coordinator_state
+        .stage0_requests()
+        .map(|req| encode_stage0_task(&req))
+        .collect::<Vec<_>>();
+    end_timer!(start);
+
+    let start = start_timer!(|| "Collecting stage0 responses");
+    let mut stage0_resps = dispatcher
+        .run(stage0_tasks)
+        .into_iter()
+        .map(|resp| resp.into_stage0().unwrap())
+        .collect::<Vec<_>>();
+    end_timer!(start);
+
+    let start = start_timer!(|| "Processing stage0 responses");
+    coordinator_state.stage_1(&stage0_resps);
+    end_timer!(start);
+
+    let mut stage1_resps = prove_after_stage0(&dispatcher, &mut coordinator_state, &stage0_resps);
+    if verify_proofs {
+        let start = start_timer!(|| "Verifying stage1 proofs");
+        redo_invalid_stage1_proofs(
+            &dispatcher,
+            &mut coordinator_state,
+            &mut stage0_resps,
+            &mut stage1_resps,
+        )
+        .unwrap_or_else(|e| panic!("{e}"));
+        end_timer!(start);
+    }
+    let stage1_resps = stage1_resps
+        .into_iter()
+        .map(|(_, resp)| resp)
+        .collect::<Vec<_>>();
+
+    let start = start_timer!(|| "Aggregating");
+    let proof = coordinator_state.aggregate(&stage1_resps);
+    end_timer!(start);
+
+    if let Some(path) = proof_out {
+        coordinator_state
+            .write_proof(&path, &proof)
+            .unwrap_or_else(|e| panic!("couldn't write the proof to {path:?}: {e}"));
+    }
+
+    end_timer!(very_start);
+
+    dispatcher.shutdown();
+}
+
+/// Runs the extra rounds the circuit has left, then stage 1, and returns the stage1 responses in
+/// order of subcircuit, each with the ID of the worker that sent it
+fn prove_after_stage0<E: SupportedCurve, P: CircuitWithPortals<E::ScalarField>>(
+    dispatcher: &Dispatcher<E>,
+    coordinator_state: &mut CoordinatorState<E, P>,
+    stage0_resps: &[Stage0Response<E>],
+) -> Vec<(usize, Stage1Response<E>)> {
+    // Like a stage1 task, a round task carries everything a worker needs to recompute the
+    // subcircuit's commitments so far
+    while coordinator_state.num_rounds_left() > 0 {
+        let start = start_timer!(|| "Collecting round responses");
+        let round_tasks = stage0_resps
+            .iter()
+            .enumerate()
+            .map(|(i, resp0)| {
+                encode_round_task(
+                    &coordinator_state.stage0_request(i),
+                    resp0,
+                    &coordinator_state.subcircuit_round_resps(i),
+                    &coordinator_state.round_request(i),
+                )
+            })
+            .collect::<Vec<_>>();
+        let round_resps = dispatcher
+            .run(round_tasks)
+            .into_iter()
+            .map(|resp| resp.into_round().unwrap())
+            .collect::<Vec<_>>();
+        coordinator_state.process_round(&round_resps);
+        end_timer!(start);
+    }
+
+    let start = start_timer!(|| "Generating stage1 requests");
+    let stage1_tasks = (0..stage0_resps.len())
+        .map(|i| (i, stage1_task(coordinator_state, stage0_resps, i)))
+        .collect::<Vec<_>>();
+    end_timer!(start);
+
+    let start = start_timer!(|| "Collecting stage1 responses");
+    let stage1_resps = dispatcher
+        .run_some(stage1_tasks)
+        .into_iter()
+        .map(|(worker_id, resp)| (worker_id, resp.into_stage1().unwrap()))
+        .collect();
+    end_timer!(start);
+    stage1_resps
+}
+
+/// Encodes the stage1 task of the given subcircuit. It carries the stage0 request and response of
+/// the subcircuit, and its round responses, so any worker can recompute the commitments.
+fn stage1_task<E: SupportedCurve, P: CircuitWithPortals<E::ScalarField>>(
+    coordinator_state: &CoordinatorState<E, P>,
+    stage0_resps: &[Stage0Response<E>],
+    subcircuit_idx: usize,
+) -> Vec<u8> {
+    encode_stage1_task(
+        &coordinator_state.stage0_request(subcircuit_idx),
+        &stage0_resps[subcircuit_idx],
+        &coordinator_state.subcircuit_round_resps(subcircuit_idx),
+        &coordinator_state.stage1_request(subcircuit_idx),
+    )
+}
+
+/// Checks every stage1 proof, and has other workers redo the subcircuits whose proofs are invalid.
+/// The worker that sent an invalid proof is hung up on. If a proof isn't made wrt the stage0
+/// commitment of its subcircuit, the stage0 response can't be trusted either, so stage 0 of that
+/// subcircuit is redone too, and `stage0_resps` is updated. The new commitment changes the
+/// challenges, so every subcircuit's extra rounds and stage 1 are then redone. Fails once a
+/// subcircuit has had `MAX_INVALID_PROOFS` invalid proofs, or if no worker is left to redo them.
+fn redo_invalid_stage1_proofs<E: SupportedCurve, P: CircuitWithPortals<E::ScalarField>>(
+    dispatcher: &Dispatcher<E>,
+    coordinator_state: &mut CoordinatorState<E, P>,
+    stage0_resps: &mut Vec<Stage0Response<E>>,
+    stage1_resps: &mut Vec<(usize, Stage1Response<E>)>,
+) -> Result<(), String> {
+    let num_subcircuits = stage1_resps.len();
+    let mut num_invalid = vec![0; num_subcircuits];
+
+    // Only the new proofs need checking each time around
+    let mut unchecked = (0..num_subcircuits).collect::<Vec<_>>();
+    loop {
+        let invalid = unchecked
+            .into_iter()
+            .filter(|&i| !coordinator_state.verify_stage1_response(&stage1_resps[i].1))
+            .collect::<Vec<_>>();
+        if invalid.is_empty() {
+            break;
+        }
+
+        let mut stage0_redos = Vec::new();
+        for &i in &invalid {
+            let (worker_id, resp) = &stage1_resps[i];
+            num_invalid[i] += 1;
+            if num_invalid[i] == MAX_INVALID_PROOFS {
+                return Err(format!(
+                    "subcircuit {i} has had {MAX_INVALID_PROOFS} invalid proofs, the last from \
+                     worker {worker_id}. Giving up"
+                ));
+            }
+            dispatcher.drop_worker(*worker_id);
+
+            if coordinator_state.stage1_commitments_match(resp) {
+                println!(
+                    "Worker {worker_id} sent an invalid proof for subcircuit {i}. Reassigning it"
+                );
+            } else {
+                println!(
+                    "Worker {worker_id} sent a proof for subcircuit {i} with the wrong \
+                     commitments. Redoing its stage 0"
+                );
+                stage0_redos.push(i);
+            }
+        }
+        // New workers may still connect, but waiting for them could take forever
+        if dispatcher.num_workers() == 0 {
+            return Err(format!(
+                "no worker is left to redo the subcircuits {invalid:?}, whose proofs were invalid"
+            ));
+        }
+
+        if stage0_redos.is_empty() {
+            // The commitments are recomputed from the seeds in the stage0 responses
+            let tasks = invalid
+                .iter()
+                .map(|&i| (i, stage1_task(coordinator_state, stage0_resps, i)))
+                .collect();
+            for (&i, (worker_id, resp)) in invalid.iter().zip(dispatcher.run_some(tasks)) {
+                stage1_resps[i] = (worker_id, resp.into_stage1().unwrap());
+            }
+            unchecked = invalid;
+        } else {
+            coordinator_state.redo_stage_0();
+            let tasks = stage0_redos
+                .iter()
+                .map(|&i| (i, encode_stage0_task(&coordinator_state.stage0_request(i))))
+                .collect();
+            for (&i, (_, resp)) in stage0_redos.iter().zip(dispatcher.run_some(tasks)) {
+                stage0_resps[i] = resp.into_stage0().unwrap();
+            }
+
+            // The challenges changed, so every proof has to be made again
+            let start = start_timer!(|| "Processing stage0 responses");
+            coordinator_state.stage_1(stage0_resps);
+            end_timer!(start);
+            *stage1_resps = prove_after_stage0(dispatcher, coordinator_state, stage0_resps);
+            unchecked = (0..num_subcircuits).collect();
+        }
+    }
+
+    Ok(())
+}
+
+fn work<E: SupportedCurve, P: CircuitWithPortals<E::ScalarField>>(
+    proving_keys: &ProvingKeys<E>,
+    coordinator_addr: SocketAddr,
+) {
+    let mut rng = rand::thread_rng();
+    let num_subcircuits = proving_keys.num_subcircuits();
+
+    // The coordinator might not be up yet
+    let mut stream = (0..NUM_CONNECT_ATTEMPTS)
+        .find_map(|_| {
+            TcpStream::connect(coordinator_addr)
+                .map_err(|_| sleep(CONNECT_RETRY_DELAY))
+                .ok()
+        })
+        .unwrap_or_else(|| panic!("couldn't connect to the coordinator at {coordinator_addr}"));
+    write_frame(&mut stream, &WorkerMsg::<E>::Ready.encode()).unwrap();
+
+    loop {
+        let task =
+            read_frame(&mut stream, MAX_TASK_LEN).expect("lost connection to the coordinator");
+        let resp = match Task::<E, P>::decode(&task).expect("malformed task") {
+            Task::Stage0(req) => {
+                let start =
+                    start_timer!(|| format!("Processing stage0 request #{}", req.subcircuit_idx));
+                let resp = WorkerState::<E, P>::new(num_subcircuits, proving_keys)
+                    .stage_0(&mut rng, &req.to_ref());
+                end_timer!(start);
+                WorkerMsg::Stage0(resp)
+            },
+            Task::Round(req0, resp0, earlier_resps, req) => {
+                let start = start_timer!(|| format!(
+                    "Processing round {} request #{}",
+                    req.round, req.subcircuit_idx
+                ));
+                let resp = WorkerState::<E, P>::new(num_subcircuits, proving_keys).round_from_seed(
+                    &mut rng,
+                    &req0.to_ref(),
+                    &resp0,
+                    &earlier_resps,
+                    &req,
+                );
+                end_timer!(start);
+                WorkerMsg::Round(resp)
+            },
+            Task::Stage1(req0, resp0, round_resps, req1) => {
+                let start =
+                    start_timer!(|| format!("Processing stage1 request #{}", req0.subcircuit_idx));
+                let resp = WorkerState::<E, P>::new(num_subcircuits, proving_keys)
+                    .stage_1_from_seed(
+                        &mut rng,
+                        &req0.to_ref(),
+                        &resp0,
+                        &round_resps,
+                        &req1.to_ref(),
+                    );
+                end_timer!(start);
+                WorkerMsg::Stage1(resp)
+            },
+            Task::Done => break,
+        };
+
+        write_frame(&mut stream, &resp.encode()).expect("lost connection to the coordinator");
+    }
+}
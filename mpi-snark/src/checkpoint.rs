@@ -0,0 +1,228 @@
+use crate::data_structures::{
+    CoordinatorStage0State, CoordinatorStage1State, Curve, ProvingKeys, RoundResponse,
+    Stage0Response, Stage1Response, SupportedCurve,
+};
+
+use distributed_prover::{aggregation::AggProvingKey, CircuitWithPortals};
+
+use ark_ec::pairing::Pairing;
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter},
+    path::{Path, PathBuf},
+};
+
+const META_FILENAME: &str = "checkpoint_meta.bin";
+const AGG_KEY_FILENAME: &str = "agg_key.bin";
+const STAGE0_STATE_FILENAME: &str = "stage0_coordinator_state.bin";
+const STAGE1_STATE_FILENAME: &str = "stage1_coordinator_state.bin";
+const STAGE0_RESP_FILENAME_PREFIX: &str = "stage0_resp";
+const STAGE1_RESP_FILENAME_PREFIX: &str = "stage1_resp";
+const ROUND_RESP_FILENAME_PREFIX: &str = "round_resp";
+const SUBCIRCUIT_STORE_DIRNAME: &str = "subcircuits";
+
+/// What a resumed run needs to know about the run that made the checkpoint
+#[derive(CanonicalSerialize, CanonicalDeserialize)]
+struct CheckpointMeta {
+    /// The curve of the proving keys the run used
+    curve: Curve,
+    /// The circuit params of the proving keys the run used
+    serialized_circ_params: Vec<u8>,
+}
+
+/// A directory holding the progress of a proof: the coordinator state after each stage and extra
+/// round, and every response received so far. Everything is written as soon as it's known, so a
+/// crashed run can be resumed without redoing any finished work.
+#[derive(Clone)]
+pub struct Checkpoint {
+    dir: PathBuf,
+}
+
+impl Checkpoint {
+    /// Starts a new checkpoint in `dir` for a proof with the given proving keys. The directory is
+    /// created if it doesn't exist. Fails if it already holds a checkpoint.
+    pub fn create<E: SupportedCurve>(dir: &Path, pks: &ProvingKeys<E>) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        if dir.join(META_FILENAME).exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{dir:?} already holds a checkpoint, resume it instead"),
+            ));
+        }
+
+        let meta = CheckpointMeta {
+            curve: pks.curve(),
+            serialized_circ_params: pks.serialized_circ_params.clone(),
+        };
+        let checkpoint = Checkpoint {
+            dir: dir.to_path_buf(),
+        };
+        checkpoint.write(META_FILENAME, &meta)?;
+
+        Ok(checkpoint)
+    }
+
+    /// Opens the checkpoint in `dir`. Fails if it was made for different proving keys.
+    pub fn open<E: SupportedCurve>(dir: &Path, pks: &ProvingKeys<E>) -> io::Result<Self> {
+        let checkpoint = Checkpoint {
+            dir: dir.to_path_buf(),
+        };
+        let meta: CheckpointMeta = checkpoint.read(META_FILENAME)?;
+        if meta.curve != pks.curve() || meta.serialized_circ_params != pks.serialized_circ_params {
+            return Err(invalid_data(format!(
+                "the checkpoint in {dir:?} was made with different proving keys"
+            )));
+        }
+
+        Ok(checkpoint)
+    }
+
+    pub fn has_agg_key(&self) -> bool {
+        self.dir.join(AGG_KEY_FILENAME).exists()
+    }
+
+    /// Saves the aggregation key. The stage1 requests depend on the key, so a resumed run has to
+    /// use the same one. Only the key itself is saved, never the randomness it was made from.
+    pub fn save_agg_key<E: Pairing>(&self, agg_pk: &AggProvingKey<E>) -> io::Result<()> {
+        self.write(AGG_KEY_FILENAME, agg_pk)
+    }
+
+    pub fn load_agg_key<'a, E: Pairing>(&self) -> io::Result<AggProvingKey<'a, E>> {
+        self.read(AGG_KEY_FILENAME)
+    }
+
+    /// The directory the coordinator keeps its subtraces and witnesses in. Since they're on disk,
+    /// the saved coordinator states only hold the path to them.
+    pub fn subcircuit_store_dir(&self) -> PathBuf {
+        self.dir.join(SUBCIRCUIT_STORE_DIRNAME)
+    }
+
+    pub fn has_stage0_state(&self) -> bool {
+        self.dir.join(STAGE0_STATE_FILENAME).exists()
+    }
+
+    pub fn has_stage1_state(&self) -> bool {
+        self.dir.join(STAGE1_STATE_FILENAME).exists()
+    }
+
+    pub fn save_stage0_state<E: Pairing, P: CircuitWithPortals<E::ScalarField>>(
+        &self,
+        state: &CoordinatorStage0State<E, P>,
+    ) -> io::Result<()> {
+        self.write(STAGE0_STATE_FILENAME, state)
+    }
+
+    pub fn load_stage0_state<E: Pairing, P: CircuitWithPortals<E::ScalarField>>(
+        &self,
+    ) -> io::Result<CoordinatorStage0State<E, P>> {
+        self.read(STAGE0_STATE_FILENAME)
+    }
+
+    pub fn save_stage1_state<E: SupportedCurve, P: CircuitWithPortals<E::ScalarField>>(
+        &self,
+        state: &CoordinatorStage1State<E, P>,
+    ) -> io::Result<()> {
+        self.write(STAGE1_STATE_FILENAME, state)
+    }
+
+    pub fn load_stage1_state<E: SupportedCurve, P: CircuitWithPortals<E::ScalarField>>(
+        &self,
+    ) -> io::Result<CoordinatorStage1State<E, P>> {
+        self.read(STAGE1_STATE_FILENAME)
+    }
+
+    pub fn save_stage0_resp<E: Pairing>(&self, resp: &Stage0Response<E>) -> io::Result<()> {
+        let filename = format!("{STAGE0_RESP_FILENAME_PREFIX}_{}.bin", resp.subcircuit_idx);
+        self.write(&filename, resp)
+    }
+
+    /// Returns the stage0 response of every subcircuit, or `None` for the ones that haven't been
+    /// saved yet
+    pub fn load_stage0_resps<E: Pairing>(
+        &self,
+        num_subcircuits: usize,
+    ) -> io::Result<Vec<Option<Stage0Response<E>>>> {
+        self.read_all(STAGE0_RESP_FILENAME_PREFIX, num_subcircuits)
+    }
+
+    pub fn save_round_resp<E: Pairing>(
+        &self,
+        round: usize,
+        resp: &RoundResponse<E>,
+    ) -> io::Result<()> {
+        let filename = format!(
+            "{ROUND_RESP_FILENAME_PREFIX}{round}_{}.bin",
+            resp.subcircuit_idx
+        );
+        self.write(&filename, resp)
+    }
+
+    /// Returns the response of every subcircuit in the given extra round, or `None` for the ones
+    /// that haven't been saved yet
+    pub fn load_round_resps<E: Pairing>(
+        &self,
+        round: usize,
+        num_subcircuits: usize,
+    ) -> io::Result<Vec<Option<RoundResponse<E>>>> {
+        self.read_all(
+            &format!("{ROUND_RESP_FILENAME_PREFIX}{round}"),
+            num_subcircuits,
+        )
+    }
+
+    pub fn save_stage1_resp<E: Pairing>(&self, resp: &Stage1Response<E>) -> io::Result<()> {
+        let filename = format!("{STAGE1_RESP_FILENAME_PREFIX}_{}.bin", resp.subcircuit_idx);
+        self.write(&filename, resp)
+    }
+
+    /// Returns the stage1 response of every subcircuit, or `None` for the ones that haven't been
+    /// saved yet
+    pub fn load_stage1_resps<E: Pairing>(
+        &self,
+        num_subcircuits: usize,
+    ) -> io::Result<Vec<Option<Stage1Response<E>>>> {
+        self.read_all(STAGE1_RESP_FILENAME_PREFIX, num_subcircuits)
+    }
+
+    /// Writes the value to a temporary file and then renames it, so a crash never leaves a
+    /// half-written file behind
+    fn write(&self, filename: &str, val: &impl CanonicalSerialize) -> io::Result<()> {
+        let path = self.dir.join(filename);
+        let tmp_path = self.dir.join(format!("{filename}.tmp"));
+
+        let mut f = BufWriter::new(File::create(&tmp_path)?);
+        val.serialize_uncompressed(&mut f)
+            .map_err(|e| invalid_data(format!("couldn't serialize {filename}: {e}")))?;
+        f.into_inner()?.sync_all()?;
+
+        fs::rename(tmp_path, path)
+    }
+
+    fn read<T: CanonicalDeserialize>(&self, filename: &str) -> io::Result<T> {
+        let f = BufReader::new(File::open(self.dir.join(filename))?);
+        T::deserialize_uncompressed_unchecked(f)
+            .map_err(|e| invalid_data(format!("couldn't deserialize {filename}: {e}")))
+    }
+
+    fn read_all<T: CanonicalDeserialize>(
+        &self,
+        filename_prefix: &str,
+        num_items: usize,
+    ) -> io::Result<Vec<Option<T>>> {
+        (0..num_items)
+            .map(|i| {
+                let filename = format!("{filename_prefix}_{i}.bin");
+                if self.dir.join(&filename).exists() {
+                    self.read(&filename).map(Some)
+                } else {
+                    Ok(None)
+                }
+            })
+            .collect()
+    }
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
@@ -0,0 +1,93 @@
+use crate::{
+    data_structures::{Curve, ProvingKeys, SupportedCurve},
+    with_curve,
+};
+
+pub use distributed_prover::circuits::RegisteredCircuit;
+use distributed_prover::circuits::{with_registered_circuit, WithRegisteredCircuit, WithSetupArgs};
+
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+use clap::Args;
+use std::{fs::File, io::Write, path::PathBuf};
+
+/// Something to do with proving keys, given the circuit they're for
+pub trait WithCircuit<E: SupportedCurve> {
+    type Output;
+
+    fn call<C: RegisteredCircuit>(self, proving_keys: ProvingKeys<E>) -> Self::Output;
+}
+
+/// Prints the info of the circuit the proving keys are for, and then calls `f` with that circuit.
+/// Panics if the keys are for a circuit that isn't registered.
+pub fn with_circuit_of<E: SupportedCurve, W: WithCircuit<E>>(
+    proving_keys: ProvingKeys<E>,
+    f: W,
+) -> W::Output {
+    struct Call<E: SupportedCurve, W: WithCircuit<E>> {
+        proving_keys: ProvingKeys<E>,
+        f: W,
+    }
+
+    impl<E: SupportedCurve, W: WithCircuit<E>> WithRegisteredCircuit for Call<E, W> {
+        type Output = W::Output;
+
+        fn call<C: RegisteredCircuit>(self) -> W::Output {
+            let params = C::Params::deserialize_uncompressed_unchecked(
+                self.proving_keys.serialized_circ_params.as_slice(),
+            )
+            .unwrap();
+            C::print_info(&params, self.proving_keys.num_subcircuits());
+
+            self.f.call::<C>(self.proving_keys)
+        }
+    }
+
+    let circ_id = proving_keys.get_id_str().to_string();
+    with_registered_circuit(&circ_id, Call { proving_keys, f })
+}
+
+/// The CLI args that every setup subcommand has
+#[derive(Args)]
+pub struct SetupArgs {
+    /// The curve to make the keys over
+    #[clap(long, value_enum, default_value_t = Curve::Bls12_381)]
+    curve: Curve,
+
+    /// Path for the output coordinator key package
+    #[clap(long, value_name = "DIR")]
+    key_out: PathBuf,
+}
+
+/// The setup subcommands of all the registered circuits. Flatten this into a binary's subcommands
+/// to give it a `setup-*` subcommand for every circuit, and run it with [`run_setup`].
+pub type SetupCommand = distributed_prover::circuits::SetupCommand<SetupArgs>;
+
+/// Makes the proving keys of the setup subcommand's circuit and writes them to the key file
+pub fn run_setup(cmd: SetupCommand) {
+    struct Setup;
+
+    impl WithSetupArgs<SetupArgs> for Setup {
+        type Output = ();
+
+        fn call<C: RegisteredCircuit>(self, setup_args: SetupArgs, args: C::Args) {
+            let SetupArgs { curve, key_out } = setup_args;
+            with_curve!(curve, setup::<C>(args, key_out));
+        }
+    }
+
+    cmd.run(Setup)
+}
+
+/// Makes the proving keys of the circuit `C` over `E`, and writes them to `key_out_path`
+fn setup<C: RegisteredCircuit, E: SupportedCurve>(args: C::Args, key_out_path: PathBuf) {
+    let circ_params = C::params_from_args::<E::ScalarField>(args);
+    let pks = ProvingKeys::<E>::new::<C::Circuit<E::ScalarField>>(circ_params, C::ID.to_string());
+    println!("Number of subcircuits: {}", pks.num_subcircuits());
+
+    let mut buf = Vec::new();
+    pks.serialize_uncompressed(&mut buf).unwrap();
+
+    let mut f = File::create(&key_out_path)
+        .unwrap_or_else(|_| panic!("could not create file {key_out_path:?}"));
+    f.write_all(&buf).unwrap();
+}
@@ -1,30 +1,153 @@
-use crate::data_structures::{
-    AggProof, ProvingKeys, Stage0RequestRef, Stage0Response, Stage1RequestRef, Stage1Response,
+use crate::{
+    checkpoint::Checkpoint,
+    data_structures::{
+        AggProof, BatchAggProof, CoordinatorStage0State, CoordinatorStage1State,
+        G16PreparedVerifyingKey, GroupAggRequestRef, GroupAggResponse, ProvingKeys, RoundRequest,
+        RoundResponse, Stage0RequestRef, Stage0Response, Stage1RequestRef, Stage1Response,
+        SupportedCurve, TreeConfig,
+    },
 };
 
 use distributed_prover::{
-    aggregation::AggProvingKey,
-    coordinator::{CoordinatorStage0State, CoordinatorStage1State},
-    poseidon_util::{gen_merkle_params, PoseidonTreeConfig as TreeConfig},
+    aggregation::{
+        tipp_size, AggProvingKey, AggVerifyingKey, GroupedAggProvingKey, GroupedAggVerifyingKey,
+        SuperComKey,
+    },
+    coordinator::FinalAggState,
+    poseidon_util::gen_merkle_params,
+    util::CircuitInstance,
     CircuitWithPortals,
 };
 
-use ark_bls12_381::{Bls12_381 as E, Fr};
 use ark_ip_proofs::tipa::TIPA;
-use ark_serialize::CanonicalDeserialize;
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
 use ark_std::{end_timer, start_timer};
-use rand::thread_rng;
+use rand::{thread_rng, Rng};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, BufWriter},
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::OnceLock,
+};
+
+/// How many invalid proofs of a subcircuit a coordinator that checks stage1 proofs takes before
+/// giving up. Several workers failing the same subcircuit points at the circuit or the keys, not
+/// the workers.
+pub const MAX_INVALID_PROOFS: usize = 3;
 
-pub struct CoordinatorState<'a, P: CircuitWithPortals<Fr>> {
-    g16_pks: &'a ProvingKeys,
-    agg_pk: AggProvingKey<'a, E>,
+pub struct CoordinatorState<'a, E: SupportedCurve, P: CircuitWithPortals<E::ScalarField>> {
+    g16_pks: &'a ProvingKeys<E>,
+    /// The prepared verifying keys of the representative subcircuits. These are only made if a
+    /// stage1 response is verified.
+    g16_pvks: OnceLock<BTreeMap<usize, G16PreparedVerifyingKey<E>>>,
+    /// The aggregation key. This is shared by the states made with `fork`
+    agg_pk: Rc<AggKey<'a, E>>,
     circ_params: P::Parameters,
     stage0_state: Option<CoordinatorStage0State<E, P>>,
-    stage1_state: Option<CoordinatorStage1State<TreeConfig, E, P>>,
+    stage1_state: Option<CoordinatorStage1State<E, P>>,
+    /// What the groups are aggregated from, once stage 1 is done. Only a state with a grouped
+    /// aggregation key has this.
+    agg_state: Option<FinalAggState<TreeConfig<E>, E>>,
+    /// The responses of each extra round done so far, by subcircuit. A worker needs them to
+    /// recompute the round commitments from their seeds.
+    round_resps: Vec<Vec<RoundResponse<E>>>,
+    /// Where to save the coordinator state after each stage, if anywhere
+    checkpoint: Option<Checkpoint>,
+    /// Where to keep the subtraces and witnesses of the circuit being proven. If `None`, they're
+    /// kept in memory.
+    store_dir: Option<PathBuf>,
+}
+
+/// The key that aggregates the subcircuit proofs, either all at once, or in groups that are
+/// aggregated separately and then combined
+enum AggKey<'a, E: SupportedCurve> {
+    Single(AggProvingKey<'a, E>),
+    Grouped(GroupedAggProvingKey<'a, E>),
 }
 
-impl<'a, P: CircuitWithPortals<Fr>> CoordinatorState<'a, P> {
-    pub fn new(g16_pks: &'a ProvingKeys) -> CoordinatorState<'a, P> {
+impl<'a, E: SupportedCurve> AggKey<'a, E> {
+    /// The key the super-commitments are made under
+    fn super_com_key(&self) -> &dyn SuperComKey<E> {
+        match self {
+            AggKey::Single(agg_pk) => &agg_pk.tipp_pk,
+            AggKey::Grouped(agg_pk) => agg_pk,
+        }
+    }
+
+    /// The key that aggregates every proof at once. Panics if the proofs are aggregated in groups.
+    fn single(&self) -> &AggProvingKey<'a, E> {
+        match self {
+            AggKey::Single(agg_pk) => agg_pk,
+            AggKey::Grouped(_) => panic!("the proofs are aggregated in groups"),
+        }
+    }
+
+    /// The key that aggregates the proofs in groups. Panics if they're aggregated all at once.
+    fn grouped(&self) -> &GroupedAggProvingKey<'a, E> {
+        match self {
+            AggKey::Grouped(agg_pk) => agg_pk,
+            AggKey::Single(_) => panic!("the proofs aren't aggregated in groups"),
+        }
+    }
+}
+
+impl<'a, E: SupportedCurve, P: CircuitWithPortals<E::ScalarField>> CoordinatorState<'a, E, P> {
+    pub fn new(g16_pks: &'a ProvingKeys<E>) -> CoordinatorState<'a, E, P> {
+        let agg_pk = generate_agg_key(g16_pks, &mut thread_rng());
+        Self::with_agg_key(g16_pks, AggKey::Single(agg_pk))
+    }
+
+    /// Makes a coordinator state whose proofs are aggregated in groups of `group_size`
+    /// subcircuits, each under its own TIPP key. Once stage 1 is done, call
+    /// `start_group_aggregation`, have each group aggregated from its `group_agg_request`, e.g.,
+    /// by a worker, and combine the results with `combine_group_aggs`.
+    pub fn with_group_size(
+        g16_pks: &'a ProvingKeys<E>,
+        group_size: usize,
+    ) -> CoordinatorState<'a, E, P> {
+        let agg_pk = generate_grouped_agg_key(g16_pks, group_size, &mut thread_rng());
+        Self::with_agg_key(g16_pks, AggKey::Grouped(agg_pk))
+    }
+
+    /// Makes a coordinator state that saves its progress to the given checkpoint. If the
+    /// checkpoint is from an earlier run, use `resume_stage_0` and `resume_stage_1` to pick up
+    /// the saved states. The aggregation key is loaded from the checkpoint, or generated and
+    /// saved to it if this is a new checkpoint.
+    pub fn with_checkpoint(
+        g16_pks: &'a ProvingKeys<E>,
+        checkpoint: Checkpoint,
+    ) -> CoordinatorState<'a, E, P> {
+        let agg_pk = if checkpoint.has_agg_key() {
+            checkpoint
+                .load_agg_key()
+                .expect("couldn't load the aggregation key")
+        } else {
+            let agg_pk = generate_agg_key(g16_pks, &mut thread_rng());
+            checkpoint
+                .save_agg_key(&agg_pk)
+                .expect("couldn't save the aggregation key");
+            agg_pk
+        };
+        let mut state = Self::with_agg_key(g16_pks, AggKey::Single(agg_pk));
+        state.store_dir = Some(checkpoint.subcircuit_store_dir());
+        state.checkpoint = Some(checkpoint);
+        state
+    }
+
+    /// Makes a coordinator state that keeps the subtraces and witnesses of the circuit in files in
+    /// `dir` rather than in memory. Use this for circuits with many subcircuits.
+    pub fn with_store_dir(g16_pks: &'a ProvingKeys<E>, dir: PathBuf) -> CoordinatorState<'a, E, P> {
+        let mut state = Self::new(g16_pks);
+        state.store_dir = Some(dir);
+        state
+    }
+
+    fn with_agg_key(
+        g16_pks: &'a ProvingKeys<E>,
+        agg_pk: AggKey<'a, E>,
+    ) -> CoordinatorState<'a, E, P> {
         let circ_params = P::Parameters::deserialize_uncompressed_unchecked(
             g16_pks.serialized_circ_params.as_slice(),
         )
@@ -32,54 +155,459 @@ impl<'a, P: CircuitWithPortals<Fr>> CoordinatorState<'a, P> {
 
         CoordinatorState {
             circ_params,
-            agg_pk: generate_agg_key(&g16_pks),
+            agg_pk: Rc::new(agg_pk),
             g16_pks,
+            g16_pvks: OnceLock::new(),
             stage0_state: None,
             stage1_state: None,
+            agg_state: None,
+            round_resps: Vec::new(),
+            checkpoint: None,
+            store_dir: None,
         }
     }
 
-    pub fn get_pks(&self) -> &ProvingKeys {
+    /// Makes a fresh state for another proof of the same circuit, e.g., so that the stages of
+    /// several proofs can be in flight at once. The aggregation key is shared rather than
+    /// generated again. The new state has no checkpoint, and keeps its subcircuits in memory.
+    pub fn fork(&self) -> CoordinatorState<'a, E, P> {
+        CoordinatorState {
+            circ_params: self.circ_params.clone(),
+            agg_pk: self.agg_pk.clone(),
+            g16_pks: self.g16_pks,
+            g16_pvks: OnceLock::new(),
+            stage0_state: None,
+            stage1_state: None,
+            agg_state: None,
+            round_resps: Vec::new(),
+            checkpoint: None,
+            store_dir: None,
+        }
+    }
+
+    /// Makes the states of a batch of `num_instances` instances of the circuit, which are proven
+    /// together into a single `BatchAggProof`. They share an aggregation key made with
+    /// `AggProvingKey::new_batch`. Start each instance with `stage_0`, then go through the rest of
+    /// the stages with `stage_1_batch`, `process_round_batch`, and `aggregate_batch`. With a store
+    /// directory, instance k keeps its subcircuits in the subdirectory `instance{k}` of it.
+    pub fn new_batch(
+        g16_pks: &'a ProvingKeys<E>,
+        num_instances: usize,
+        store_dir: Option<PathBuf>,
+    ) -> Vec<CoordinatorState<'a, E, P>> {
+        let agg_pk = generate_batch_agg_key(g16_pks, num_instances, &mut thread_rng());
+        let base_state = Self::with_agg_key(g16_pks, AggKey::Single(agg_pk));
+        (0..num_instances)
+            .map(|k| match &store_dir {
+                Some(dir) => base_state.fork_with_store_dir(dir.join(format!("instance{k}"))),
+                None => base_state.fork(),
+            })
+            .collect()
+    }
+
+    /// Like `fork`, but the new state keeps its subcircuits in files in `dir`, like a state made
+    /// with `with_store_dir`
+    pub fn fork_with_store_dir(&self, dir: PathBuf) -> CoordinatorState<'a, E, P> {
+        let mut state = self.fork();
+        state.store_dir = Some(dir);
+        state
+    }
+
+    pub fn get_pks(&self) -> &ProvingKeys<E> {
         &self.g16_pks
     }
 
-    pub fn stage_0(&mut self) -> Vec<Stage0RequestRef> {
-        let mut rng = thread_rng();
+    /// Makes a random instance of the circuit the proving keys are for
+    pub fn rand_circuit(&self) -> P {
+        P::rand(&mut thread_rng(), &self.circ_params)
+    }
+
+    /// Rebuilds the given instance. Panics if it's not an instance of the circuit the proving keys
+    /// are for.
+    pub fn circuit_from_instance(&self, instance: &CircuitInstance) -> P {
+        assert!(
+            instance.serialized_circ_params == self.g16_pks.serialized_circ_params,
+            "the instance's circuit params don't match the proving keys"
+        );
+        instance.to_circuit()
+    }
 
-        let circ = P::rand(&mut rng, &self.circ_params);
-        let num_subcircuits = self.g16_pks.num_subcircuits();
+    /// Starts proving the given circuit. Use `rand_circuit` or `circuit_from_instance` to get one.
+    /// The requests are made one at a time by `stage0_requests`.
+    pub fn stage_0(&mut self, circ: P) {
+        assert_eq!(
+            circ.num_subcircuits(),
+            self.g16_pks.num_subcircuits(),
+            "the circuit doesn't match the proving keys"
+        );
 
-        self.stage0_state = Some(CoordinatorStage0State::new::<TreeConfig>(circ));
-        (0..num_subcircuits)
-            .map(|idx| self.stage0_state.as_ref().unwrap().gen_request(idx))
-            .collect::<Vec<_>>()
+        let stage0_state = match &self.store_dir {
+            Some(dir) => CoordinatorStage0State::new_on_disk::<TreeConfig<E>>(circ, dir)
+                .expect("couldn't write the subcircuit store"),
+            None => CoordinatorStage0State::new::<TreeConfig<E>>(circ),
+        };
+        if let Some(checkpoint) = &self.checkpoint {
+            checkpoint
+                .save_stage0_state(&stage0_state)
+                .expect("couldn't checkpoint the stage0 state");
+        }
+        self.stage0_state = Some(stage0_state);
+    }
+
+    /// Loads the stage0 state from the checkpoint, so that `stage0_requests` makes the stage0
+    /// requests again. Panics if there is no saved stage0 state.
+    pub fn resume_stage_0(&mut self) {
+        let checkpoint = self
+            .checkpoint
+            .as_ref()
+            .expect("no checkpoint to resume from");
+        self.stage0_state = Some(
+            checkpoint
+                .load_stage0_state()
+                .expect("couldn't load the stage0 state"),
+        );
+    }
+
+    /// Makes the stage0 requests, in order of subcircuit. Requests are made lazily, so they can be
+    /// sent and dropped one at a time.
+    pub fn stage0_requests(&self) -> impl Iterator<Item = Stage0RequestRef<E>> + '_ {
+        (0..self.g16_pks.num_subcircuits()).map(|idx| self.stage0_request(idx))
+    }
+
+    /// Makes the stage0 request of the given subcircuit. This also works in stage 1, where a
+    /// worker needs the stage0 request again to recompute its commitment.
+    pub fn stage0_request(&self, subcircuit_idx: usize) -> Stage0RequestRef<E> {
+        match (&self.stage0_state, &self.stage1_state) {
+            (Some(stage0_state), _) => stage0_state.gen_request(subcircuit_idx),
+            (None, Some(stage1_state)) => stage1_state.gen_stage0_request(subcircuit_idx),
+            (None, None) => panic!("stage 0 hasn't started"),
+        }
     }
 
-    pub fn stage_1(&mut self, stage0_resps: &[Stage0Response]) -> Vec<Stage1RequestRef<P>> {
+    /// Processes the stage0 responses. If the circuit has extra rounds, their requests are then
+    /// made by `round_request`. Otherwise, the stage1 requests are made by `stage1_requests`.
+    pub fn stage_1(&mut self, stage0_resps: &[Stage0Response<E>]) {
         let tree_params = gen_merkle_params();
-        let num_subcircuits = self.g16_pks.num_subcircuits();
 
         // Consume the stage0 state and the responses
-        self.stage1_state = Some(self.stage0_state.take().unwrap().process_stage0_responses(
-            &self.agg_pk.tipp_pk,
+        let stage1_state = self.stage0_state.take().unwrap().process_stage0_responses(
+            self.agg_pk.super_com_key(),
             tree_params,
             &stage0_resps,
-        ));
+        );
+        if let Some(checkpoint) = &self.checkpoint {
+            checkpoint
+                .save_stage1_state(&stage1_state)
+                .expect("couldn't checkpoint the stage1 state");
+        }
+        self.stage1_state = Some(stage1_state);
+        self.round_resps.clear();
+    }
+
+    /// Loads the stage1 state from the checkpoint, along with the responses of the extra rounds
+    /// it's done, so that the rest of the rounds and stage 1 pick up from there. Panics if there
+    /// is no saved stage1 state.
+    pub fn resume_stage_1(&mut self) {
+        let checkpoint = self
+            .checkpoint
+            .as_ref()
+            .expect("no checkpoint to resume from");
+        let stage1_state: CoordinatorStage1State<E, P> = checkpoint
+            .load_stage1_state()
+            .expect("couldn't load the stage1 state");
+
+        // The responses of a round are saved before the state that processed them
+        let num_subcircuits = self.g16_pks.num_subcircuits();
+        self.round_resps = (0..stage1_state.num_rounds_done())
+            .map(|round| {
+                checkpoint
+                    .load_round_resps::<E>(round, num_subcircuits)
+                    .expect("couldn't load the round responses")
+                    .into_iter()
+                    .collect::<Option<Vec<_>>>()
+                    .unwrap_or_else(|| panic!("the responses of round {round} weren't all saved"))
+            })
+            .collect();
+        self.stage0_state = None;
+        self.stage1_state = Some(stage1_state);
+    }
+
+    /// The number of extra rounds of the circuit that aren't done yet. Stage 1 can only start once
+    /// this is 0. Panics if stage 1 hasn't started.
+    pub fn num_rounds_left(&self) -> usize {
+        let stage1_state = self.stage1_state.as_ref().expect("stage 1 hasn't started");
+        P::NUM_EXTRA_ROUNDS - stage1_state.num_rounds_done()
+    }
+
+    /// Makes the request of the given subcircuit for the next extra round. Panics if there are no
+    /// rounds left.
+    pub fn round_request(&self, subcircuit_idx: usize) -> RoundRequest<E, P> {
+        let stage1_state = self.stage1_state.as_ref().expect("stage 1 hasn't started");
+        stage1_state.gen_round_request(subcircuit_idx)
+    }
+
+    /// Processes the responses of the next extra round, which derives the round's challenges. The
+    /// responses MUST be in order of subcircuit.
+    pub fn process_round(&mut self, round_resps: &[RoundResponse<E>]) {
+        let stage1_state = self.stage1_state.as_mut().expect("stage 1 hasn't started");
+        stage1_state.process_round_responses(self.agg_pk.super_com_key(), round_resps);
+        if let Some(checkpoint) = &self.checkpoint {
+            checkpoint
+                .save_stage1_state(stage1_state)
+                .expect("couldn't checkpoint the stage1 state");
+        }
+        self.round_resps.push(round_resps.to_vec());
+    }
 
-        (0..num_subcircuits)
-            .map(|idx| self.stage1_state.as_ref().unwrap().gen_request(idx))
-            .collect::<Vec<_>>()
+    /// Returns the responses of the given subcircuit in every extra round done so far. A worker
+    /// that wasn't the one to do them needs these to recompute the commitments.
+    pub fn subcircuit_round_resps(&self, subcircuit_idx: usize) -> Vec<RoundResponse<E>> {
+        self.round_resps
+            .iter()
+            .map(|resps| resps[subcircuit_idx].clone())
+            .collect()
     }
 
-    pub fn aggregate(&mut self, stage1_resps: &[Stage1Response]) -> AggProof {
-        let final_agg_state = self.stage1_state.take().unwrap().into_agg_state();
-        final_agg_state.gen_agg_proof(&self.agg_pk, stage1_resps)
+    /// Makes the stage1 requests, in order of subcircuit. Like the stage0 requests, they're made
+    /// lazily.
+    pub fn stage1_requests(&self) -> impl Iterator<Item = Stage1RequestRef<E, P>> + '_ {
+        (0..self.g16_pks.num_subcircuits()).map(|idx| self.stage1_request(idx))
+    }
+
+    /// Makes the stage1 request of the given subcircuit
+    pub fn stage1_request(&self, subcircuit_idx: usize) -> Stage1RequestRef<E, P> {
+        let stage1_state = self.stage1_state.as_ref().expect("stage 1 hasn't started");
+        stage1_state.gen_request(subcircuit_idx)
+    }
+
+    /// Checks the given stage1 response against the verifying key of its subcircuit and the
+    /// subcircuit's stage0 commitment. Use this to catch a bad proof before it spoils the
+    /// aggregate. Panics if stage 1 hasn't started.
+    pub fn verify_stage1_response(&self, resp: &Stage1Response<E>) -> bool {
+        let stage1_state = self.stage1_state.as_ref().expect("stage 1 hasn't started");
+        if resp.subcircuit_idx >= self.g16_pks.num_subcircuits() {
+            return false;
+        }
+
+        let pvks = self
+            .g16_pvks
+            .get_or_init(|| self.g16_pks.prepared_verifying_keys());
+        let pvk = &pvks[&self.g16_pks.representative(resp.subcircuit_idx)];
+        stage1_state.verify_stage1_response(pvk, resp)
+    }
+
+    /// Returns whether the given stage1 proof is made wrt the stage0 commitment of its subcircuit.
+    /// If not, the stage0 response of the subcircuit can't be trusted either, so stage 0 of it has
+    /// to be done again with `redo_stage_0`. Panics if stage 1 hasn't started.
+    pub fn stage1_commitments_match(&self, resp: &Stage1Response<E>) -> bool {
+        let stage1_state = self.stage1_state.as_ref().expect("stage 1 hasn't started");
+        stage1_state.expected_ds(resp.subcircuit_idx).as_deref() == Some(resp.proof.ds.as_slice())
+    }
+
+    /// Goes back to stage 0, e.g., because a stage0 response turned out to be bad. The stage0
+    /// requests stay the same. Once the subcircuits are redone, call `stage_1` with the new
+    /// responses. Panics if stage 1 hasn't started.
+    pub fn redo_stage_0(&mut self) {
+        let stage1_state = self.stage1_state.take().expect("stage 1 hasn't started");
+        self.stage0_state = Some(stage1_state.into_stage0_state());
+        self.round_resps.clear();
+    }
+
+    /// Aggregates the stage1 responses into the final proof. If stage 1 was skipped because all its
+    /// responses were already checkpointed, the stage1 state is loaded from the checkpoint. Panics
+    /// if the proofs are aggregated in groups.
+    pub fn aggregate(&mut self, stage1_resps: &[Stage1Response<E>]) -> AggProof<E> {
+        let final_agg_state = self.take_agg_state();
+        final_agg_state.gen_agg_proof(self.agg_pk.single(), stage1_resps)
+    }
+
+    /// The number of groups the proofs are aggregated in, or `None` if they're aggregated all at
+    /// once by `aggregate`
+    pub fn num_agg_groups(&self) -> Option<usize> {
+        match &*self.agg_pk {
+            AggKey::Single(_) => None,
+            AggKey::Grouped(agg_pk) => Some(agg_pk.num_groups()),
+        }
+    }
+
+    /// Finishes stage 1 of a state made with `with_group_size`, so that the groups can be
+    /// aggregated. Like `aggregate`, this loads the stage1 state from the checkpoint if stage 1
+    /// was skipped.
+    pub fn start_group_aggregation(&mut self) {
+        assert!(
+            self.num_agg_groups().is_some(),
+            "the proofs aren't aggregated in groups"
+        );
+        self.agg_state = Some(self.take_agg_state());
+    }
+
+    /// Makes the request to aggregate the given group. `stage1_resps` are the stage1 responses of
+    /// every subcircuit, in order of subcircuit. Panics if `start_group_aggregation` wasn't called.
+    pub fn group_agg_request<'b>(
+        &'b self,
+        group_idx: usize,
+        stage1_resps: &'b [Stage1Response<E>],
+    ) -> GroupAggRequestRef<'b, E> {
+        let agg_state = self
+            .agg_state
+            .as_ref()
+            .expect("group aggregation hasn't started");
+        agg_state.gen_group_request(self.agg_pk.grouped(), group_idx, stage1_resps)
+    }
+
+    /// Combines the aggregates of every group into the final proof. `group_resps` MUST be in order
+    /// of group. Panics if `start_group_aggregation` wasn't called.
+    pub fn combine_group_aggs(&mut self, group_resps: Vec<GroupAggResponse<E>>) -> AggProof<E> {
+        assert!(
+            group_resps
+                .iter()
+                .enumerate()
+                .all(|(g, resp)| resp.group_idx == g),
+            "the group aggregates are out of order"
+        );
+        let agg_state = self
+            .agg_state
+            .take()
+            .expect("group aggregation hasn't started");
+        agg_state.combine_group_agg_proofs(group_resps.into_iter().map(|resp| resp.proof).collect())
+    }
+
+    /// Consumes the stage1 state, or loads it from the checkpoint if stage 1 was skipped
+    fn take_agg_state(&mut self) -> FinalAggState<TreeConfig<E>, E> {
+        let stage1_state = match self.stage1_state.take() {
+            Some(stage1_state) => stage1_state,
+            None => self
+                .checkpoint
+                .as_ref()
+                .expect("no stage1 state to aggregate")
+                .load_stage1_state()
+                .expect("couldn't load the stage1 state"),
+        };
+        stage1_state.into_agg_state()
+    }
+
+    /// Processes the stage0 responses of every instance of a batch made with `new_batch`, where
+    /// `stage0_resps[k]` are the responses of the k-th instance. The instances share their
+    /// challenges, so every instance must be done with stage 0.
+    pub fn stage_1_batch(states: &mut [Self], stage0_resps: &[Vec<Stage0Response<E>>]) {
+        let agg_pk = batch_agg_key(states);
+        let stage0_states = states
+            .iter_mut()
+            .map(|state| state.stage0_state.take().expect("stage 0 hasn't started"))
+            .collect();
+        let stage1_states = CoordinatorStage0State::process_batch_stage0_responses(
+            stage0_states,
+            &agg_pk.single().tipp_pk,
+            gen_merkle_params(),
+            stage0_resps,
+        );
+        for (state, stage1_state) in states.iter_mut().zip(stage1_states) {
+            state.stage1_state = Some(stage1_state);
+            state.round_resps.clear();
+        }
+    }
+
+    /// Processes the responses of the next extra round of every instance of a batch, where
+    /// `round_resps[k]` are the responses of the k-th instance, in order of subcircuit
+    pub fn process_round_batch(states: &mut [Self], round_resps: &[Vec<RoundResponse<E>>]) {
+        let agg_pk = batch_agg_key(states);
+        let mut stage1_states = states
+            .iter_mut()
+            .map(|state| state.stage1_state.take().expect("stage 1 hasn't started"))
+            .collect::<Vec<_>>();
+        CoordinatorStage1State::process_batch_round_responses(
+            &mut stage1_states,
+            &agg_pk.single().tipp_pk,
+            round_resps,
+        );
+        for ((state, stage1_state), resps) in states.iter_mut().zip(stage1_states).zip(round_resps)
+        {
+            state.stage1_state = Some(stage1_state);
+            state.round_resps.push(resps.clone());
+        }
+    }
+
+    /// Aggregates the stage1 responses of every instance of a batch into a single proof, where
+    /// `stage1_resps[k]` are the responses of the k-th instance
+    pub fn aggregate_batch(
+        states: &mut [Self],
+        stage1_resps: &[Vec<Stage1Response<E>>],
+    ) -> BatchAggProof<E> {
+        let agg_pk = batch_agg_key(states);
+        let agg_states = states
+            .iter_mut()
+            .map(|state| {
+                state
+                    .stage1_state
+                    .take()
+                    .expect("no stage1 state to aggregate")
+                    .into_agg_state()
+            })
+            .collect::<Vec<_>>();
+        FinalAggState::gen_batch_agg_proof(&agg_states, agg_pk.single(), stage1_resps)
+    }
+
+    /// The key that verifies the proofs this state makes. States made with `fork` share it.
+    /// Panics if the proofs are aggregated in groups, which `grouped_agg_vk` is for.
+    pub fn agg_vk(&self) -> AggVerifyingKey<E> {
+        self.agg_pk.single().vk()
+    }
+
+    /// The key that verifies the proofs of a state made with `with_group_size`, with
+    /// `HekatonProof::verify_grouped`
+    pub fn grouped_agg_vk(&self) -> GroupedAggVerifyingKey<E> {
+        self.agg_pk.grouped().vk()
+    }
+
+    /// Writes the proof to `path`, and the key that verifies it to `path` with `.vk` appended
+    pub fn write_proof(&self, path: &Path, proof: &impl CanonicalSerialize) -> io::Result<()> {
+        write_to_path(path, proof)?;
+        let vk_path = append_to_path(path, ".vk");
+        match &*self.agg_pk {
+            AggKey::Single(agg_pk) => write_to_path(&vk_path, &agg_pk.vk()),
+            AggKey::Grouped(agg_pk) => write_to_path(&vk_path, &agg_pk.vk()),
+        }
     }
 }
 
-fn generate_agg_key(g16_pks: &ProvingKeys) -> AggProvingKey<E> {
-    let mut rng = thread_rng();
+/// Returns `path` with `suffix` appended to its last component
+pub fn append_to_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
 
+/// Serializes the value, uncompressed, to a new file at `path`
+pub fn write_to_path(path: &Path, val: &impl CanonicalSerialize) -> io::Result<()> {
+    let mut f = BufWriter::new(File::create(path)?);
+    val.serialize_uncompressed(&mut f).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("couldn't serialize {path:?}: {e}"),
+        )
+    })?;
+    f.into_inner()?.sync_all()
+}
+
+/// Returns the aggregation key that every state of the batch shares. Panics if they don't share one.
+fn batch_agg_key<'a, E: SupportedCurve, P: CircuitWithPortals<E::ScalarField>>(
+    states: &[CoordinatorState<'a, E, P>],
+) -> Rc<AggKey<'a, E>> {
+    let agg_pk = states.first().expect("empty batch").agg_pk.clone();
+    assert!(
+        states
+            .iter()
+            .all(|state| Rc::ptr_eq(&state.agg_pk, &agg_pk)),
+        "the states weren't made together by new_batch"
+    );
+    agg_pk
+}
+
+/// Generates a fresh aggregation key. The TIPP trapdoor is dropped as soon as the key is made.
+fn generate_agg_key<'a, E: SupportedCurve>(
+    g16_pks: &'a ProvingKeys<E>,
+    rng: &mut impl Rng,
+) -> AggProvingKey<'a, E> {
     let num_subcircuits = g16_pks.num_subcircuits();
 
     let pk_fetcher = |subcircuit_idx: usize| g16_pks.get_pk(subcircuit_idx);
@@ -87,11 +615,42 @@ fn generate_agg_key(g16_pks: &ProvingKeys) -> AggProvingKey<E> {
     // Construct the aggregator commitment key
     let start = start_timer!(|| format!("Generating aggregation key "));
     let agg_pk = {
-        // Need some intermediate keys
-        let (tipp_pk, _tipp_vk) =
-            TIPA::<E, sha2::Sha256>::setup(num_subcircuits, &mut rng).unwrap();
-        AggProvingKey::new(tipp_pk, pk_fetcher)
+        // The TIPP verifying key is derived from the proving key when it's needed, by `agg_vk`
+        let (tipp_pk, _) = TIPA::<E, sha2::Sha256>::setup(tipp_size(num_subcircuits), rng).unwrap();
+        AggProvingKey::new(tipp_pk, num_subcircuits, pk_fetcher)
     };
     end_timer!(start);
     agg_pk
 }
+
+/// Like `generate_agg_key`, but the proofs are aggregated in groups of `group_size` subcircuits,
+/// each under its own TIPP key
+fn generate_grouped_agg_key<'a, E: SupportedCurve>(
+    g16_pks: &'a ProvingKeys<E>,
+    group_size: usize,
+    rng: &mut impl Rng,
+) -> GroupedAggProvingKey<'a, E> {
+    let pk_fetcher = |subcircuit_idx: usize| g16_pks.get_pk(subcircuit_idx);
+
+    let start = start_timer!(|| format!("Generating grouped aggregation key"));
+    let agg_pk = GroupedAggProvingKey::new(g16_pks.num_subcircuits(), group_size, rng, pk_fetcher);
+    end_timer!(start);
+    agg_pk
+}
+
+/// Like `generate_agg_key`, but the key aggregates the proofs of `num_instances` instances at once
+fn generate_batch_agg_key<'a, E: SupportedCurve>(
+    g16_pks: &'a ProvingKeys<E>,
+    num_instances: usize,
+    rng: &mut impl Rng,
+) -> AggProvingKey<'a, E> {
+    let num_subcircuits = g16_pks.num_subcircuits();
+    let pk_fetcher = |subcircuit_idx: usize| g16_pks.get_pk(subcircuit_idx);
+
+    let start = start_timer!(|| format!("Generating batch aggregation key"));
+    let (tipp_pk, _) =
+        TIPA::<E, sha2::Sha256>::setup(tipp_size(num_instances * num_subcircuits), rng).unwrap();
+    let agg_pk = AggProvingKey::new_batch(tipp_pk, num_subcircuits, num_instances, pk_fetcher);
+    end_timer!(start);
+    agg_pk
+}
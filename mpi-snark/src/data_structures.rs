@@ -1,62 +1,190 @@
 use distributed_prover::{
     coordinator::G16ProvingKeyGenerator,
-    poseidon_util::{
-        gen_merkle_params, PoseidonTreeConfig as TreeConfig, PoseidonTreeConfigVar as TreeConfigVar,
-    },
+    poseidon_util::{gen_merkle_params, PoseidonTreeConfig, PoseidonTreeConfigVar},
+    util::prepare_g16_verifying_key,
     CircuitWithPortals,
 };
 
-use ark_bls12_381::{Bls12_381 as E, Fr};
-use ark_ip_proofs::tipa::Proof;
+use ark_bls12_381::Bls12_381;
+use ark_bn254::Bn254;
+use ark_crypto_primitives::sponge::Absorb;
+use ark_ec::pairing::Pairing;
 use ark_serialize::{
-    CanonicalDeserialize, CanonicalSerialize, Compress, SerializationError, Write,
+    CanonicalDeserialize, CanonicalSerialize, Compress, Read, SerializationError, Valid, Validate,
+    Write,
 };
 use std::collections::BTreeMap;
 
-pub type G16Proof = distributed_prover::util::G16Proof<E>;
-pub type G16ProvingKey = distributed_prover::util::G16ProvingKey<E>;
-pub type G16Com = distributed_prover::util::G16Com<E>;
-pub type G16ComRandomness = distributed_prover::util::G16ComRandomness<E>;
-pub type AggProof = Proof<E>;
+pub type TreeConfig<E> = PoseidonTreeConfig<<E as Pairing>::ScalarField>;
+pub type TreeConfigVar<E> = PoseidonTreeConfigVar<<E as Pairing>::ScalarField>;
 
-pub type Stage0Request = distributed_prover::coordinator::Stage0Request<Fr>;
+pub type G16Proof<E> = distributed_prover::util::G16Proof<E>;
+pub type G16ProvingKey<E> = distributed_prover::util::G16ProvingKey<E>;
+pub type G16PreparedVerifyingKey<E> = distributed_prover::util::G16PreparedVerifyingKey<E>;
+pub type G16Com<E> = distributed_prover::util::G16Com<E>;
+pub type G16ComRandomness<E> = distributed_prover::util::G16ComRandomness<E>;
+pub type AggProof<E> = distributed_prover::aggregation::HekatonProof<TreeConfig<E>, E>;
+pub type BatchAggProof<E> = distributed_prover::aggregation::HekatonBatchProof<TreeConfig<E>, E>;
 
-pub type Stage0RequestRef<'a> = distributed_prover::coordinator::Stage0RequestRef<'a, Fr>;
+pub type Stage0Request<E> =
+    distributed_prover::coordinator::Stage0Request<<E as Pairing>::ScalarField>;
 
-pub type Stage1Request<P> = distributed_prover::coordinator::Stage1Request<TreeConfig, Fr, P>;
+pub type Stage0RequestRef<'a, E> =
+    distributed_prover::coordinator::Stage0RequestRef<'a, <E as Pairing>::ScalarField>;
 
-pub type Stage1RequestRef<'a, P> =
-    distributed_prover::coordinator::Stage1RequestRef<'a, TreeConfig, Fr, P>;
+pub type Stage1Request<E, P> =
+    distributed_prover::coordinator::Stage1Request<TreeConfig<E>, <E as Pairing>::ScalarField, P>;
 
-pub type Stage0Response = distributed_prover::worker::Stage0Response<E>;
+pub type Stage1RequestRef<'a, E, P> = distributed_prover::coordinator::Stage1RequestRef<
+    'a,
+    TreeConfig<E>,
+    <E as Pairing>::ScalarField,
+    P,
+>;
 
-pub type Stage1Response = distributed_prover::worker::Stage1Response<E>;
+pub type Stage0Response<E> = distributed_prover::worker::Stage0Response<E>;
 
+pub type Stage1Response<E> = distributed_prover::worker::Stage1Response<E>;
 
-pub const TEST_CIRCUIT_ID: &'static str = "test circuit";
-pub const MERKLE_CIRCUIT_ID: &'static str = "BigMerkle circuit";
-pub const VKD_CIRCUIT_ID: &'static str = "VKD circuit";
-pub const VM_CIRCUIT_ID: &'static str = "VM circuit";
+pub type RoundRequest<E, P> =
+    distributed_prover::coordinator::RoundRequest<<E as Pairing>::ScalarField, P>;
 
+pub type RoundResponse<E> = distributed_prover::worker::RoundResponse<E>;
+
+pub type GroupAggRequest<E> =
+    distributed_prover::coordinator::GroupAggRequest<'static, TreeConfig<E>, E>;
+
+pub type GroupAggRequestRef<'a, E> =
+    distributed_prover::coordinator::GroupAggRequestRef<'a, TreeConfig<E>, E>;
+
+pub type GroupAggResponse<E> = distributed_prover::coordinator::GroupAggResponse<E>;
+
+pub type CoordinatorStage0State<E, P> =
+    distributed_prover::coordinator::CoordinatorStage0State<E, P>;
+
+pub type CoordinatorStage1State<E, P> =
+    distributed_prover::coordinator::CoordinatorStage1State<TreeConfig<E>, E, P>;
+
+/// Identifies one of the proofs of a run that makes several. Requests and responses carry the ID of
+/// the proof they're for, so that the stages of consecutive proofs can be in flight at once.
+pub type ProofId = u64;
+
+/// A chunk of requests or responses, all for the same proof
 #[derive(CanonicalSerialize, CanonicalDeserialize)]
-pub struct ProvingKeys {
+pub struct ProofMessage<T> {
+    pub proof_id: ProofId,
+    pub items: Vec<T>,
+}
+
+/// The pairing-friendly curves the prover can run over
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Curve {
+    #[value(name = "bls12-381")]
+    Bls12_381,
+    #[value(name = "bn254")]
+    Bn254,
+}
+
+impl Curve {
+    /// Reads the curve of a serialized `ProvingKeys`, without reading the keys themselves. This is
+    /// how the binaries know which curve to deserialize the keys over.
+    pub fn read_from_key_file(reader: impl Read) -> Result<Self, SerializationError> {
+        Curve::deserialize_uncompressed_unchecked(reader)
+    }
+}
+
+impl CanonicalSerialize for Curve {
+    fn serialize_with_mode<W: Write>(
+        &self,
+        writer: W,
+        compress: Compress,
+    ) -> Result<(), SerializationError> {
+        (*self as u8).serialize_with_mode(writer, compress)
+    }
+
+    fn serialized_size(&self, compress: Compress) -> usize {
+        (*self as u8).serialized_size(compress)
+    }
+}
+
+impl Valid for Curve {
+    fn check(&self) -> Result<(), SerializationError> {
+        Ok(())
+    }
+}
+
+impl CanonicalDeserialize for Curve {
+    fn deserialize_with_mode<R: Read>(
+        reader: R,
+        compress: Compress,
+        validate: Validate,
+    ) -> Result<Self, SerializationError> {
+        match u8::deserialize_with_mode(reader, compress, validate)? {
+            0 => Ok(Curve::Bls12_381),
+            1 => Ok(Curve::Bn254),
+            _ => Err(SerializationError::InvalidData),
+        }
+    }
+}
+
+/// A pairing the prover can run over. Its scalar field needs Poseidon parameters for the execution
+/// tree, hence the `Absorb` bound.
+pub trait SupportedCurve: Pairing<ScalarField: Absorb> {
+    const CURVE: Curve;
+}
+
+impl SupportedCurve for Bls12_381 {
+    const CURVE: Curve = Curve::Bls12_381;
+}
+
+impl SupportedCurve for Bn254 {
+    const CURVE: Curve = Curve::Bn254;
+}
+
+/// Calls the given generic function with the pairing of the given curve as its last type
+/// parameter. Any other type parameters are given as usual, e.g., `with_curve!(curve, f::<T>(x))`
+/// calls `f::<T, E>(x)`.
+#[macro_export]
+macro_rules! with_curve {
+    ($curve:expr, $f:ident $(::<$($ty:ty),+>)? ($($arg:expr),* $(,)?)) => {
+        match $curve {
+            $crate::data_structures::Curve::Bls12_381 => {
+                $f::<$($($ty,)+)? ark_bls12_381::Bls12_381>($($arg),*)
+            },
+            $crate::data_structures::Curve::Bn254 => $f::<$($($ty,)+)? ark_bn254::Bn254>($($arg),*),
+        }
+    };
+}
+
+#[derive(CanonicalSerialize, CanonicalDeserialize)]
+pub struct ProvingKeys<E: Pairing> {
+    /// The curve the keys are over. This comes first, so it can be read by
+    /// `Curve::read_from_key_file`.
+    curve: Curve,
     circuit_id: String,
     /// The parameters to the underlying circuit, serialized
     pub serialized_circ_params: Vec<u8>,
     /// The proving keys for a minimal set of subcircuits
-    minimal_proving_keys: BTreeMap<usize, G16ProvingKey>,
+    minimal_proving_keys: BTreeMap<usize, G16ProvingKey<E>>,
     /// The map from subcircuit idx to its canonical representative in `minimal_proving_keys`
     subcircuit_representative_map: BTreeMap<usize, usize>,
+    /// The number of constraints of each representative subcircuit, measured at setup. This is
+    /// how the coordinator estimates the cost of proving a subcircuit.
+    representative_num_constraints: BTreeMap<usize, usize>,
 }
 
-impl ProvingKeys {
-    pub fn new<P: CircuitWithPortals<Fr>>(circ_params: P::Parameters, id_str: String) -> Self {
+impl<E: SupportedCurve> ProvingKeys<E> {
+    pub fn new<P: CircuitWithPortals<E::ScalarField>>(
+        circ_params: P::Parameters,
+        id_str: String,
+    ) -> Self {
         let mut rng = rand::thread_rng();
 
         let circ = P::rand(&mut rng, &circ_params);
         let tree_params = gen_merkle_params();
 
-        let pk_generator = G16ProvingKeyGenerator::<TreeConfig, TreeConfigVar, E, _>::new(
+        let pk_generator = G16ProvingKeyGenerator::<TreeConfig<E>, TreeConfigVar<E>, E, _>::new(
+            &mut rng,
             circ.clone(),
             tree_params.clone(),
         );
@@ -67,14 +195,16 @@ impl ProvingKeys {
             .serialize_uncompressed(&mut serialized_circ_params)
             .unwrap();
 
-        // Generate the relevant proving keys
-        let minimal_proving_keys: BTreeMap<usize, G16ProvingKey> = {
-            let minimal_subcircuit_indices = circ.get_unique_subcircuits();
-            minimal_subcircuit_indices
-                .iter()
-                .map(|&i| (i, pk_generator.gen_pk(&mut rng, i)))
-                .collect()
-        };
+        // Generate the relevant proving keys, and measure the size of each subcircuit
+        let minimal_subcircuit_indices = circ.get_unique_subcircuits();
+        let minimal_proving_keys: BTreeMap<usize, G16ProvingKey<E>> = minimal_subcircuit_indices
+            .iter()
+            .map(|&i| (i, pk_generator.gen_pk(&mut rng, i)))
+            .collect();
+        let representative_num_constraints = minimal_subcircuit_indices
+            .iter()
+            .map(|&i| (i, pk_generator.num_constraints(i)))
+            .collect();
 
         // Generate the full index mapping
         let subcircuit_representative_map = (0..circ.num_subcircuits())
@@ -82,21 +212,48 @@ impl ProvingKeys {
             .collect();
 
         ProvingKeys {
+            curve: E::CURVE,
             circuit_id: id_str,
             serialized_circ_params,
             minimal_proving_keys,
             subcircuit_representative_map,
+            representative_num_constraints,
         }
     }
 
-    pub fn get_pk(&self, subcircuit_idx: usize) -> &G16ProvingKey {
-        let representative_idx = self
+    pub fn get_pk(&self, subcircuit_idx: usize) -> &G16ProvingKey<E> {
+        self.minimal_proving_keys
+            .get(&self.representative(subcircuit_idx))
+            .expect("missing proving key")
+    }
+
+    /// Returns the index of the subcircuit whose proving key the given subcircuit uses
+    pub fn representative(&self, subcircuit_idx: usize) -> usize {
+        *self
             .subcircuit_representative_map
             .get(&subcircuit_idx)
-            .expect("subcircuit index out of range");
+            .expect("subcircuit index out of range")
+    }
+
+    /// Returns the number of constraints of the given subcircuit, as measured at setup
+    pub fn num_constraints(&self, subcircuit_idx: usize) -> usize {
+        *self
+            .representative_num_constraints
+            .get(&self.representative(subcircuit_idx))
+            .expect("missing constraint count")
+    }
+
+    /// Prepares the verifying key of every representative subcircuit. These are keyed by the
+    /// representative's index.
+    pub fn prepared_verifying_keys(&self) -> BTreeMap<usize, G16PreparedVerifyingKey<E>> {
         self.minimal_proving_keys
-            .get(representative_idx)
-            .expect("missing proving key")
+            .iter()
+            .map(|(&i, pk)| (i, prepare_g16_verifying_key(&pk.vk)))
+            .collect()
+    }
+
+    pub fn curve(&self) -> Curve {
+        self.curve
     }
 
     pub fn get_id_str(&self) -> &str {
@@ -108,27 +265,20 @@ impl ProvingKeys {
     }
 }
 
-impl<'a> CanonicalSerialize for &'a ProvingKeys {
+/// Serializes the keys in the same format as `ProvingKeys` itself, so the bytes can be read back
+/// with `CanonicalDeserialize` and `Curve::read_from_key_file`
+impl<'a, E: Pairing> CanonicalSerialize for &'a ProvingKeys<E> {
     #[inline]
     fn serialize_with_mode<W: Write>(
         &self,
-        mut writer: W,
+        writer: W,
         compress: Compress,
     ) -> Result<(), SerializationError> {
-        self.serialized_circ_params
-            .serialize_with_mode(&mut writer, compress)?;
-        self.minimal_proving_keys
-            .serialize_with_mode(&mut writer, compress)?;
-        self.subcircuit_representative_map
-            .serialize_with_mode(&mut writer, compress)?;
-
-        Ok(())
+        (**self).serialize_with_mode(writer, compress)
     }
 
     #[inline]
     fn serialized_size(&self, compress: Compress) -> usize {
-        self.serialized_circ_params.serialized_size(compress)
-            + self.minimal_proving_keys.serialized_size(compress)
-            + self.subcircuit_representative_map.serialized_size(compress)
+        (**self).serialized_size(compress)
     }
 }
@@ -1,8 +1,12 @@
 use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
 use mpi::traits::Equivalence;
 
+pub mod checkpoint;
+pub mod circuits;
 pub mod coordinator;
 pub mod data_structures;
+pub mod net;
+pub mod schedule;
 pub mod worker;
 
 #[macro_export]
@@ -31,11 +35,10 @@ macro_rules! construct_partitioned_buffer_for_scatter {
 
 #[macro_export]
 macro_rules! construct_partitioned_mut_buffer_for_gather {
-    ($size: expr, $default: expr, $flattened_item_bytes: expr) => {{
-        let item_size = $default.uncompressed_size();
+    ($defaults: expr, $flattened_item_bytes: expr) => {{
+        // The root sends nothing. Rank i + 1 sends an item the size of the i-th default
         let item_bytes = std::iter::once(vec![])
-            .chain(std::iter::repeat(vec![0u8; item_size]))
-            .take($size as usize)
+            .chain(($defaults).iter().map(|d| vec![0u8; d.uncompressed_size()]))
             .collect::<Vec<_>>();
         let counts = item_bytes
             .iter()
@@ -56,11 +59,16 @@ macro_rules! construct_partitioned_mut_buffer_for_gather {
 
 #[macro_export]
 macro_rules! deserialize_flattened_bytes {
-    ($flattened_item_bytes: expr, $default: expr, $item_type: ty) => {{
-        let item_size = $default.uncompressed_size();
-        $flattened_item_bytes
-            .chunks_exact(item_size)
-            .map(<$item_type>::deserialize_uncompressed_unchecked)
+    ($flattened_item_bytes: expr, $defaults: expr, $item_type: ty) => {{
+        // The i-th item is the size of the i-th default
+        let mut rest = &$flattened_item_bytes[..];
+        ($defaults)
+            .iter()
+            .map(|d| {
+                let (item_bytes, tail) = rest.split_at(d.uncompressed_size());
+                rest = tail;
+                <$item_type>::deserialize_uncompressed_unchecked(item_bytes)
+            })
             .collect::<Result<Vec<_>, _>>()
     }};
 }
@@ -116,7 +124,3 @@ pub struct VkdMerkleParams;
 impl distributed_prover::vkd::MerkleTreeParameters for VkdMerkleParams {
     const DEPTH: usize = distributed_prover::vkd::DEPTH;
 }
-
-/// The cost of 1 cycle of vnTinyRam with 32-bit words, not including memory and routing
-/// Comes from Fig. 7 of https://eprint.iacr.org/2013/879
-pub const VM_CONSTRAINTS_PER_CYCLE: usize = 1114;
@@ -0,0 +1,724 @@
+use crate::data_structures::{
+    RoundRequest, RoundResponse, Stage0Request, Stage0RequestRef, Stage0Response, Stage1Request,
+    Stage1RequestRef, Stage1Response, SupportedCurve,
+};
+use crate::serialize_to_vec;
+
+use distributed_prover::CircuitWithPortals;
+
+use ark_ec::pairing::Pairing;
+use ark_serialize::{CanonicalDeserialize, SerializationError};
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    io::{self, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+// Messages over TCP are sent as frames. A frame is its length as a little-endian u64, followed by
+// that many bytes. The bytes of a message are a tag, followed by the uncompressed
+// CanonicalSerialize encoding of its contents.
+
+/// The longest frame the coordinator takes from a worker. A response is a commitment and its seed,
+/// or a proof, which are a few group elements each, so this leaves plenty of room.
+pub const MAX_RESPONSE_LEN: u64 = 1 << 20;
+
+/// The longest frame a worker takes from the coordinator. The largest task is a stage1 task, which
+/// carries the subtraces and witnesses of a subcircuit.
+pub const MAX_TASK_LEN: u64 = 1 << 32;
+
+const STAGE0_TAG: u8 = 0;
+const STAGE1_TAG: u8 = 1;
+const DONE_TAG: u8 = 2;
+const READY_TAG: u8 = 3;
+const ROUND_TAG: u8 = 4;
+
+/// Writes the given bytes as a single frame
+pub fn write_frame(mut w: impl Write, bytes: &[u8]) -> io::Result<()> {
+    w.write_all(&(bytes.len() as u64).to_le_bytes())?;
+    w.write_all(bytes)?;
+    w.flush()
+}
+
+/// Reads a single frame of at most `max_len` bytes, and returns its bytes. The buffer grows as the
+/// bytes arrive, so a corrupt length doesn't make us allocate more than the peer actually sends.
+pub fn read_frame(mut r: impl Read, max_len: u64) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 8];
+    r.read_exact(&mut len)?;
+    let len = u64::from_le_bytes(len);
+    if len > max_len {
+        return Err(invalid_data(format!("frame of {len} bytes is too long")));
+    }
+
+    let mut bytes = Vec::new();
+    (&mut r).take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("frame ended after {} of {len} bytes", bytes.len()),
+        ));
+    }
+    Ok(bytes)
+}
+
+/// A message from the coordinator to a worker
+#[allow(clippy::large_enum_variant)]
+pub enum Task<E: SupportedCurve, P: CircuitWithPortals<E::ScalarField>> {
+    /// Commit to the subtraces of a subcircuit
+    Stage0(Stage0Request<E>),
+    /// Commit to a subcircuit in an extra round. Like a stage1 task, this comes with the
+    /// subcircuit's stage0 request and response, and its responses in the earlier rounds.
+    Round(
+        Stage0Request<E>,
+        Stage0Response<E>,
+        Vec<RoundResponse<E>>,
+        RoundRequest<E, P>,
+    ),
+    /// Prove a subcircuit. This comes with the subcircuit's stage0 request and response, and its
+    /// responses in every extra round, so that the worker can recompute the commitments from their
+    /// seeds. Any worker can do this, not just the one that did stage0.
+    Stage1(
+        Stage0Request<E>,
+        Stage0Response<E>,
+        Vec<RoundResponse<E>>,
+        Stage1Request<E, P>,
+    ),
+    /// There is no more work
+    Done,
+}
+
+impl<E: SupportedCurve, P: CircuitWithPortals<E::ScalarField>> Task<E, P> {
+    pub fn decode(bytes: &[u8]) -> Result<Self, SerializationError> {
+        let (tag, mut rest) = bytes.split_first().ok_or(SerializationError::InvalidData)?;
+        let task = match *tag {
+            STAGE0_TAG => Task::Stage0(Stage0Request::deserialize_uncompressed_unchecked(
+                &mut rest,
+            )?),
+            ROUND_TAG => Task::Round(
+                Stage0Request::deserialize_uncompressed_unchecked(&mut rest)?,
+                Stage0Response::deserialize_uncompressed_unchecked(&mut rest)?,
+                Vec::deserialize_uncompressed_unchecked(&mut rest)?,
+                RoundRequest::deserialize_uncompressed_unchecked(&mut rest)?,
+            ),
+            STAGE1_TAG => Task::Stage1(
+                Stage0Request::deserialize_uncompressed_unchecked(&mut rest)?,
+                Stage0Response::deserialize_uncompressed_unchecked(&mut rest)?,
+                Vec::deserialize_uncompressed_unchecked(&mut rest)?,
+                Stage1Request::deserialize_uncompressed_unchecked(&mut rest)?,
+            ),
+            DONE_TAG => Task::Done,
+            _ => return Err(SerializationError::InvalidData),
+        };
+
+        match rest.is_empty() {
+            true => Ok(task),
+            false => Err(SerializationError::InvalidData),
+        }
+    }
+}
+
+/// Encodes a [`Task::Stage0`]
+pub fn encode_stage0_task<E: Pairing>(req: &Stage0RequestRef<E>) -> Vec<u8> {
+    [&[STAGE0_TAG][..], &serialize_to_vec(req)].concat()
+}
+
+/// Encodes a [`Task::Round`]. The stage0 request and the responses are the ones of the same
+/// subcircuit.
+pub fn encode_round_task<E: SupportedCurve, P: CircuitWithPortals<E::ScalarField>>(
+    stage0_req: &Stage0RequestRef<E>,
+    stage0_resp: &Stage0Response<E>,
+    earlier_round_resps: &[RoundResponse<E>],
+    round_req: &RoundRequest<E, P>,
+) -> Vec<u8> {
+    assert_eq!(stage0_req.subcircuit_idx, round_req.subcircuit_idx);
+    [
+        &[ROUND_TAG][..],
+        &serialize_to_vec(stage0_req),
+        &serialize_to_vec(stage0_resp),
+        &serialize_to_vec(earlier_round_resps),
+        &serialize_to_vec(round_req),
+    ]
+    .concat()
+}
+
+/// Encodes a [`Task::Stage1`]. The stage0 request and the responses are the ones of the same
+/// subcircuit.
+pub fn encode_stage1_task<E: SupportedCurve, P: CircuitWithPortals<E::ScalarField>>(
+    stage0_req: &Stage0RequestRef<E>,
+    stage0_resp: &Stage0Response<E>,
+    round_resps: &[RoundResponse<E>],
+    stage1_req: &Stage1RequestRef<E, P>,
+) -> Vec<u8> {
+    assert_eq!(stage0_req.subcircuit_idx, stage1_req.subcircuit_idx);
+    [
+        &[STAGE1_TAG][..],
+        &serialize_to_vec(stage0_req),
+        &serialize_to_vec(stage0_resp),
+        &serialize_to_vec(round_resps),
+        &serialize_to_vec(stage1_req),
+    ]
+    .concat()
+}
+
+/// A message from a worker to the coordinator
+#[allow(clippy::large_enum_variant)]
+pub enum WorkerMsg<E: Pairing> {
+    /// The worker has connected and wants work
+    Ready,
+    Stage0(Stage0Response<E>),
+    Round(RoundResponse<E>),
+    Stage1(Stage1Response<E>),
+}
+
+impl<E: Pairing> WorkerMsg<E> {
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            WorkerMsg::Ready => vec![READY_TAG],
+            WorkerMsg::Stage0(resp) => [&[STAGE0_TAG][..], &serialize_to_vec(resp)].concat(),
+            WorkerMsg::Round(resp) => [&[ROUND_TAG][..], &serialize_to_vec(resp)].concat(),
+            WorkerMsg::Stage1(resp) => [&[STAGE1_TAG][..], &serialize_to_vec(resp)].concat(),
+        }
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, SerializationError> {
+        let (tag, mut rest) = bytes.split_first().ok_or(SerializationError::InvalidData)?;
+        let msg = match *tag {
+            READY_TAG => WorkerMsg::Ready,
+            STAGE0_TAG => WorkerMsg::Stage0(Stage0Response::deserialize_uncompressed_unchecked(
+                &mut rest,
+            )?),
+            ROUND_TAG => WorkerMsg::Round(RoundResponse::deserialize_uncompressed_unchecked(
+                &mut rest,
+            )?),
+            STAGE1_TAG => WorkerMsg::Stage1(Stage1Response::deserialize_uncompressed_unchecked(
+                &mut rest,
+            )?),
+            _ => return Err(SerializationError::InvalidData),
+        };
+
+        match rest.is_empty() {
+            true => Ok(msg),
+            false => Err(SerializationError::InvalidData),
+        }
+    }
+
+    /// Returns whether this is the response to the given encoded task for the given subcircuit
+    fn answers(&self, subcircuit_idx: usize, task: &[u8]) -> bool {
+        match (self, task.first()) {
+            (WorkerMsg::Stage0(resp), Some(&STAGE0_TAG)) => resp.subcircuit_idx == subcircuit_idx,
+            (WorkerMsg::Round(resp), Some(&ROUND_TAG)) => resp.subcircuit_idx == subcircuit_idx,
+            (WorkerMsg::Stage1(resp), Some(&STAGE1_TAG)) => resp.subcircuit_idx == subcircuit_idx,
+            _ => false,
+        }
+    }
+
+    pub fn into_stage0(self) -> Option<Stage0Response<E>> {
+        match self {
+            WorkerMsg::Stage0(resp) => Some(resp),
+            _ => None,
+        }
+    }
+
+    pub fn into_round(self) -> Option<RoundResponse<E>> {
+        match self {
+            WorkerMsg::Round(resp) => Some(resp),
+            _ => None,
+        }
+    }
+
+    pub fn into_stage1(self) -> Option<Stage1Response<E>> {
+        match self {
+            WorkerMsg::Stage1(resp) => Some(resp),
+            _ => None,
+        }
+    }
+}
+
+/// Hands out encoded tasks to the workers connected over TCP, and collects their responses.
+/// Workers pull tasks one at a time, so faster workers end up doing more of them.
+///
+/// A task whose worker hangs up is handed to another worker. So is a task whose worker doesn't
+/// answer within the timeout, if there is one. In that case the first worker isn't cut off, and
+/// whichever answer arrives first is used. Responses aren't checked here. A caller that finds one
+/// invalid can hang up on the worker that sent it with `drop_worker`, and hand the task out again
+/// with `run_some`.
+pub struct Dispatcher<E: Pairing> {
+    state: Mutex<DispatchState<E>>,
+    cond: Condvar,
+    task_timeout: Option<Duration>,
+}
+
+struct DispatchState<E: Pairing> {
+    /// Counts the calls to `run`, so that late answers to an earlier batch can be told apart
+    batch: usize,
+    /// The current batch of tasks, each with the index of its subcircuit. This is empty between
+    /// batches.
+    tasks: Vec<(usize, Arc<Vec<u8>>)>,
+    /// The positions in `tasks` of the tasks that are waiting to be handed out
+    queue: VecDeque<usize>,
+    /// The tasks that have been handed out and not answered yet
+    assignments: Vec<Assignment>,
+    /// The responses to the current batch of tasks, in the order of `tasks`. Each comes with the
+    /// ID of the worker that sent it.
+    resps: Vec<Option<(usize, WorkerMsg<E>)>>,
+    num_missing_resps: usize,
+    /// The connected workers, by ID. These handles are used to hang up on them at shutdown.
+    workers: BTreeMap<usize, TcpStream>,
+    /// The IDs of the workers that are working on a task, possibly from an earlier batch
+    busy_workers: BTreeSet<usize>,
+    next_worker_id: usize,
+    done: bool,
+}
+
+struct Assignment {
+    /// The position of the task in the batch
+    task_idx: usize,
+    worker_id: usize,
+    /// When the task gets handed to another worker. This is `None` if there's no timeout, or if
+    /// it's already been handed to another worker.
+    deadline: Option<Instant>,
+}
+
+// Not derived, since that would require E: Default
+impl<E: Pairing> Default for DispatchState<E> {
+    fn default() -> Self {
+        DispatchState {
+            batch: 0,
+            tasks: Vec::new(),
+            queue: VecDeque::new(),
+            assignments: Vec::new(),
+            resps: Vec::new(),
+            num_missing_resps: 0,
+            workers: BTreeMap::new(),
+            busy_workers: BTreeSet::new(),
+            next_worker_id: 0,
+            done: false,
+        }
+    }
+}
+
+impl<E: Pairing> DispatchState<E> {
+    /// Puts the given task back in the queue, unless it's already answered or waiting there
+    fn requeue(&mut self, idx: usize) {
+        if self.resps[idx].is_none() && !self.queue.contains(&idx) {
+            self.queue.push_back(idx);
+        }
+    }
+
+    /// Requeues every task whose deadline has passed. Returns whether there were any.
+    fn requeue_expired(&mut self, now: Instant) -> bool {
+        let mut expired = Vec::new();
+        for a in self.assignments.iter_mut() {
+            if a.deadline.is_some_and(|d| d <= now) {
+                println!(
+                    "Worker {} timed out on subcircuit {}. Reassigning it",
+                    a.worker_id, self.tasks[a.task_idx].0
+                );
+                a.deadline = None;
+                expired.push(a.task_idx);
+            }
+        }
+
+        for &idx in &expired {
+            self.requeue(idx);
+        }
+        !expired.is_empty()
+    }
+
+    /// Removes the assignment of the given task to the given worker
+    fn unassign(&mut self, worker_id: usize, idx: usize) {
+        self.assignments
+            .retain(|a| (a.worker_id, a.task_idx) != (worker_id, idx));
+    }
+}
+
+impl<E: Pairing> Dispatcher<E> {
+    /// Makes a dispatcher that reassigns a task if it isn't answered within `task_timeout`
+    pub fn new(task_timeout: Option<Duration>) -> Arc<Self> {
+        Arc::new(Dispatcher {
+            state: Mutex::new(DispatchState::default()),
+            cond: Condvar::new(),
+            task_timeout,
+        })
+    }
+
+    /// Accepts workers on the given listener in the background. Each worker is served on its own
+    /// thread.
+    pub fn listen(self: &Arc<Self>, listener: TcpListener) {
+        let dispatcher = Arc::clone(self);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let (stream, handle) = match stream.and_then(|s| s.try_clone().map(|c| (s, c))) {
+                    Ok(s) => s,
+                    Err(e) => {
+                        println!("Failed to accept worker: {e}");
+                        continue;
+                    },
+                };
+
+                let worker_id = {
+                    let mut state = dispatcher.state.lock().unwrap();
+                    let id = state.next_worker_id;
+                    state.next_worker_id += 1;
+                    state.workers.insert(id, handle);
+                    id
+                };
+                let dispatcher = Arc::clone(&dispatcher);
+                thread::spawn(move || {
+                    let peer = stream.peer_addr();
+                    if let Err(e) = dispatcher.serve_worker(worker_id, stream) {
+                        println!("Dropped worker {worker_id} at {peer:?}: {e}");
+                    }
+
+                    dispatcher.state.lock().unwrap().workers.remove(&worker_id);
+                    dispatcher.cond.notify_all();
+                });
+            }
+        });
+    }
+
+    /// Hands out the given tasks, where `tasks[i]` is for subcircuit `i`. Blocks until every task
+    /// has a response, and returns the responses in the same order.
+    pub fn run(&self, tasks: Vec<Vec<u8>>) -> Vec<WorkerMsg<E>> {
+        self.run_some(tasks.into_iter().enumerate().collect())
+            .into_iter()
+            .map(|(_, resp)| resp)
+            .collect()
+    }
+
+    /// Hands out the given tasks, each for the given subcircuit. Blocks until every task has a
+    /// response, and returns the responses in the same order, each with the ID of the worker that
+    /// sent it.
+    pub fn run_some(&self, tasks: Vec<(usize, Vec<u8>)>) -> Vec<(usize, WorkerMsg<E>)> {
+        let mut state = self.state.lock().unwrap();
+        assert!(!state.done, "the dispatcher is shut down");
+
+        state.batch += 1;
+        state.resps = tasks.iter().map(|_| None).collect();
+        state.num_missing_resps = tasks.len();
+        state.queue = (0..tasks.len()).collect();
+        state.tasks = tasks
+            .into_iter()
+            .map(|(subcircuit_idx, task)| (subcircuit_idx, Arc::new(task)))
+            .collect();
+        state.assignments.clear();
+        self.cond.notify_all();
+
+        while state.num_missing_resps > 0 {
+            let now = Instant::now();
+            if state.requeue_expired(now) {
+                self.cond.notify_all();
+            }
+
+            // Wake up in time for the next deadline
+            let next_deadline = state.assignments.iter().filter_map(|a| a.deadline).min();
+            state = match next_deadline {
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(now);
+                    self.cond.wait_timeout(state, timeout).unwrap().0
+                },
+                None => self.cond.wait(state).unwrap(),
+            };
+        }
+
+        state.assignments.clear();
+        state.tasks.clear();
+        state.resps.drain(..).map(Option::unwrap).collect()
+    }
+
+    /// Hangs up on the given worker, e.g., because it sent an invalid response. It gets no more
+    /// tasks, and any task it's working on is handed to another worker.
+    pub fn drop_worker(&self, worker_id: usize) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(stream) = state.workers.remove(&worker_id) {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        self.cond.notify_all();
+    }
+
+    /// The number of workers that are connected and haven't been dropped
+    pub fn num_workers(&self) -> usize {
+        self.state.lock().unwrap().workers.len()
+    }
+
+    /// Tells every worker that there is no more work, and waits for them all to disconnect.
+    /// Workers that are still busy with a task that someone else already did are hung up on.
+    pub fn shutdown(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.done = true;
+        // A dropped worker may still be marked busy until its thread notices
+        for id in &state.busy_workers {
+            if let Some(stream) = state.workers.get(id) {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        }
+        self.cond.notify_all();
+
+        while !state.workers.is_empty() {
+            state = self.cond.wait(state).unwrap();
+        }
+    }
+
+    fn serve_worker(&self, worker_id: usize, mut stream: TcpStream) -> io::Result<()> {
+        match WorkerMsg::<E>::decode(&read_frame(&mut stream, MAX_RESPONSE_LEN)?) {
+            Ok(WorkerMsg::Ready) => (),
+            _ => return Err(invalid_data("expected a ready message".to_string())),
+        }
+
+        while let Some((batch, idx, subcircuit_idx, task)) = self.next_task(worker_id) {
+            let resp = write_frame(&mut stream, &task)
+                .and_then(|()| read_frame(&mut stream, MAX_RESPONSE_LEN))
+                .and_then(|bytes| {
+                    WorkerMsg::<E>::decode(&bytes).map_err(|e| invalid_data(e.to_string()))
+                })
+                .and_then(|resp| match resp.answers(subcircuit_idx, &task) {
+                    true => Ok(resp),
+                    false => Err(invalid_data(format!(
+                        "bad response to the task for subcircuit {subcircuit_idx}"
+                    ))),
+                });
+
+            let mut state = self.state.lock().unwrap();
+            state.busy_workers.remove(&worker_id);
+            if state.batch != batch || state.tasks.is_empty() {
+                // This is a late answer to a batch that's already finished
+                resp?;
+                continue;
+            }
+            state.unassign(worker_id, idx);
+            match resp {
+                Ok(resp) => {
+                    if state.resps[idx].is_none() {
+                        state.resps[idx] = Some((worker_id, resp));
+                        state.num_missing_resps -= 1;
+                    }
+                    self.cond.notify_all();
+                },
+                Err(e) => {
+                    // Let another worker do it
+                    state.requeue(idx);
+                    self.cond.notify_all();
+                    return Err(e);
+                },
+            }
+        }
+
+        write_frame(&mut stream, &[DONE_TAG])
+    }
+
+    /// Blocks until there's a task to hand out, and assigns it to the given worker. Returns the
+    /// batch, the position of the task in it, its subcircuit index, and the task. Returns `None`
+    /// once the dispatcher is shut down, or once the worker is dropped.
+    fn next_task(&self, worker_id: usize) -> Option<(usize, usize, usize, Arc<Vec<u8>>)> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.done || !state.workers.contains_key(&worker_id) {
+                return None;
+            }
+
+            // Skip the tasks that were answered while waiting in the queue
+            while let Some(idx) = state.queue.pop_front() {
+                if state.resps[idx].is_none() {
+                    state.busy_workers.insert(worker_id);
+                    state.assignments.push(Assignment {
+                        task_idx: idx,
+                        worker_id,
+                        deadline: self.task_timeout.map(|t| Instant::now() + t),
+                    });
+                    // Let `run_some` know about the new deadline
+                    self.cond.notify_all();
+                    let (subcircuit_idx, task) = &state.tasks[idx];
+                    return Some((state.batch, idx, *subcircuit_idx, Arc::clone(task)));
+                }
+            }
+
+            state = self.cond.wait(state).unwrap();
+        }
+    }
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use distributed_prover::tree_hash_circuit::MerkleTreeCircuit;
+
+    use ark_bls12_381::Bls12_381 as E;
+
+    use std::{net::SocketAddr, sync::mpsc};
+
+    /// What a test worker does with the first task it gets
+    #[derive(Clone, Copy)]
+    enum Behavior {
+        Answer,
+        HangUp,
+        Stall,
+        /// Send an invalid response, i.e., one with a nonzero commitment seed
+        Lie,
+    }
+
+    /// Spawns a worker that answers stage0 tasks with dummy responses. It signals on `got_task`
+    /// when it gets its first task. Returns the number of tasks it answered.
+    fn spawn_worker(
+        addr: SocketAddr,
+        behavior: Behavior,
+        got_task: mpsc::Sender<()>,
+    ) -> thread::JoinHandle<usize> {
+        thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            write_frame(&mut stream, &WorkerMsg::<E>::Ready.encode()).unwrap();
+
+            let mut num_tasks = 0;
+            loop {
+                let task = read_frame(&mut stream, MAX_TASK_LEN).unwrap();
+                let req = match Task::<E, MerkleTreeCircuit>::decode(&task).unwrap() {
+                    Task::Stage0(req) => req,
+                    Task::Round(..) | Task::Stage1(..) => panic!("unexpected task"),
+                    Task::Done => return num_tasks,
+                };
+                let _ = got_task.send(());
+
+                match behavior {
+                    Behavior::Answer => (),
+                    Behavior::HangUp => return num_tasks,
+                    // Wait until the coordinator hangs up
+                    Behavior::Stall => {
+                        assert!(read_frame(&mut stream, MAX_TASK_LEN).is_err());
+                        return num_tasks;
+                    },
+                    // Ignore any more tasks until the coordinator hangs up
+                    Behavior::Lie => {
+                        let resp = Stage0Response::<E> {
+                            subcircuit_idx: req.subcircuit_idx,
+                            com_seed: [1u8; 32],
+                            ..Stage0Response::dummy()
+                        };
+                        write_frame(&mut stream, &WorkerMsg::Stage0(resp).encode()).unwrap();
+                        while read_frame(&mut stream, MAX_TASK_LEN).is_ok() {}
+                        return num_tasks;
+                    },
+                }
+
+                let resp = Stage0Response::<E> {
+                    subcircuit_idx: req.subcircuit_idx,
+                    ..Stage0Response::dummy()
+                };
+                write_frame(&mut stream, &WorkerMsg::Stage0(resp).encode()).unwrap();
+                num_tasks += 1;
+            }
+        })
+    }
+
+    // Runs a dispatcher on localhost where the first worker to connect misbehaves on its first
+    // task, and two more workers answer everything. Checks that every task gets answered exactly
+    // once.
+    fn run_dispatch(task_timeout: Option<Duration>, first_worker: Behavior) {
+        let num_subcircuits = 16;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let dispatcher = Dispatcher::<E>::new(task_timeout);
+        dispatcher.listen(listener);
+
+        let tasks = (0..num_subcircuits)
+            .map(|i| {
+                let mut req = Stage0Request::<E>::empty();
+                req.subcircuit_idx = i;
+                encode_stage0_task(&req.to_ref())
+            })
+            .collect::<Vec<_>>();
+        let run = {
+            let dispatcher = Arc::clone(&dispatcher);
+            thread::spawn(move || {
+                // Like the tcp_node coordinator, drop the worker of every invalid response and hand
+                // its task out again
+                let mut resps =
+                    dispatcher.run_some(tasks.clone().into_iter().enumerate().collect());
+                loop {
+                    let invalid = (0..num_subcircuits)
+                        .filter(|&i| match &resps[i].1 {
+                            WorkerMsg::Stage0(resp) => resp.com_seed != [0u8; 32],
+                            _ => true,
+                        })
+                        .collect::<Vec<_>>();
+                    if invalid.is_empty() {
+                        break;
+                    }
+
+                    for &i in &invalid {
+                        dispatcher.drop_worker(resps[i].0);
+                    }
+                    let redos = invalid.iter().map(|&i| (i, tasks[i].clone())).collect();
+                    for (&i, resp) in invalid.iter().zip(dispatcher.run_some(redos)) {
+                        resps[i] = resp;
+                    }
+                }
+                resps.into_iter().map(|(_, resp)| resp).collect::<Vec<_>>()
+            })
+        };
+
+        // Make sure the first worker gets a task before the others connect
+        let (tx, rx) = mpsc::channel();
+        let mut workers = vec![spawn_worker(addr, first_worker, tx.clone())];
+        rx.recv().unwrap();
+        workers.extend((0..2).map(|_| spawn_worker(addr, Behavior::Answer, tx.clone())));
+
+        let resps = run.join().unwrap();
+        dispatcher.shutdown();
+
+        for (i, resp) in resps.into_iter().enumerate() {
+            assert_eq!(resp.into_stage0().unwrap().subcircuit_idx, i);
+        }
+        let num_tasks = workers
+            .into_iter()
+            .map(|w| w.join().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(num_tasks[0], 0);
+        assert_eq!(num_tasks.iter().sum::<usize>(), num_subcircuits);
+    }
+
+    // A task whose worker hangs up must be done by another worker
+    #[test]
+    fn test_dispatch_hang_up() {
+        run_dispatch(None, Behavior::HangUp);
+    }
+
+    // A task whose worker doesn't answer in time must be done by another worker, and the stalled
+    // worker must be hung up on at shutdown
+    #[test]
+    fn test_dispatch_timeout() {
+        run_dispatch(Some(Duration::from_millis(200)), Behavior::Stall);
+    }
+
+    // A task whose worker sends an invalid response must be done by another worker, and the
+    // worker that sent it must be hung up on. The liar doesn't answer the tasks it gets after its
+    // lie, so those are reassigned once they time out.
+    #[test]
+    fn test_dispatch_invalid_response() {
+        run_dispatch(Some(Duration::from_millis(200)), Behavior::Lie);
+    }
+
+    #[test]
+    fn test_frame_too_long() {
+        let mut bytes = (MAX_RESPONSE_LEN + 1).to_le_bytes().to_vec();
+        bytes.extend_from_slice(&[0u8; 16]);
+        assert!(read_frame(bytes.as_slice(), MAX_RESPONSE_LEN).is_err());
+
+        // A frame that's shorter than its length says is an error, and isn't allocated up front
+        let mut bytes = MAX_TASK_LEN.to_le_bytes().to_vec();
+        bytes.extend_from_slice(b"hello");
+        assert!(read_frame(bytes.as_slice(), MAX_TASK_LEN).is_err());
+
+        let mut buf = Vec::new();
+        write_frame(&mut buf, b"hello").unwrap();
+        assert_eq!(
+            read_frame(buf.as_slice(), MAX_RESPONSE_LEN).unwrap(),
+            b"hello"
+        );
+    }
+}
@@ -0,0 +1,440 @@
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    time::{Duration, Instant},
+};
+
+/// Which subcircuits each worker proves. Subcircuits can differ a lot in cost, e.g., the leaves and
+/// the root of a tree, so handing every worker the same number of them can leave most of the
+/// workers waiting on the one with the costly subcircuits.
+pub struct WorkPlan {
+    /// The subcircuits of each worker, in increasing order
+    assignments: Vec<Vec<usize>>,
+}
+
+impl WorkPlan {
+    /// Assigns the subcircuits longest-processing-time first: the costliest subcircuit goes to the
+    /// least loaded worker, then the next costliest, and so on. `cost` gives the cost of each
+    /// subcircuit. The plan only depends on the costs, so every run with the same keys gets the
+    /// same plan.
+    pub fn lpt(num_subcircuits: usize, num_workers: usize, cost: impl Fn(usize) -> usize) -> Self {
+        assert!(num_workers > 0, "there are no workers");
+
+        // Costliest first. Equal costs go in order of index
+        let mut order = (0..num_subcircuits).collect::<Vec<_>>();
+        order.sort_by_key(|&i| (Reverse(cost(i)), i));
+
+        // The workers by load. Of the workers with the least load, the one with the fewest
+        // subcircuits comes first, so that free subcircuits are spread out too
+        let mut workers = (0..num_workers)
+            .map(|w| Reverse((0, 0, w)))
+            .collect::<BinaryHeap<_>>();
+        let mut assignments = vec![Vec::new(); num_workers];
+        for i in order {
+            let Reverse((load, num_assigned, w)) = workers.pop().unwrap();
+            assignments[w].push(i);
+            workers.push(Reverse((load + cost(i), num_assigned + 1, w)));
+        }
+
+        for subcircuits in &mut assignments {
+            subcircuits.sort_unstable();
+        }
+        WorkPlan { assignments }
+    }
+
+    pub fn num_workers(&self) -> usize {
+        self.assignments.len()
+    }
+
+    pub fn num_subcircuits(&self) -> usize {
+        self.assignments.iter().map(Vec::len).sum()
+    }
+
+    /// Returns the subcircuits of the given worker, in increasing order
+    pub fn subcircuits(&self, worker: usize) -> &[usize] {
+        &self.assignments[worker]
+    }
+
+    /// Returns the number of subcircuits of each worker
+    pub fn counts(&self) -> Vec<usize> {
+        self.assignments.iter().map(Vec::len).collect()
+    }
+
+    /// Returns the worker of each subcircuit, indexed by subcircuit
+    pub fn workers_by_subcircuit(&self) -> Vec<usize> {
+        let mut workers = vec![0; self.num_subcircuits()];
+        for (w, subcircuits) in self.assignments.iter().enumerate() {
+            for &i in subcircuits {
+                workers[i] = w;
+            }
+        }
+        workers
+    }
+
+    /// Puts responses back in order of subcircuit. `per_worker` has every worker's responses, in
+    /// the order of `subcircuits`.
+    pub fn reorder<T>(&self, per_worker: Vec<Vec<T>>) -> Vec<T> {
+        assert_eq!(
+            per_worker.len(),
+            self.num_workers(),
+            "wrong number of workers"
+        );
+
+        let mut slots = std::iter::repeat_with(|| None)
+            .take(self.num_subcircuits())
+            .collect::<Vec<_>>();
+        for (subcircuits, resps) in self.assignments.iter().zip(per_worker) {
+            assert_eq!(subcircuits.len(), resps.len(), "wrong number of responses");
+            for (&i, resp) in subcircuits.iter().zip(resps) {
+                slots[i] = Some(resp);
+            }
+        }
+        slots.into_iter().map(Option::unwrap).collect()
+    }
+
+    /// Returns the total cost of each worker's subcircuits
+    pub fn loads(&self, cost: impl Fn(usize) -> usize) -> Vec<usize> {
+        self.assignments
+            .iter()
+            .map(|subcircuits| subcircuits.iter().map(|&i| cost(i)).sum())
+            .collect()
+    }
+}
+
+/// Who is working on which subcircuits of a stage, and what happens to the workers that stall. A
+/// worker that misses its deadline is dropped: it gets no more work, in this stage or any later
+/// one, and its subcircuits are queued. Queued subcircuits are handed to the live workers that
+/// are done with their own. A worker has at most one batch of subcircuits at a time, so a message
+/// from a worker is always the answer to its current batch.
+pub struct Assignments {
+    /// How long a worker gets for a batch. If `None`, workers are never dropped for being slow.
+    task_timeout: Option<Duration>,
+    workers: Vec<WorkerStatus>,
+    /// The batch each worker is working on, if any
+    batches: Vec<Option<Batch>>,
+    /// The worker that answered each subcircuit of the current stage, if any
+    answered_by: Vec<Option<usize>>,
+    /// The subcircuits of the current stage that are waiting to be handed out
+    queue: Vec<usize>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum WorkerStatus {
+    Live,
+    /// Dropped while it had a batch, and not heard from since. It may still be working on it.
+    Stalled,
+    Dropped,
+}
+
+struct Batch {
+    /// The subcircuits, in the order they were sent
+    subcircuits: Vec<usize>,
+    deadline: Option<Instant>,
+}
+
+impl Assignments {
+    pub fn new(num_workers: usize, task_timeout: Option<Duration>) -> Self {
+        Assignments {
+            task_timeout,
+            workers: vec![WorkerStatus::Live; num_workers],
+            batches: std::iter::repeat_with(|| None).take(num_workers).collect(),
+            answered_by: Vec::new(),
+            queue: Vec::new(),
+        }
+    }
+
+    /// Starts a stage where every live worker gets the subcircuits `plan` gives it. The
+    /// subcircuits of the dropped workers are queued. Returns the batches to send.
+    pub fn start_stage(&mut self, plan: &WorkPlan, now: Instant) -> Vec<(usize, Vec<usize>)> {
+        assert_eq!(
+            plan.num_workers(),
+            self.workers.len(),
+            "wrong number of workers"
+        );
+        self.start_stage_queued(plan.num_subcircuits(), Vec::new());
+
+        let mut batches = Vec::new();
+        for w in 0..self.workers.len() {
+            let subcircuits = plan.subcircuits(w).to_vec();
+            if self.workers[w] != WorkerStatus::Live {
+                self.queue.extend(subcircuits);
+            } else if !subcircuits.is_empty() {
+                self.assign(w, subcircuits.clone(), now);
+                batches.push((w, subcircuits));
+            }
+        }
+        self.queue.sort_unstable();
+        batches
+    }
+
+    /// Starts a stage of just the given subcircuits, all queued, e.g., because the workers don't
+    /// have the state that the plan's batches need. Use `reassign` to hand them out.
+    pub fn start_stage_queued(&mut self, num_subcircuits: usize, subcircuits: Vec<usize>) {
+        assert!(
+            self.batches.iter().all(Option::is_none),
+            "the last stage isn't done"
+        );
+        self.answered_by = vec![None; num_subcircuits];
+        self.queue = subcircuits;
+    }
+
+    /// Records that the given worker answered its batch, and returns the subcircuits of the batch,
+    /// in the order they were sent. Returns `None` if the worker has no batch, e.g., because it
+    /// was dropped. Such an answer should be ignored.
+    pub fn complete(&mut self, worker: usize) -> Option<Vec<usize>> {
+        if self.workers[worker] == WorkerStatus::Stalled {
+            self.workers[worker] = WorkerStatus::Dropped;
+        }
+        let batch = self.batches[worker].take()?;
+        for &i in &batch.subcircuits {
+            self.answered_by[i] = Some(worker);
+        }
+        Some(batch.subcircuits)
+    }
+
+    /// Drops the given worker, e.g., because it sent a malformed response. It gets no more work,
+    /// and the subcircuits of its batch, if it has one, are queued.
+    pub fn drop_worker(&mut self, worker: usize) {
+        if self.workers[worker] != WorkerStatus::Live {
+            return;
+        }
+        self.workers[worker] = match self.batches[worker].take() {
+            Some(batch) => {
+                self.queue.extend(batch.subcircuits);
+                WorkerStatus::Stalled
+            },
+            None => WorkerStatus::Dropped,
+        };
+    }
+
+    /// Takes back the response to the given subcircuit, e.g., because it's invalid, and queues
+    /// the subcircuit again. Returns the worker that answered it.
+    pub fn requeue(&mut self, subcircuit: usize) -> Option<usize> {
+        let worker = self.answered_by[subcircuit].take();
+        if worker.is_some() {
+            self.queue.push(subcircuit);
+        }
+        worker
+    }
+
+    /// Drops every worker that has missed the deadline of its batch. Returns the dropped workers.
+    pub fn expire(&mut self, now: Instant) -> Vec<usize> {
+        let expired = (0..self.workers.len())
+            .filter(|&w| {
+                self.batches[w]
+                    .as_ref()
+                    .and_then(|b| b.deadline)
+                    .is_some_and(|d| d <= now)
+            })
+            .collect::<Vec<_>>();
+        for &w in &expired {
+            self.drop_worker(w);
+        }
+        expired
+    }
+
+    /// Hands the queued subcircuits to the live workers that have no batch, longest-processing-time
+    /// first by `cost`. Returns the new batches. If every live worker is busy, the subcircuits stay
+    /// queued.
+    pub fn reassign(
+        &mut self,
+        now: Instant,
+        cost: impl Fn(usize) -> usize,
+    ) -> Vec<(usize, Vec<usize>)> {
+        let idle = (0..self.workers.len())
+            .filter(|&w| self.workers[w] == WorkerStatus::Live && self.batches[w].is_none())
+            .collect::<Vec<_>>();
+        if self.queue.is_empty() || idle.is_empty() {
+            return Vec::new();
+        }
+
+        let queue = std::mem::take(&mut self.queue);
+        let plan = WorkPlan::lpt(queue.len(), idle.len(), |j| cost(queue[j]));
+        let mut batches = Vec::new();
+        for (k, &w) in idle.iter().enumerate() {
+            let subcircuits = plan
+                .subcircuits(k)
+                .iter()
+                .map(|&j| queue[j])
+                .collect::<Vec<_>>();
+            if !subcircuits.is_empty() {
+                self.assign(w, subcircuits.clone(), now);
+                batches.push((w, subcircuits));
+            }
+        }
+        batches
+    }
+
+    fn assign(&mut self, worker: usize, subcircuits: Vec<usize>, now: Instant) {
+        assert!(self.batches[worker].is_none(), "worker {worker} is busy");
+        self.batches[worker] = Some(Batch {
+            subcircuits,
+            deadline: self.task_timeout.map(|t| now + t),
+        });
+    }
+
+    /// Returns whether every subcircuit of the current stage has a response
+    pub fn is_stage_done(&self) -> bool {
+        self.queue.is_empty() && self.batches.iter().all(Option::is_none)
+    }
+
+    /// Returns the worker that answered the given subcircuit in the current stage, if any
+    pub fn answered_by(&self, subcircuit: usize) -> Option<usize> {
+        self.answered_by[subcircuit]
+    }
+
+    /// Returns the earliest deadline of any batch, if there are any
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.batches
+            .iter()
+            .flatten()
+            .filter_map(|b| b.deadline)
+            .min()
+    }
+
+    pub fn is_live(&self, worker: usize) -> bool {
+        self.workers[worker] == WorkerStatus::Live
+    }
+
+    pub fn num_live(&self) -> usize {
+        (0..self.workers.len()).filter(|&w| self.is_live(w)).count()
+    }
+
+    /// Returns the workers that were dropped while they had a batch, and haven't been heard from
+    /// since. They may still be working.
+    pub fn stalled_workers(&self) -> Vec<usize> {
+        (0..self.workers.len())
+            .filter(|&w| self.workers[w] == WorkerStatus::Stalled)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A few costly subcircuits and many cheap ones should be spread so no worker is stuck with
+    // more than one costly subcircuit
+    #[test]
+    fn test_lpt_balances_uneven_costs() {
+        // Subcircuits 0 and 1 are costly, like the root of a tree. The rest are cheap leaves
+        let cost = |i: usize| if i < 2 { 100 } else { 10 };
+        let plan = WorkPlan::lpt(22, 4, cost);
+
+        let loads = plan.loads(cost);
+        assert_eq!(loads.iter().sum::<usize>(), 400);
+        assert_eq!(loads, vec![100; 4]);
+        assert_eq!(plan.subcircuits(0)[0], 0);
+        assert_eq!(plan.subcircuits(1)[0], 1);
+        assert_eq!(plan.counts(), vec![1, 1, 10, 10]);
+    }
+
+    // Equal costs give every worker the same number of subcircuits, even if the costs are zero
+    #[test]
+    fn test_lpt_equal_costs() {
+        for cost in [0, 7] {
+            let plan = WorkPlan::lpt(12, 4, |_| cost);
+            assert_eq!(plan.counts(), vec![3; 4]);
+        }
+
+        // Leftover subcircuits go to different workers
+        let plan = WorkPlan::lpt(6, 4, |_| 1);
+        assert_eq!(plan.counts(), vec![2, 2, 1, 1]);
+    }
+
+    // Responses come back in subcircuit order
+    #[test]
+    fn test_reorder() {
+        let cost = |i: usize| 10 - i;
+        let plan = WorkPlan::lpt(10, 3, cost);
+
+        let workers = plan.workers_by_subcircuit();
+        let per_worker = (0..plan.num_workers())
+            .map(|w| plan.subcircuits(w).to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(plan.reorder(per_worker), (0..10).collect::<Vec<_>>());
+        for (i, &w) in workers.iter().enumerate() {
+            assert!(plan.subcircuits(w).contains(&i));
+        }
+    }
+
+    // A worker that misses its deadline is dropped, its subcircuits go to a worker that's done,
+    // and its late answer is ignored
+    #[test]
+    fn test_assignments_timeout() {
+        let timeout = Duration::from_secs(10);
+        let plan = WorkPlan::lpt(6, 3, |_| 1);
+        let mut assignments = Assignments::new(3, Some(timeout));
+
+        let start = Instant::now();
+        let batches = assignments.start_stage(&plan, start);
+        assert_eq!(batches.len(), 3);
+        assert_eq!(assignments.next_deadline(), Some(start + timeout));
+
+        // Workers 0 and 1 answer, worker 2 stalls
+        assert_eq!(assignments.complete(0).unwrap(), plan.subcircuits(0));
+        assert_eq!(assignments.complete(1).unwrap(), plan.subcircuits(1));
+        assert!(assignments.expire(start + timeout / 2).is_empty());
+        assert_eq!(assignments.expire(start + timeout), vec![2]);
+        assert_eq!(assignments.stalled_workers(), vec![2]);
+
+        // Its subcircuits are split between the other two
+        let later = start + timeout;
+        let batches = assignments.reassign(later, |_| 1);
+        assert_eq!(batches.len(), 2);
+        assert!(batches.iter().all(|(w, b)| *w != 2 && b.len() == 1));
+        assert_eq!(assignments.next_deadline(), Some(later + timeout));
+
+        // The late answer doesn't count
+        assert!(assignments.complete(2).is_none());
+        assert!(assignments.stalled_workers().is_empty());
+        assert!(!assignments.is_stage_done());
+        for (w, _) in batches {
+            assignments.complete(w).unwrap();
+        }
+        assert!(assignments.is_stage_done());
+        for &i in plan.subcircuits(2) {
+            assert_ne!(assignments.answered_by(i), Some(2));
+        }
+
+        // The dropped worker gets nothing in the next stage
+        let batches = assignments.start_stage(&plan, later);
+        assert!(batches.iter().all(|(w, _)| *w != 2));
+        assert_eq!(assignments.num_live(), 2);
+        assert!(assignments.reassign(later, |_| 1).is_empty());
+        for w in 0..2 {
+            assignments.complete(w).unwrap();
+        }
+        assert_eq!(assignments.reassign(later, |_| 1).len(), 2);
+    }
+
+    // A rejected response is queued again, and only goes to a live worker
+    #[test]
+    fn test_assignments_requeue() {
+        let plan = WorkPlan::lpt(4, 2, |_| 1);
+        let mut assignments = Assignments::new(2, None);
+
+        let now = Instant::now();
+        assignments.start_stage(&plan, now);
+        assert_eq!(assignments.next_deadline(), None);
+        assignments.complete(0).unwrap();
+        assignments.complete(1).unwrap();
+        assert!(assignments.is_stage_done());
+
+        let bad = plan.subcircuits(1)[0];
+        assert_eq!(assignments.requeue(bad), Some(1));
+        assignments.drop_worker(1);
+        assert!(!assignments.is_stage_done());
+        assert_eq!(assignments.reassign(now, |_| 1), vec![(0, vec![bad])]);
+        assignments.complete(0).unwrap();
+        assert_eq!(assignments.answered_by(bad), Some(0));
+        assert!(assignments.is_stage_done());
+
+        // A stage of just that subcircuit
+        assignments.start_stage_queued(4, vec![bad]);
+        assert!(!assignments.is_stage_done());
+        assert_eq!(assignments.reassign(now, |_| 1), vec![(0, vec![bad])]);
+        assignments.complete(0).unwrap();
+        assert!(assignments.is_stage_done());
+    }
+}
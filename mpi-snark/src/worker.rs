@@ -1,57 +1,68 @@
 use crate::data_structures::{
-    G16Com, G16ComRandomness, ProvingKeys, Stage0RequestRef, Stage0Response, Stage1RequestRef,
-    Stage1Response,
+    G16Com, G16ComRandomness, ProvingKeys, RoundRequest, RoundResponse, Stage0RequestRef,
+    Stage0Response, Stage1RequestRef, Stage1Response, SupportedCurve, TreeConfig, TreeConfigVar,
 };
 
 use distributed_prover::{
     eval_tree::ExecTreeParams,
-    poseidon_util::{
-        gen_merkle_params, PoseidonTreeConfig as TreeConfig, PoseidonTreeConfigVar as TreeConfigVar,
-    },
+    poseidon_util::gen_merkle_params,
     subcircuit_circuit::SubcircuitWithPortalsProver,
     util::QAP,
-    worker::{process_stage0_request_get_cb, process_stage1_request_with_cb},
+    worker::{
+        process_round_request, process_round_request_with_cb, process_stage0_request_get_cb,
+        process_stage1_request_with_cb, process_stage1_request_with_rounds,
+    },
     CircuitWithPortals,
 };
 
-use ark_bls12_381::{Bls12_381 as E, Fr};
 use ark_cp_groth16::committer::CommitmentBuilder as G16CommitmentBuilder;
+use ark_ec::pairing::Pairing;
 use ark_ff::UniformRand;
 use rand::{Rng, SeedableRng};
 
-type CommitterState<'a, P> =
-    G16CommitmentBuilder<'a, SubcircuitWithPortalsProver<Fr, P, TreeConfig, TreeConfigVar>, E, QAP>;
+type CommitterState<'a, E, P> = G16CommitmentBuilder<
+    'a,
+    SubcircuitWithPortalsProver<<E as Pairing>::ScalarField, P, TreeConfig<E>, TreeConfigVar<E>>,
+    E,
+    QAP,
+>;
 
-pub struct WorkerState<'a, P: CircuitWithPortals<Fr>> {
-    g16_pks: &'a ProvingKeys,
-    tree_params: ExecTreeParams<TreeConfig>,
-    cb: Option<CommitterState<'a, P>>,
-    com: G16Com,
-    com_rand: G16ComRandomness,
+pub struct WorkerState<'a, E: SupportedCurve, P: CircuitWithPortals<E::ScalarField>> {
+    g16_pks: &'a ProvingKeys<E>,
+    tree_params: ExecTreeParams<TreeConfig<E>>,
+    cb: Option<CommitterState<'a, E, P>>,
+    /// The commitments of stage 0 and of the extra rounds done so far
+    coms: Vec<G16Com<E>>,
+    /// The randomness of the above commitments
+    com_rands: Vec<G16ComRandomness<E>>,
     #[allow(unused)]
     num_subcircuits: usize,
 }
 
-impl<'a, P: CircuitWithPortals<Fr>> WorkerState<'a, P> {
-    pub fn new(num_subcircuits: usize, g16_pks: &'a ProvingKeys) -> Self {
+impl<'a, E: SupportedCurve, P: CircuitWithPortals<E::ScalarField>> WorkerState<'a, E, P> {
+    pub fn new(num_subcircuits: usize, g16_pks: &'a ProvingKeys<E>) -> Self {
         let tree_params = gen_merkle_params();
         WorkerState {
             g16_pks,
             tree_params,
             cb: None,
-            com: G16Com::default(),
-            com_rand: G16ComRandomness::default(),
+            coms: Vec::new(),
+            com_rands: Vec::new(),
             num_subcircuits,
         }
     }
 
-    pub fn stage_0(&mut self, mut rng: impl Rng, stage0_req: &Stage0RequestRef) -> Stage0Response {
+    pub fn stage_0(
+        &mut self,
+        mut rng: impl Rng,
+        stage0_req: &Stage0RequestRef<E>,
+    ) -> Stage0Response<E> {
         let subcircuit_idx = stage0_req.subcircuit_idx;
         let g16_pk = self.g16_pks.get_pk(subcircuit_idx);
 
         // Process the request. This returns the response and the commitment builder. Save the
         // builder as state
-        let (resp, cb) = process_stage0_request_get_cb::<_, TreeConfigVar, _, P, _>(
+        let (resp, cb) = process_stage0_request_get_cb::<_, TreeConfigVar<E>, _, P, _>(
             &mut rng,
             self.tree_params.clone(),
             g16_pk,
@@ -62,24 +73,91 @@ impl<'a, P: CircuitWithPortals<Fr>> WorkerState<'a, P> {
         let com = resp.com;
         let com_rand = {
             let mut subcircuit_rng = rand_chacha::ChaCha12Rng::from_seed(resp.com_seed);
-            Fr::rand(&mut subcircuit_rng)
+            E::ScalarField::rand(&mut subcircuit_rng)
         };
 
         // Now set the local values
         self.cb = Some(cb);
-        self.com = com;
-        self.com_rand = com_rand;
+        self.coms = vec![com];
+        self.com_rands = vec![com_rand];
 
         resp
     }
 
-    pub fn stage_1(self, mut rng: impl Rng, stage1_req: &Stage1RequestRef<P>) -> Stage1Response {
+    /// Does the next extra round of the circuit, with the commitment builder from `stage_0`. The
+    /// rounds MUST be done in order.
+    pub fn round(&mut self, mut rng: impl Rng, round_req: &RoundRequest<E, P>) -> RoundResponse<E> {
+        let cb = self.cb.as_mut().expect("stage 0 isn't done");
+        assert_eq!(
+            round_req.round,
+            self.coms.len() - 1,
+            "the rounds are out of order"
+        );
+
+        let (resp, com_rand) = process_round_request_with_cb(&mut rng, cb, round_req.clone());
+        self.coms.push(resp.com);
+        self.com_rands.push(com_rand);
+
+        resp
+    }
+
+    /// Does an extra round of a subcircuit whose earlier stages may have been done by a different
+    /// worker. Like `stage_1_from_seed`, the commitments of stage 0 and the earlier rounds are
+    /// recomputed from the seeds in their responses.
+    pub fn round_from_seed(
+        &self,
+        mut rng: impl Rng,
+        stage0_req: &Stage0RequestRef<E>,
+        stage0_resp: &Stage0Response<E>,
+        earlier_round_resps: &[RoundResponse<E>],
+        round_req: &RoundRequest<E, P>,
+    ) -> RoundResponse<E> {
+        let g16_pk = self.g16_pks.get_pk(stage0_req.subcircuit_idx);
+        process_round_request::<_, TreeConfigVar<E>, _, P, _>(
+            &mut rng,
+            self.tree_params.clone(),
+            g16_pk,
+            stage0_req.to_owned(),
+            stage0_resp,
+            earlier_round_resps,
+            round_req.clone(),
+        )
+    }
+
+    pub fn stage_1(
+        self,
+        mut rng: impl Rng,
+        stage1_req: &Stage1RequestRef<E, P>,
+    ) -> Stage1Response<E> {
         // Use the builder to respond
         process_stage1_request_with_cb(
             &mut rng,
             self.cb.unwrap(),
-            self.com,
-            self.com_rand,
+            &self.coms,
+            &self.com_rands,
+            stage1_req.to_owned(),
+        )
+    }
+
+    /// Does stage 1 for a subcircuit whose earlier stages may have been done by a different worker.
+    /// The commitments are recomputed from the seeds in the stage 0 response and the responses of
+    /// every extra round, so this doesn't need `stage_0` to have been called first.
+    pub fn stage_1_from_seed(
+        &self,
+        mut rng: impl Rng,
+        stage0_req: &Stage0RequestRef<E>,
+        stage0_resp: &Stage0Response<E>,
+        round_resps: &[RoundResponse<E>],
+        stage1_req: &Stage1RequestRef<E, P>,
+    ) -> Stage1Response<E> {
+        let g16_pk = self.g16_pks.get_pk(stage0_req.subcircuit_idx);
+        process_stage1_request_with_rounds::<_, TreeConfigVar<E>, _, P, _>(
+            &mut rng,
+            self.tree_params.clone(),
+            g16_pk,
+            stage0_req.to_owned(),
+            stage0_resp,
+            round_resps,
             stage1_req.to_owned(),
         )
     }
@@ -91,7 +169,10 @@ impl<'a, P: CircuitWithPortals<Fr>> WorkerState<'a, P> {
 ///  `CommitterState` is `!Send` because it contains `ConstraintSystemRef`s.
 ///  However, no other thread has access to the `WorkerState` of another thread,
 ///  and so we don't have any mutable access issues.
-unsafe impl<'a, P: CircuitWithPortals<Fr>> Send for WorkerState<'a, P> {}
+unsafe impl<'a, E: SupportedCurve, P: CircuitWithPortals<E::ScalarField>> Send
+    for WorkerState<'a, E, P>
+{
+}
 
 /// Safety: This is only safe because:
 /// * In `node.rs`, `WorkerState` is only ever accessed by one thread at a time
@@ -99,4 +180,7 @@ unsafe impl<'a, P: CircuitWithPortals<Fr>> Send for WorkerState<'a, P> {}
 ///  `CommitterState` is `!Send` because it contains `ConstraintSystemRef`s.
 ///  However, no other thread has access to the `WorkerState` of another thread,
 ///  and so we don't have any mutable access issues.
-unsafe impl<'a, P: CircuitWithPortals<Fr>> Sync for WorkerState<'a, P> {}
+unsafe impl<'a, E: SupportedCurve, P: CircuitWithPortals<E::ScalarField>> Sync
+    for WorkerState<'a, E, P>
+{
+}